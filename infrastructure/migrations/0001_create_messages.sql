@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS messages (
+    nonce BIGINT,
+    chat_id TEXT,
+    signature TEXT,
+    content BYTEA,
+    content_iv BYTEA
+);
@@ -0,0 +1,4 @@
+-- chat_id and signature are stored as decoded bytes, matching how they are bound and read
+ALTER TABLE messages
+    ALTER COLUMN chat_id TYPE BYTEA USING convert_to(chat_id, 'UTF8'),
+    ALTER COLUMN signature TYPE BYTEA USING convert_to(signature, 'UTF8');
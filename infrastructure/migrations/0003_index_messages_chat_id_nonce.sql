@@ -0,0 +1,2 @@
+-- Serves the per-chat nonce range scans of fetch_history and the MAX(nonce) lookup
+CREATE INDEX IF NOT EXISTS messages_chat_id_nonce_idx ON messages (chat_id, nonce);
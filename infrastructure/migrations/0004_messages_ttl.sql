@@ -0,0 +1,4 @@
+-- Messages may carry a TTL counted from the moment they were stored
+ALTER TABLE messages
+    ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN IF NOT EXISTS ttl_seconds BIGINT;
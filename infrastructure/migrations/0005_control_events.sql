@@ -0,0 +1,8 @@
+-- Subscribe and unsubscribe actions, recorded so presence can be rebuilt after a restart
+CREATE TABLE IF NOT EXISTS control_events (
+    id BIGSERIAL PRIMARY KEY,
+    connection_id UUID NOT NULL,
+    chat_id TEXT NOT NULL,
+    action TEXT NOT NULL CHECK (action IN ('subscribe', 'unsubscribe')),
+    recorded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
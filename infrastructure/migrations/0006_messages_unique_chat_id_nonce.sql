@@ -0,0 +1,7 @@
+-- A chat holds each nonce once, so retried sends cannot store a message twice
+DELETE FROM messages a
+    USING messages b
+    WHERE a.chat_id = b.chat_id AND a.nonce = b.nonce AND a.ctid > b.ctid;
+CREATE UNIQUE INDEX IF NOT EXISTS messages_chat_id_nonce_key ON messages (chat_id, nonce);
+-- The unique index serves the same scans as the plain one it replaces
+DROP INDEX IF EXISTS messages_chat_id_nonce_idx;
@@ -0,0 +1,5 @@
+-- Nonce of the newest deleted message per chat, so nonces continue after a chat's messages are deleted
+CREATE TABLE IF NOT EXISTS chat_heads (
+    chat_id BYTEA PRIMARY KEY,
+    head BIGINT NOT NULL
+);
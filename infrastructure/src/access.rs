@@ -0,0 +1,158 @@
+use std::{env::var, fmt, net::IpAddr, sync::Arc};
+
+use ipnet::{AddrParseError, IpNet};
+use log::debug;
+
+/// Decides which peer addresses may connect to the server
+///
+/// A peer matching any denied network is rejected. Otherwise it is accepted
+/// if the allowlist is empty or one of its networks contains the peer.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    /// Networks peers must belong to, empty to allow every address
+    allow: Vec<IpNet>,
+    /// Networks that are always rejected
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Builds a filter from comma separated lists of CIDRs
+    ///
+    /// Bare addresses are accepted and treated as single-host networks.
+    ///
+    /// # Errors
+    /// Returns an error if any entry is neither a CIDR nor an IP address
+    pub fn parse(allow: &str, deny: &str) -> Result<Self, AddrParseError> {
+        Ok(Self {
+            allow: parse_networks(allow)?,
+            deny: parse_networks(deny)?,
+        })
+    }
+
+    /// Builds a filter from the environment
+    ///
+    /// # Environment Variables
+    /// - `ALLOW_CIDRS` - Networks allowed to connect (default: every address)
+    /// - `DENY_CIDRS` - Networks rejected before the handshake (default: none)
+    ///
+    /// # Errors
+    /// Returns an error if either variable contains an invalid entry
+    pub fn from_env() -> Result<Self, AddrParseError> {
+        let allow = var("ALLOW_CIDRS")
+            .inspect_err(|_| debug!("ALLOW_CIDRS environment variable is unset, allowing all..."))
+            .unwrap_or_default();
+        let deny = var("DENY_CIDRS").unwrap_or_default();
+
+        Self::parse(&allow, &deny)
+    }
+
+    /// Checks whether a peer with the given address may connect
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        // IPv4 peers on a dual-stack socket show up as IPv4-mapped IPv6 addresses
+        let addr = addr.to_canonical();
+
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&addr))
+    }
+}
+
+/// Bearer token guarding the admin endpoints
+#[derive(Clone)]
+pub struct AdminToken(Arc<str>);
+
+impl AdminToken {
+    /// Wraps the token clients have to present
+    pub fn new(token: &str) -> Self {
+        Self(token.into())
+    }
+
+    /// Reads the token from the environment
+    ///
+    /// # Environment Variables
+    /// - `ADMIN_TOKEN` - Token for the admin endpoints (default: unset, admin endpoints disabled)
+    pub fn from_env() -> Option<Self> {
+        var("ADMIN_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty())
+            .map(|token| Self::new(&token))
+    }
+
+    /// Checks whether an `Authorization` header value carries this token
+    ///
+    /// The comparison takes the same time wherever the tokens differ.
+    pub fn authorizes(&self, authorization: Option<&str>) -> bool {
+        let Some(presented) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        let (presented, expected) = (presented.as_bytes(), self.0.as_bytes());
+
+        presented.len() == expected.len()
+            && presented
+                .iter()
+                .zip(expected)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AdminToken(..)")
+    }
+}
+
+/// Parses a comma separated list of CIDRs or addresses, skipping empty entries
+fn parse_networks(list: &str) -> Result<Vec<IpNet>, AddrParseError> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.parse::<IpAddr>() {
+            Ok(addr) => Ok(IpNet::from(addr)),
+            Err(_) => entry.parse(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// Tests that a peer inside the allowlist is accepted while others are rejected
+    #[test]
+    fn test_allowed_ip() {
+        let filter = IpFilter::parse("10.0.0.0/8, 192.168.1.7", "").unwrap();
+
+        assert!(filter.is_allowed("10.1.2.3".parse().unwrap()));
+        assert!(filter.is_allowed("192.168.1.7".parse().unwrap()));
+        assert!(filter.is_allowed("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!filter.is_allowed("192.168.1.8".parse().unwrap()));
+    }
+
+    /// Tests that a denied peer is rejected even if the allowlist contains it
+    #[test]
+    fn test_denied_ip() {
+        let filter = IpFilter::parse("10.0.0.0/8", "10.66.0.0/16,2001:db8::/32").unwrap();
+
+        assert!(!filter.is_allowed("10.66.1.1".parse().unwrap()));
+        assert!(!filter.is_allowed("2001:db8::1".parse().unwrap()));
+        assert!(filter.is_allowed("10.65.1.1".parse().unwrap()));
+    }
+
+    /// Tests that without any configuration every peer is accepted
+    #[test]
+    fn test_default_allows_all() {
+        let filter = IpFilter::default();
+
+        assert!(filter.is_allowed("203.0.113.9".parse().unwrap()));
+        assert!(filter.is_allowed("::1".parse().unwrap()));
+        assert!(
+            IpFilter::parse("", " ")
+                .unwrap()
+                .is_allowed("127.0.0.1".parse().unwrap())
+        );
+        assert!(IpFilter::parse("not-a-cidr", "").is_err());
+    }
+}
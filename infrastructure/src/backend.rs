@@ -0,0 +1,419 @@
+use std::{collections::HashMap, env::var, fmt, str::FromStr, time::Duration};
+
+use anyhow::{Result, anyhow};
+use futures::{Stream, StreamExt};
+use log::{error, info, warn};
+use protocol::entity::{
+    message::{Message, OutcomeMessage},
+    websocket::ControlAction,
+};
+use tokio::task::JoinHandle;
+use traits::message::{InsertOutcome, MessagesDB};
+use uuid::Uuid;
+
+use crate::{
+    database::PostgresDatabase, memory::MemoryDatabase, redis_db::RedisDatabase,
+    sqlite::SqliteDatabase,
+};
+
+/// Storage backends that can be selected at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DbBackend {
+    /// PostgreSQL, see [`PostgresDatabase`]
+    #[default]
+    Postgres,
+    /// SQLite, see [`SqliteDatabase`]
+    Sqlite,
+    /// Process memory, see [`MemoryDatabase`]
+    Memory,
+    /// Redis, see [`RedisDatabase`]
+    Redis,
+}
+
+impl FromStr for DbBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "postgres" => Ok(Self::Postgres),
+            "sqlite" => Ok(Self::Sqlite),
+            "memory" => Ok(Self::Memory),
+            "redis" => Ok(Self::Redis),
+            other => Err(anyhow!(
+                "unknown database backend {other:?}, expected postgres, sqlite, memory or redis"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DbBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Postgres => "postgres",
+            Self::Sqlite => "sqlite",
+            Self::Memory => "memory",
+            Self::Redis => "redis",
+        })
+    }
+}
+
+/// Message store backed by whichever backend was selected at runtime
+///
+/// Dispatches every [`MessagesDB`] call to the wrapped store, so the use
+/// cases stay generic while the concrete backend is chosen by configuration.
+#[derive(Clone)]
+pub enum AnyDb {
+    /// Messages stored in PostgreSQL
+    Postgres(PostgresDatabase),
+    /// Messages stored in SQLite
+    Sqlite(SqliteDatabase),
+    /// Messages stored in process memory
+    Memory(MemoryDatabase),
+    /// Messages stored in Redis, boxed as its connection is much larger than a pool handle
+    Redis(Box<RedisDatabase>),
+}
+
+impl AnyDb {
+    /// Opens the backend selected by the environment
+    ///
+    /// # Errors
+    /// Will return an error if the backend is unknown or cannot be opened
+    ///
+    /// # Environment Variables
+    /// - `DB_BACKEND` - One of `postgres`, `sqlite`, `memory` or `redis` (default: "postgres")
+    pub async fn from_env() -> Result<Self> {
+        let backend = match var("DB_BACKEND") {
+            Ok(value) => value.parse()?,
+            Err(_) => {
+                warn!("DB_BACKEND environment variable is unset, using default...");
+                DbBackend::default()
+            }
+        };
+
+        Self::open(backend).await
+    }
+
+    /// Opens the given backend, reading its connection settings from the environment
+    ///
+    /// # Arguments
+    /// * `backend` - Backend to open
+    pub async fn open(backend: DbBackend) -> Result<Self> {
+        info!("Using the {backend} database backend");
+
+        Ok(match backend {
+            DbBackend::Postgres => Self::Postgres(PostgresDatabase::new().await?),
+            DbBackend::Sqlite => Self::Sqlite(SqliteDatabase::new().await?),
+            DbBackend::Memory => Self::Memory(MemoryDatabase::default()),
+            DbBackend::Redis => Self::Redis(Box::new(RedisDatabase::new().await?)),
+        })
+    }
+
+    /// Returns the backend this store dispatches to
+    pub fn backend(&self) -> DbBackend {
+        match self {
+            Self::Postgres(_) => DbBackend::Postgres,
+            Self::Sqlite(_) => DbBackend::Sqlite,
+            Self::Memory(_) => DbBackend::Memory,
+            Self::Redis(_) => DbBackend::Redis,
+        }
+    }
+
+    /// Deletes every message whose TTL has passed
+    ///
+    /// # Returns
+    /// * `Result<Vec<Vec<u8>>>` - Chats that messages were deleted from
+    pub async fn delete_expired(&self) -> Result<Vec<Vec<u8>>> {
+        match self {
+            Self::Postgres(db) => db.delete_expired().await,
+            Self::Sqlite(db) => db.delete_expired().await,
+            Self::Memory(db) => db.delete_expired().await,
+            Self::Redis(db) => db.delete_expired().await,
+        }
+    }
+
+    /// Spawns a background task that periodically prunes messages older than `ttl`
+    ///
+    /// Only the Postgres backend records when messages were stored, the
+    /// others keep their messages and no task is spawned.
+    ///
+    /// # Arguments
+    /// * `interval` - Time between two sweeps
+    /// * `ttl` - Age after which messages are deleted
+    /// * `on_pruned` - Called with the chats each sweep deleted messages from
+    pub fn spawn_retention_sweeper(
+        &self,
+        interval: Duration,
+        ttl: Duration,
+        on_pruned: impl Fn(&[Vec<u8>]) + Send + 'static,
+    ) -> Option<JoinHandle<()>> {
+        match self {
+            Self::Postgres(db) => Some(db.spawn_retention_sweeper(interval, ttl, on_pruned)),
+            other => {
+                warn!(
+                    "The {} backend does not prune messages by age, MESSAGE_TTL_SECS is ignored",
+                    other.backend()
+                );
+                None
+            }
+        }
+    }
+
+    /// Spawns a background task that periodically deletes expired messages
+    ///
+    /// # Arguments
+    /// * `interval` - Time between two sweeps
+    /// * `on_expired` - Called with the chats each sweep deleted messages from
+    pub fn spawn_expiry_sweeper(
+        &self,
+        interval: Duration,
+        on_expired: impl Fn(&[Vec<u8>]) + Send + 'static,
+    ) -> JoinHandle<()> {
+        let db = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match db.delete_expired().await {
+                    Ok(chats) if chats.is_empty() => {}
+                    Ok(chats) => {
+                        info!("Deleted expired messages from {} chats", chats.len());
+                        on_expired(&chats);
+                    }
+                    Err(e) => error!("failed to delete expired messages: {e}"),
+                }
+            }
+        })
+    }
+}
+
+impl MessagesDB for AnyDb {
+    async fn insert_message(&self, message: Message) -> Result<InsertOutcome> {
+        match self {
+            Self::Postgres(db) => db.insert_message(message).await,
+            Self::Sqlite(db) => db.insert_message(message).await,
+            Self::Memory(db) => db.insert_message(message).await,
+            Self::Redis(db) => db.insert_message(message).await,
+        }
+    }
+
+    async fn fetch_history(
+        &self,
+        chat_id: &[u8],
+        nonce: usize,
+        amount: usize,
+    ) -> Result<Vec<OutcomeMessage>> {
+        match self {
+            Self::Postgres(db) => db.fetch_history(chat_id, nonce, amount).await,
+            Self::Sqlite(db) => db.fetch_history(chat_id, nonce, amount).await,
+            Self::Memory(db) => db.fetch_history(chat_id, nonce, amount).await,
+            Self::Redis(db) => db.fetch_history(chat_id, nonce, amount).await,
+        }
+    }
+
+    fn stream_history<'a>(
+        &'a self,
+        chat_id: &'a [u8],
+        nonce: usize,
+        amount: usize,
+    ) -> impl Stream<Item = Result<OutcomeMessage>> + Send + 'a {
+        match self {
+            Self::Postgres(db) => db.stream_history(chat_id, nonce, amount).boxed(),
+            Self::Sqlite(db) => db.stream_history(chat_id, nonce, amount).boxed(),
+            Self::Memory(db) => db.stream_history(chat_id, nonce, amount).boxed(),
+            Self::Redis(db) => db.stream_history(chat_id, nonce, amount).boxed(),
+        }
+    }
+
+    async fn fetch_recent(&self, chat_id: &[u8], amount: usize) -> Result<Vec<OutcomeMessage>> {
+        match self {
+            Self::Postgres(db) => db.fetch_recent(chat_id, amount).await,
+            Self::Sqlite(db) => db.fetch_recent(chat_id, amount).await,
+            Self::Memory(db) => db.fetch_recent(chat_id, amount).await,
+            Self::Redis(db) => db.fetch_recent(chat_id, amount).await,
+        }
+    }
+
+    async fn last_nonce(&self, chat_id: &[u8]) -> Result<usize> {
+        match self {
+            Self::Postgres(db) => db.last_nonce(chat_id).await,
+            Self::Sqlite(db) => db.last_nonce(chat_id).await,
+            Self::Memory(db) => db.last_nonce(chat_id).await,
+            Self::Redis(db) => db.last_nonce(chat_id).await,
+        }
+    }
+
+    async fn head_nonces(&self, chat_ids: &[Vec<u8>]) -> Result<HashMap<Vec<u8>, usize>> {
+        match self {
+            Self::Postgres(db) => db.head_nonces(chat_ids).await,
+            Self::Sqlite(db) => db.head_nonces(chat_ids).await,
+            Self::Memory(db) => db.head_nonces(chat_ids).await,
+            Self::Redis(db) => db.head_nonces(chat_ids).await,
+        }
+    }
+
+    async fn record_control_event(
+        &self,
+        connection_id: Uuid,
+        chat_id: &str,
+        action: ControlAction,
+    ) -> Result<()> {
+        match self {
+            Self::Postgres(db) => {
+                db.record_control_event(connection_id, chat_id, action)
+                    .await
+            }
+            Self::Sqlite(db) => {
+                db.record_control_event(connection_id, chat_id, action)
+                    .await
+            }
+            Self::Memory(db) => {
+                db.record_control_event(connection_id, chat_id, action)
+                    .await
+            }
+            Self::Redis(db) => {
+                db.record_control_event(connection_id, chat_id, action)
+                    .await
+            }
+        }
+    }
+
+    async fn ping(&self) -> Result<()> {
+        match self {
+            Self::Postgres(db) => db.ping().await,
+            Self::Sqlite(db) => db.ping().await,
+            Self::Memory(db) => db.ping().await,
+            Self::Redis(db) => db.ping().await,
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use misc::base64::encode_base64;
+
+    use super::*;
+
+    /// Stores two messages and reads them back through every query
+    async fn assert_working_store(db: &AnyDb) {
+        let chat_id = uuid::Uuid::new_v4().as_bytes().repeat(2);
+        for nonce in 1..=2 {
+            let message = Message {
+                nonce,
+                chat_id: encode_base64(&chat_id).await,
+                signature: encode_base64(b"signature").await,
+                content: encode_base64(b"content").await,
+                content_iv: encode_base64(&[nonce as u8; 12]).await,
+                ..Default::default()
+            };
+            db.insert_message(message).await.unwrap();
+        }
+        // A message sent without a nonce is stored under the next one
+        let auto = Message {
+            nonce: Message::AUTO_NONCE,
+            chat_id: encode_base64(&chat_id).await,
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[3; 12]).await,
+            ..Default::default()
+        };
+        let outcome = db.insert_message(auto).await.unwrap();
+        assert_eq!(outcome, InsertOutcome::Inserted(3));
+
+        let history = db.fetch_history(&chat_id, 1, 10).await.unwrap();
+        assert_eq!(
+            history.iter().map(|m| m.nonce).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        assert_eq!(history[1].content_iv, encode_base64(&[2; 12]).await);
+        let recent = db.fetch_recent(&chat_id, 1).await.unwrap();
+        assert_eq!(recent[0].nonce, 3);
+        assert_eq!(db.last_nonce(&chat_id).await.unwrap(), 3);
+
+        let empty_chat = uuid::Uuid::new_v4().as_bytes().repeat(2);
+        let heads = db
+            .head_nonces(&[chat_id.clone(), empty_chat])
+            .await
+            .unwrap();
+        assert_eq!(heads, HashMap::from([(chat_id, 3)]));
+    }
+
+    /// Expires every message of a chat and checks that its nonces continue
+    async fn assert_expiry_keeps_head(db: &AnyDb) {
+        let chat_id = uuid::Uuid::new_v4().as_bytes().repeat(2);
+        let message = async || Message {
+            nonce: Message::AUTO_NONCE,
+            chat_id: encode_base64(&chat_id).await,
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[1; 12]).await,
+            ttl_seconds: Some(0),
+        };
+        for _ in 0..2 {
+            db.insert_message(message().await).await.unwrap();
+        }
+
+        assert!(db.delete_expired().await.unwrap().contains(&chat_id));
+
+        assert!(db.fetch_history(&chat_id, 0, 10).await.unwrap().is_empty());
+        assert_eq!(db.last_nonce(&chat_id).await.unwrap(), 2);
+        let heads = db
+            .head_nonces(std::slice::from_ref(&chat_id))
+            .await
+            .unwrap();
+        assert_eq!(heads, HashMap::from([(chat_id.clone(), 2)]));
+        let outcome = db.insert_message(message().await).await.unwrap();
+        assert_eq!(outcome, InsertOutcome::Inserted(3));
+    }
+
+    /// Tests that every `DB_BACKEND` value parses and unknown values are rejected
+    #[test]
+    fn test_backend_values_parse() {
+        for backend in [
+            DbBackend::Postgres,
+            DbBackend::Sqlite,
+            DbBackend::Memory,
+            DbBackend::Redis,
+        ] {
+            assert_eq!(backend.to_string().parse::<DbBackend>().unwrap(), backend);
+        }
+        assert_eq!(" Memory ".parse::<DbBackend>().unwrap(), DbBackend::Memory);
+        assert!("mongo".parse::<DbBackend>().is_err());
+    }
+
+    /// Tests that the memory backend constructs a working store
+    #[tokio::test]
+    async fn test_memory_backend_is_working_store() {
+        let db = AnyDb::open(DbBackend::Memory).await.unwrap();
+
+        assert_eq!(db.backend(), DbBackend::Memory);
+        assert_working_store(&db).await;
+        assert_expiry_keeps_head(&db).await;
+    }
+
+    /// Tests that the sqlite backend constructs a working store
+    #[tokio::test]
+    async fn test_sqlite_backend_is_working_store() {
+        let db = AnyDb::Sqlite(SqliteDatabase::connect("sqlite::memory:").await.unwrap());
+
+        assert_eq!(db.backend(), DbBackend::Sqlite);
+        assert_working_store(&db).await;
+        assert_expiry_keeps_head(&db).await;
+    }
+
+    /// Tests that the postgres backend dispatches to a store that answers queries
+    #[tokio::test]
+    #[ignore = "requires a running Postgres at DATABASE_URL"]
+    async fn test_postgres_backend_is_working_store() {
+        let url =
+            std::env::var("DATABASE_URL").expect("DATABASE_URL must point to a test database");
+        let db = AnyDb::Postgres(PostgresDatabase::connect(&url).await.unwrap());
+
+        assert_eq!(db.backend(), DbBackend::Postgres);
+        let chat_id = uuid::Uuid::new_v4().as_bytes().repeat(2);
+        assert!(db.fetch_history(&chat_id, 0, 10).await.unwrap().is_empty());
+        assert_eq!(db.last_nonce(&chat_id).await.unwrap(), 0);
+        assert!(db.head_nonces(&[chat_id]).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+use std::{hash::Hash, num::NonZeroUsize, sync::Mutex};
+
+use lru::LruCache;
+use prometheus::IntCounter;
+
+/// Set of recently seen keys with a fixed capacity
+///
+/// When full, inserting a new key evicts the least recently seen one and
+/// counts the eviction. An evicted key is simply forgotten, so callers relying
+/// on the cache for deduplication lose protection for it but stay correct.
+pub struct BoundedCache<K: Hash + Eq> {
+    /// Keys ordered by how recently they were seen
+    entries: Mutex<LruCache<K, ()>>,
+    /// Counter incremented for every evicted key
+    evictions: IntCounter,
+}
+
+impl<K: Hash + Eq> BoundedCache<K> {
+    /// Creates an empty cache
+    ///
+    /// # Arguments
+    /// * `capacity` - Maximum number of keys kept, at least 1
+    /// * `evictions` - Counter incremented for every evicted key
+    pub fn new(capacity: usize, evictions: IntCounter) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            evictions,
+        }
+    }
+
+    /// Records a key as seen
+    ///
+    /// # Returns
+    /// `true` if the key was not in the cache yet
+    pub fn insert(&self, key: K) -> bool {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.get(&key).is_some() {
+            return false;
+        }
+
+        if entries.push(key, ()).is_some() {
+            self.evictions.inc();
+        }
+        true
+    }
+
+    /// Checks whether a key is in the cache without refreshing it
+    pub fn contains(&self, key: &K) -> bool {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(key)
+    }
+
+    /// Returns the number of keys in the cache
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Checks whether the cache holds no keys
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::Metrics;
+
+    use super::*;
+
+    /// Tests that exceeding the capacity evicts the oldest keys and counts the evictions
+    #[test]
+    fn test_exceeding_capacity_evicts_oldest() {
+        let metrics = Metrics::new();
+        let cache = BoundedCache::new(2, metrics.cache_evictions("test"));
+
+        assert!(cache.insert(1));
+        assert!(cache.insert(2));
+        assert!(!cache.insert(1));
+        assert!(cache.insert(3));
+        assert!(cache.insert(4));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(&3));
+        assert!(cache.contains(&4));
+        assert!(!cache.contains(&1));
+        assert!(!cache.contains(&2));
+        assert_eq!(metrics.cache_evictions("test").get(), 2);
+    }
+}
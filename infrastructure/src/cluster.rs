@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::StreamExt;
+use log::{error, info};
+use protocol::entity::message::ClusterEvent;
+use redis::{AsyncCommands, Client, aio::ConnectionManager};
+use traits::message::{MessagesDB, MessagesRepository};
+
+use crate::{redis_db::redis_url, websocket::WebSocketService};
+
+/// Prefix of the channel each chat's events are published on
+const CHANNEL_PREFIX: &str = "seed:chat-events:";
+
+/// Relays broadcasts between the instances of a cluster over Redis pub/sub
+///
+/// Every instance publishes the events it broadcasts on a channel named
+/// after the chat and subscribes to the channels of all chats. Events come
+/// back to the instance that published them too, the use case recognizes
+/// and skips those by their origin.
+pub struct RedisRelay {
+    /// Client both the publishing and the subscribing connection are opened from
+    client: Client,
+}
+
+impl RedisRelay {
+    /// Creates a relay for the configured server
+    ///
+    /// # Environment Variables
+    /// - `REDIS_URL` - Redis connection URL (default: "redis://127.0.0.1:6379")
+    ///
+    /// # Errors
+    /// Will return an error if the URL is invalid
+    pub fn from_env() -> Result<Self> {
+        Self::open(&redis_url())
+    }
+
+    /// Creates a relay for the server at the given URL
+    ///
+    /// # Errors
+    /// Will return an error if the URL is invalid
+    pub fn open(connection_url: &str) -> Result<Self> {
+        let client = Client::open(connection_url)
+            .inspect_err(|e| error!("invalid redis connection url: {e}"))?;
+        Ok(Self { client })
+    }
+
+    /// Starts relaying events between this instance and the rest of the cluster
+    ///
+    /// Spawns one task publishing the events taken from `outbound` and one
+    /// delivering the events of the cluster to the subscribers of `service`.
+    ///
+    /// # Arguments
+    /// * `outbound` - Receiver of the events this instance broadcasts
+    /// * `service` - Service whose subscribers receive the events of the cluster
+    ///
+    /// # Errors
+    /// Will return an error if unable to connect or subscribe to the server
+    pub async fn spawn<MR, DB>(
+        self,
+        outbound: flume::Receiver<ClusterEvent>,
+        service: Arc<WebSocketService<MR, DB>>,
+    ) -> Result<()>
+    where
+        MR: MessagesRepository + Clone + Send + Sync + 'static,
+        DB: MessagesDB + Clone + Send + Sync + 'static,
+    {
+        let mut publisher = ConnectionManager::new(self.client.clone())
+            .await
+            .inspect_err(|e| error!("failed to connect to redis: {e}"))?;
+        let mut pubsub = self
+            .client
+            .get_async_pubsub()
+            .await
+            .inspect_err(|e| error!("failed to connect to redis: {e}"))?;
+        pubsub.psubscribe(format!("{CHANNEL_PREFIX}*")).await?;
+
+        tokio::spawn(async move {
+            while let Ok(event) = outbound.recv_async().await {
+                let channel = format!("{CHANNEL_PREFIX}{}", event.message.chat_id);
+                let payload = match serde_json::to_string(&event) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        error!("Error encoding cluster event: {e}");
+                        continue;
+                    }
+                };
+                if let Err(e) = publisher.publish::<_, _, ()>(&channel, payload).await {
+                    error!("Error publishing event on {channel}: {e}");
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            // Events are delivered one at a time so each chat keeps its order
+            let mut messages = pubsub.into_on_message();
+            while let Some(message) = messages.next().await {
+                let event = message
+                    .get_payload::<String>()
+                    .map_err(anyhow::Error::from)
+                    .and_then(|payload| Ok(serde_json::from_str::<ClusterEvent>(&payload)?));
+                match event {
+                    Ok(event) => {
+                        service.deliver_remote(event).await;
+                    }
+                    Err(e) => error!(
+                        "Error decoding cluster event on {}: {e}",
+                        message.get_channel_name()
+                    ),
+                }
+            }
+            error!("Cluster subscription ended, events of other instances are no longer delivered");
+        });
+
+        info!("Relaying broadcasts through the cluster");
+        Ok(())
+    }
+}
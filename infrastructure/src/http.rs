@@ -0,0 +1,599 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use protocol::entity::websocket::{ChatStat, ServerStatus};
+use serde_json::json;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request},
+    http::{Response, StatusCode, header},
+};
+
+use crate::access::AdminToken;
+
+/// Default upper bound for the size of an HTTP request head
+pub const MAX_REQUEST_HEAD_BYTES: usize = 8192;
+
+/// Default upper bound for the size of a request target
+pub const MAX_REQUEST_URI_BYTES: usize = 2048;
+
+/// WebSocket subprotocol the server speaks
+pub const SUBPROTOCOL: &str = "seed.v1";
+
+/// Maximum number of headers accepted in a single request
+const MAX_HEADERS: usize = 64;
+
+/// Room left on the request line for the method and version around the target
+const REQUEST_LINE_OVERHEAD: usize = 32;
+
+/// Size limits enforced while reading a request head
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    /// Maximum number of bytes the whole head may occupy
+    pub max_head_bytes: usize,
+
+    /// Maximum number of bytes the request target may occupy
+    pub max_uri_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_head_bytes: MAX_REQUEST_HEAD_BYTES,
+            max_uri_bytes: MAX_REQUEST_URI_BYTES,
+        }
+    }
+}
+
+/// The parsed head of an HTTP request
+///
+/// Keeps the raw bytes read from the socket so that a WebSocket upgrade can
+/// be handed to the handshake as if nothing had been consumed.
+#[derive(Debug)]
+pub struct RequestHead {
+    /// Request method, e.g. `GET`
+    pub method: String,
+
+    /// Request target including the query string
+    pub path: String,
+
+    /// Request headers in the order they were received
+    pub headers: Vec<(String, String)>,
+
+    /// Every byte read from the socket while parsing the head
+    raw: Vec<u8>,
+}
+
+impl RequestHead {
+    /// Returns the value of the first header matching `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the request path without the query string
+    pub fn route(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    /// Returns the value of the first query parameter named `name`
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        let (_, query) = self.path.split_once('?')?;
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Checks whether the request asks for a WebSocket upgrade
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+    }
+
+    /// Consumes the head, returning the raw bytes read from the socket
+    pub fn into_raw(self) -> Vec<u8> {
+        self.raw
+    }
+}
+
+/// Errors that can occur while reading an HTTP request head
+#[derive(Error, Debug)]
+pub enum HttpError {
+    /// The request head exceeded the configured size limit
+    #[error("request head is too large")]
+    HeadTooLarge,
+
+    /// The request target exceeded the configured size limit
+    #[error("request target is too long")]
+    UriTooLong,
+
+    /// The peer closed the connection before sending a complete head
+    #[error("connection closed before the request head was complete")]
+    Closed,
+
+    /// The request head could not be parsed
+    #[error("malformed request head: {0}")]
+    Malformed(#[from] httparse::Error),
+
+    /// Reading from the socket failed
+    #[error("failed to read request head: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl HttpError {
+    /// Returns the response the peer should receive, if it is still listening
+    pub fn response(&self) -> Option<Response<String>> {
+        let (status, error) = match self {
+            Self::HeadTooLarge => (
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                "request header fields too large",
+            ),
+            Self::UriTooLong => (StatusCode::URI_TOO_LONG, "uri too long"),
+            Self::Malformed(_) => (StatusCode::BAD_REQUEST, "bad request"),
+            Self::Closed | Self::Io(_) => return None,
+        };
+
+        Some(json_response(status, format!(r#"{{"error":"{error}"}}"#)))
+    }
+}
+
+/// Reads and parses an HTTP request head from the stream
+///
+/// # Arguments
+/// * `stream` - Stream to read the request from
+/// * `limits` - Size limits for the head and the request target
+///
+/// # Errors
+/// Returns [`HttpError::HeadTooLarge`] if no complete head fits in the head limit
+/// and [`HttpError::UriTooLong`] if the request target exceeds the URI limit
+pub async fn read_request_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    limits: RequestLimits,
+) -> Result<RequestHead, HttpError> {
+    let max_size = limits.max_head_bytes;
+
+    let mut raw = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(HttpError::Closed);
+        }
+        raw.extend_from_slice(&chunk[..read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+
+        let status = request.parse(&raw)?;
+
+        // Reject oversized targets as soon as they are known, or as soon as the
+        // request line has grown too long to hold a target within the limit
+        let line_unfinished = !raw.contains(&b'\n');
+        if request
+            .path
+            .is_some_and(|path| path.len() > limits.max_uri_bytes)
+            || (line_unfinished && raw.len() > limits.max_uri_bytes + REQUEST_LINE_OVERHEAD)
+        {
+            return Err(HttpError::UriTooLong);
+        }
+
+        match status {
+            httparse::Status::Complete(len) if len <= max_size => {
+                let method = request.method.unwrap_or_default().to_string();
+                let path = request.path.unwrap_or_default().to_string();
+                let headers = request
+                    .headers
+                    .iter()
+                    .map(|h| {
+                        let value = String::from_utf8_lossy(h.value).into_owned();
+                        (h.name.to_string(), value)
+                    })
+                    .collect();
+
+                return Ok(RequestHead {
+                    method,
+                    path,
+                    headers,
+                    raw,
+                });
+            }
+            httparse::Status::Complete(_) => return Err(HttpError::HeadTooLarge),
+            httparse::Status::Partial if raw.len() >= max_size => {
+                return Err(HttpError::HeadTooLarge);
+            }
+            httparse::Status::Partial => continue,
+        }
+    }
+}
+
+/// Builds a JSON response with the given status and body
+pub fn json_response(status: StatusCode, body: String) -> Response<String> {
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+/// Builds the response of the health check from its outcome
+///
+/// Answers 200 `{"status":"ok"}` when healthy and 503 with the error otherwise.
+pub fn health_response(outcome: anyhow::Result<()>) -> Response<String> {
+    match outcome {
+        Ok(()) => json_response(StatusCode::OK, json!({"status": "ok"}).to_string()),
+        Err(err) => json_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({"status": "unavailable", "error": err.to_string()}).to_string(),
+        ),
+    }
+}
+
+/// Builds the response of the status endpoint
+pub fn status_response(status: ServerStatus) -> Response<String> {
+    json_response(StatusCode::OK, json!(status).to_string())
+}
+
+/// Builds the response of the admin chat listing
+///
+/// The endpoint is disabled without a configured token and answers 404 like
+/// an unknown route. A request without the right bearer token gets a 401.
+///
+/// # Arguments
+/// * `head` - The request, whose `Authorization` header is checked
+/// * `token` - The configured admin token, if any
+/// * `stats` - Gathers the live chats once the request is authorized
+pub fn admin_chats_response(
+    head: &RequestHead,
+    token: Option<&AdminToken>,
+    stats: impl FnOnce() -> Vec<ChatStat>,
+) -> Response<String> {
+    let Some(token) = token else {
+        return json_response(
+            StatusCode::NOT_FOUND,
+            json!({"error": "not found"}).to_string(),
+        );
+    };
+    if !token.authorizes(head.header("authorization")) {
+        let mut response = json_response(
+            StatusCode::UNAUTHORIZED,
+            json!({"error": "unauthorized"}).to_string(),
+        );
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            header::HeaderValue::from_static("Bearer"),
+        );
+        return response;
+    }
+
+    json_response(StatusCode::OK, json!(stats()).to_string())
+}
+
+/// Answers the subprotocols a client offers in its WebSocket handshake
+///
+/// [`SUBPROTOCOL`] is echoed back if the client offers it. A client offering
+/// nothing is accepted without a subprotocol. A client offering only other
+/// subprotocols is accepted the same way, or refused with a 400 if `strict`.
+///
+/// # Arguments
+/// * `request` - The handshake request, whose `Sec-WebSocket-Protocol` headers are read
+/// * `response` - The handshake response the negotiated subprotocol is added to
+/// * `strict` - Whether to refuse clients offering only unsupported subprotocols
+#[allow(clippy::result_large_err)]
+pub fn negotiate_subprotocol(
+    request: &Request,
+    mut response: Response<()>,
+    strict: bool,
+) -> Result<Response<()>, ErrorResponse> {
+    let mut offered = request
+        .headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .peekable();
+    if offered.peek().is_none() {
+        return Ok(response);
+    }
+
+    if offered.any(|protocol| protocol == SUBPROTOCOL) {
+        response.headers_mut().insert(
+            header::SEC_WEBSOCKET_PROTOCOL,
+            header::HeaderValue::from_static(SUBPROTOCOL),
+        );
+        return Ok(response);
+    }
+
+    if strict {
+        let mut refusal = Response::new(Some(
+            json!({"error": format!("unsupported subprotocol, expected {SUBPROTOCOL}")})
+                .to_string(),
+        ));
+        *refusal.status_mut() = StatusCode::BAD_REQUEST;
+        return Err(refusal);
+    }
+    Ok(response)
+}
+
+/// Builds a plain text response in the Prometheus text exposition format
+pub fn metrics_response(body: String) -> Response<String> {
+    let mut response = Response::new(body);
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    response
+}
+
+/// Writes a complete HTTP/1.1 response to the stream and flushes it
+///
+/// The response always carries `Content-Length` and `Connection: close`.
+pub async fn write_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    response: Response<String>,
+) -> io::Result<()> {
+    let (parts, body) = response.into_parts();
+
+    let mut output = format!(
+        "HTTP/1.1 {} {}\r\n",
+        parts.status.as_u16(),
+        parts.status.canonical_reason().unwrap_or_default()
+    );
+    for (name, value) in parts.headers.iter() {
+        output.push_str(&format!(
+            "{}: {}\r\n",
+            name,
+            value.to_str().unwrap_or_default()
+        ));
+    }
+    output.push_str(&format!(
+        "content-length: {}\r\nconnection: close\r\n\r\n",
+        body.len()
+    ));
+    output.push_str(&body);
+
+    stream.write_all(output.as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Stream wrapper that replays already consumed bytes before reading from the inner stream
+///
+/// Used to hand a connection whose request head was already read to the
+/// WebSocket handshake.
+pub struct Replay<S> {
+    /// Bytes to yield before reading from `inner`
+    prefix: Vec<u8>,
+    /// Number of prefix bytes already yielded
+    position: usize,
+    /// The underlying stream
+    inner: S,
+}
+
+impl<S> Replay<S> {
+    /// Creates a new stream that yields `prefix` before reading from `inner`
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replay<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position < self.prefix.len() {
+            let remaining = &self.prefix[self.position..];
+            let len = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..len]);
+            self.position += len;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replay<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use protocol::entity::{
+        message::IncomeMessage,
+        websocket::{ConnectedMessage, WebSocketManager},
+    };
+    use serde_json::Value;
+    use use_case::test_utils::channel_connection;
+
+    use super::*;
+
+    /// Builds a request head for `GET /admin/chats` with the given headers
+    fn admin_request(headers: &[(&str, &str)]) -> RequestHead {
+        RequestHead {
+            method: "GET".to_string(),
+            path: "/admin/chats".to_string(),
+            headers: headers
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            raw: Vec::new(),
+        }
+    }
+
+    /// Tests that the admin listing reports seeded chats only to the right bearer token
+    #[test]
+    fn test_admin_chats_lists_live_chats() {
+        let manager = WebSocketManager::default();
+        let (alice, _alice_rx) = channel_connection();
+        let (bob, _bob_rx) = channel_connection();
+        manager.add_subscription(&alice, "first", usize::MAX);
+        manager.add_subscription(&bob, "first", usize::MAX);
+        manager.add_subscription(&bob, "second", usize::MAX);
+        let (sender, receiver) = flume::unbounded();
+        for _ in 0..2 {
+            sender
+                .send(ConnectedMessage {
+                    connection: alice.clone(),
+                    message: IncomeMessage::Ping,
+                })
+                .unwrap();
+        }
+        manager
+            .message_queues
+            .insert("second".to_string(), (sender, receiver));
+        let token = AdminToken::new("operator");
+
+        let response = admin_chats_response(
+            &admin_request(&[("Authorization", "Bearer operator")]),
+            Some(&token),
+            || manager.snapshot(),
+        );
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(
+            body,
+            json!([
+                {"chat_id": "first", "subscriber_count": 2, "queued_messages": 0},
+                {"chat_id": "second", "subscriber_count": 1, "queued_messages": 2},
+            ])
+        );
+
+        for headers in [vec![], vec![("Authorization", "Bearer guessed")]] {
+            let response =
+                admin_chats_response(&admin_request(&headers), Some(&token), || unreachable!());
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = admin_chats_response(&admin_request(&[]), None, || unreachable!());
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Tests that a request head is parsed and its raw bytes are replayed unchanged
+    #[tokio::test]
+    async fn test_read_request_head_and_replay() {
+        let request = b"GET /ws?x=1 HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\r\n";
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(request).await.unwrap();
+
+        let head = read_request_head(&mut server, RequestLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.route(), "/ws");
+        assert_eq!(head.query_param("x"), Some("1"));
+        assert_eq!(head.query_param("y"), None);
+        assert!(head.is_websocket_upgrade());
+
+        let mut replay = Replay::new(head.into_raw(), server);
+        let mut replayed = vec![0u8; request.len()];
+        replay.read_exact(&mut replayed).await.unwrap();
+        assert_eq!(replayed, request);
+    }
+
+    /// Tests that an upgrade request with an oversized target is rejected before the handshake
+    #[tokio::test]
+    async fn test_oversized_request_line_is_rejected() {
+        let limits = RequestLimits {
+            max_uri_bytes: 64,
+            ..Default::default()
+        };
+        let request = format!(
+            "GET /ws?{} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\r\n",
+            "x".repeat(512)
+        );
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let err = read_request_head(&mut server, limits).await.unwrap_err();
+
+        assert!(matches!(err, HttpError::UriTooLong));
+        assert_eq!(err.response().unwrap().status(), StatusCode::URI_TOO_LONG);
+    }
+
+    /// Tests that a request line without a line break is rejected once it outgrows the limit
+    #[tokio::test]
+    async fn test_unterminated_request_line_is_rejected() {
+        let limits = RequestLimits {
+            max_uri_bytes: 64,
+            ..Default::default()
+        };
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client
+            .write_all(format!("GET /{}", "x".repeat(512)).as_bytes())
+            .await
+            .unwrap();
+
+        let err = read_request_head(&mut server, limits).await.unwrap_err();
+
+        assert!(matches!(err, HttpError::UriTooLong));
+    }
+
+    /// Performs a handshake offering `protocols` and returns the lowercased response head
+    async fn handshake_offering(protocols: &str, strict: bool) -> String {
+        let request = format!(
+            "GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Protocol: {protocols}\r\n\r\n"
+        );
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        #[allow(clippy::result_large_err)]
+        let callback =
+            |request: &Request, response| negotiate_subprotocol(request, response, strict);
+        let _ = tokio_tungstenite::accept_hdr_async(server, callback).await;
+
+        let mut response = vec![0u8; 1024];
+        let read = client.read(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response[..read]).to_ascii_lowercase()
+    }
+
+    /// Tests that the seed subprotocol is echoed back and strict mode refuses unknown ones
+    #[tokio::test]
+    async fn test_handshake_negotiates_subprotocol() {
+        let response = handshake_offering("chat, seed.v1", true).await;
+        assert!(response.starts_with("http/1.1 101"));
+        assert!(response.contains("sec-websocket-protocol: seed.v1\r\n"));
+
+        let response = handshake_offering("chat", false).await;
+        assert!(response.starts_with("http/1.1 101"));
+        assert!(!response.contains("sec-websocket-protocol"));
+
+        let response = handshake_offering("chat", true).await;
+        assert!(response.starts_with("http/1.1 400"));
+    }
+}
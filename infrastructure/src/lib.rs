@@ -1,2 +1,13 @@
+pub mod access;
+pub mod backend;
+pub mod cache;
+pub mod cluster;
 pub mod database;
+pub mod http;
+pub mod memory;
+pub mod metrics;
+pub mod recent;
+pub mod redis_db;
+pub mod resume;
+pub mod sqlite;
 pub mod websocket;
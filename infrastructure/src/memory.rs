@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Result, anyhow};
+use futures::{Stream, TryStreamExt, stream};
+use misc::base64::decode_base64;
+use protocol::{
+    entity::{
+        message::{self, OutcomeMessage},
+        websocket::ControlAction,
+    },
+    error::SeedError,
+};
+use traits::message::{InsertOutcome, MessagesDB};
+use uuid::Uuid;
+
+/// A stored message together with the time it was stored
+struct StoredMessage {
+    /// The message as it is replayed to clients
+    message: OutcomeMessage,
+    /// When the message was inserted
+    stored_at: Instant,
+}
+
+impl StoredMessage {
+    /// Checks whether the message's TTL has passed
+    fn is_expired(&self, now: Instant) -> bool {
+        self.message
+            .ttl_seconds
+            .is_some_and(|ttl| self.stored_at + Duration::from_secs(ttl) <= now)
+    }
+}
+
+/// The stored messages of a chat
+#[derive(Default)]
+struct Chat {
+    /// Messages still stored, in ascending nonce order
+    messages: Vec<StoredMessage>,
+    /// Highest nonce the chat ever stored, deleted messages included
+    head: usize,
+}
+
+/// Message store that keeps every chat in process memory
+///
+/// Nothing survives a restart, which makes it suitable for development
+/// and for deployments that only need short-lived history.
+#[derive(Clone, Default)]
+pub struct MemoryDatabase {
+    /// Chats by decoded chat id
+    chats: Arc<Mutex<HashMap<Vec<u8>, Chat>>>,
+    /// Recorded control events, oldest first
+    control_events: Arc<Mutex<Vec<ControlEvent>>>,
+}
+
+/// A subscription change recorded by [`MemoryDatabase::record_control_event`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlEvent {
+    /// The connection whose subscriptions changed
+    pub connection_id: Uuid,
+    /// The chat ID as sent by the client
+    pub chat_id: String,
+    /// Whether the connection subscribed or unsubscribed
+    pub action: ControlAction,
+}
+
+impl MemoryDatabase {
+    /// Deletes every message whose TTL has passed
+    ///
+    /// Chats keep their head, so their nonces do not start over.
+    ///
+    /// # Returns
+    /// * `Result<Vec<Vec<u8>>>` - Chats that messages were deleted from
+    pub async fn delete_expired(&self) -> Result<Vec<Vec<u8>>> {
+        let now = Instant::now();
+        let mut chats = self.chats.lock().map_err(|e| anyhow!(e.to_string()))?;
+
+        let mut swept = Vec::new();
+        for (chat_id, chat) in chats.iter_mut() {
+            let before = chat.messages.len();
+            chat.messages.retain(|stored| !stored.is_expired(now));
+            if chat.messages.len() < before {
+                swept.push(chat_id.clone());
+            }
+        }
+
+        Ok(swept)
+    }
+
+    /// Returns the recorded control events, oldest first
+    pub fn control_events(&self) -> Result<Vec<ControlEvent>> {
+        let events = self
+            .control_events
+            .lock()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        Ok(events.clone())
+    }
+
+    /// Returns the messages of a chat selected by `select`
+    fn select(
+        &self,
+        chat_id: &[u8],
+        select: impl FnOnce(&[StoredMessage]) -> &[StoredMessage],
+    ) -> Result<Vec<OutcomeMessage>> {
+        let chats = self.chats.lock().map_err(|e| anyhow!(e.to_string()))?;
+        let messages = chats
+            .get(chat_id)
+            .map(|chat| chat.messages.as_slice())
+            .unwrap_or_default();
+
+        Ok(select(messages)
+            .iter()
+            .map(|stored| stored.message.clone())
+            .collect())
+    }
+}
+
+impl MessagesDB for MemoryDatabase {
+    /// Stores a message after checking that its nonce follows the chat head
+    ///
+    /// # Errors
+    /// Returns errors for:
+    /// - Base64 decoding failures
+    /// - Invalid sequence of nonces
+    async fn insert_message(&self, message: message::Message) -> Result<InsertOutcome> {
+        let chat_id = decode_base64(message.chat_id.clone()).await?;
+        decode_base64(message.signature.clone()).await?;
+        decode_base64(message.content.clone()).await?;
+        decode_base64(message.content_iv.clone()).await?;
+
+        let mut chats = self.chats.lock().map_err(|e| anyhow!(e.to_string()))?;
+        let chat = chats.entry(chat_id).or_default();
+        let last_nonce = chat.head;
+
+        // The chat is locked, so the next nonce cannot be taken in the meantime
+        if message.has_auto_nonce() {
+            let nonce = last_nonce
+                .checked_add(1)
+                .ok_or_else(|| anyhow!(SeedError::InvalidNonce))?;
+            chat.messages.push(StoredMessage {
+                message: OutcomeMessage {
+                    nonce,
+                    ..message.into()
+                },
+                stored_at: Instant::now(),
+            });
+            chat.head = nonce;
+            return Ok(InsertOutcome::Inserted(nonce));
+        }
+
+        // A retry of a stored message is acknowledged without storing it again
+        if message.nonce <= last_nonce {
+            let stored = chat.messages.iter().any(|stored| {
+                stored.message.nonce == message.nonce
+                    && stored.message.signature == message.signature
+            });
+            return if stored {
+                Ok(InsertOutcome::Duplicate)
+            } else {
+                Err(anyhow!(SeedError::InvalidNonce))
+            };
+        }
+
+        // Validate sequential nonce increment
+        if last_nonce.checked_add(1) != Some(message.nonce) {
+            return Err(anyhow!(SeedError::InvalidNonce));
+        }
+
+        let nonce = message.nonce;
+        chat.messages.push(StoredMessage {
+            message: message.into(),
+            stored_at: Instant::now(),
+        });
+        chat.head = nonce;
+        Ok(InsertOutcome::Inserted(nonce))
+    }
+
+    async fn fetch_history(
+        &self,
+        chat_id: &[u8],
+        nonce: usize,
+        amount: usize,
+    ) -> Result<Vec<OutcomeMessage>> {
+        self.select(chat_id, |messages| {
+            let start = messages.partition_point(|stored| stored.message.nonce < nonce);
+            let end = start.saturating_add(amount).min(messages.len());
+            &messages[start..end]
+        })
+    }
+
+    fn stream_history<'a>(
+        &'a self,
+        chat_id: &'a [u8],
+        nonce: usize,
+        amount: usize,
+    ) -> impl Stream<Item = Result<OutcomeMessage>> + Send + 'a {
+        stream::once(self.fetch_history(chat_id, nonce, amount))
+            .map_ok(|messages| stream::iter(messages.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    async fn fetch_recent(&self, chat_id: &[u8], amount: usize) -> Result<Vec<OutcomeMessage>> {
+        self.select(chat_id, |messages| {
+            &messages[messages.len().saturating_sub(amount)..]
+        })
+    }
+
+    async fn last_nonce(&self, chat_id: &[u8]) -> Result<usize> {
+        let chats = self.chats.lock().map_err(|e| anyhow!(e.to_string()))?;
+        let last_nonce = chats.get(chat_id).map_or(0, |chat| chat.head);
+
+        Ok(last_nonce)
+    }
+
+    async fn head_nonces(&self, chat_ids: &[Vec<u8>]) -> Result<HashMap<Vec<u8>, usize>> {
+        let chats = self.chats.lock().map_err(|e| anyhow!(e.to_string()))?;
+
+        Ok(chat_ids
+            .iter()
+            .filter_map(|chat_id| {
+                // A rejected first message leaves a chat that never stored anything
+                let head = chats.get(chat_id)?.head;
+                (head > 0).then(|| (chat_id.clone(), head))
+            })
+            .collect())
+    }
+
+    async fn record_control_event(
+        &self,
+        connection_id: Uuid,
+        chat_id: &str,
+        action: ControlAction,
+    ) -> Result<()> {
+        let mut events = self
+            .control_events
+            .lock()
+            .map_err(|e| anyhow!(e.to_string()))?;
+        events.push(ControlEvent {
+            connection_id,
+            chat_id: chat_id.to_string(),
+            action,
+        });
+        Ok(())
+    }
+
+    /// Process memory is always reachable
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use protocol::entity::websocket::{DisconnectReason, WebSocketConnection, WebSocketManager};
+use use_case::websocket::DeliveryCounters;
+
+/// Prometheus metrics collected by the server
+///
+/// Every metric is registered in a dedicated [`Registry`] so that separate
+/// service instances (e.g. in tests) do not share counters.
+#[derive(Clone)]
+pub struct Metrics {
+    /// Registry holding every metric below
+    registry: Registry,
+    /// Connections ended by the server, labelled by reason
+    disconnects: IntCounterVec,
+    /// Entries evicted from bounded caches, labelled by cache
+    cache_evictions: IntCounterVec,
+    /// Serialized payload bytes sent by finished connections
+    bytes_sent: IntCounter,
+    /// Serialized payload bytes sent by finished connections, labelled by chat
+    chat_bytes_sent: IntCounterVec,
+    /// Messages accepted from clients
+    messages_sent: IntCounter,
+    /// Counters advanced by the use case while delivering messages
+    delivery: DeliveryCounters,
+    /// Connections holding at least one subscription, updated when rendered
+    active_connections: IntGauge,
+    /// Chats with at least one subscriber, updated when rendered
+    active_chats: IntGauge,
+}
+
+impl Metrics {
+    /// Creates and registers a fresh set of metrics
+    pub fn new() -> Self {
+        Self::with_delivery(DeliveryCounters::default())
+    }
+
+    /// Creates a fresh set of metrics that also registers the use case's counters
+    pub fn with_delivery(delivery: DeliveryCounters) -> Self {
+        let registry = Registry::new();
+
+        let disconnects = IntCounterVec::new(
+            Opts::new(
+                "seed_disconnects_total",
+                "Connections closed by the server, by reason",
+            ),
+            &["reason"],
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(disconnects.clone()))
+            .expect("metric is registered once");
+
+        let cache_evictions = IntCounterVec::new(
+            Opts::new(
+                "seed_cache_evictions_total",
+                "Entries evicted from bounded caches, by cache",
+            ),
+            &["cache"],
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(cache_evictions.clone()))
+            .expect("metric is registered once");
+
+        let bytes_sent = IntCounter::new(
+            "seed_bytes_sent_total",
+            "Serialized payload bytes sent to clients",
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(bytes_sent.clone()))
+            .expect("metric is registered once");
+
+        let chat_bytes_sent = IntCounterVec::new(
+            Opts::new(
+                "seed_chat_bytes_sent_total",
+                "Serialized payload bytes sent to clients, by chat",
+            ),
+            &["chat"],
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(chat_bytes_sent.clone()))
+            .expect("metric is registered once");
+
+        let messages_sent =
+            IntCounter::new("seed_messages_sent_total", "Messages accepted from clients")
+                .expect("metric options are valid");
+        registry
+            .register(Box::new(messages_sent.clone()))
+            .expect("metric is registered once");
+
+        registry
+            .register(Box::new(delivery.persisted.clone()))
+            .expect("metric is registered once");
+        registry
+            .register(Box::new(delivery.broadcast_failures.clone()))
+            .expect("metric is registered once");
+
+        let active_connections = IntGauge::new(
+            "seed_active_connections",
+            "Connections holding at least one subscription",
+        )
+        .expect("metric options are valid");
+        registry
+            .register(Box::new(active_connections.clone()))
+            .expect("metric is registered once");
+
+        let active_chats = IntGauge::new("seed_active_chats", "Chats with at least one subscriber")
+            .expect("metric options are valid");
+        registry
+            .register(Box::new(active_chats.clone()))
+            .expect("metric is registered once");
+
+        Self {
+            registry,
+            disconnects,
+            cache_evictions,
+            bytes_sent,
+            chat_bytes_sent,
+            messages_sent,
+            delivery,
+            active_connections,
+            active_chats,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    ///
+    /// The connection and chat gauges are read from `manager` first.
+    pub fn render(&self, manager: &WebSocketManager) -> String {
+        self.active_connections
+            .set(manager.active_connections() as i64);
+        self.active_chats.set(manager.active_chats() as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("text encoding of gathered metrics succeeds");
+        String::from_utf8(buffer).expect("text exposition format is UTF-8")
+    }
+
+    /// Counts a message accepted from a client
+    pub fn record_message_sent(&self) {
+        self.messages_sent.inc();
+    }
+
+    /// Returns how many messages were accepted from clients
+    pub fn messages_sent(&self) -> u64 {
+        self.messages_sent.get()
+    }
+
+    /// Counts a message stored outside the chat message processors
+    pub fn record_persisted(&self) {
+        self.delivery.persisted.inc();
+    }
+
+    /// Returns how many messages were stored
+    pub fn messages_persisted(&self) -> u64 {
+        self.delivery.persisted.get()
+    }
+
+    /// Returns how many broadcast sends failed
+    pub fn broadcast_failures(&self) -> u64 {
+        self.delivery.broadcast_failures.get()
+    }
+
+    /// Returns the registry holding every metric
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Counts a connection closed by the server
+    pub fn record_disconnect(&self, reason: DisconnectReason) {
+        self.disconnects.with_label_values(&[reason.as_str()]).inc();
+    }
+
+    /// Returns how many connections were closed for the given reason
+    pub fn disconnects(&self, reason: DisconnectReason) -> u64 {
+        self.disconnects.with_label_values(&[reason.as_str()]).get()
+    }
+
+    /// Adds the bytes a finished connection sent to the totals
+    pub fn record_bytes_sent(&self, connection: &WebSocketConnection) {
+        self.bytes_sent.inc_by(connection.bytes_sent());
+        for (chat_id, bytes) in connection.chat_bytes_sent() {
+            self.chat_bytes_sent
+                .with_label_values(&[chat_id.as_str()])
+                .inc_by(bytes);
+        }
+    }
+
+    /// Returns the bytes sent by finished connections
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.get()
+    }
+
+    /// Returns the bytes finished connections sent for the given chat
+    pub fn chat_bytes_sent(&self, chat_id: &str) -> u64 {
+        self.chat_bytes_sent.with_label_values(&[chat_id]).get()
+    }
+
+    /// Returns the eviction counter of the named cache
+    pub fn cache_evictions(&self, cache: &str) -> IntCounter {
+        self.cache_evictions.with_label_values(&[cache])
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
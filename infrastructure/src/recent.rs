@@ -0,0 +1,325 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futures::{Stream, StreamExt, stream};
+use lru::LruCache;
+use misc::base64::{canonical_base64, decode_base64, encode_base64};
+use protocol::entity::{
+    message::{Message, OutcomeMessage},
+    websocket::ControlAction,
+};
+use traits::message::{InsertOutcome, MessagesDB};
+use uuid::Uuid;
+
+/// Message kept in the cache
+struct CachedMessage {
+    /// The message as history queries return it
+    message: OutcomeMessage,
+    /// When the message's TTL runs out, if it expires at all
+    expires_at: Option<Instant>,
+}
+
+impl CachedMessage {
+    /// Checks whether the message is still served at `now`
+    fn is_live(&self, now: Instant) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+/// Message store that keeps the newest messages of recently active chats in memory
+///
+/// Every message stored through the wrapper is appended to a bounded buffer
+/// of its chat. Recent context and history replays are answered from the
+/// buffer when it holds every requested message, anything older is read from
+/// the wrapped store. Chats are evicted least recently used first.
+///
+/// Only messages stored through this wrapper are seen, so the cache must be
+/// disabled when several servers write to the same database, and chats must
+/// be invalidated when the wrapped store deletes their messages.
+#[derive(Clone)]
+pub struct RecentCache<DB: MessagesDB> {
+    /// The wrapped message store
+    db: DB,
+    /// Newest messages of each cached chat, keyed by the binary chat id, in nonce order
+    chats: Arc<Mutex<LruCache<Vec<u8>, VecDeque<CachedMessage>>>>,
+    /// Maximum number of messages kept per chat, 0 disables the cache
+    per_chat: usize,
+}
+
+impl<DB: MessagesDB> RecentCache<DB> {
+    /// Wraps a message store
+    ///
+    /// # Arguments
+    /// * `db` - The message store to wrap
+    /// * `per_chat` - Maximum number of messages kept per chat, 0 disables the cache
+    /// * `chats` - Maximum number of chats kept, at least 1
+    pub fn new(db: DB, per_chat: usize, chats: usize) -> Self {
+        let chats = NonZeroUsize::new(chats).unwrap_or(NonZeroUsize::MIN);
+
+        Self {
+            db,
+            chats: Arc::new(Mutex::new(LruCache::new(chats))),
+            per_chat,
+        }
+    }
+
+    /// Returns the wrapped message store
+    pub fn inner(&self) -> &DB {
+        &self.db
+    }
+
+    /// Forgets the cached messages of a chat
+    ///
+    /// Must be called when messages of the chat are changed or deleted
+    /// by anything but this wrapper, e.g. by the expiry and retention sweeps.
+    pub fn invalidate(&self, chat_id: &[u8]) {
+        self.lock().pop(chat_id);
+    }
+
+    /// Locks the cached chats
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruCache<Vec<u8>, VecDeque<CachedMessage>>> {
+        self.chats.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Appends a stored message to the buffer of its chat
+    ///
+    /// A message that does not follow the newest cached one starts the buffer
+    /// over, so the buffer always holds a gapless run of the newest messages.
+    async fn remember(&self, message: Message) {
+        let Ok(chat_id) = decode_base64(message.chat_id.clone()).await else {
+            return;
+        };
+
+        // Encode the fields the way history queries return them
+        let expires_at = message
+            .ttl_seconds
+            .map(|ttl| Instant::now() + Duration::from_secs(ttl));
+        let mut message = OutcomeMessage::from(message);
+        message.chat_id = encode_base64(&chat_id).await;
+        for field in [
+            &mut message.signature,
+            &mut message.content,
+            &mut message.content_iv,
+        ] {
+            if let Some(canonical) = canonical_base64(field).await {
+                *field = canonical;
+            }
+        }
+
+        let mut chats = self.lock();
+        let buffer = chats.get_or_insert_mut(chat_id, VecDeque::new);
+        if buffer
+            .back()
+            .is_some_and(|last| last.message.nonce + 1 != message.nonce)
+        {
+            buffer.clear();
+        }
+        buffer.push_back(CachedMessage {
+            message,
+            expires_at,
+        });
+        while buffer.len() > self.per_chat {
+            buffer.pop_front();
+        }
+    }
+
+    /// Returns up to `amount` cached messages from `nonce` on
+    ///
+    /// # Returns
+    /// `None` if messages from `nonce` on may be missing from the buffer
+    fn cached_history(
+        &self,
+        chat_id: &[u8],
+        nonce: usize,
+        amount: usize,
+    ) -> Option<Vec<OutcomeMessage>> {
+        let mut chats = self.lock();
+        let buffer = chats.get(chat_id)?;
+        if buffer.front()?.message.nonce > nonce {
+            return None;
+        }
+
+        let now = Instant::now();
+        Some(
+            buffer
+                .iter()
+                .filter(|cached| cached.message.nonce >= nonce && cached.is_live(now))
+                .take(amount)
+                .map(|cached| cached.message.clone())
+                .collect(),
+        )
+    }
+
+    /// Returns the last `amount` cached messages in ascending nonce order
+    ///
+    /// # Returns
+    /// `None` if the buffer holds fewer messages and older ones may exist
+    fn cached_recent(&self, chat_id: &[u8], amount: usize) -> Option<Vec<OutcomeMessage>> {
+        let mut chats = self.lock();
+        let buffer = chats.get(chat_id)?;
+
+        let now = Instant::now();
+        let live: Vec<&CachedMessage> =
+            buffer.iter().filter(|cached| cached.is_live(now)).collect();
+        // A buffer starting at the first nonce holds the whole chat
+        let whole_chat = buffer.front()?.message.nonce <= 1;
+        if live.len() < amount && !whole_chat {
+            return None;
+        }
+
+        let skip = live.len().saturating_sub(amount);
+        Some(
+            live[skip..]
+                .iter()
+                .map(|cached| cached.message.clone())
+                .collect(),
+        )
+    }
+}
+
+impl<DB: MessagesDB> MessagesDB for RecentCache<DB> {
+    async fn insert_message(&self, message: Message) -> Result<InsertOutcome> {
+        if self.per_chat == 0 {
+            return self.db.insert_message(message).await;
+        }
+
+        // A duplicate is cached already, or was evicted as too old to cache
+        let outcome = self.db.insert_message(message.clone()).await?;
+        if let InsertOutcome::Inserted(nonce) = outcome {
+            self.remember(Message { nonce, ..message }).await;
+        }
+        Ok(outcome)
+    }
+
+    async fn fetch_history(
+        &self,
+        chat_id: &[u8],
+        nonce: usize,
+        amount: usize,
+    ) -> Result<Vec<OutcomeMessage>> {
+        match self.cached_history(chat_id, nonce, amount) {
+            Some(messages) => Ok(messages),
+            None => self.db.fetch_history(chat_id, nonce, amount).await,
+        }
+    }
+
+    fn stream_history<'a>(
+        &'a self,
+        chat_id: &'a [u8],
+        nonce: usize,
+        amount: usize,
+    ) -> impl Stream<Item = Result<OutcomeMessage>> + Send + 'a {
+        match self.cached_history(chat_id, nonce, amount) {
+            Some(messages) => stream::iter(messages.into_iter().map(Ok)).boxed(),
+            None => self.db.stream_history(chat_id, nonce, amount).boxed(),
+        }
+    }
+
+    async fn fetch_recent(&self, chat_id: &[u8], amount: usize) -> Result<Vec<OutcomeMessage>> {
+        match self.cached_recent(chat_id, amount) {
+            Some(messages) => Ok(messages),
+            None => self.db.fetch_recent(chat_id, amount).await,
+        }
+    }
+
+    async fn last_nonce(&self, chat_id: &[u8]) -> Result<usize> {
+        self.db.last_nonce(chat_id).await
+    }
+
+    async fn head_nonces(&self, chat_ids: &[Vec<u8>]) -> Result<HashMap<Vec<u8>, usize>> {
+        self.db.head_nonces(chat_ids).await
+    }
+
+    async fn record_control_event(
+        &self,
+        connection_id: Uuid,
+        chat_id: &str,
+        action: ControlAction,
+    ) -> Result<()> {
+        self.db
+            .record_control_event(connection_id, chat_id, action)
+            .await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.db.ping().await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use traits::message::MessagesRepository;
+    use use_case::{
+        config::SeedConfig,
+        messages::MessagesUseCase,
+        test_utils::{MockDb, channel_connection, sent_responses},
+    };
+
+    use super::*;
+
+    /// Stores messages with nonces `1..=count` in a chat through the cache
+    async fn store(db: &RecentCache<MockDb>, chat_id: &[u8], count: usize) {
+        for nonce in 1..=count {
+            let message = Message {
+                nonce,
+                chat_id: encode_base64(chat_id).await,
+                signature: encode_base64(b"signature").await,
+                content: encode_base64(&[nonce as u8]).await,
+                content_iv: encode_base64(&[9; 12]).await,
+                ..Default::default()
+            };
+            db.insert_message(message).await.unwrap();
+        }
+    }
+
+    /// Tests that a late subscriber's recent context is served without a database query
+    #[tokio::test]
+    async fn test_recent_context_is_served_from_cache() {
+        let mock = MockDb::default();
+        let db = RecentCache::new(mock.clone(), 10, 16);
+        store(&db, b"chat", 5).await;
+        let use_case = MessagesUseCase::new(db, Arc::new(SeedConfig::default()));
+        let (connection, mut rx) = channel_connection();
+
+        use_case
+            .recent_context_response(connection, b"chat", 3)
+            .await;
+
+        assert_eq!(mock.queries.load(Ordering::SeqCst), 0);
+        let nonces: Vec<_> = sent_responses(&mut rx)
+            .iter()
+            .map(|response| response["response"]["message"]["nonce"].clone())
+            .collect();
+        assert_eq!(nonces, [3, 4, 5]);
+    }
+
+    /// Tests that replays from before the cached messages are read from the database
+    #[tokio::test]
+    async fn test_older_history_falls_back_to_database() {
+        let mock = MockDb::default();
+        let db = RecentCache::new(mock.clone(), 3, 16);
+        store(&db, b"chat", 5).await;
+
+        let cached = db.fetch_history(b"chat", 4, 10).await.unwrap();
+        assert_eq!(cached.iter().map(|m| m.nonce).collect::<Vec<_>>(), [4, 5]);
+        assert_eq!(mock.queries.load(Ordering::SeqCst), 0);
+
+        let older = db.fetch_history(b"chat", 1, 10).await.unwrap();
+        assert_eq!(older.len(), 5);
+        assert_eq!(db.fetch_recent(b"chat", 4).await.unwrap().len(), 4);
+        assert_eq!(mock.queries.load(Ordering::SeqCst), 2);
+
+        // Once invalidated even recent messages come from the database
+        db.invalidate(b"chat");
+        assert_eq!(db.fetch_recent(b"chat", 1).await.unwrap()[0].nonce, 5);
+        assert_eq!(mock.queries.load(Ordering::SeqCst), 3);
+    }
+}
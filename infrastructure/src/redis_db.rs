@@ -0,0 +1,556 @@
+use std::{
+    collections::HashMap,
+    env::var,
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use futures::{Stream, TryStreamExt, stream};
+use log::{error, warn};
+use misc::base64::{decode_base64, encode_base64};
+use protocol::{
+    entity::{
+        message::{self, OutcomeMessage},
+        websocket::ControlAction,
+    },
+    error::SeedError,
+};
+use redis::{
+    AsyncCommands, Client, Script,
+    aio::{ConnectionLike, ConnectionManager},
+};
+use traits::message::{InsertOutcome, MessagesDB};
+use uuid::Uuid;
+
+/// Prefix of the sorted sets holding each chat's messages, followed by the base64 chat ID
+const CHAT_KEY_PREFIX: &str = "seed:chat:";
+
+/// Sorted set of `"<chat key> <nonce>"` entries scored by when the message expires
+const EXPIRY_KEY: &str = "seed:expiry";
+
+/// Hash of chat keys to the nonce of their newest expired message
+///
+/// Keeps the nonces of a chat going once all of its messages expired.
+const HEADS_KEY: &str = "seed:heads";
+
+/// List of recorded control events, oldest first
+const CONTROL_EVENTS_KEY: &str = "seed:control_events";
+
+/// Stores a message unless its nonce does not follow the chat head
+///
+/// A nonce of 0 stores the message under the next nonce of the chat. Returns
+/// `{1, nonce}` if the message was stored, `{2, nonce}` if the very same
+/// message is already stored under its nonce and `{0, 0}` if the nonce was rejected.
+static INSERT_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+            local head = redis.call('ZRANGE', KEYS[1], -1, -1, 'WITHSCORES')
+            local last = math.max(tonumber(head[2] or 0), tonumber(redis.call('HGET', KEYS[3], KEYS[1]) or 0))
+            local nonce = tonumber(ARGV[1])
+            local member = ARGV[2]
+            if nonce == 0 then
+                nonce = last + 1
+                local message = cjson.decode(member)
+                message.nonce = nonce
+                member = cjson.encode(message)
+            elseif nonce <= last then
+                for _, member in ipairs(redis.call('ZRANGEBYSCORE', KEYS[1], nonce, nonce)) do
+                    if member == ARGV[2] then
+                        return {2, nonce}
+                    end
+                end
+                return {0, 0}
+            elseif last + 1 ~= nonce then
+                return {0, 0}
+            end
+            redis.call('ZADD', KEYS[1], nonce, member)
+            if tonumber(ARGV[3]) > 0 then
+                redis.call('ZADD', KEYS[2], ARGV[3], KEYS[1] .. ' ' .. nonce)
+            end
+            return {1, nonce}
+        "#,
+    )
+});
+
+/// Deletes every message that expired at or before `ARGV[1]`
+///
+/// The newest expired nonce of each chat is kept in `KEYS[2]`. Returns the
+/// keys of the chats that messages were deleted from.
+static EXPIRE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+            local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+            local swept, seen = {}, {}
+            for _, entry in ipairs(due) do
+                local key, nonce = string.match(entry, '^(.*) (%d+)$')
+                if redis.call('ZREMRANGEBYSCORE', key, nonce, nonce) > 0 and not seen[key] then
+                    seen[key] = true
+                    table.insert(swept, key)
+                end
+                if tonumber(nonce) > tonumber(redis.call('HGET', KEYS[2], key) or 0) then
+                    redis.call('HSET', KEYS[2], key, nonce)
+                end
+            end
+            redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', ARGV[1])
+            return swept
+        "#,
+    )
+});
+
+/// Returns the head nonce of every chat in `KEYS[2..]`, expired messages included
+static HEADS_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+            local heads = {}
+            for i = 2, #KEYS do
+                local head = redis.call('ZRANGE', KEYS[i], -1, -1, 'WITHSCORES')
+                local expired = redis.call('HGET', KEYS[1], KEYS[i])
+                heads[i - 1] = math.max(tonumber(head[2] or 0), tonumber(expired or 0))
+            end
+            return heads
+        "#,
+    )
+});
+
+/// Reads the Redis connection URL from `REDIS_URL`, falling back to a local server
+pub(crate) fn redis_url() -> String {
+    var("REDIS_URL")
+        .inspect_err(|_| warn!("REDIS_URL environment variable is unset, using default..."))
+        .unwrap_or("redis://127.0.0.1:6379".to_string())
+}
+
+/// Message store keeping each chat as a Redis sorted set scored by nonce
+///
+/// Suits deployments of several stateless instances that only need
+/// short-lived history. Messages are stored as JSON members, so a history
+/// read is a single score range query.
+///
+/// The scripts touch the keys of several chats together with the shared
+/// expiry and head keys, which Redis Cluster would spread over different
+/// slots. The store therefore needs a single Redis server, optionally with
+/// replicas, not a cluster.
+#[derive(Clone)]
+pub struct RedisDatabase<C = ConnectionManager> {
+    /// Connection every command is sent over
+    connection: C,
+}
+
+impl RedisDatabase {
+    /// Creates a new RedisDatabase instance connected to the configured server
+    ///
+    /// # Errors
+    /// Will return an error if unable to connect to the server
+    ///
+    /// # Environment Variables
+    /// - `REDIS_URL` - Redis connection URL (default: "redis://127.0.0.1:6379")
+    pub async fn new() -> Result<Self> {
+        Self::connect(&redis_url()).await
+    }
+
+    /// Connects to the server at the given URL
+    ///
+    /// The connection reconnects on its own if the server goes away.
+    ///
+    /// # Arguments
+    /// * `connection_url` - Redis connection URL
+    ///
+    /// # Errors
+    /// Will return an error if the URL is invalid or the server cannot be reached
+    pub async fn connect(connection_url: &str) -> Result<Self> {
+        let client = Client::open(connection_url)
+            .inspect_err(|e| error!("invalid redis connection url: {e}"))?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .inspect_err(|e| error!("failed to connect to redis: {e}"))?;
+
+        Ok(Self::with_connection(connection))
+    }
+}
+
+impl<C: ConnectionLike + Clone + Send + Sync> RedisDatabase<C> {
+    /// Creates a store that sends its commands over `connection`
+    pub fn with_connection(connection: C) -> Self {
+        Self { connection }
+    }
+
+    /// Deletes every message whose TTL has passed
+    ///
+    /// # Returns
+    /// * `Result<Vec<Vec<u8>>>` - Chats that messages were deleted from
+    pub async fn delete_expired(&self) -> Result<Vec<Vec<u8>>> {
+        let keys: Vec<String> = EXPIRE_SCRIPT
+            .key(EXPIRY_KEY)
+            .key(HEADS_KEY)
+            .arg(unix_now())
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+
+        let mut chats = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(chat_id) = key.strip_prefix(CHAT_KEY_PREFIX) {
+                chats.push(decode_base64(chat_id.to_string()).await?);
+            }
+        }
+        Ok(chats)
+    }
+
+    /// Looks up the head nonces of several chats in one script call
+    async fn heads(&self, chat_ids: &[&[u8]]) -> Result<Vec<usize>> {
+        let mut invocation = HEADS_SCRIPT.key(HEADS_KEY);
+        for chat_id in chat_ids {
+            invocation.key(chat_key(chat_id).await);
+        }
+
+        Ok(invocation
+            .invoke_async(&mut self.connection.clone())
+            .await?)
+    }
+}
+
+impl<C: ConnectionLike + Clone + Send + Sync> MessagesDB for RedisDatabase<C> {
+    /// Stores a message after checking that its nonce follows the chat head
+    ///
+    /// The check and the write run as one script, so concurrent inserts
+    /// into a chat cannot both take the same nonce. The script also picks
+    /// the next nonce for a message sent without one.
+    ///
+    /// # Errors
+    /// Returns errors for:
+    /// - Base64 decoding failures
+    /// - Invalid sequence of nonces
+    /// - Redis command failures
+    async fn insert_message(&self, message: message::Message) -> Result<InsertOutcome> {
+        let chat_id = decode_base64(message.chat_id.clone()).await?;
+        decode_base64(message.signature.clone()).await?;
+        decode_base64(message.content.clone()).await?;
+        decode_base64(message.content_iv.clone()).await?;
+
+        let expires_at = message.ttl_seconds.map_or(0, |ttl| unix_now() + ttl);
+        let nonce = message.nonce;
+        // Replays report the chat in its canonical encoding, whatever the sender used
+        let stored = OutcomeMessage {
+            chat_id: encode_base64(&chat_id).await,
+            ..message.into()
+        };
+
+        let (outcome, nonce): (u8, usize) = INSERT_SCRIPT
+            .key(chat_key(&chat_id).await)
+            .key(EXPIRY_KEY)
+            .key(HEADS_KEY)
+            .arg(nonce)
+            .arg(serde_json::to_string(&stored)?)
+            .arg(expires_at)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        match outcome {
+            1 => Ok(InsertOutcome::Inserted(nonce)),
+            2 => Ok(InsertOutcome::Duplicate),
+            _ => Err(anyhow!(SeedError::InvalidNonce)),
+        }
+    }
+
+    async fn fetch_history(
+        &self,
+        chat_id: &[u8],
+        nonce: usize,
+        amount: usize,
+    ) -> Result<Vec<OutcomeMessage>> {
+        let members: Vec<String> = self
+            .connection
+            .clone()
+            .zrangebyscore_limit(chat_key(chat_id).await, nonce, "+inf", 0, amount as isize)
+            .await?;
+
+        parse_messages(members)
+    }
+
+    fn stream_history<'a>(
+        &'a self,
+        chat_id: &'a [u8],
+        nonce: usize,
+        amount: usize,
+    ) -> impl Stream<Item = Result<OutcomeMessage>> + Send + 'a {
+        stream::once(self.fetch_history(chat_id, nonce, amount))
+            .map_ok(|messages| stream::iter(messages.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    /// Fetches the last messages of a chat in ascending nonce order
+    async fn fetch_recent(&self, chat_id: &[u8], amount: usize) -> Result<Vec<OutcomeMessage>> {
+        // A start of -0 would select the whole set
+        if amount == 0 {
+            return Ok(Vec::new());
+        }
+
+        let members: Vec<String> = self
+            .connection
+            .clone()
+            .zrange(chat_key(chat_id).await, -(amount as isize), -1)
+            .await?;
+
+        parse_messages(members)
+    }
+
+    /// Returns the highest nonce a chat ever stored, or 0 if it never had messages
+    async fn last_nonce(&self, chat_id: &[u8]) -> Result<usize> {
+        let heads = self.heads(&[chat_id]).await?;
+        Ok(heads.first().copied().unwrap_or(0))
+    }
+
+    /// Looks up the head nonces of several chats in one round trip
+    async fn head_nonces(&self, chat_ids: &[Vec<u8>]) -> Result<HashMap<Vec<u8>, usize>> {
+        if chat_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let keys: Vec<&[u8]> = chat_ids.iter().map(Vec::as_slice).collect();
+        let heads = self.heads(&keys).await?;
+
+        Ok(chat_ids
+            .iter()
+            .zip(heads)
+            .filter(|(_, head)| *head > 0)
+            .map(|(chat_id, head)| (chat_id.clone(), head))
+            .collect())
+    }
+
+    async fn record_control_event(
+        &self,
+        connection_id: Uuid,
+        chat_id: &str,
+        action: ControlAction,
+    ) -> Result<()> {
+        let event = serde_json::json!({
+            "connectionId": connection_id.to_string(),
+            "queueId": chat_id,
+            "action": action.as_str(),
+            "recordedAt": unix_now(),
+        });
+        let _: usize = self
+            .connection
+            .clone()
+            .rpush(CONTROL_EVENTS_KEY, event.to_string())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sends a `PING` to the server
+    async fn ping(&self) -> Result<()> {
+        redis::cmd("PING")
+            .query_async::<()>(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+}
+
+/// Returns the key of the sorted set holding a chat's messages
+async fn chat_key(chat_id: &[u8]) -> String {
+    format!("{CHAT_KEY_PREFIX}{}", encode_base64(chat_id).await)
+}
+
+/// Parses stored JSON members back into messages
+fn parse_messages(members: Vec<String>) -> Result<Vec<OutcomeMessage>> {
+    members
+        .iter()
+        .map(|member| serde_json::from_str(member).map_err(|e| anyhow!(e)))
+        .collect()
+}
+
+/// Returns the current time in whole seconds since the Unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use redis::{Value, cmd};
+    use redis_test::{MockCmd, MockRedisConnection};
+
+    use super::*;
+
+    /// Returns a message of chat `[7; 32]` with the given nonce
+    async fn message(nonce: usize) -> message::Message {
+        message::Message {
+            nonce,
+            chat_id: encode_base64(&[7; 32]).await,
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the command the insert script is invoked with for `message`
+    async fn insert_command(message: &message::Message) -> redis::Cmd {
+        let stored = serde_json::to_string(&OutcomeMessage::from(message.clone())).unwrap();
+        let mut command = cmd("EVALSHA");
+        command
+            .arg(INSERT_SCRIPT.get_hash())
+            .arg(3)
+            .arg(chat_key(&[7; 32]).await)
+            .arg(EXPIRY_KEY)
+            .arg(HEADS_KEY)
+            .arg(message.nonce)
+            .arg(stored)
+            .arg(0);
+        command
+    }
+
+    /// Returns the reply of the insert script
+    fn script_reply(outcome: i64, nonce: i64) -> Value {
+        Value::Array(vec![Value::Int(outcome), Value::Int(nonce)])
+    }
+
+    /// Tests that an insert runs the nonce check script and reports a rejected nonce
+    #[tokio::test]
+    async fn test_insert_reports_rejected_nonce() {
+        let (first, repeated) = (message(1).await, message(1).await);
+        let connection = MockRedisConnection::new([
+            MockCmd::new(insert_command(&first).await, Ok(script_reply(1, 1))),
+            MockCmd::new(insert_command(&repeated).await, Ok(script_reply(0, 0))),
+        ]);
+        let db = RedisDatabase::with_connection(connection);
+
+        let outcome = db.insert_message(first).await.unwrap();
+        assert_eq!(outcome, InsertOutcome::Inserted(1));
+        let err = db.insert_message(repeated).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SeedError::InvalidNonce)));
+    }
+
+    /// Tests that history is read as a nonce range of the chat's sorted set
+    #[tokio::test]
+    async fn test_fetch_history_reads_score_range() {
+        let stored: Vec<_> = [3, 4]
+            .into_iter()
+            .map(|nonce| OutcomeMessage {
+                nonce,
+                chat_id: "chat".to_string(),
+                ..Default::default()
+            })
+            .map(|message| Value::BulkString(serde_json::to_vec(&message).unwrap()))
+            .collect();
+        let mut range = cmd("ZRANGEBYSCORE");
+        range
+            .arg(chat_key(b"chat").await)
+            .arg(3)
+            .arg("+inf")
+            .arg("LIMIT")
+            .arg(0)
+            .arg(2);
+        let connection = MockRedisConnection::new([MockCmd::new(range, Ok(Value::Array(stored)))]);
+        let db = RedisDatabase::with_connection(connection);
+
+        let history = db.fetch_history(b"chat", 3, 2).await.unwrap();
+
+        let nonces: Vec<_> = history.iter().map(|message| message.nonce).collect();
+        assert_eq!(nonces, [3, 4]);
+    }
+
+    /// Connects to the server at `REDIS_URL`
+    #[cfg(feature = "redis-integration")]
+    async fn live_database() -> RedisDatabase {
+        RedisDatabase::connect(&redis_url()).await.unwrap()
+    }
+
+    /// Returns a message of a fresh chat with the given nonce and TTL
+    #[cfg(feature = "redis-integration")]
+    async fn live_message(
+        chat_id: &[u8],
+        nonce: usize,
+        ttl_seconds: Option<u64>,
+    ) -> message::Message {
+        message::Message {
+            nonce,
+            chat_id: encode_base64(chat_id).await,
+            signature: encode_base64(format!("signature {nonce}").as_bytes()).await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ttl_seconds,
+        }
+    }
+
+    /// Tests the insert script against a real server: assigned and explicit
+    /// nonces follow the head, retries are recognized and gaps are rejected
+    #[cfg(feature = "redis-integration")]
+    #[tokio::test]
+    async fn test_insert_script_on_live_server() {
+        let db = live_database().await;
+        let chat_id = Uuid::new_v4().as_bytes().repeat(2);
+
+        let first = db
+            .insert_message(live_message(&chat_id, message::Message::AUTO_NONCE, None).await)
+            .await
+            .unwrap();
+        let second = db
+            .insert_message(live_message(&chat_id, 2, None).await)
+            .await
+            .unwrap();
+        let retry = db
+            .insert_message(live_message(&chat_id, 2, None).await)
+            .await
+            .unwrap();
+        let gap = db
+            .insert_message(live_message(&chat_id, 4, None).await)
+            .await
+            .unwrap_err();
+
+        assert_eq!(first, InsertOutcome::Inserted(1));
+        assert_eq!(second, InsertOutcome::Inserted(2));
+        assert_eq!(retry, InsertOutcome::Duplicate);
+        assert!(matches!(gap.downcast_ref(), Some(SeedError::InvalidNonce)));
+        let history = db.fetch_history(&chat_id, 1, 10).await.unwrap();
+        assert_eq!(history.iter().map(|m| m.nonce).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(db.last_nonce(&chat_id).await.unwrap(), 2);
+    }
+
+    /// Tests the expiry and head scripts against a real server: an expired
+    /// chat is reported, emptied and keeps counting from its last nonce
+    #[cfg(feature = "redis-integration")]
+    #[tokio::test]
+    async fn test_expire_script_on_live_server() {
+        let db = live_database().await;
+        let chat_id = Uuid::new_v4().as_bytes().repeat(2);
+        for nonce in 1..=2 {
+            db.insert_message(live_message(&chat_id, nonce, Some(0)).await)
+                .await
+                .unwrap();
+        }
+
+        let swept = db.delete_expired().await.unwrap();
+
+        assert!(swept.contains(&chat_id));
+        assert!(db.fetch_history(&chat_id, 0, 10).await.unwrap().is_empty());
+        assert_eq!(db.last_nonce(&chat_id).await.unwrap(), 2);
+        let heads = db
+            .head_nonces(std::slice::from_ref(&chat_id))
+            .await
+            .unwrap();
+        assert_eq!(heads, HashMap::from([(chat_id.clone(), 2)]));
+        let outcome = db
+            .insert_message(live_message(&chat_id, message::Message::AUTO_NONCE, None).await)
+            .await
+            .unwrap();
+        assert_eq!(outcome, InsertOutcome::Inserted(3));
+    }
+
+    /// Tests that a chat without messages reports head nonce 0
+    #[tokio::test]
+    async fn test_empty_chat_has_nonce_zero() {
+        let mut heads = cmd("EVALSHA");
+        heads
+            .arg(HEADS_SCRIPT.get_hash())
+            .arg(2)
+            .arg(HEADS_KEY)
+            .arg(chat_key(b"chat").await);
+        let reply = Value::Array(vec![Value::Int(0)]);
+        let connection = MockRedisConnection::new([MockCmd::new(heads, Ok(reply))]);
+        let db = RedisDatabase::with_connection(connection);
+
+        assert_eq!(db.last_nonce(b"chat").await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use protocol::clock::{SharedClock, SystemClock};
+use uuid::Uuid;
+
+/// Subscriptions saved for a client identity
+struct Snapshot {
+    /// When the snapshot was saved
+    saved_at: Instant,
+    /// Subscribed chat ids and the nonce to replay each of them from
+    subscriptions: Vec<(String, usize)>,
+}
+
+/// In-memory registry of subscription snapshots keyed by client identity
+///
+/// Lets a reconnecting client restore every subscription with a single
+/// `resume`. The registry holds at most `capacity` identities, dropping the
+/// least recently saved one when full, and forgets snapshots older than `ttl`.
+///
+/// It also remembers the resume sessions that logged out, so their resume
+/// tokens are refused until they expire on their own after `ttl`.
+pub struct SubscriptionRegistry {
+    /// Snapshots ordered by how recently they were saved
+    entries: Mutex<LruCache<String, Snapshot>>,
+    /// Logged out resume sessions and when they were revoked
+    revoked: Mutex<LruCache<Uuid, Instant>>,
+    /// How long a snapshot can be restored after it was saved
+    ttl: Duration,
+    /// Source of the current time for the TTL
+    clock: SharedClock,
+}
+
+impl SubscriptionRegistry {
+    /// Creates an empty registry
+    ///
+    /// # Arguments
+    /// * `capacity` - Maximum number of client identities kept, at least 1
+    /// * `ttl` - How long a snapshot can be restored after it was saved
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self::with_clock(capacity, ttl, SystemClock::shared())
+    }
+
+    /// Creates an empty registry that reads the current time from `clock`
+    ///
+    /// # Arguments
+    /// * `capacity` - Maximum number of client identities kept, at least 1
+    /// * `ttl` - How long a snapshot can be restored after it was saved
+    /// * `clock` - Source of the current time for the TTL
+    pub fn with_clock(capacity: usize, ttl: Duration, clock: SharedClock) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            revoked: Mutex::new(LruCache::new(capacity)),
+            ttl,
+            clock,
+        }
+    }
+
+    /// Saves the current subscriptions of a client, replacing older ones
+    ///
+    /// A client without subscriptions has nothing to resume, so its snapshot
+    /// is dropped instead.
+    ///
+    /// # Arguments
+    /// * `client_id` - Identity the client resumed under
+    /// * `subscriptions` - Subscribed chat ids with the nonce to replay from
+    pub fn save(&self, client_id: &str, subscriptions: Vec<(String, usize)>) {
+        if subscriptions.is_empty() {
+            self.forget(client_id);
+            return;
+        }
+
+        let snapshot = Snapshot {
+            saved_at: self.clock.now(),
+            subscriptions,
+        };
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .put(client_id.to_string(), snapshot);
+    }
+
+    /// Drops the snapshot of a client, so a later resume restores nothing
+    pub fn forget(&self, client_id: &str) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop(client_id);
+    }
+
+    /// Refuses the resume tokens of a session from now on
+    pub fn revoke(&self, session: Uuid) {
+        self.revoked
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .put(session, self.clock.now());
+    }
+
+    /// Checks whether the resume tokens of a session were revoked
+    ///
+    /// Revocations are dropped once every token they cover has expired.
+    pub fn is_revoked(&self, session: Uuid) -> bool {
+        let mut revoked = self.revoked.lock().unwrap_or_else(|e| e.into_inner());
+        match revoked.peek(&session) {
+            Some(&revoked_at)
+                if self.clock.now().saturating_duration_since(revoked_at) < self.ttl =>
+            {
+                true
+            }
+            Some(_) => {
+                revoked.pop(&session);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the subscriptions saved for a client within the TTL
+    ///
+    /// Expired snapshots are removed and reported as empty.
+    pub fn restore(&self, client_id: &str) -> Vec<(String, usize)> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        match entries.get(client_id) {
+            Some(snapshot)
+                if self
+                    .clock
+                    .now()
+                    .saturating_duration_since(snapshot.saved_at)
+                    < self.ttl =>
+            {
+                snapshot.subscriptions.clone()
+            }
+            Some(_) => {
+                entries.pop(client_id);
+                Vec::new()
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use protocol::clock::MockClock;
+
+    use super::*;
+
+    /// Tests that snapshots are restored within the TTL and forgotten after it
+    #[test]
+    fn test_restore_respects_ttl() {
+        let registry = SubscriptionRegistry::new(4, Duration::from_secs(60));
+        registry.save("alice", vec![("chat".to_string(), 4)]);
+        assert_eq!(registry.restore("alice"), [("chat".to_string(), 4)]);
+        assert!(registry.restore("bob").is_empty());
+
+        let registry = SubscriptionRegistry::new(4, Duration::ZERO);
+        registry.save("alice", vec![("chat".to_string(), 4)]);
+        assert!(registry.restore("alice").is_empty());
+    }
+
+    /// Tests that a snapshot expires once the clock passes its TTL
+    #[test]
+    fn test_restore_expires_with_clock() {
+        let clock = Arc::new(MockClock::default());
+        let registry = SubscriptionRegistry::with_clock(4, Duration::from_secs(300), clock.clone());
+        registry.save("alice", vec![("chat".to_string(), 4)]);
+
+        clock.advance(Duration::from_secs(299));
+        assert_eq!(registry.restore("alice"), [("chat".to_string(), 4)]);
+        clock.advance(Duration::from_secs(1));
+        assert!(registry.restore("alice").is_empty());
+    }
+
+    /// Tests that a revoked session stays revoked until its tokens expired
+    #[test]
+    fn test_revocation_lasts_the_ttl() {
+        let clock = Arc::new(MockClock::default());
+        let registry = SubscriptionRegistry::with_clock(4, Duration::from_secs(300), clock.clone());
+        let session = Uuid::new_v4();
+        assert!(!registry.is_revoked(session));
+
+        registry.revoke(session);
+        clock.advance(Duration::from_secs(299));
+        assert!(registry.is_revoked(session));
+        assert!(!registry.is_revoked(Uuid::new_v4()));
+        clock.advance(Duration::from_secs(1));
+        assert!(!registry.is_revoked(session));
+    }
+}
@@ -0,0 +1,392 @@
+use anyhow::{Result, anyhow};
+use futures::{Stream, TryStreamExt};
+use log::{error, warn};
+use misc::base64::{decode_base64, encode_base64};
+use protocol::{
+    entity::{
+        message::{self, OutcomeMessage},
+        websocket::ControlAction,
+    },
+    error::SeedError,
+};
+use sqlx::{
+    Row, SqlitePool,
+    sqlite::{SqlitePoolOptions, SqliteRow},
+};
+use std::{collections::HashMap, env::var};
+use traits::message::{InsertOutcome, MessagesDB};
+use uuid::Uuid;
+
+/// Schema of the messages, chat head and control event tables, created on connect if missing
+const SCHEMA: &str = r#"
+    CREATE TABLE IF NOT EXISTS messages (
+        nonce INTEGER NOT NULL,
+        chat_id BLOB NOT NULL,
+        signature BLOB NOT NULL,
+        content BLOB NOT NULL,
+        content_iv BLOB NOT NULL,
+        created_at INTEGER NOT NULL DEFAULT (unixepoch()),
+        ttl_seconds INTEGER,
+        PRIMARY KEY (chat_id, nonce)
+    );
+    CREATE TABLE IF NOT EXISTS chat_heads (
+        chat_id BLOB PRIMARY KEY,
+        head INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS control_events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        connection_id TEXT NOT NULL,
+        chat_id TEXT NOT NULL,
+        action TEXT NOT NULL CHECK (action IN ('subscribe', 'unsubscribe')),
+        recorded_at INTEGER NOT NULL DEFAULT (unixepoch())
+    )
+"#;
+
+/// Represents a SQLite database connection pool
+///
+/// Stores messages in a single file, or in memory for `sqlite::memory:` URLs.
+#[derive(Clone)]
+pub struct SqliteDatabase {
+    /// The underlying connection pool to the SQLite database
+    pub db: SqlitePool,
+}
+
+impl SqliteDatabase {
+    /// Creates a new SqliteDatabase instance with a connection pool
+    ///
+    /// # Errors
+    /// Will return an error if unable to open the database
+    ///
+    /// # Environment Variables
+    /// - `SQLITE_URL` - SQLite connection URL (default: "sqlite://seed-rust.db?mode=rwc")
+    pub async fn new() -> Result<Self> {
+        let connection_url = var("SQLITE_URL")
+            .inspect_err(|_| warn!("SQLITE_URL environment variable is unset, using default..."))
+            .unwrap_or("sqlite://seed-rust.db?mode=rwc".to_string());
+
+        Self::connect(&connection_url).await
+    }
+
+    /// Opens the database at the given URL and creates the schema if missing
+    ///
+    /// # Arguments
+    /// * `connection_url` - SQLite connection URL
+    ///
+    /// # Errors
+    /// Will return an error if unable to open the database or to create the schema
+    pub async fn connect(connection_url: &str) -> Result<Self> {
+        // Every connection to an in-memory database sees its own database,
+        // so keep a single connection open for the lifetime of the pool
+        let mut pool_options = SqlitePoolOptions::new();
+        if connection_url.contains(":memory:") {
+            pool_options = pool_options
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None);
+        }
+
+        let pool = pool_options
+            .connect(connection_url)
+            .await
+            .inspect_err(|e| error!("failed to connect to sqlite pool: {e}"))?;
+
+        sqlx::query(SCHEMA)
+            .execute(&pool)
+            .await
+            .inspect_err(|e| error!("failed to create the sqlite schema: {e}"))?;
+
+        Ok(Self { db: pool })
+    }
+
+    /// Deletes every message whose TTL has passed
+    ///
+    /// The newest deleted nonce of each chat is kept in `chat_heads`, so the
+    /// chat's nonces do not start over.
+    ///
+    /// # Returns
+    /// * `Result<Vec<Vec<u8>>>` - Chats that messages were deleted from
+    pub async fn delete_expired(&self) -> Result<Vec<Vec<u8>>> {
+        let mut transaction = self.db.begin().await?;
+        // Both statements must agree on which messages expired
+        let now: i64 = sqlx::query_scalar("SELECT unixepoch()")
+            .fetch_one(&mut *transaction)
+            .await?;
+
+        let chats: Vec<Vec<u8>> = sqlx::query_scalar(
+            r#"
+                INSERT INTO chat_heads (chat_id, head)
+                SELECT chat_id, MAX(nonce) FROM messages
+                WHERE ttl_seconds IS NOT NULL
+                  AND created_at + ttl_seconds <= ?
+                GROUP BY chat_id
+                ON CONFLICT (chat_id) DO UPDATE SET head = MAX(head, excluded.head)
+                RETURNING chat_id
+            "#,
+        )
+        .bind(now)
+        .fetch_all(&mut *transaction)
+        .await?;
+        sqlx::query(
+            r#"
+                DELETE FROM messages
+                WHERE ttl_seconds IS NOT NULL
+                  AND created_at + ttl_seconds <= ?
+            "#,
+        )
+        .bind(now)
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(chats)
+    }
+
+    /// Checks whether a chat already holds a message with the given nonce and signature
+    async fn is_stored(&self, chat_id: &[u8], nonce: usize, signature: &[u8]) -> Result<bool> {
+        let stored: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM messages WHERE chat_id = ? AND nonce = ? AND signature = ?)",
+        )
+        .bind(chat_id)
+        .bind(nonce as i64)
+        .bind(signature)
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(stored)
+    }
+}
+
+impl MessagesDB for SqliteDatabase {
+    /// Inserts a new message after checking that its nonce follows the chat head
+    ///
+    /// # Errors
+    /// Returns errors for:
+    /// - Base64 decoding failures
+    /// - Invalid sequence of nonces
+    /// - Database insertion errors
+    async fn insert_message(&self, message: message::Message) -> Result<InsertOutcome> {
+        let auto_nonce = message.has_auto_nonce();
+        let chat_id = decode_base64(message.chat_id).await?;
+        let signature = decode_base64(message.signature).await?;
+        let content = decode_base64(message.content).await?;
+        let content_iv = decode_base64(message.content_iv).await?;
+
+        // Writes are serialized, so picking the next nonce in the insert itself is atomic
+        if auto_nonce {
+            let nonce: i64 = sqlx::query_scalar(
+                r#"
+                    INSERT INTO messages (nonce, chat_id, signature, content, content_iv, ttl_seconds)
+                    SELECT
+                        MAX(
+                            COALESCE((SELECT MAX(nonce) FROM messages WHERE chat_id = ?1), 0),
+                            COALESCE((SELECT head FROM chat_heads WHERE chat_id = ?1), 0)
+                        ) + 1,
+                        ?1, ?2, ?3, ?4, ?5
+                    RETURNING nonce
+                "#,
+            )
+            .bind(&chat_id)
+            .bind(&signature)
+            .bind(content)
+            .bind(content_iv)
+            .bind(message.ttl_seconds.map(|ttl| ttl as i64))
+            .fetch_one(&self.db)
+            .await?;
+            return Ok(InsertOutcome::Inserted(nonce as usize));
+        }
+
+        // A retry of a stored message is acknowledged without storing it again
+        let last_nonce = self.last_nonce(&chat_id).await?;
+        if message.nonce <= last_nonce {
+            return if self.is_stored(&chat_id, message.nonce, &signature).await? {
+                Ok(InsertOutcome::Duplicate)
+            } else {
+                Err(anyhow!(SeedError::InvalidNonce))
+            };
+        }
+
+        // Validate sequential nonce increment
+        if last_nonce.checked_add(1) != Some(message.nonce) {
+            return Err(anyhow!(SeedError::InvalidNonce));
+        }
+
+        // A concurrent insert may have taken the nonce in the meantime
+        let inserted = sqlx::query(
+            r#"
+                INSERT INTO messages (nonce, chat_id, signature, content, content_iv, ttl_seconds)
+                VALUES (?, ?, ?, ?, ?, ?)
+                ON CONFLICT (chat_id, nonce) DO NOTHING
+            "#,
+        )
+        .bind(message.nonce as i64)
+        .bind(&chat_id)
+        .bind(&signature)
+        .bind(content)
+        .bind(content_iv)
+        .bind(message.ttl_seconds.map(|ttl| ttl as i64))
+        .execute(&self.db)
+        .await?
+        .rows_affected()
+            > 0;
+        if inserted {
+            return Ok(InsertOutcome::Inserted(message.nonce));
+        }
+
+        if self.is_stored(&chat_id, message.nonce, &signature).await? {
+            Ok(InsertOutcome::Duplicate)
+        } else {
+            Err(anyhow!(SeedError::InvalidNonce))
+        }
+    }
+
+    /// Fetches message history for a given chat from the database
+    ///
+    /// Collects [`SqliteDatabase::stream_history`] into a vector.
+    async fn fetch_history(
+        &self,
+        chat_id: &[u8],
+        nonce: usize,
+        amount: usize,
+    ) -> Result<Vec<OutcomeMessage>> {
+        self.stream_history(chat_id, nonce, amount)
+            .try_collect()
+            .await
+    }
+
+    /// Streams message history for a given chat from the database
+    fn stream_history<'a>(
+        &'a self,
+        chat_id: &'a [u8],
+        nonce: usize,
+        amount: usize,
+    ) -> impl Stream<Item = Result<OutcomeMessage>> + Send + 'a {
+        sqlx::query(
+            r#"
+                SELECT nonce, chat_id, signature, content, content_iv, ttl_seconds
+                FROM messages
+                WHERE chat_id = ? AND nonce >= ?
+                ORDER BY nonce ASC
+                LIMIT ?
+            "#,
+        )
+        .bind(chat_id)
+        .bind(nonce as i64)
+        .bind(amount as i64)
+        .fetch(&self.db)
+        .map_err(|e| anyhow!(e))
+        .and_then(row_to_message)
+    }
+
+    /// Fetches the last messages of a chat in ascending nonce order
+    async fn fetch_recent(&self, chat_id: &[u8], amount: usize) -> Result<Vec<OutcomeMessage>> {
+        // Take the newest rows first so the limit keeps the most recent messages
+        let rows = sqlx::query(
+            r#"
+                SELECT nonce, chat_id, signature, content, content_iv, ttl_seconds
+                FROM messages
+                WHERE chat_id = ?
+                ORDER BY nonce DESC
+                LIMIT ?
+            "#,
+        )
+        .bind(chat_id)
+        .bind(amount as i64)
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut messages = Vec::with_capacity(rows.len());
+        for row in rows.into_iter().rev() {
+            messages.push(row_to_message(row).await?);
+        }
+
+        Ok(messages)
+    }
+
+    /// Returns the highest nonce a chat ever stored, or 0 if it never had messages
+    async fn last_nonce(&self, chat_id: &[u8]) -> Result<usize> {
+        let last_nonce: i64 = sqlx::query_scalar(
+            r#"
+                SELECT MAX(
+                    COALESCE((SELECT MAX(nonce) FROM messages WHERE chat_id = ?1), 0),
+                    COALESCE((SELECT head FROM chat_heads WHERE chat_id = ?1), 0)
+                )
+            "#,
+        )
+        .bind(chat_id)
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(last_nonce as usize)
+    }
+
+    /// Looks up the head nonces of several chats with one grouped query
+    async fn head_nonces(&self, chat_ids: &[Vec<u8>]) -> Result<HashMap<Vec<u8>, usize>> {
+        if chat_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let placeholders = vec!["?"; chat_ids.len()].join(", ");
+        let sql = format!(
+            "SELECT chat_id, MAX(head) AS head FROM ( \
+                 SELECT chat_id, nonce AS head FROM messages WHERE chat_id IN ({placeholders}) \
+                 UNION ALL \
+                 SELECT chat_id, head FROM chat_heads WHERE chat_id IN ({placeholders}) \
+             ) GROUP BY chat_id"
+        );
+        let mut query = sqlx::query(&sql);
+        for chat_id in chat_ids.iter().chain(chat_ids) {
+            query = query.bind(chat_id.as_slice());
+        }
+
+        let mut heads = HashMap::with_capacity(chat_ids.len());
+        for row in query.fetch_all(&self.db).await? {
+            let chat_id: Vec<u8> = row.try_get("chat_id")?;
+            let head: i64 = row.try_get("head")?;
+            heads.insert(chat_id, head as usize);
+        }
+
+        Ok(heads)
+    }
+
+    /// Appends a row to the control event table
+    async fn record_control_event(
+        &self,
+        connection_id: Uuid,
+        chat_id: &str,
+        action: ControlAction,
+    ) -> Result<()> {
+        sqlx::query("INSERT INTO control_events (connection_id, chat_id, action) VALUES (?, ?, ?)")
+            .bind(connection_id.to_string())
+            .bind(chat_id)
+            .bind(action.as_str())
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Runs `SELECT 1` on a pooled connection
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.db).await?;
+        Ok(())
+    }
+}
+
+/// Converts a messages row, base64 encoding its binary fields
+async fn row_to_message(row: SqliteRow) -> Result<OutcomeMessage> {
+    let nonce: i64 = row.try_get("nonce")?;
+    let chat_id: Vec<u8> = row.try_get("chat_id")?;
+    let signature: Vec<u8> = row.try_get("signature")?;
+    let content: Vec<u8> = row.try_get("content")?;
+    let content_iv: Vec<u8> = row.try_get("content_iv")?;
+    let ttl_seconds: Option<i64> = row.try_get("ttl_seconds")?;
+
+    Ok(OutcomeMessage {
+        nonce: nonce as usize,
+        chat_id: encode_base64(&chat_id).await,
+        signature: encode_base64(&signature).await,
+        content: encode_base64(&content).await,
+        content_iv: encode_base64(&content_iv).await,
+        ttl_seconds: ttl_seconds.map(|ttl| ttl as u64),
+    })
+}
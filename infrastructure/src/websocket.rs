@@ -1,22 +1,67 @@
-use futures::StreamExt;
-use log::debug;
-use std::{ops::ControlFlow, sync::Arc};
-use tokio_tungstenite::tungstenite::Message;
+use dashmap::DashMap;
+use futures::{SinkExt, StreamExt, stream};
+use std::{
+    ops::ControlFlow,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::time::{Instant, Interval};
+use tokio_tungstenite::tungstenite::{
+    Message,
+    protocol::{CloseFrame, frame::coding::CloseCode},
+};
+use tracing::{Instrument, debug};
 
-use misc::base64::decode_base64;
+use crate::{
+    cache::BoundedCache, database::DatabaseError, metrics::Metrics, resume::SubscriptionRegistry,
+};
+use misc::base64::{canonical_base64, decode_base64};
 use use_case::{messages::MessagesUseCase, websocket::WebSocketUseCase};
+use uuid::Uuid;
 
 use traits::{
-    message::{MessagesDB, MessagesRepository},
+    message::{InsertOutcome, MessagesDB, MessagesRepository},
     websocket::WebsocketRepository,
 };
 
-use protocol::entity::{
-    self,
-    message::IncomeMessage,
-    websocket::{WebSocketConnection, WebSocketManager},
+use protocol::{
+    entity::{
+        self,
+        message::{ClusterEvent, IncomeMessage},
+        quota::TokenBucket,
+        response::SeedErrorCode,
+        websocket::{
+            BroadcastReport, ChatStat, ControlAction, DisconnectReason, SeedCloseReason,
+            ServerStatus, SubscriptionRequest, WebSocketConnection, WebSocketManager,
+        },
+    },
+    error::SeedError,
 };
 
+/// Identifies an accepted message by chat id, nonce and signature
+type ReplayKey = (String, usize, String);
+
+/// Decodes a message received in a binary frame
+///
+/// Binary frames carry the same JSON as text frames, or CBOR when the `cbor`
+/// feature is enabled. The JSON error is reported if neither decodes.
+fn decode_binary(bytes: &[u8]) -> Result<IncomeMessage, serde_json::Error> {
+    let json_err = match serde_json::from_slice(bytes) {
+        Ok(incoming) => return Ok(incoming),
+        Err(err) => err,
+    };
+
+    #[cfg(feature = "cbor")]
+    if let Ok(incoming) = ciborium::from_reader(bytes) {
+        return Ok(incoming);
+    }
+
+    Err(json_err)
+}
+
 /// Service for handling WebSocket connections and messages.
 ///
 /// This service manages the lifecycle of WebSocket connections, processes incoming
@@ -29,9 +74,21 @@ pub struct WebSocketService<MR: MessagesRepository + Clone, DB: MessagesDB + Clo
     websocket_use_case: WebSocketUseCase<MR>,
     /// Use case for message handling operations
     messages_use_case: MessagesUseCase<DB>,
+    /// Metrics collected while serving connections
+    metrics: Metrics,
+    /// Recently accepted messages, used to acknowledge replays without reprocessing them
+    replay_cache: Arc<BoundedCache<ReplayKey>>,
+    /// Subscriptions saved per client identity for fast reconnects
+    resume_registry: Arc<SubscriptionRegistry>,
+    /// Message rate limit of every open connection, by connection id
+    rate_limits: Arc<DashMap<Uuid, TokenBucket>>,
+    /// Time the service was created at
+    started_at: SystemTime,
+    /// Number of connections accepted since the service was created
+    accepted_connections: Arc<AtomicUsize>,
 }
 
-impl<MR: MessagesRepository + Clone, DB: MessagesDB + Clone> WebSocketService<MR, DB> {
+impl<MR: MessagesRepository + Clone + 'static, DB: MessagesDB + Clone> WebSocketService<MR, DB> {
     /// Creates a new WebSocket service instance.
     ///
     /// # Arguments
@@ -48,13 +105,119 @@ impl<MR: MessagesRepository + Clone, DB: MessagesDB + Clone> WebSocketService<MR
         websocket_use_case: WebSocketUseCase<MR>,
         messages_use_case: MessagesUseCase<DB>,
     ) -> Self {
+        let metrics = Metrics::with_delivery(websocket_use_case.counters().clone());
+        let replay_cache = BoundedCache::new(
+            websocket_use_case.config().replay_cache_capacity,
+            metrics.cache_evictions("replay"),
+        );
+        let resume_registry = SubscriptionRegistry::new(
+            websocket_use_case.config().resume_registry_capacity,
+            websocket_use_case.config().resume_ttl,
+        );
+
         Self {
             manager: Arc::new(manager),
             websocket_use_case,
             messages_use_case,
+            metrics,
+            replay_cache: Arc::new(replay_cache),
+            resume_registry: Arc::new(resume_registry),
+            rate_limits: Arc::new(DashMap::new()),
+            started_at: SystemTime::now(),
+            accepted_connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the metrics collected by this service
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Closes every session and waits for queued messages to be stored.
+    ///
+    /// Each subscribed connection is sent a close frame, then the chat
+    /// message queues are given up to `shutdown_timeout` to drain.
+    pub async fn shutdown(&self) {
+        let closed = self.manager.close_all().await;
+        tracing::info!("Sent a close frame to {closed} connections");
+
+        let drained = async {
+            while self
+                .manager
+                .message_queues
+                .iter()
+                .any(|queue| !queue.0.is_empty())
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let timeout = self.websocket_use_case.config().shutdown_timeout;
+        if tokio::time::timeout(timeout, drained).await.is_err() {
+            tracing::warn!("Shutting down with messages still queued");
+        }
+    }
+
+    /// Checks that the message store answers within `health_check_timeout`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the store is unreachable or does not answer in time
+    pub async fn check_health(&self) -> anyhow::Result<()> {
+        let timeout = self.websocket_use_case.config().health_check_timeout;
+        match tokio::time::timeout(timeout, self.messages_use_case.db.ping()).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!(
+                "database did not answer within {}ms",
+                timeout.as_millis()
+            )),
+        }
+    }
+
+    /// Returns the subscriber count and queue length of every live chat
+    pub fn chat_stats(&self) -> Vec<ChatStat> {
+        self.manager.snapshot()
+    }
+
+    /// Returns the start time, uptime and connection counts of this service
+    pub fn status(&self) -> ServerStatus {
+        let unix_secs = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+
+        ServerStatus {
+            started_at: unix_secs(self.started_at),
+            uptime_seconds: self.started_at.elapsed().unwrap_or_default().as_secs(),
+            total_connections: self.accepted_connections.load(Ordering::Relaxed),
+            active_connections: self.manager.active_connections(),
         }
     }
 
+    /// Renders the metrics of this service in the Prometheus text exposition format
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(&self.manager)
+    }
+
+    /// Delivers an event relayed by another instance of the cluster to the local subscribers.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event received from the cluster
+    pub async fn deliver_remote(&self, event: ClusterEvent) -> BroadcastReport {
+        self.websocket_use_case
+            .deliver_remote(self.manager.clone(), event)
+            .await
+    }
+
+    /// Starts the background maintenance tasks for the managed connections.
+    ///
+    /// Currently spawns the periodic removal of subscribers whose sessions are closed.
+    pub fn start_background_tasks(&self) {
+        self.websocket_use_case
+            .spawn_subscriber_reconciliation(self.manager.clone());
+    }
+
     /// Handles a new WebSocket connection by processing its message stream.
     ///
     /// This method continuously processes incoming messages from the WebSocket stream
@@ -63,94 +226,369 @@ impl<MR: MessagesRepository + Clone, DB: MessagesDB + Clone> WebSocketService<MR
     /// # Arguments
     ///
     /// * `connection` - The WebSocket connection to handle
-    pub async fn handle_connection(&self, connection: WebSocketConnection) {
-        let connection = Arc::new(connection);
+    pub async fn handle_connection(&self, mut connection: WebSocketConnection) {
+        self.accepted_connections.fetch_add(1, Ordering::Relaxed);
+        if self.manager.ensure_unique_id(&mut connection) {
+            tracing::warn!(
+                "Connection id clashed with a registered connection, using {} instead",
+                connection.id
+            );
+        }
+
+        // Correlate everything logged for this connection through its id
+        let span = tracing::info_span!("connection", connection.id = %connection.id);
+        self.serve_connection(Arc::new(connection))
+            .instrument(span)
+            .await;
+    }
+
+    /// Processes the message stream of a connection until it closes, then cleans up.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The WebSocket connection to serve
+    async fn serve_connection(&self, connection: Arc<WebSocketConnection>) {
         let manager = self.manager.clone();
         let websocket_use_case = self.websocket_use_case.clone();
         let messages_use_case = self.messages_use_case.clone();
 
-        // Spawn a task to handle this connection
-
         debug!(
             "Starting to handle websocket messages for connection: {}",
             connection.id
         );
 
-        let mut stream = connection.session.lock().await;
+        let mut stream = connection.stream.lock().await;
+
+        // Connections past their maximum lifetime are forced to reconnect
+        let config = websocket_use_case.config();
+        let deadline = config
+            .max_connection_lifetime
+            .map(|lifetime| Instant::now() + connection.remaining_lifetime(lifetime));
+        let idle_timeout = config.idle_timeout;
+        let mut idle_deadline = idle_timeout.map(|idle| Instant::now() + idle);
+        let mut pings = config
+            .ping_interval
+            .map(|period| tokio::time::interval_at(Instant::now() + period, period));
 
         // Process each message in the stream until connection closes
-        while let Some(Ok(msg)) = stream.next().await {
-            match msg {
-                Message::Text(text) => match serde_json::from_str::<IncomeMessage>(&text) {
-                    Ok(incoming) => {
-                        // Process the message and break the loop if needed
-                        if let ControlFlow::Break(_) = Self::process_message(
-                            manager.clone(),
-                            connection.clone(),
-                            incoming,
-                            &websocket_use_case,
-                            &messages_use_case,
-                        )
+        loop {
+            let next = tokio::select! {
+                next = stream.next() => next,
+                _ = sleep_until(deadline) => {
+                    self.expire_connection(connection.clone(), &messages_use_case)
+                        .await;
+                    break;
+                }
+                _ = sleep_until(idle_deadline) => {
+                    let reason = DisconnectReason::IdleTimeout;
+                    tracing::info!("Closing connection {}: {}", connection.id, reason.as_str());
+                    self.metrics.record_disconnect(reason);
+                    break;
+                }
+                _ = tick(&mut pings) => {
+                    // The pong counts as activity, so only dead peers run into the idle timeout
+                    let _ = connection
+                        .session
+                        .lock()
                         .await
-                        {
-                            break;
-                        }
-                    }
-                    Err(err) => {
-                        // Log parsing errors and send failure status
-                        log::error!("Failed to parse message: {}", err);
-                        let _ = messages_use_case
-                            .status_response(connection.clone(), false)
-                            .await;
-                    }
-                },
+                        .send(Message::Ping(Default::default()))
+                        .await
+                        .map_err(|e| debug!("Error sending ping: {}", e));
+                    continue;
+                }
+            };
+            idle_deadline = idle_timeout.map(|idle| Instant::now() + idle);
+            let Some(Ok(msg)) = next else {
+                break;
+            };
+
+            let parsed = match msg {
+                Message::Text(text) => serde_json::from_str::<IncomeMessage>(&text),
+                Message::Binary(bytes) => decode_binary(&bytes),
                 Message::Close(_) => {
-                    log::info!("WebSocket connection closed by client");
+                    tracing::info!("WebSocket connection closed by client");
                     break;
                 }
-                _ => {} // Handle other message types if needed
+                _ => continue, // Handle other message types if needed
+            };
+
+            match parsed {
+                Ok(incoming) => {
+                    // Process the message and break the loop if needed
+                    if let ControlFlow::Break(reason) =
+                        self.process_message(connection.clone(), incoming).await
+                    {
+                        tracing::info!("Closing connection {}: {}", connection.id, reason.as_str());
+                        self.metrics.record_disconnect(reason);
+                        break;
+                    }
+                }
+                Err(err) => {
+                    // Log parsing errors and send failure status
+                    tracing::error!("Failed to parse message: {}", err);
+                    let _ = messages_use_case
+                        .status_response(connection.clone(), Err(SeedErrorCode::BadRequest))
+                        .await;
+                }
             }
         }
 
-        // Clean up on disconnect
+        // Keep the final cursors for a resume, then clean up on disconnect
+        self.save_subscriptions(&connection);
+        for (chat_id, _) in connection.subscription_snapshot() {
+            self.record_control_event(&connection, &chat_id, ControlAction::Unsubscribe)
+                .await;
+        }
         websocket_use_case
-            .disconnect(manager.clone(), connection.clone())
+            .disconnect(
+                manager.clone(),
+                connection.clone(),
+                Some(SeedCloseReason::Normal),
+            )
+            .await;
+        self.rate_limits.remove(&connection.id);
+        self.metrics.record_bytes_sent(&connection);
+    }
+
+    /// Takes a token from the rate limit bucket of a connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The connection that sent a message
+    ///
+    /// # Returns
+    ///
+    /// Whether the message is within the rate limit, always `true` without one
+    fn within_rate_limit(&self, connection: &WebSocketConnection) -> bool {
+        let Some(rate) = self.websocket_use_case.config().rate_limit else {
+            return true;
+        };
+        let now = connection.now();
+        self.rate_limits
+            .entry(connection.id)
+            .or_insert_with(|| TokenBucket::new(rate, Duration::from_secs(1), now))
+            .try_take(1, now)
+    }
+
+    /// Charges an accepted message to the sending allowance of its connection and counts it.
+    ///
+    /// The allowance was checked before the message was accepted, and the
+    /// messages of a connection are processed one at a time, so it still fits.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The connection that sent the message
+    /// * `message` - The accepted message
+    fn record_accepted(
+        &self,
+        connection: &WebSocketConnection,
+        message: &entity::message::Message,
+    ) {
+        let limits = self.websocket_use_case.config().quota_limits();
+        let _ = connection.consume_quota(limits, message.content.len() as u64);
+        self.metrics.record_message_sent();
+    }
+
+    /// Closes a connection that outlived its maximum lifetime.
+    ///
+    /// Tells the client that it has to re-authenticate and sends a close frame
+    /// whose code hints that it should reconnect.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The expired WebSocket connection
+    /// * `messages_use_case` - The messages use case for sending the error response
+    async fn expire_connection(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        messages_use_case: &MessagesUseCase<DB>,
+    ) {
+        let reason = DisconnectReason::ReauthRequired;
+        tracing::info!("Closing connection {}: {}", connection.id, reason.as_str());
+        self.metrics.record_disconnect(reason);
+
+        let _ = messages_use_case
+            .error_response(connection.clone(), SeedErrorCode::ReauthRequired)
             .await;
+
+        let frame = CloseFrame {
+            code: CloseCode::Restart,
+            reason: reason.as_str().into(),
+        };
+        let _ = connection
+            .session
+            .lock()
+            .await
+            .send(Message::Close(Some(frame)))
+            .await
+            .map_err(|e| tracing::error!("Error sending close frame: {}", e));
+    }
+
+    /// Saves the subscriptions of a resumed connection in the resume registry.
+    ///
+    /// Connections that never sent a `resume` have no identity and are skipped.
+    fn save_subscriptions(&self, connection: &WebSocketConnection) {
+        if let Some(client_id) = connection.client_id() {
+            self.resume_registry
+                .save(client_id, connection.subscription_snapshot());
+        }
+    }
+
+    /// Records a subscription change as a control event, if enabled.
+    ///
+    /// Failures are logged and otherwise ignored, the subscription itself
+    /// does not depend on the record.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The connection whose subscriptions changed
+    /// * `chat_id` - The chat ID as sent by the client
+    /// * `action` - Whether the connection subscribed or unsubscribed
+    async fn record_control_event(
+        &self,
+        connection: &WebSocketConnection,
+        chat_id: &str,
+        action: ControlAction,
+    ) {
+        if !self.websocket_use_case.config().control_events {
+            return;
+        }
+
+        let _ = self
+            .messages_use_case
+            .db
+            .record_control_event(connection.id, chat_id, action)
+            .await
+            .inspect_err(|e| tracing::error!("Error recording control event: {e}"));
     }
 
     /// Processes an incoming WebSocket message based on its type.
     ///
-    /// This method handles different types of incoming messages (ping, send, subscribe, unsubscribe)
-    /// and performs the appropriate actions for each type.
+    /// This method handles different types of incoming messages (ping, send, subscribe,
+    /// unsubscribe, resume) and performs the appropriate actions for each type.
     ///
     /// # Arguments
     ///
-    /// * `manager` - The WebSocket manager
     /// * `connection` - The WebSocket connection that sent the message
     /// * `incoming` - The parsed incoming message
-    /// * `websocket_use_case` - The WebSocket use case for processing operations
-    /// * `messages_use_case` - The messages use case for message handling
     ///
     /// # Returns
     ///
-    /// A `ControlFlow` indicating whether to continue processing messages or break the connection
+    /// A `ControlFlow` indicating whether to continue processing messages or break the
+    /// connection, carrying the reason for the disconnect
     async fn process_message(
-        manager: Arc<WebSocketManager>,
+        &self,
         connection: Arc<WebSocketConnection>,
-        incoming: IncomeMessage,
-        websocket_use_case: &WebSocketUseCase<MR>,
-        messages_use_case: &MessagesUseCase<DB>,
-    ) -> ControlFlow<()> {
+        mut incoming: IncomeMessage,
+    ) -> ControlFlow<DisconnectReason> {
+        let manager = self.manager.clone();
+        let websocket_use_case = &self.websocket_use_case;
+        let messages_use_case = &self.messages_use_case;
+        let replay_cache = &self.replay_cache;
+
+        // Key every chat by one encoding, whichever base64 alphabet the client used.
+        // Chat IDs that are not base64 at all are left for validation to reject.
+        for chat_id in incoming.chat_ids_mut() {
+            if let Some(canonical) = canonical_base64(chat_id).await {
+                *chat_id = canonical;
+            }
+        }
+
+        // Drop messages over the rate limit, the connection stays open
+        if !self.within_rate_limit(&connection) {
+            tracing::warn!("Connection {} exceeded its rate limit", connection.id);
+            let _ = messages_use_case
+                .status_response(connection, Err(SeedErrorCode::RateLimited))
+                .await;
+            return ControlFlow::Continue(());
+        }
+
         match &incoming {
             IncomeMessage::Ping => {
-                // Handle ping messages by sending a positive status response
-                let _ = messages_use_case.status_response(connection, true).await;
+                // Answer pings on their own channel so they are not taken for acknowledgements
+                let _ = messages_use_case.pong_response(connection).await;
             }
             IncomeMessage::Send(msg) => {
+                debug!(chat_id = %msg.chat_id, nonce = msg.nonce, "Processing send");
+
                 // Validate the message before processing
-                if !messages_use_case.is_valid_message(msg.clone().into()).await {
-                    let _ = messages_use_case.status_response(connection, false).await;
-                    return ControlFlow::Break(());
+                if let Err(err) = messages_use_case.validate_message(msg.clone().into()).await {
+                    tracing::warn!(
+                        "Rejected invalid message from connection {}: {}",
+                        connection.id,
+                        err
+                    );
+                    let _ = messages_use_case
+                        .error_response(connection.clone(), err.code())
+                        .await;
+                    let _ = messages_use_case
+                        .status_response(connection, Err(err.code()))
+                        .await;
+                    return ControlFlow::Break(DisconnectReason::InvalidMessage);
+                }
+
+                // In strict mode a connection may only send to chats it is subscribed to
+                if websocket_use_case.config().strict_subscriptions
+                    && !manager.is_subscribed(&connection, &msg.chat_id)
+                {
+                    tracing::warn!(
+                        "Connection {} sent to chat {} without subscribing",
+                        connection.id,
+                        msg.chat_id
+                    );
+                    let _ = messages_use_case
+                        .error_response(connection.clone(), SeedErrorCode::NotSubscribed)
+                        .await;
+                    let _ = messages_use_case
+                        .status_response(connection, Err(SeedErrorCode::NotSubscribed))
+                        .await;
+                    return ControlFlow::Continue(());
+                }
+
+                // A replay of an accepted message is acknowledged without being processed again
+                let replay_key = (msg.chat_id.clone(), msg.nonce, msg.signature.clone());
+                if replay_cache.contains(&replay_key) {
+                    tracing::debug!(
+                        "Ignoring replayed message {} in chat {}",
+                        msg.nonce,
+                        msg.chat_id
+                    );
+                    let _ = messages_use_case
+                        .send_ack_response(connection, &msg.chat_id, msg.nonce)
+                        .await;
+                    return ControlFlow::Continue(());
+                }
+
+                // Check the connection's sending allowance, which is only charged
+                // once the message is accepted
+                let limits = websocket_use_case.config().quota_limits();
+                if let Err(quota) = connection.check_quota(limits, msg.content.len() as u64) {
+                    tracing::warn!("Connection {} exceeded its sending quota", connection.id);
+                    let _ = messages_use_case
+                        .rate_limited_response(connection.clone(), quota)
+                        .await;
+                    let _ = messages_use_case
+                        .status_response(connection, Err(SeedErrorCode::RateLimited))
+                        .await;
+                    return ControlFlow::Continue(());
+                }
+
+                // Without history the message is only relayed to live subscribers
+                if !websocket_use_case.config().history_enabled {
+                    let report = websocket_use_case
+                        .broadcast_event(manager.clone(), incoming.clone())
+                        .await;
+                    tracing::info!(
+                        "Relayed message to {} subscribers, {} failed",
+                        report.delivered,
+                        report.failed_count()
+                    );
+                    replay_cache.insert(replay_key);
+                    self.record_accepted(&connection, msg);
+                    let _ = messages_use_case
+                        .send_ack_response(connection, &msg.chat_id, msg.nonce)
+                        .await;
+                    return ControlFlow::Continue(());
                 }
 
                 // Create a connected message to send
@@ -162,73 +600,2271 @@ impl<MR: MessagesRepository + Clone, DB: MessagesDB + Clone> WebSocketService<MR
                 // Check if there are subscribers for this chat
                 let contains_key = manager.message_queues.contains_key(&msg.chat_id);
 
-                if contains_key {
-                    // If there are subscribers, add the message to the queue
-                    if let Some(queue) = manager.message_queues.get_mut(&msg.chat_id) {
-                        let _ = queue.0.send(message).map_err(|e| log::error!("{e}"));
-                        log::info!("Message has been successfully added to the queue");
+                // A message sent without a nonce is stored right away, so the
+                // sender can be acknowledged with the nonce the store picked
+                if contains_key && !msg.has_auto_nonce() {
+                    // If there are subscribers, add the message to the queue. A full
+                    // queue rejects the message rather than holding up the sender.
+                    let rejected = manager
+                        .message_queues
+                        .get(&msg.chat_id)
+                        .is_some_and(|queue| match queue.0.try_send(message) {
+                            Ok(()) => {
+                                tracing::info!("Message has been successfully added to the queue");
+                                false
+                            }
+                            Err(flume::TrySendError::Full(_)) => true,
+                            Err(e) => {
+                                tracing::error!("{e}");
+                                false
+                            }
+                        });
+                    if rejected {
+                        tracing::warn!("Queue of chat {} is full, rejecting message", msg.chat_id);
+                        let _ = messages_use_case
+                            .status_response(connection, Err(SeedErrorCode::QueueFull))
+                            .await;
+                        return ControlFlow::Continue(());
+                    }
+
+                    // In live-first mode subscribers get the message before it is persisted
+                    if websocket_use_case.config().live_first {
+                        let report = websocket_use_case
+                            .broadcast_event(manager.clone(), incoming.clone())
+                            .await;
+                        tracing::debug!(
+                            "Delivered message live to {} subscribers, {} failed",
+                            report.delivered,
+                            report.failed_count()
+                        );
                     }
+                    replay_cache.insert(replay_key);
+                    self.record_accepted(&connection, msg);
 
-                    // Send a positive status response
-                    let _ = messages_use_case.status_response(connection, true).await;
+                    // Acknowledge the queued message
+                    let _ = messages_use_case
+                        .send_ack_response(connection, &msg.chat_id, msg.nonce)
+                        .await;
                 } else {
-                    // If no subscribers, store the message in the database
-                    log::info!("There is no subscribers to receive message in the queue");
-                    if let Err(err) = messages_use_case.db.insert_message(msg.clone()).await {
-                        log::info!("Error inserting message into database: {}", err);
+                    // Without subscribers or a nonce, store the message in the database
+                    if !contains_key {
+                        tracing::info!("There is no subscribers to receive message in the queue");
+                    }
+                    let outcome = messages_use_case.db.insert_message(msg.clone()).await;
+                    if let Err(err) = &outcome {
+                        tracing::info!("Error inserting message into database: {}", err);
+                        let code = if let Some(DatabaseError::Timeout) = err.downcast_ref() {
+                            let _ = messages_use_case
+                                .error_response(connection.clone(), SeedErrorCode::DbTimeout)
+                                .await;
+                            SeedErrorCode::DbTimeout
+                        } else if let Some(
+                            DatabaseError::PoolTimeout | DatabaseError::NonceContention,
+                        ) = err.downcast_ref()
+                        {
+                            // The database or chat is only overloaded, the client may retry the send
+                            let _ = messages_use_case
+                                .status_response(connection.clone(), Err(SeedErrorCode::Busy))
+                                .await;
+                            return ControlFlow::Continue(());
+                        } else if let Some(seed_error) = err.downcast_ref::<SeedError>() {
+                            seed_error.code()
+                        } else {
+                            SeedErrorCode::Internal
+                        };
                         let _ = messages_use_case
-                            .status_response(connection.clone(), false)
+                            .status_response(connection.clone(), Err(code))
                             .await;
-                        return ControlFlow::Break(());
+
+                        let reason = match err.downcast_ref() {
+                            Some(SeedError::InvalidNonce) => DisconnectReason::InvalidNonce,
+                            _ => DisconnectReason::StorageFailure,
+                        };
+                        return ControlFlow::Break(reason);
+                    }
+                    // A retried message is acknowledged again without counting it twice
+                    let nonce = match outcome {
+                        Ok(InsertOutcome::Inserted(nonce)) => {
+                            self.metrics.record_persisted();
+                            self.record_accepted(&connection, msg);
+                            nonce
+                        }
+                        _ => msg.nonce,
+                    };
+
+                    // Retries of a message without a nonce cannot be told from new messages
+                    if msg.has_auto_nonce() {
+                        if contains_key {
+                            let stored = IncomeMessage::Send(entity::message::Message {
+                                nonce,
+                                ..msg.clone()
+                            });
+                            let report = websocket_use_case
+                                .broadcast_event(manager.clone(), stored)
+                                .await;
+                            tracing::debug!(
+                                "Delivered message {nonce} to {} subscribers, {} failed",
+                                report.delivered,
+                                report.failed_count()
+                            );
+                        }
+                    } else {
+                        replay_cache.insert(replay_key);
                     }
 
-                    // Send a positive status response
+                    // Acknowledge the stored message
                     let _ = messages_use_case
-                        .status_response(connection.clone(), true)
+                        .send_ack_response(connection.clone(), &msg.chat_id, nonce)
                         .await;
                 }
             }
             IncomeMessage::Subscribe(msg) => {
-                // Decode the chat ID from base64
-                let chat_id = match decode_base64(msg.chat_id.clone()).await {
-                    Ok(chat_id) => chat_id,
-                    Err(err) => {
-                        log::error!("Error decoding chat ID: {}", err);
-                        let _ = messages_use_case
-                            .status_response(connection.clone(), false)
-                            .await;
-                        return ControlFlow::Break(());
-                    }
-                };
-
-                // Handle the subscription
+                return self.subscribe(connection, msg).await;
+            }
+            IncomeMessage::Unsubscribe(msg) => {
+                // Handle unsubscription
                 websocket_use_case
-                    .handle_subscribe(manager.clone(), connection.clone(), &msg.chat_id)
+                    .handle_unsubscribe(manager.clone(), connection.clone(), &msg.chat_id)
+                    .await;
+                connection.forget_subscription(&msg.chat_id);
+                self.save_subscriptions(&connection);
+                self.record_control_event(&connection, &msg.chat_id, ControlAction::Unsubscribe)
                     .await;
+                let _ = messages_use_case.status_response(connection, Ok(())).await;
+            }
+            IncomeMessage::Resume(request) => {
+                let secret = websocket_use_case.config().subscription_secret.as_ref();
+
+                // A resume token carries the subscriptions itself, but only if we signed
+                // it, it has not expired and its session did not log out since
+                let resume_ttl = websocket_use_case.config().resume_ttl;
+                let from_token = match (&request.resume_token, secret) {
+                    (Some(token), Some(secret)) => secret
+                        .verify_resume(token, SystemTime::now(), resume_ttl)
+                        .await
+                        .filter(|state| !self.resume_registry.is_revoked(state.session)),
+                    _ => None,
+                };
+                if request.resume_token.is_some() && from_token.is_none() {
+                    tracing::warn!("Connection {} sent an invalid resume token", connection.id);
+                    let _ = messages_use_case
+                        .status_response(connection, Err(SeedErrorCode::Unauthorized))
+                        .await;
+                    return ControlFlow::Continue(());
+                }
+
+                // Anyone can claim a client id, so with subscribe tokens required
+                // only a signed resume token may bring back saved subscriptions
+                if secret.is_some() && from_token.is_none() && request.client_id.is_some() {
+                    tracing::warn!(
+                        "Connection {} tried to resume by client id without a resume token",
+                        connection.id
+                    );
+                    let _ = messages_use_case
+                        .status_response(connection, Err(SeedErrorCode::Unauthorized))
+                        .await;
+                    return ControlFlow::Continue(());
+                }
+
+                // The connection carries on the session of the token it resumed with
+                if let Some(state) = &from_token
+                    && !connection.join_resume_session(state.session)
+                {
+                    tracing::warn!(
+                        "Connection {} tried to resume a second session",
+                        connection.id
+                    );
+                    let _ = messages_use_case
+                        .status_response(connection, Err(SeedErrorCode::Unauthorized))
+                        .await;
+                    return ControlFlow::Continue(());
+                }
 
-                // Send various responses indicating successful subscription
+                // A connection keeps the identity it resumed under first
+                if let Some(client_id) = &request.client_id
+                    && !connection.set_client_id(client_id.clone())
+                {
+                    tracing::warn!(
+                        "Connection {} tried to resume under a second identity",
+                        connection.id
+                    );
+                    let _ = messages_use_case
+                        .status_response(connection, Err(SeedErrorCode::Unauthorized))
+                        .await;
+                    return ControlFlow::Continue(());
+                }
                 let _ = messages_use_case
-                    .status_response(connection.clone(), true)
+                    .status_response(connection.clone(), Ok(()))
                     .await;
+
+                // Re-subscribe to every saved chat from where the client left off,
+                // replaying a bounded number of chats at a time
+                let saved = match (from_token, &request.client_id) {
+                    (Some(state), _) => state.chats,
+                    (None, Some(client_id)) => self.resume_registry.restore(client_id),
+                    (None, None) => Vec::new(),
+                };
+                let concurrency = websocket_use_case.config().resume_replay_concurrency.max(1);
+                let mut replays = stream::iter(saved)
+                    .map(|(chat_id, nonce)| {
+                        let connection = connection.clone();
+                        async move {
+                            // Chats in a verified resume token were authorized when subscribed
+                            let token = match secret {
+                                Some(secret) => Some(secret.sign(&chat_id).await),
+                                None => None,
+                            };
+                            let request = SubscriptionRequest {
+                                rtype: SubscriptionRequest::RTYPE.to_string(),
+                                chat_id,
+                                nonce,
+                                recent_context: None,
+                                limit: None,
+                                token,
+                            };
+                            self.subscribe(connection, &request).await
+                        }
+                    })
+                    .buffer_unordered(concurrency);
+                while let Some(flow) = replays.next().await {
+                    flow?;
+                }
+            }
+            IncomeMessage::Quota => {
+                let limits = websocket_use_case.config().quota_limits();
+                let quota = connection.quota_status(limits);
                 let _ = messages_use_case
-                    .unread_message_response(connection.clone(), &chat_id, msg.nonce)
+                    .quota_status_response(connection, quota)
                     .await;
+            }
+            IncomeMessage::HeadNonces(request) => {
+                if let Err(e) = messages_use_case
+                    .head_nonces_response(connection.clone(), &request.chats)
+                    .await
+                {
+                    tracing::warn!("Failed to look up head nonces: {e}");
+                    let _ = messages_use_case
+                        .status_response(connection, Err(SeedErrorCode::Internal))
+                        .await;
+                }
+            }
+            IncomeMessage::SyncDiff(request) => {
+                if let Err(e) = messages_use_case
+                    .sync_diff_response(connection.clone(), &request.chat_id, &request.have_ranges)
+                    .await
+                {
+                    tracing::warn!("Failed to sync chat {}: {e}", request.chat_id);
+                    let _ = messages_use_case
+                        .status_response(connection, Err(SeedErrorCode::Internal))
+                        .await;
+                }
+            }
+            IncomeMessage::Logout => {
                 let _ = messages_use_case
-                    .wait_event_response(connection.clone(), &msg.chat_id)
+                    .status_response(connection.clone(), Ok(()))
                     .await;
+                let chat_ids = websocket_use_case.logout(manager, connection.clone()).await;
+                for chat_id in chat_ids {
+                    self.record_control_event(&connection, &chat_id, ControlAction::Unsubscribe)
+                        .await;
+                }
+                if let Some(client_id) = connection.client_id() {
+                    self.resume_registry.forget(client_id);
+                }
+                self.resume_registry.revoke(connection.resume_session());
+                return ControlFlow::Break(DisconnectReason::Logout);
             }
-            IncomeMessage::Unsubscribe(msg) => {
-                // Handle unsubscription
-                websocket_use_case
-                    .handle_unsubscribe(manager.clone(), connection.clone(), &msg.chat_id)
+            IncomeMessage::None => {
+                // A placeholder carries nothing to act on, so tell the client it was ignored
+                tracing::warn!("Ignored empty message from connection {}", connection.id);
+                let _ = messages_use_case
+                    .status_response(connection, Err(SeedErrorCode::BadRequest))
                     .await;
-                let _ = messages_use_case.status_response(connection, true).await;
             }
-            IncomeMessage::None => {
-                // No-op for None messages
+            IncomeMessage::Unknown(unknown) => {
+                let code = if unknown.is_malformed() {
+                    tracing::warn!(
+                        "Rejected malformed {:?} message from connection {}",
+                        unknown.rtype,
+                        connection.id
+                    );
+                    SeedErrorCode::BadRequest
+                } else {
+                    tracing::warn!(
+                        "Rejected message of unknown type {:?} from connection {}",
+                        unknown.rtype,
+                        connection.id
+                    );
+                    SeedErrorCode::UnknownType
+                };
+                let _ = messages_use_case
+                    .error_response(connection.clone(), code)
+                    .await;
+                let _ = messages_use_case
+                    .status_response(connection, Err(code))
+                    .await;
             }
         }
         // Continue processing messages
         ControlFlow::Continue(())
     }
+
+    /// Subscribes a connection to a chat and replays what it missed.
+    ///
+    /// Sends the status, the optional recent context, the unread replay, the
+    /// wait event and finally a subscribe complete notification.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The WebSocket connection that subscribes
+    /// * `msg` - The subscription request
+    ///
+    /// # Returns
+    ///
+    /// A `ControlFlow` indicating whether to continue processing messages or break the
+    /// connection, carrying the reason for the disconnect
+    async fn subscribe(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        msg: &SubscriptionRequest,
+    ) -> ControlFlow<DisconnectReason> {
+        let websocket_use_case = &self.websocket_use_case;
+        let messages_use_case = &self.messages_use_case;
+
+        // The inner type has to agree with the message tag
+        if msg.rtype != SubscriptionRequest::RTYPE {
+            tracing::warn!(
+                "Rejected subscription with request type {:?} on connection {}",
+                msg.rtype,
+                connection.id
+            );
+            let _ = messages_use_case
+                .error_response(connection.clone(), SeedErrorCode::BadRequest)
+                .await;
+            let _ = messages_use_case
+                .status_response(connection, Err(SeedErrorCode::BadRequest))
+                .await;
+            return ControlFlow::Continue(());
+        }
+
+        // Decode the chat ID from base64
+        let chat_id = match decode_base64(msg.chat_id.clone()).await {
+            Ok(chat_id) => chat_id,
+            Err(err) => {
+                tracing::error!("Error decoding chat ID: {}", err);
+                let _ = messages_use_case
+                    .status_response(connection.clone(), Err(SeedErrorCode::InvalidEncoding))
+                    .await;
+                return ControlFlow::Break(DisconnectReason::InvalidChatId);
+            }
+        };
+
+        // Handle the subscription, rejecting it without closing the connection
+        if let Err(err) = websocket_use_case
+            .handle_subscribe(
+                self.manager.clone(),
+                connection.clone(),
+                &msg.chat_id,
+                msg.token.as_deref(),
+            )
+            .await
+        {
+            tracing::warn!("Rejected subscription to chat {}: {}", msg.chat_id, err);
+            let _ = messages_use_case
+                .status_response(connection.clone(), Err(err.code()))
+                .await;
+            return ControlFlow::Continue(());
+        }
+        connection.track_subscription(&msg.chat_id, msg.nonce);
+        self.record_control_event(&connection, &msg.chat_id, ControlAction::Subscribe)
+            .await;
+
+        // Send various responses indicating successful subscription
+        let _ = messages_use_case
+            .status_response(connection.clone(), Ok(()))
+            .await;
+        if websocket_use_case.config().history_enabled {
+            if let Some(amount) = msg.recent_context {
+                messages_use_case
+                    .recent_context_response(connection.clone(), &chat_id, amount)
+                    .await;
+            }
+            let _ = messages_use_case
+                .unread_message_response(connection.clone(), &chat_id, msg.nonce, msg.limit)
+                .await;
+        }
+        // Only a chat with a live queue ever delivers the events a client waits for
+        if self.manager.message_queues.contains_key(&msg.chat_id) {
+            let _ = messages_use_case
+                .wait_event_response(connection.clone(), &msg.chat_id)
+                .await;
+        }
+        let resume_token = match &websocket_use_case.config().subscription_secret {
+            Some(secret) => Some(
+                secret
+                    .sign_resume(
+                        connection.resume_session(),
+                        &connection.subscription_snapshot(),
+                        SystemTime::now(),
+                    )
+                    .await,
+            ),
+            None => None,
+        };
+        let _ = messages_use_case
+            .subscribe_complete_response(connection.clone(), &msg.chat_id, resume_token)
+            .await;
+
+        self.save_subscriptions(&connection);
+        ControlFlow::Continue(())
+    }
+}
+
+/// Completes at `deadline`, never if there is none
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Completes at the next tick of `interval`, never if there is none
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{sync::atomic::Ordering, time::Duration};
+
+    use futures::channel::mpsc;
+    use misc::base64::encode_base64;
+    use serde_json::{Value, json};
+    use tokio_tungstenite::tungstenite::Error as WsError;
+    use use_case::{
+        auth::SubscriptionSecret,
+        config::SeedConfig,
+        test_utils::{MockDb, channel_connection, scripted_connection, sent_responses},
+    };
+
+    use protocol::{clock::MockClock, entity::websocket::ResumeRequest};
+
+    use super::*;
+    use crate::sqlite::SqliteDatabase;
+
+    /// Creates a service backed by an in-memory database
+    async fn service() -> WebSocketService<MessagesUseCase<MockDb>, MockDb> {
+        service_with(SeedConfig::default()).await
+    }
+
+    /// Creates a service backed by an in-memory database using the given configuration
+    async fn service_with(config: SeedConfig) -> WebSocketService<MessagesUseCase<MockDb>, MockDb> {
+        service_with_db(MockDb::default(), config).await
+    }
+
+    /// Creates a service backed by `db` using the given configuration
+    async fn service_with_db<DB: MessagesDB + Clone + 'static>(
+        db: DB,
+        config: SeedConfig,
+    ) -> WebSocketService<MessagesUseCase<DB>, DB> {
+        let config = Arc::new(config);
+        let messages_use_case = MessagesUseCase::new(db, config.clone());
+        let websocket_use_case = WebSocketUseCase::new(messages_use_case.clone(), config).await;
+
+        WebSocketService::new(
+            WebSocketManager::default(),
+            websocket_use_case,
+            messages_use_case,
+        )
+    }
+
+    /// Registers a queue for a chat as if its message processor already ran,
+    /// so none is started and nothing drains the queue
+    fn pretend_processor<MR, DB>(service: &WebSocketService<MR, DB>, chat_id: &str)
+    where
+        MR: MessagesRepository + Clone + 'static,
+        DB: MessagesDB + Clone,
+    {
+        service
+            .manager
+            .message_queues
+            .insert(chat_id.to_string(), flume::unbounded());
+    }
+
+    /// Tests that a message with an out-of-order nonce breaks with `InvalidNonce`
+    #[tokio::test]
+    async fn test_invalid_nonce_breaks_with_reason() {
+        let service = service().await;
+        let (connection, mut rx) = scripted_connection(vec![]);
+        let incoming = IncomeMessage::Send(entity::message::Message {
+            nonce: 5,
+            chat_id: encode_base64(&[7; 32]).await,
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        });
+
+        let flow = service
+            .process_message(Arc::new(connection), incoming)
+            .await;
+
+        assert_eq!(flow, ControlFlow::Break(DisconnectReason::InvalidNonce));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses[0]["response"]["status"], false);
+        assert_eq!(responses[0]["response"]["code"], "invalid_nonce");
+    }
+
+    /// Tests that content larger than `max_message_bytes` breaks with a false status
+    #[tokio::test]
+    async fn test_oversized_message_breaks() {
+        let service = service_with(SeedConfig {
+            max_message_bytes: 16,
+            ..Default::default()
+        })
+        .await;
+        let (connection, mut rx) = channel_connection();
+        let incoming = IncomeMessage::Send(entity::message::Message {
+            nonce: 1,
+            chat_id: encode_base64(&[7; 32]).await,
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(&[1; 17]).await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        });
+
+        let flow = service.process_message(connection, incoming).await;
+
+        assert_eq!(flow, ControlFlow::Break(DisconnectReason::InvalidMessage));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses[0]["response"]["code"], "message_too_large");
+        assert_eq!(responses[1]["response"]["status"], false);
+    }
+
+    /// Tests that strict mode rejects sends to unsubscribed chats and accepts subscribed ones
+    #[tokio::test]
+    async fn test_strict_mode_requires_subscription() {
+        let service = service_with(SeedConfig {
+            strict_subscriptions: true,
+            ..Default::default()
+        })
+        .await;
+        let (connection, mut rx) = channel_connection();
+        let subscribed = encode_base64(&[1; 32]).await;
+        let unsubscribed = encode_base64(&[2; 32]).await;
+        service
+            .manager
+            .add_subscription(&connection, &subscribed, usize::MAX);
+        let send = async |chat_id: &str| {
+            IncomeMessage::Send(entity::message::Message {
+                nonce: 1,
+                chat_id: chat_id.to_string(),
+                signature: encode_base64(b"signature").await,
+                content: encode_base64(b"content").await,
+                content_iv: encode_base64(&[9; 12]).await,
+                ..Default::default()
+            })
+        };
+
+        let flow = service
+            .process_message(connection.clone(), send(&unsubscribed).await)
+            .await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses[0]["response"]["code"], "not_subscribed");
+        assert_eq!(responses[1]["response"]["status"], false);
+
+        let flow = service
+            .process_message(connection.clone(), send(&subscribed).await)
+            .await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["type"], "sendAck");
+    }
+
+    /// Tests that a message sent by one connection is delivered live to another subscribed one
+    #[tokio::test]
+    async fn test_sent_message_reaches_subscriber() {
+        let service = service().await;
+        let (sender, mut sender_rx) = channel_connection();
+        let (subscriber, mut subscriber_rx) = channel_connection();
+        let chat_id = encode_base64(&[7; 32]).await;
+
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 1,
+            recent_context: None,
+            limit: None,
+            token: None,
+        });
+        let flow = service.process_message(subscriber, subscribe).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        sent_responses(&mut subscriber_rx);
+
+        let send = IncomeMessage::Send(entity::message::Message {
+            nonce: 1,
+            chat_id: chat_id.clone(),
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        });
+        let flow = service.process_message(sender, send).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(sent_responses(&mut sender_rx)[0]["type"], "sendAck");
+
+        let delivered = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let responses = sent_responses(&mut subscriber_rx);
+                if !responses.is_empty() {
+                    return responses;
+                }
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(delivered[0]["response"]["type"], "new");
+        assert_eq!(delivered[0]["response"]["message"]["queueId"], chat_id);
+        assert_eq!(delivered[0]["response"]["message"]["nonce"], 1);
+        // The sender is not subscribed and gets nothing but its status
+        assert!(sent_responses(&mut sender_rx).is_empty());
+    }
+
+    /// Tests that a message sent to a chat without subscribers is replayed when its sender subscribes
+    #[tokio::test]
+    async fn test_own_message_is_replayed_after_subscribing() {
+        let service = service().await;
+        let (connection, mut rx) = channel_connection();
+        let chat_id = encode_base64(&[7; 32]).await;
+
+        let send = IncomeMessage::Send(entity::message::Message {
+            nonce: 1,
+            chat_id: chat_id.clone(),
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        });
+        let flow = service.process_message(connection.clone(), send).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(sent_responses(&mut rx)[0]["type"], "sendAck");
+
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 0,
+            recent_context: None,
+            limit: None,
+            token: None,
+        });
+        let flow = service.process_message(connection, subscribe).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+
+        let replayed: Vec<_> = sent_responses(&mut rx)
+            .into_iter()
+            .filter(|r| r["response"]["type"] == "new")
+            .collect();
+        assert_eq!(replayed.len(), 1);
+        let message = &replayed[0]["response"]["message"];
+        assert_eq!(message["nonce"], 1);
+        assert_eq!(message["queueId"], chat_id);
+        assert_eq!(message["content"], encode_base64(b"content").await);
+    }
+
+    /// Tests that a message sent to a chat whose bounded queue is full is rejected
+    #[tokio::test]
+    async fn test_full_chat_queue_rejects_message() {
+        let service = service_with(SeedConfig {
+            chat_queue_capacity: Some(1),
+            ..Default::default()
+        })
+        .await;
+        let (connection, mut rx) = channel_connection();
+        let chat_id = encode_base64(&[7; 32]).await;
+        // Pretend a message processor already runs for the chat, but never drains it
+        let queue = service.websocket_use_case.chat_queue();
+        service
+            .manager
+            .message_queues
+            .insert(chat_id.clone(), queue);
+        let send = async |nonce| {
+            IncomeMessage::Send(entity::message::Message {
+                nonce,
+                chat_id: chat_id.clone(),
+                signature: encode_base64(b"signature").await,
+                content: encode_base64(b"content").await,
+                content_iv: encode_base64(&[9; 12]).await,
+                ..Default::default()
+            })
+        };
+
+        for (nonce, expected) in [
+            (
+                1,
+                json!({"type": "sendAck", "response": {"queueId": chat_id, "nonce": 1}}),
+            ),
+            (
+                2,
+                json!({"type": "response", "response": {"status": false, "code": "queue_full"}}),
+            ),
+        ] {
+            let flow = service
+                .process_message(connection.clone(), send(nonce).await)
+                .await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+            assert_eq!(sent_responses(&mut rx)[0], expected);
+        }
+        assert_eq!(
+            service
+                .manager
+                .message_queues
+                .get(&chat_id)
+                .unwrap()
+                .0
+                .len(),
+            1
+        );
+    }
+
+    /// Tests that live-first mode neither delivers nor charges a message its full queue rejects
+    #[tokio::test]
+    async fn test_live_first_full_queue_delivers_nothing() {
+        let config = SeedConfig {
+            chat_queue_capacity: Some(1),
+            live_first: true,
+            quota_messages: Some(10),
+            ..Default::default()
+        };
+        let limits = config.quota_limits();
+        let service = service_with(config).await;
+        let chat_id = encode_base64(&[7; 32]).await;
+        let (subscriber, mut subscriber_rx) = channel_connection();
+        let (sender, mut sender_rx) = channel_connection();
+        service
+            .manager
+            .add_subscription(&subscriber, &chat_id, usize::MAX);
+        // Pretend a message processor already runs for the chat, but never drains it
+        let (queue, persister) = flume::bounded(1);
+        service
+            .manager
+            .message_queues
+            .insert(chat_id.clone(), (queue.clone(), persister));
+        queue
+            .try_send(entity::websocket::ConnectedMessage {
+                connection: sender.clone(),
+                message: IncomeMessage::Ping,
+            })
+            .unwrap();
+        let before = sender.quota_status(limits);
+
+        let send = IncomeMessage::Send(entity::message::Message {
+            nonce: 1,
+            chat_id: chat_id.clone(),
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        });
+        let flow = service.process_message(sender.clone(), send).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(
+            sent_responses(&mut sender_rx)[0]["response"]["code"],
+            "queue_full"
+        );
+        assert!(sent_responses(&mut subscriber_rx).is_empty());
+        let after = sender.quota_status(limits);
+        assert_eq!(
+            (after.messages_remaining, after.bytes_remaining),
+            (before.messages_remaining, before.bytes_remaining)
+        );
+    }
+
+    /// Tests that a ping is answered with a pong instead of a status
+    #[tokio::test]
+    async fn test_ping_is_answered_with_pong() {
+        let service = service().await;
+        let (connection, mut rx) = channel_connection();
+
+        let flow = service
+            .process_message(connection, IncomeMessage::Ping)
+            .await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(
+            sent_responses(&mut rx),
+            [serde_json::json!({"type": "pong"})]
+        );
+    }
+
+    /// Tests that the status endpoint counts accepted and currently registered connections
+    #[tokio::test]
+    async fn test_status_reports_connection_counts() {
+        let service = service().await;
+        let (finished, _finished_rx) = scripted_connection(vec![]);
+        service.handle_connection(finished).await;
+        let (connection, _rx) = channel_connection();
+        service
+            .manager
+            .add_subscription(&connection, "chat", usize::MAX);
+
+        let response = crate::http::status_response(service.status());
+
+        assert_eq!(
+            response.status(),
+            tokio_tungstenite::tungstenite::http::StatusCode::OK
+        );
+        let body: serde_json::Value = serde_json::from_str(response.body()).unwrap();
+        assert_eq!(body["total_connections"], 1);
+        assert_eq!(body["active_connections"], 1);
+        assert!(body["started_at"].as_u64().unwrap() > 0);
+        assert!(body["uptime_seconds"].is_u64());
+    }
+
+    /// Tests that a binary frame that does not decode is answered with a failed status
+    #[tokio::test]
+    async fn test_undecodable_binary_frame_fails() {
+        let service = service().await;
+        let (connection, mut rx) = scripted_connection(vec![Message::Binary(vec![1, 2, 3].into())]);
+
+        service.handle_connection(connection).await;
+
+        let responses = sent_responses(&mut rx);
+        assert_eq!(
+            responses[0]["response"],
+            json!({"status": false, "code": "bad_request"})
+        );
+    }
+
+    /// Tests that an unknown type is rejected by name while a malformed known type stays a bad request
+    #[tokio::test]
+    async fn test_unknown_message_type_is_rejected() {
+        let service = service().await;
+        let frames = [
+            r#"{"type":"teleport","message":{"queueId":"chat"}}"#,
+            r#"{"type":"send","message":{"nonce":"one"}}"#,
+        ]
+        .map(|frame| Message::Text(frame.into()));
+        let (connection, mut rx) = scripted_connection(frames.to_vec());
+
+        service.handle_connection(connection).await;
+
+        let responses = sent_responses(&mut rx);
+        assert_eq!(
+            responses,
+            [
+                json!({"type": "error", "response": {"code": "unknown_type"}}),
+                json!({"type": "response", "response": {"status": false, "code": "unknown_type"}}),
+                json!({"type": "error", "response": {"code": "bad_request"}}),
+                json!({"type": "response", "response": {"status": false, "code": "bad_request"}}),
+            ]
+        );
+    }
+
+    /// Tests that a send in a binary frame is processed like one in a text frame
+    #[tokio::test]
+    async fn test_binary_send_is_processed() {
+        let service = service().await;
+        let send = json!({
+            "type": "send",
+            "message": {
+                "nonce": 1,
+                "queueId": encode_base64(&[7; 32]).await,
+                "signature": encode_base64(b"signature").await,
+                "content": encode_base64(b"content").await,
+                "contentIV": encode_base64(&[9; 12]).await,
+            }
+        });
+        let frame = Message::Binary(serde_json::to_vec(&send).unwrap().into());
+        let (connection, mut rx) = scripted_connection(vec![frame]);
+
+        service.handle_connection(connection).await;
+
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses[0]["type"], "sendAck");
+    }
+
+    /// Renders the fields of a span or event as `name=value` pairs
+    struct FieldWriter(String);
+
+    impl tracing::field::Visit for FieldWriter {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!("{}={:?} ", field.name(), value));
+        }
+    }
+
+    /// Records every event together with the fields of the spans it was emitted in
+    #[derive(Default)]
+    struct SpanRecorder {
+        spans: std::sync::Mutex<Vec<String>>,
+        entered: std::sync::Mutex<Vec<u64>>,
+        events: std::sync::Mutex<Vec<(String, Vec<String>)>>,
+    }
+
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = FieldWriter(String::new());
+            span.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(fields.0);
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = FieldWriter(String::new());
+            event.record(&mut fields);
+            let spans = self.spans.lock().unwrap();
+            let context = self
+                .entered
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|id| spans[*id as usize - 1].clone())
+                .collect();
+            self.events.lock().unwrap().push((fields.0, context));
+        }
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _: &tracing::span::Id) {
+            self.entered.lock().unwrap().pop();
+        }
+    }
+
+    /// Tests that the events of a processed send carry the id of their connection
+    #[tokio::test]
+    async fn test_send_is_traced_with_connection_id() {
+        let recorder = Arc::new(SpanRecorder::default());
+        let _guard = tracing::dispatcher::set_default(&tracing::Dispatch::new(recorder.clone()));
+        let service = service().await;
+        let send = json!({
+            "type": "send",
+            "message": {
+                "nonce": 1,
+                "queueId": encode_base64(&[7; 32]).await,
+                "signature": encode_base64(b"signature").await,
+                "content": encode_base64(b"content").await,
+                "contentIV": encode_base64(&[9; 12]).await,
+            }
+        });
+        let (connection, mut rx) =
+            scripted_connection(vec![Message::Text(send.to_string().into())]);
+        let connection_id = connection.id;
+
+        service.handle_connection(connection).await;
+
+        assert_eq!(sent_responses(&mut rx)[0]["type"], "sendAck");
+        let events = recorder.events.lock().unwrap();
+        let (_, context) = events
+            .iter()
+            .find(|(fields, _)| fields.contains("Processing send"))
+            .unwrap();
+        assert_eq!(context, &[format!("connection.id={connection_id} ")]);
+    }
+
+    /// Tests that parallel sends without a nonce get contiguous nonces, acked and delivered
+    #[tokio::test]
+    async fn test_parallel_auto_nonce_sends_are_contiguous() {
+        let service = service().await;
+        let (subscriber, mut subscriber_rx) = channel_connection();
+        let chat_id = encode_base64(&[6; 32]).await;
+
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 1,
+            recent_context: None,
+            limit: None,
+            token: None,
+        });
+        let flow = service.process_message(subscriber, subscribe).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        sent_responses(&mut subscriber_rx);
+
+        let mut senders = Vec::new();
+        for _ in 0..8 {
+            let (sender, rx) = channel_connection();
+            let send = IncomeMessage::Send(entity::message::Message {
+                nonce: entity::message::Message::AUTO_NONCE,
+                chat_id: chat_id.clone(),
+                signature: encode_base64(b"signature").await,
+                content: encode_base64(b"content").await,
+                content_iv: encode_base64(&[9; 12]).await,
+                ..Default::default()
+            });
+            senders.push((service.process_message(sender, send), rx));
+        }
+        let (sends, receivers): (Vec<_>, Vec<_>) = senders.into_iter().unzip();
+        let flows = futures::future::join_all(sends).await;
+        assert!(flows.iter().all(|flow| *flow == ControlFlow::Continue(())));
+
+        let mut acked: Vec<u64> = receivers
+            .into_iter()
+            .map(|mut rx| {
+                let ack = sent_responses(&mut rx).remove(0);
+                assert_eq!(ack["type"], "sendAck");
+                ack["response"]["nonce"].as_u64().unwrap()
+            })
+            .collect();
+        acked.sort_unstable();
+        assert_eq!(acked, (1..=8).collect::<Vec<_>>());
+
+        let mut delivered: Vec<u64> = sent_responses(&mut subscriber_rx)
+            .iter()
+            .filter(|response| response["response"]["type"] == "new")
+            .map(|event| event["response"]["message"]["nonce"].as_u64().unwrap())
+            .collect();
+        delivered.sort_unstable();
+        assert_eq!(delivered, acked);
+    }
+
+    /// Tests that pipelined sends are each acknowledged with the nonce that was stored
+    #[tokio::test]
+    async fn test_send_ack_carries_stored_nonce() {
+        let service = service().await;
+        let (connection, mut rx) = channel_connection();
+        let chat_id = encode_base64(&[3; 32]).await;
+
+        for nonce in 1..=3 {
+            let send = IncomeMessage::Send(entity::message::Message {
+                nonce,
+                chat_id: chat_id.clone(),
+                signature: encode_base64(b"signature").await,
+                content: encode_base64(b"content").await,
+                content_iv: encode_base64(&[9; 12]).await,
+                ..Default::default()
+            });
+            let flow = service.process_message(connection.clone(), send).await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+        }
+
+        let acks: Vec<Value> = sent_responses(&mut rx);
+        assert_eq!(
+            acks,
+            (1..=3)
+                .map(|nonce| json!({
+                    "type": "sendAck",
+                    "response": {"queueId": chat_id, "nonce": nonce},
+                }))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    /// Tests that a send retried on another instance is acknowledged again but stored once
+    #[tokio::test]
+    async fn test_retried_send_is_stored_once() {
+        let db = SqliteDatabase::connect("sqlite::memory:").await.unwrap();
+        let chat_id = encode_base64(&[7; 32]).await;
+        let send = IncomeMessage::Send(entity::message::Message {
+            nonce: 1,
+            chat_id: chat_id.clone(),
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        });
+
+        // Each instance has its own replay cache, only the database sees the retry
+        for _ in 0..2 {
+            let service = service_with_db(db.clone(), SeedConfig::default()).await;
+            let (connection, mut rx) = channel_connection();
+
+            let flow = service.process_message(connection, send.clone()).await;
+
+            // The retry is acknowledged with the nonce of the stored message
+            assert_eq!(flow, ControlFlow::Continue(()));
+            let ack = &sent_responses(&mut rx)[0];
+            assert_eq!(ack["type"], "sendAck");
+            assert_eq!(ack["response"]["nonce"], 1);
+        }
+        let stored = db.fetch_history(&[7; 32], 0, 10).await.unwrap();
+        assert_eq!(stored.len(), 1);
+    }
+
+    /// Tests that a CBOR encoded message in a binary frame is processed
+    #[cfg(feature = "cbor")]
+    #[tokio::test]
+    async fn test_cbor_frame_is_processed() {
+        let service = service().await;
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&json!({"type": "ping"}), &mut bytes).unwrap();
+        let (connection, mut rx) = scripted_connection(vec![Message::Binary(bytes.into())]);
+
+        service.handle_connection(connection).await;
+
+        assert_eq!(sent_responses(&mut rx), [json!({"type": "pong"})]);
+    }
+
+    /// Tests that the bytes a connection sent are added to the metrics once it ends
+    #[tokio::test]
+    async fn test_bytes_sent_are_recorded_on_disconnect() {
+        let service = service().await;
+        let (connection, mut rx) = scripted_connection(vec![Message::Binary(vec![1].into())]);
+
+        service.handle_connection(connection).await;
+
+        let Ok(Message::Text(error)) = rx.try_recv() else {
+            panic!("expected an error response");
+        };
+        assert_eq!(service.metrics().bytes_sent(), error.len() as u64);
+    }
+
+    /// Tests that a connection past its maximum lifetime is closed with `reauth_required`
+    #[tokio::test]
+    async fn test_connection_past_max_lifetime_is_closed() {
+        let service = service_with(SeedConfig {
+            max_connection_lifetime: Some(Duration::from_millis(10)),
+            ..Default::default()
+        })
+        .await;
+        let (tx, mut rx) = mpsc::unbounded();
+        let sink = tx.sink_map_err(|_| WsError::ConnectionClosed);
+        let connection =
+            WebSocketConnection::from_parts(Box::pin(sink), Box::pin(futures::stream::pending()));
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            service.handle_connection(connection),
+        )
+        .await
+        .unwrap();
+
+        let mut frames = Vec::new();
+        while let Ok(frame) = rx.try_recv() {
+            frames.push(frame);
+        }
+        let Message::Text(error) = &frames[0] else {
+            panic!("expected an error response, got {:?}", frames[0]);
+        };
+        let error: serde_json::Value = serde_json::from_str(error).unwrap();
+        assert_eq!(error["response"]["code"], "reauth_required");
+        let Message::Close(Some(frame)) = &frames[1] else {
+            panic!("expected a close frame, got {:?}", frames[1]);
+        };
+        assert_eq!(frame.code, CloseCode::Restart);
+        assert_eq!(frame.reason, "reauth_required");
+        assert_eq!(
+            service
+                .metrics()
+                .disconnects(DisconnectReason::ReauthRequired),
+            1
+        );
+    }
+
+    /// Tests that a silent connection is pinged, then closed and cleaned up after the idle timeout
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_connection_is_closed() {
+        // Time is paused and jumps ahead whenever the connection only waits
+        let service = service_with(SeedConfig {
+            idle_timeout: Some(Duration::from_secs(60)),
+            ping_interval: Some(Duration::from_secs(20)),
+            ..Default::default()
+        })
+        .await;
+        let chat_id = encode_base64(&[7; 32]).await;
+        pretend_processor(&service, &chat_id);
+        let subscribe =
+            format!(r#"{{"type":"subscribe","message":{{"queueId":"{chat_id}","nonce":1}}}}"#);
+        // The client subscribes and then never sends another frame
+        let frames = futures::stream::iter([Ok(Message::Text(subscribe.into()))])
+            .chain(futures::stream::pending());
+        let (tx, mut rx) = mpsc::unbounded();
+        let sink = tx.sink_map_err(|_| WsError::ConnectionClosed);
+        let connection = WebSocketConnection::from_parts(Box::pin(sink), Box::pin(frames));
+
+        tokio::time::timeout(
+            Duration::from_secs(300),
+            service.handle_connection(connection),
+        )
+        .await
+        .unwrap();
+
+        assert!(service.manager.connections.is_empty());
+        assert!(service.manager.chats.is_empty());
+        assert_eq!(
+            service.metrics().disconnects(DisconnectReason::IdleTimeout),
+            1
+        );
+        let mut pings = 0;
+        while let Ok(frame) = rx.try_recv() {
+            pings += usize::from(matches!(frame, Message::Ping(_)));
+        }
+        assert!(pings > 0);
+    }
+
+    /// Tests that a connection whose clock passed its lifetime is closed without waiting it out
+    #[tokio::test]
+    async fn test_lifetime_follows_connection_clock() {
+        let service = service_with(SeedConfig {
+            max_connection_lifetime: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        })
+        .await;
+        let clock = Arc::new(MockClock::default());
+        let (connection, mut rx) = channel_connection();
+        let connection = Arc::into_inner(connection)
+            .unwrap()
+            .with_clock(clock.clone());
+
+        clock.advance(Duration::from_secs(3600));
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            service.handle_connection(connection),
+        )
+        .await
+        .unwrap();
+
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses[0]["response"]["code"], "reauth_required");
+    }
+
+    /// Tests that the subscribe sequence ends with a subscribe complete notification
+    #[tokio::test]
+    async fn test_subscribe_ends_with_subscribe_complete() {
+        let service = service().await;
+        let (connection, mut rx) = scripted_connection(vec![]);
+        let chat_id = encode_base64(&[7; 32]).await;
+        pretend_processor(&service, &chat_id);
+        let incoming = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 0,
+            recent_context: None,
+            limit: None,
+            token: None,
+        });
+
+        let flow = service
+            .process_message(Arc::new(connection), incoming)
+            .await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        let types: Vec<_> = responses.iter().map(|r| r["type"].clone()).collect();
+        assert_eq!(types, ["response", "upToDate", "wait", "subscribeComplete"]);
+        assert_eq!(responses[3]["response"]["queueId"], chat_id);
+    }
+
+    /// Tests that a subscribe whose inner type is not `subscribe` is rejected as a bad request
+    #[tokio::test]
+    async fn test_subscribe_with_wrong_rtype_is_rejected() {
+        let service = service().await;
+        let (connection, mut rx) = channel_connection();
+        let chat_id = encode_base64(&[7; 32]).await;
+        pretend_processor(&service, &chat_id);
+        let subscribe = |rtype: &str| {
+            IncomeMessage::Subscribe(SubscriptionRequest {
+                rtype: rtype.to_string(),
+                chat_id: chat_id.clone(),
+                nonce: 0,
+                recent_context: None,
+                limit: None,
+                token: None,
+            })
+        };
+
+        let flow = service
+            .process_message(connection.clone(), subscribe("unsubscribe"))
+            .await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses[0]["response"]["code"], "bad_request");
+        assert_eq!(responses[1]["response"]["status"], false);
+        assert!(!service.manager.is_subscribed(&connection, &chat_id));
+
+        let flow = service
+            .process_message(connection.clone(), subscribe(SubscriptionRequest::RTYPE))
+            .await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(sent_responses(&mut rx)[0]["response"]["status"], true);
+        assert!(service.manager.is_subscribed(&connection, &chat_id));
+    }
+
+    /// Tests that a subscribe with a forged token is answered with an unauthorized status
+    #[tokio::test]
+    async fn test_subscribe_with_forged_token_is_unauthorized() {
+        let service = service_with(SeedConfig {
+            subscription_secret: Some(SubscriptionSecret::new("server secret")),
+            ..Default::default()
+        })
+        .await;
+        let (connection, mut rx) = channel_connection();
+        let chat_id = encode_base64(&[7; 32]).await;
+        let token = SubscriptionSecret::new("guessed secret")
+            .sign(&chat_id)
+            .await;
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 0,
+            recent_context: None,
+            limit: None,
+            token: Some(token),
+        });
+
+        let flow = service.process_message(connection.clone(), subscribe).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(
+            responses[0]["response"],
+            json!({"status": false, "code": "unauthorized"})
+        );
+        assert!(!service.manager.is_subscribed(&connection, &chat_id));
+    }
+
+    /// Tests that with history disabled subscribing queries nothing and sends are only relayed
+    #[tokio::test]
+    async fn test_history_disabled_relays_without_storing() {
+        let service = service_with(SeedConfig {
+            history_enabled: false,
+            ..Default::default()
+        })
+        .await;
+        let chat_id = encode_base64(&[7; 32]).await;
+        let (subscriber, mut subscriber_rx) = channel_connection();
+        let (sender, _sender_rx) = channel_connection();
+
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 0,
+            recent_context: None,
+            limit: None,
+            token: None,
+        });
+        let send = IncomeMessage::Send(entity::message::Message {
+            nonce: 1,
+            chat_id: chat_id.clone(),
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        });
+        for (connection, incoming) in [(subscriber, subscribe), (sender, send)] {
+            let flow = service.process_message(connection, incoming).await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+        }
+
+        let responses = sent_responses(&mut subscriber_rx);
+        let types: Vec<_> = responses.iter().map(|r| r["type"].clone()).collect();
+        assert_eq!(types, ["response", "subscribeComplete", "event"]);
+        assert_eq!(responses[2]["response"]["message"]["nonce"], 1);
+        let db = &service.messages_use_case.db;
+        assert_eq!(db.queries.load(Ordering::SeqCst), 0);
+        assert!(db.messages.lock().unwrap().is_empty());
+    }
+
+    /// Tests that a chat ID sent in the URL-safe alphabet reaches subscribers using the standard one
+    #[tokio::test]
+    async fn test_chat_id_alphabets_share_subscriptions() {
+        let service = service_with(SeedConfig {
+            history_enabled: false,
+            ..Default::default()
+        })
+        .await;
+        let chat_id = [0xfb; 32];
+        let subscribe_id = encode_base64(&chat_id).await;
+        let (subscriber, mut subscriber_rx) = channel_connection();
+        let (sender, mut sender_rx) = channel_connection();
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: subscribe_id.clone(),
+            nonce: 1,
+            recent_context: None,
+            limit: None,
+            token: None,
+        });
+        let url_safe = misc::base64::encode_base64_url(&chat_id).await;
+        assert_ne!(url_safe, subscribe_id);
+        let send = |chat_id: &str| {
+            IncomeMessage::Send(entity::message::Message {
+                nonce: 1,
+                chat_id: chat_id.to_string(),
+                signature: "c2lnbmF0dXJl".to_string(),
+                content: "Y29udGVudA==".to_string(),
+                content_iv: "CQkJCQkJCQkJCQkJ".to_string(),
+                ..Default::default()
+            })
+        };
+
+        // The second send is a replay of the first and is only acknowledged
+        for (connection, incoming) in [
+            (subscriber, subscribe),
+            (sender.clone(), send(&url_safe)),
+            (sender, send(&subscribe_id)),
+        ] {
+            let flow = service.process_message(connection, incoming).await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+        }
+
+        let delivered = sent_responses(&mut subscriber_rx);
+        let events: Vec<_> = delivered
+            .iter()
+            .filter(|r| r["response"]["type"] == "new")
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["response"]["message"]["queueId"], subscribe_id);
+        let acks = sent_responses(&mut sender_rx);
+        assert!(acks.iter().all(|r| r["type"] == "sendAck"));
+        assert_eq!(acks.len(), 2);
+    }
+
+    /// Tests that live-first mode delivers to subscribers before the message is persisted
+    #[tokio::test]
+    async fn test_live_first_delivers_before_persisting() {
+        let service = service_with(SeedConfig {
+            live_first: true,
+            ..Default::default()
+        })
+        .await;
+        let chat_id = encode_base64(&[7; 32]).await;
+        // A persister that never drains the queue stands in for a slow database
+        let queue = flume::unbounded();
+        let persister = queue.1.clone();
+        service
+            .manager
+            .message_queues
+            .insert(chat_id.clone(), queue);
+
+        let (subscriber, mut subscriber_rx) = channel_connection();
+        let (sender, mut sender_rx) = channel_connection();
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 1,
+            recent_context: None,
+            limit: None,
+            token: None,
+        });
+        let send = IncomeMessage::Send(entity::message::Message {
+            nonce: 1,
+            chat_id: chat_id.clone(),
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        });
+        let flow = service.process_message(subscriber, subscribe).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        sent_responses(&mut subscriber_rx);
+
+        let flow = tokio::time::timeout(
+            Duration::from_secs(1),
+            service.process_message(sender, send),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let delivered = sent_responses(&mut subscriber_rx);
+        assert_eq!(delivered[0]["response"]["type"], "new");
+        assert_eq!(delivered[0]["response"]["message"]["nonce"], 1);
+        assert_eq!(sent_responses(&mut sender_rx)[0]["type"], "sendAck");
+        // The message is still waiting to be persisted
+        assert_eq!(persister.len(), 1);
+        assert!(
+            service
+                .messages_use_case
+                .db
+                .messages
+                .lock()
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    /// Tests that a subscribe followed by an unsubscribe records two ordered control events
+    #[tokio::test]
+    async fn test_subscribe_and_unsubscribe_record_control_events() {
+        let service = service_with(SeedConfig {
+            control_events: true,
+            ..Default::default()
+        })
+        .await;
+        let chat_id = encode_base64(&[7; 32]).await;
+        pretend_processor(&service, &chat_id);
+        let (connection, _rx) = channel_connection();
+
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 1,
+            recent_context: None,
+            limit: None,
+            token: None,
+        });
+        let unsubscribe = IncomeMessage::Unsubscribe(entity::message::Message {
+            chat_id: chat_id.clone(),
+            ..Default::default()
+        });
+        for incoming in [subscribe, unsubscribe] {
+            let flow = service.process_message(connection.clone(), incoming).await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+        }
+
+        let events = service.messages_use_case.db.control_events.lock().unwrap();
+        assert_eq!(
+            *events,
+            [
+                (connection.id, chat_id.clone(), ControlAction::Subscribe),
+                (connection.id, chat_id, ControlAction::Unsubscribe),
+            ]
+        );
+    }
+
+    /// Tests that a live event and its history replay carry byte-identical chat IDs
+    #[tokio::test]
+    async fn test_live_and_history_events_share_chat_id() {
+        let config = SeedConfig {
+            live_first: true,
+            ..Default::default()
+        };
+        let db = SqliteDatabase::connect("sqlite::memory:").await.unwrap();
+        let service = service_with_db(db.clone(), config).await;
+        let chat_id = encode_base64(&[5; 32]).await;
+        pretend_processor(&service, &chat_id);
+        let subscribe = |nonce| {
+            IncomeMessage::Subscribe(SubscriptionRequest {
+                rtype: SubscriptionRequest::RTYPE.to_string(),
+                chat_id: chat_id.clone(),
+                nonce,
+                recent_context: None,
+                limit: None,
+                token: None,
+            })
+        };
+        let message = entity::message::Message {
+            nonce: 1,
+            chat_id: chat_id.clone(),
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        };
+
+        let (live, mut live_rx) = channel_connection();
+        let (sender, _sender_rx) = channel_connection();
+        let live_steps = [
+            (live, subscribe(2)),
+            (sender, IncomeMessage::Send(message.clone())),
+        ];
+        for (connection, incoming) in live_steps {
+            let flow = service.process_message(connection, incoming).await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+        }
+        db.insert_message(message).await.unwrap();
+        let (replayed, mut replayed_rx) = channel_connection();
+        let flow = service.process_message(replayed, subscribe(1)).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+
+        let event = |responses: Vec<serde_json::Value>| {
+            responses
+                .into_iter()
+                .find(|r| r["response"]["type"] == "new")
+                .unwrap()
+        };
+        let live_event = event(sent_responses(&mut live_rx));
+        let history_event = event(sent_responses(&mut replayed_rx));
+        assert_eq!(
+            live_event["response"]["message"]["queueId"],
+            history_event["response"]["message"]["queueId"]
+        );
+        assert_eq!(history_event["response"]["message"]["queueId"], chat_id);
+    }
+
+    /// Tests that head nonces match the last stored nonce per chat and missing chats report 0
+    #[tokio::test]
+    async fn test_head_nonces_report_last_nonce_per_chat() {
+        let service = service().await;
+        let busy = encode_base64(&[1; 32]).await;
+        let quiet = encode_base64(&[2; 32]).await;
+        let empty = encode_base64(&[3; 32]).await;
+        service
+            .messages_use_case
+            .db
+            .messages
+            .lock()
+            .unwrap()
+            .extend(
+                [(&busy, 1), (&busy, 2), (&busy, 3), (&quiet, 1)].map(|(chat_id, nonce)| {
+                    entity::message::OutcomeMessage {
+                        nonce,
+                        chat_id: chat_id.clone(),
+                        ..Default::default()
+                    }
+                }),
+            );
+        let (connection, mut rx) = channel_connection();
+
+        let request = IncomeMessage::HeadNonces(entity::message::HeadNoncesRequest {
+            chats: vec![busy.clone(), quiet.clone(), empty.clone()],
+        });
+        let flow = service.process_message(connection, request).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses[0]["type"], "headNonces");
+        let heads = &responses[0]["response"]["heads"];
+        assert_eq!(
+            (&heads[&busy], &heads[&quiet], &heads[&empty]),
+            (&3.into(), &1.into(), &0.into())
+        );
+        let db = &service.messages_use_case.db;
+        assert_eq!(db.queries.load(Ordering::SeqCst), 1);
+    }
+
+    /// Tests that a sync diff delivers only the stored messages outside the client's ranges
+    #[tokio::test]
+    async fn test_sync_diff_sends_only_missing_messages() {
+        let service = service().await;
+        let chat_id = encode_base64(&[7; 32]).await;
+        // Nonce 8 expired while the client was offline
+        let stored = [1, 2, 3, 4, 5, 6, 7, 9, 10, 11];
+        service
+            .messages_use_case
+            .db
+            .messages
+            .lock()
+            .unwrap()
+            .extend(stored.map(|nonce| entity::message::OutcomeMessage {
+                nonce,
+                chat_id: chat_id.clone(),
+                ..Default::default()
+            }));
+        let (connection, mut rx) = channel_connection();
+
+        let request = IncomeMessage::SyncDiff(entity::message::SyncDiffRequest {
+            chat_id: chat_id.clone(),
+            have_ranges: vec![(6, 9), (1, 3), (11, 20)],
+        });
+        let flow = service.process_message(connection, request).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        let (last, events) = responses.split_last().unwrap();
+        let nonces: Vec<_> = events
+            .iter()
+            .map(|r| r["response"]["message"]["nonce"].clone())
+            .collect();
+        assert_eq!(nonces, [4, 5, 10]);
+        assert_eq!(last["type"], "upToDate");
+        assert_eq!(last["response"]["headNonce"], 11);
+    }
+
+    /// Tests that rapid repeated subscribes to a chat get a single wait event within the window
+    #[tokio::test]
+    async fn test_wait_events_are_debounced() {
+        let service = service_with(SeedConfig {
+            wait_event_debounce: Some(Duration::from_secs(60)),
+            history_enabled: false,
+            ..Default::default()
+        })
+        .await;
+        let chat_id = encode_base64(&[8; 32]).await;
+        let (connection, mut rx) = channel_connection();
+        // Waits are only sent for chats with a live queue
+        pretend_processor(&service, &chat_id);
+
+        for _ in 0..3 {
+            let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+                rtype: SubscriptionRequest::RTYPE.to_string(),
+                chat_id: chat_id.clone(),
+                nonce: 0,
+                recent_context: None,
+                limit: None,
+                token: None,
+            });
+            let flow = service.process_message(connection.clone(), subscribe).await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+        }
+
+        let responses = sent_responses(&mut rx);
+        let waits = responses.iter().filter(|r| r["response"]["type"] == "wait");
+        assert_eq!(waits.count(), 1);
+        let completed = responses
+            .iter()
+            .filter(|r| r["type"] == "subscribeComplete");
+        assert_eq!(completed.count(), 3);
+    }
+
+    /// Tests that a wait event is only sent for chats with a live message queue
+    #[tokio::test]
+    async fn test_wait_event_requires_live_queue() {
+        let service = service_with(SeedConfig {
+            history_enabled: false,
+            ..Default::default()
+        })
+        .await;
+        let idle_chat = encode_base64(&[7; 32]).await;
+        let live_chat = encode_base64(&[8; 32]).await;
+        pretend_processor(&service, &live_chat);
+
+        for (chat_id, expected) in [
+            (idle_chat, vec!["response", "subscribeComplete"]),
+            (live_chat, vec!["response", "wait", "subscribeComplete"]),
+        ] {
+            let (connection, mut rx) = channel_connection();
+            let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+                rtype: SubscriptionRequest::RTYPE.to_string(),
+                chat_id,
+                nonce: 0,
+                recent_context: None,
+                limit: None,
+                token: None,
+            });
+
+            let flow = service.process_message(connection, subscribe).await;
+
+            assert_eq!(flow, ControlFlow::Continue(()));
+            let responses = sent_responses(&mut rx);
+            let types: Vec<_> = responses.iter().map(|r| r["type"].clone()).collect();
+            assert_eq!(types, expected);
+        }
+    }
+
+    /// Tests that a subscriber requesting recent context receives exactly the last messages first
+    #[tokio::test]
+    async fn test_subscribe_with_recent_context() {
+        let chat_id = encode_base64(&[7; 32]).await;
+        let db = MockDb::with_history(&chat_id, &[1, 2, 3, 4, 5]);
+        let service = service_with_db(db, SeedConfig::default()).await;
+        pretend_processor(&service, &chat_id);
+        let (subscriber, mut rx) = channel_connection();
+        let incoming = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 6,
+            recent_context: Some(3),
+            limit: None,
+            token: None,
+        });
+
+        let flow = service.process_message(subscriber, incoming).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        let recent: Vec<_> = responses[1..4]
+            .iter()
+            .map(|r| r["response"]["message"]["nonce"].clone())
+            .collect();
+        assert_eq!(recent, [3, 4, 5]);
+        assert_eq!(responses[4]["type"], "upToDate");
+    }
+
+    /// Tests that a client resuming within the TTL gets its subscriptions and cursors back
+    #[tokio::test]
+    async fn test_resume_restores_subscriptions() {
+        let first_chat = encode_base64(&[1; 32]).await;
+        let second_chat = encode_base64(&[2; 32]).await;
+        let db = MockDb::with_history(&first_chat, &[1, 2, 3]);
+        let service = service_with_db(db, SeedConfig::default()).await;
+        for chat_id in [&first_chat, &second_chat] {
+            pretend_processor(&service, chat_id);
+        }
+
+        let resume = Message::Text(r#"{"type":"resume","message":{"clientId":"alice"}}"#.into());
+        let subscribe = |chat_id: &str| {
+            let request =
+                format!(r#"{{"type":"subscribe","message":{{"queueId":"{chat_id}","nonce":1}}}}"#);
+            Message::Text(request.into())
+        };
+
+        // The first connection replays the first chat up to nonce 3, then drops
+        let (connection, _rx) = scripted_connection(vec![
+            resume.clone(),
+            subscribe(&first_chat),
+            subscribe(&second_chat),
+        ]);
+        service.handle_connection(connection).await;
+        assert!(service.manager.connections.is_empty());
+
+        // The second connection only resumes
+        let (connection, mut rx) = scripted_connection(vec![resume]);
+        service.handle_connection(connection).await;
+
+        let responses = sent_responses(&mut rx);
+        let completed: Vec<_> = responses
+            .iter()
+            .filter(|r| r["type"] == "subscribeComplete")
+            .map(|r| r["response"]["queueId"].clone())
+            .collect();
+        assert_eq!(completed, [first_chat.clone(), second_chat]);
+        // Nothing is replayed twice, the first chat continues after nonce 3
+        assert!(responses.iter().all(|r| r["response"]["type"] != "new"));
+        assert_eq!(service.resume_registry.restore("alice")[0], (first_chat, 4));
+    }
+
+    /// Tests that a resume token from a subscribe restores its subscriptions in one round trip
+    #[tokio::test]
+    async fn test_resume_token_restores_subscriptions() {
+        let secret = SubscriptionSecret::new("server secret");
+        let chat_id = encode_base64(&[1; 32]).await;
+        let config = SeedConfig {
+            subscription_secret: Some(secret.clone()),
+            ..Default::default()
+        };
+        let db = MockDb::with_history(&chat_id, &[1, 2, 3]);
+        let service = service_with_db(db, config).await;
+        pretend_processor(&service, &chat_id);
+
+        // The first connection replays the chat up to nonce 3 and gets a token
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 1,
+            recent_context: None,
+            limit: None,
+            token: Some(secret.sign(&chat_id).await),
+        });
+        let (connection, mut rx) = channel_connection();
+        let flow = service.process_message(connection, subscribe).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        let complete = responses.last().unwrap();
+        assert_eq!(complete["type"], "subscribeComplete");
+        let token = complete["response"]["resumeToken"].as_str().unwrap();
+
+        // The second connection resumes with the token alone
+        let (connection, mut rx) = channel_connection();
+        let resume = IncomeMessage::Resume(ResumeRequest {
+            client_id: None,
+            resume_token: Some(token.to_string()),
+        });
+        let flow = service.process_message(connection.clone(), resume).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses[0]["response"]["status"], true);
+        assert!(service.manager.is_subscribed(&connection, &chat_id));
+        // Nothing is replayed twice, the chat continues after nonce 3
+        assert!(responses.iter().all(|r| r["response"]["type"] != "new"));
+        assert_eq!(connection.subscription_snapshot(), [(chat_id, 4)]);
+    }
+
+    /// Tests that a resume token altered by the client is rejected without subscribing
+    #[tokio::test]
+    async fn test_tampered_resume_token_is_rejected() {
+        let secret = SubscriptionSecret::new("server secret");
+        let service = service_with(SeedConfig {
+            subscription_secret: Some(secret.clone()),
+            ..Default::default()
+        })
+        .await;
+        let chat_id = encode_base64(&[1; 32]).await;
+        let token = secret
+            .sign_resume(Uuid::new_v4(), &[(chat_id.clone(), 4)], SystemTime::now())
+            .await;
+
+        // Rewind the cursor without re-signing the token
+        let mut bytes = decode_base64(token).await.unwrap();
+        let cursor = bytes.windows(3).position(|w| w == b",4]").unwrap() + 1;
+        bytes[cursor] = b'0';
+        let tampered = encode_base64(&bytes).await;
+
+        let (connection, mut rx) = channel_connection();
+        let resume = IncomeMessage::Resume(ResumeRequest {
+            client_id: None,
+            resume_token: Some(tampered),
+        });
+        let flow = service.process_message(connection.clone(), resume).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(
+            responses[0]["response"],
+            json!({"status": false, "code": "unauthorized"})
+        );
+        assert_eq!(responses.len(), 1);
+        assert!(!service.manager.is_subscribed(&connection, &chat_id));
+    }
+
+    /// Tests that with subscribe tokens required, resuming another client's id is refused
+    #[tokio::test]
+    async fn test_resume_by_client_id_requires_token() {
+        let secret = SubscriptionSecret::new("server secret");
+        let service = service_with(SeedConfig {
+            subscription_secret: Some(secret),
+            ..Default::default()
+        })
+        .await;
+        let chat_id = encode_base64(&[1; 32]).await;
+        service
+            .resume_registry
+            .save("alice", vec![(chat_id.clone(), 4)]);
+
+        // Another client claims alice's id without her resume token
+        let (connection, mut rx) = channel_connection();
+        let resume = IncomeMessage::Resume(ResumeRequest {
+            client_id: Some("alice".to_string()),
+            resume_token: None,
+        });
+        let flow = service.process_message(connection.clone(), resume).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(
+            responses[0]["response"],
+            json!({"status": false, "code": "unauthorized"})
+        );
+        assert_eq!(responses.len(), 1);
+        assert!(!service.manager.is_subscribed(&connection, &chat_id));
+        assert_eq!(connection.client_id(), None);
+    }
+
+    /// Tests that a resume token older than the resume TTL is rejected
+    #[tokio::test]
+    async fn test_expired_resume_token_is_rejected() {
+        let secret = SubscriptionSecret::new("server secret");
+        let config = SeedConfig {
+            subscription_secret: Some(secret.clone()),
+            ..Default::default()
+        };
+        let issued_at = SystemTime::now() - config.resume_ttl;
+        let service = service_with(config).await;
+        let chat_id = encode_base64(&[1; 32]).await;
+        let token = secret
+            .sign_resume(Uuid::new_v4(), &[(chat_id.clone(), 4)], issued_at)
+            .await;
+
+        let (connection, mut rx) = channel_connection();
+        let resume = IncomeMessage::Resume(ResumeRequest {
+            client_id: None,
+            resume_token: Some(token),
+        });
+        let flow = service.process_message(connection.clone(), resume).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(
+            responses[0]["response"],
+            json!({"status": false, "code": "unauthorized"})
+        );
+        assert!(!service.manager.is_subscribed(&connection, &chat_id));
+    }
+
+    /// Tests that a logout revokes every resume token of its session, also those
+    /// issued to the connection the session started on
+    #[tokio::test]
+    async fn test_logout_revokes_resume_tokens() {
+        let secret = SubscriptionSecret::new("server secret");
+        let service = service_with(SeedConfig {
+            subscription_secret: Some(secret.clone()),
+            ..Default::default()
+        })
+        .await;
+        let chat_id = encode_base64(&[1; 32]).await;
+        pretend_processor(&service, &chat_id);
+        let resume = |token: &str| {
+            IncomeMessage::Resume(ResumeRequest {
+                client_id: None,
+                resume_token: Some(token.to_string()),
+            })
+        };
+
+        // The first connection starts the session and gets a token
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 1,
+            recent_context: None,
+            limit: None,
+            token: Some(secret.sign(&chat_id).await),
+        });
+        let (first, mut rx) = channel_connection();
+        let flow = service.process_message(first.clone(), subscribe).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        let token = responses.last().unwrap()["response"]["resumeToken"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        // A second connection carries the session on and logs out
+        let (second, mut rx) = channel_connection();
+        let flow = service
+            .process_message(second.clone(), resume(&token))
+            .await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(sent_responses(&mut rx)[0]["response"]["status"], true);
+        assert_eq!(second.resume_session(), first.id);
+        let flow = service
+            .process_message(second.clone(), IncomeMessage::Logout)
+            .await;
+        assert_eq!(flow, ControlFlow::Break(DisconnectReason::Logout));
+
+        // The token issued to the first connection no longer resumes anything
+        let (third, mut rx) = channel_connection();
+        let flow = service.process_message(third.clone(), resume(&token)).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(
+            sent_responses(&mut rx)[0]["response"],
+            json!({"status": false, "code": "unauthorized"})
+        );
+        assert!(!service.manager.is_subscribed(&third, &chat_id));
+    }
+
+    /// Tests that resuming many chats replays at most the configured number at once
+    #[tokio::test]
+    async fn test_resume_replays_are_bounded() {
+        let config = SeedConfig {
+            resume_replay_concurrency: 3,
+            // Leave room for every replay so only the concurrency bounds them
+            max_buffered_history_frames: 3,
+            ..Default::default()
+        };
+        let db = MockDb {
+            stream_delay: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let service = service_with_db(db.clone(), config).await;
+
+        let mut chats = Vec::new();
+        for byte in 0..10u8 {
+            let chat_id = encode_base64(&[byte; 32]).await;
+            pretend_processor(&service, &chat_id);
+            // A chat without messages would be caught up without streaming any history
+            db.messages
+                .lock()
+                .unwrap()
+                .push(entity::message::OutcomeMessage {
+                    nonce: 1,
+                    chat_id: chat_id.clone(),
+                    ..Default::default()
+                });
+            chats.push((chat_id, 1));
+        }
+        service.resume_registry.save("alice", chats);
+
+        let (connection, mut rx) = channel_connection();
+        let resume =
+            serde_json::from_str(r#"{"type":"resume","message":{"clientId":"alice"}}"#).unwrap();
+        let flow = service.process_message(connection, resume).await;
+
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(db.peak_streams.load(Ordering::SeqCst), 3);
+        let completed = sent_responses(&mut rx)
+            .iter()
+            .filter(|r| r["type"] == "subscribeComplete")
+            .count();
+        assert_eq!(completed, 10);
+    }
+
+    /// Tests that the health check answers 200 with a reachable database and 503 without one
+    #[tokio::test]
+    async fn test_health_check_reflects_database() {
+        for (unhealthy, status) in [(false, 200), (true, 503)] {
+            let db = MockDb {
+                unhealthy,
+                ..Default::default()
+            };
+            let service = service_with_db(db, SeedConfig::default()).await;
+
+            let response = crate::http::health_response(service.check_health().await);
+
+            assert_eq!(response.status().as_u16(), status);
+            let body: Value = serde_json::from_str(response.body()).unwrap();
+            if unhealthy {
+                assert_eq!(body["status"], "unavailable");
+                assert_eq!(body["error"], "injected database outage");
+            } else {
+                assert_eq!(body, json!({"status": "ok"}));
+            }
+        }
+    }
+
+    /// Tests that a burst over the rate limit is partly rejected without closing the connection
+    #[tokio::test]
+    async fn test_burst_over_rate_limit_is_rejected() {
+        let service = service_with(SeedConfig {
+            rate_limit: Some(3),
+            ..Default::default()
+        })
+        .await;
+        let ping = Message::Text(r#"{"type":"ping"}"#.into());
+        let (connection, mut rx) = scripted_connection(vec![ping; 10]);
+
+        service.handle_connection(connection).await;
+
+        let responses = sent_responses(&mut rx);
+        let pongs = responses.iter().filter(|r| r["type"] == "pong");
+        assert_eq!(pongs.count(), 3);
+        let rejected = responses
+            .iter()
+            .filter(|r| r["response"] == json!({"status": false, "code": "rate_limited"}));
+        assert_eq!(rejected.count(), 7);
+        // The bucket goes away with the connection
+        assert!(service.rate_limits.is_empty());
+    }
+
+    /// Tests that the reported allowance decreases with every message and resets after the window
+    #[tokio::test]
+    async fn test_quota_status_tracks_sent_messages() {
+        let service = service_with(SeedConfig {
+            quota_messages: Some(2),
+            quota_window: Duration::from_millis(200),
+            ..Default::default()
+        })
+        .await;
+        let (connection, mut rx) = channel_connection();
+        let chat_id = encode_base64(&[3; 32]).await;
+        let send = |nonce: usize| {
+            let chat_id = chat_id.clone();
+            async move {
+                IncomeMessage::Send(entity::message::Message {
+                    nonce,
+                    chat_id,
+                    signature: encode_base64(b"signature").await,
+                    content: encode_base64(b"content").await,
+                    content_iv: encode_base64(&[9; 12]).await,
+                    ..Default::default()
+                })
+            }
+        };
+        let quota = async |rx: &mut mpsc::UnboundedReceiver<Message>| {
+            let flow = service
+                .process_message(connection.clone(), IncomeMessage::Quota)
+                .await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+            let responses = sent_responses(rx);
+            assert_eq!(responses[0]["type"], "quotaStatus");
+            responses[0]["response"]["messagesRemaining"]
+                .as_u64()
+                .unwrap()
+        };
+
+        assert_eq!(quota(&mut rx).await, 2);
+        for nonce in 1..=2 {
+            let flow = service
+                .process_message(connection.clone(), send(nonce).await)
+                .await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+            sent_responses(&mut rx);
+            assert_eq!(quota(&mut rx).await, 2 - nonce as u64);
+        }
+
+        // The third message exceeds the allowance
+        let flow = service
+            .process_message(connection.clone(), send(3).await)
+            .await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses[0]["response"]["code"], "rate_limited");
+        assert_eq!(responses[0]["response"]["quota"]["messagesRemaining"], 0);
+        assert_eq!(responses[1]["response"]["status"], false);
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(quota(&mut rx).await, 2);
+    }
+
+    /// Tests that a logout leaves the connection nowhere in the manager and nothing to resume
+    #[tokio::test]
+    async fn test_logout_forgets_connection() {
+        let service = service().await;
+        let (connection, mut rx) = channel_connection();
+        let chat_id = encode_base64(&[7; 32]).await;
+        pretend_processor(&service, &chat_id);
+
+        let resume = IncomeMessage::Resume(ResumeRequest {
+            client_id: Some("alice".to_string()),
+            resume_token: None,
+        });
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: chat_id.clone(),
+            nonce: 1,
+            recent_context: None,
+            limit: None,
+            token: None,
+        });
+        for incoming in [resume, subscribe] {
+            let flow = service.process_message(connection.clone(), incoming).await;
+            assert_eq!(flow, ControlFlow::Continue(()));
+        }
+        service.save_subscriptions(&connection);
+        assert!(!service.resume_registry.restore("alice").is_empty());
+        sent_responses(&mut rx);
+
+        let flow = service
+            .process_message(connection.clone(), IncomeMessage::Logout)
+            .await;
+
+        assert_eq!(flow, ControlFlow::Break(DisconnectReason::Logout));
+        assert!(service.manager.connections.is_empty());
+        assert!(service.manager.chats.is_empty());
+        assert!(service.manager.handles.is_empty());
+        assert!(service.manager.ids.is_empty());
+        assert!(connection.subscription_snapshot().is_empty());
+        assert!(service.resume_registry.restore("alice").is_empty());
+
+        // The status is acknowledged before the session is closed
+        let frames: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert!(matches!(&frames[0], Message::Text(text) if text.contains(r#""status":true"#)));
+        assert!(matches!(&frames[1], Message::Close(Some(frame)) if frame.reason == "logout"));
+    }
+
+    /// Tests that the metrics count an accepted message and render the active subscriptions
+    #[tokio::test]
+    async fn test_send_moves_metrics() {
+        let service = service().await;
+        let (subscriber, _subscriber_rx) = channel_connection();
+        let (sender, _sender_rx) = channel_connection();
+        let watched = encode_base64(&[1; 32]).await;
+        pretend_processor(&service, &watched);
+        let subscribe = IncomeMessage::Subscribe(SubscriptionRequest {
+            rtype: SubscriptionRequest::RTYPE.to_string(),
+            chat_id: watched,
+            nonce: 1,
+            recent_context: None,
+            limit: None,
+            token: None,
+        });
+        let flow = service.process_message(subscriber, subscribe).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+
+        // Nobody subscribed to this chat, so the message is stored right away
+        let send = IncomeMessage::Send(entity::message::Message {
+            nonce: 1,
+            chat_id: encode_base64(&[2; 32]).await,
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&[9; 12]).await,
+            ..Default::default()
+        });
+        let flow = service.process_message(sender, send).await;
+        assert_eq!(flow, ControlFlow::Continue(()));
+
+        assert_eq!(service.metrics().messages_sent(), 1);
+        assert_eq!(service.metrics().messages_persisted(), 1);
+        assert_eq!(service.metrics().broadcast_failures(), 0);
+        let rendered = service.render_metrics();
+        for line in [
+            "seed_messages_sent_total 1",
+            "seed_messages_persisted_total 1",
+            "seed_active_connections 1",
+            "seed_active_chats 1",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "{line} in {rendered}");
+        }
+    }
 }
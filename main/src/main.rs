@@ -8,29 +8,50 @@ extern crate pretty_env_logger;
 
 use std::sync::Arc;
 
-use anyhow::Result;
-use infrastructure::database::PostgresDatabase;
+use anyhow::{Result, anyhow};
+use infrastructure::access::{AdminToken, IpFilter};
+use infrastructure::backend::AnyDb;
+use infrastructure::cluster::RedisRelay;
+use infrastructure::http::{
+    Replay, RequestHead, RequestLimits, admin_chats_response, health_response, json_response,
+    metrics_response, negotiate_subprotocol, read_request_head, status_response, write_response,
+};
+use infrastructure::recent::RecentCache;
 use infrastructure::websocket::WebSocketService;
-use log::error;
-use protocol::entity::websocket::{WebSocketConnection, WebSocketManager};
+use log::{error, warn};
+use misc::{env::env_or, net::bind_address};
+use protocol::{
+    entity::{
+        compat::ProtocolVersion,
+        websocket::{WebSocketConnection, WebSocketManager},
+    },
+    schema::protocol_schema,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
 use tokio_tungstenite::{
     accept_hdr_async,
     tungstenite::{
         handshake::server::{Request, Response},
-        http::{StatusCode},
+        http::{self, StatusCode},
     },
 };
 use traits::message::{MessagesDB, MessagesRepository};
+use use_case::{cluster::ClusterLink, config::SeedConfig};
 
 /// Main application entry point
 ///
 /// Sets up the following components:
 /// - Logging system
 /// - TLS configuration
-/// - Database connection pool
+/// - Storage backend selected at runtime
 /// - Use cases and business logic
 /// - WebSocket service
+/// - Broadcast relay between instances in cluster mode
 /// - HTTP server with WebSocket endpoint
+/// - Graceful shutdown on SIGINT or SIGTERM
 ///
 /// # Returns
 ///
@@ -46,15 +67,69 @@ async fn main() -> Result<()> {
         Err(_) => 8080,
     };
 
-    let listener = tokio::net::TcpListener::bind(format!("127.0.0.1:{port}"));
+    // Listen on loopback unless another interface is asked for, e.g. `0.0.0.0` in a container
+    let bind_host = env_or("BIND_ADDR", "127.0.0.1".to_string());
+    let bind_addr = bind_address(&bind_host, port)
+        .map_err(|err| anyhow!("invalid BIND_ADDR {bind_host:?}: {err}"))?;
+
+    let listener = tokio::net::TcpListener::bind(bind_addr);
+
+    // Open the storage backend selected by DB_BACKEND
+    let db = AnyDb::from_env().await?;
 
-    // Initialize database connection pool
-    let pg_pool = PostgresDatabase::new().await?;
+    // Load runtime configuration
+    let config = Arc::new(SeedConfig::from_env());
+    let strict_subprotocol = config.strict_subprotocol;
+    let ip_filter = IpFilter::from_env()?;
+    let admin_token = AdminToken::from_env();
+    let request_limits = RequestLimits {
+        max_head_bytes: config.max_request_head_bytes,
+        max_uri_bytes: config.max_request_uri_bytes,
+    };
+
+    // Keep the newest messages of active chats in memory, other instances of
+    // a cluster write to the store without the cache seeing it
+    let recent_cache_messages = if config.cluster_mode && config.recent_cache_messages > 0 {
+        warn!(
+            "RECENT_CACHE_MESSAGES is ignored in CLUSTER_MODE, the recent message cache is disabled"
+        );
+        0
+    } else {
+        config.recent_cache_messages
+    };
+    let db = RecentCache::new(db, recent_cache_messages, config.recent_cache_chats);
+
+    // Sweep expired messages unless the server only relays them
+    if config.history_enabled {
+        let cache = db.clone();
+        db.inner()
+            .spawn_expiry_sweeper(config.expiry_sweep_interval, move |chats| {
+                chats.iter().for_each(|chat_id| cache.invalidate(chat_id))
+            });
+    }
+
+    // Prune messages past the retention period whatever their TTL
+    if let Some(retention) = config.message_retention {
+        let cache = db.clone();
+        db.inner().spawn_retention_sweeper(
+            config.retention_sweep_interval,
+            retention,
+            move |chats| chats.iter().for_each(|chat_id| cache.invalidate(chat_id)),
+        );
+    }
 
     // Set up application use cases
-    let messages_use_case = use_case::messages::MessagesUseCase::new(pg_pool);
-    let websocket_use_case =
-        use_case::websocket::WebSocketUseCase::new(messages_use_case.clone()).await;
+    let cluster_mode = config.cluster_mode;
+    let messages_use_case = use_case::messages::MessagesUseCase::new(db, config.clone())
+        .with_verifier(use_case::signature::verifier_from_env().await?);
+    let mut websocket_use_case =
+        use_case::websocket::WebSocketUseCase::new(messages_use_case.clone(), config).await;
+    let mut cluster_outbound = None;
+    if cluster_mode {
+        let (link, outbound) = ClusterLink::new();
+        websocket_use_case = websocket_use_case.with_cluster(link);
+        cluster_outbound = Some(outbound);
+    }
     let websocket_manager = WebSocketManager::default();
 
     // Create the WebSocket service to handle connections
@@ -63,32 +138,179 @@ async fn main() -> Result<()> {
         websocket_use_case,
         messages_use_case,
     ));
+    websocket_service.start_background_tasks();
+
+    // Exchange broadcasts with the other instances of the cluster
+    if let Some(outbound) = cluster_outbound {
+        RedisRelay::from_env()?
+            .spawn(outbound, websocket_service.clone())
+            .await?;
+    }
 
     let listener = listener.await?;
-    while let Ok((stream, _)) = listener.accept().await {
-        tokio::spawn(handle_handshake(stream, websocket_service.clone()));
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            () = &mut shutdown => break,
+        };
+
+        // Drop disallowed peers before reading anything from them
+        if !ip_filter.is_allowed(peer.ip()) {
+            warn!("rejected connection from disallowed peer {peer}");
+            continue;
+        }
+        tokio::spawn(handle_stream(
+            stream,
+            websocket_service.clone(),
+            request_limits,
+            admin_token.clone(),
+            strict_subprotocol,
+        ));
     }
 
+    // Let clients see a close frame and queued messages reach storage before exiting
+    websocket_service.shutdown().await;
+
     Ok(())
 }
 
-async fn handle_handshake<MR: MessagesRepository + Clone, DB: MessagesDB + Clone>(
-    stream: tokio::net::TcpStream,
+/// Completes once the process is asked to stop with SIGINT or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                error!("failed to listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        () = terminate => {}
+    }
+}
+
+/// Routes a freshly accepted TCP connection
+///
+/// WebSocket upgrade requests are handed to the WebSocket handshake,
+/// every other request is answered as plain HTTP.
+async fn handle_stream<MR: MessagesRepository + Clone + 'static, DB: MessagesDB + Clone>(
+    mut stream: TcpStream,
     ws_service: Arc<WebSocketService<MR, DB>>,
+    limits: RequestLimits,
+    admin_token: Option<AdminToken>,
+    strict_subprotocol: bool,
 ) {
+    let head = match read_request_head(&mut stream, limits).await {
+        Ok(head) => head,
+        Err(err) => {
+            error!("failed to read request: {err}");
+            // Oversized or malformed requests are answered before any upgrade
+            if let Some(response) = err.response() {
+                let _ = write_response(&mut stream, response).await;
+            }
+            return;
+        }
+    };
+
+    if head.is_websocket_upgrade() {
+        // Clients pick the wire format with `?protocol=<version>`, up to `PROTOCOL_VERSION`,
+        // defaulting to the original
+        let version = match head.query_param("protocol").map(str::parse) {
+            None => ProtocolVersion::default(),
+            Some(Ok(version)) => version,
+            Some(Err(err)) => {
+                let body = format!(r#"{{"error":"{err}"}}"#);
+                let response = json_response(StatusCode::BAD_REQUEST, body);
+                if let Err(err) = write_response(&mut stream, response).await {
+                    error!("failed to write http response: {err}");
+                }
+                return;
+            }
+        };
+
+        // Replay the consumed request head so the handshake sees the full request
+        let stream = Replay::new(head.into_raw(), stream);
+        handle_handshake(stream, ws_service, version, strict_subprotocol).await;
+        return;
+    }
+
+    let response = handle_http(&head, &ws_service, admin_token.as_ref()).await;
+    if let Err(err) = write_response(&mut stream, response).await {
+        error!("failed to write http response: {err}");
+    }
+}
+
+/// Answers a plain HTTP request
+///
+/// # Endpoints
+/// - `GET /protocol/schema` - JSON Schema of the seed wire format
+/// - `GET /metrics` - Prometheus metrics of the WebSocket service
+/// - `GET /healthz` - Whether the database answers, for load balancer health checks
+/// - `GET /status` - Uptime and connection counts, for dashboards
+/// - `GET /admin/chats` - Live chats with their subscriber counts, behind `ADMIN_TOKEN`
+async fn handle_http<MR: MessagesRepository + Clone + 'static, DB: MessagesDB + Clone>(
+    head: &RequestHead,
+    ws_service: &WebSocketService<MR, DB>,
+    admin_token: Option<&AdminToken>,
+) -> http::Response<String> {
+    match (head.method.as_str(), head.route()) {
+        ("GET", "/protocol/schema") => json_response(StatusCode::OK, protocol_schema().to_string()),
+        ("GET", "/metrics") => metrics_response(ws_service.render_metrics()),
+        ("GET", "/healthz") => health_response(ws_service.check_health().await),
+        ("GET", "/status") => status_response(ws_service.status()),
+        ("GET", "/admin/chats") => {
+            admin_chats_response(head, admin_token, || ws_service.chat_stats())
+        }
+        _ => json_response(
+            StatusCode::NOT_FOUND,
+            r#"{"error":"not found"}"#.to_string(),
+        ),
+    }
+}
+
+/// Performs the WebSocket handshake and hands the connection to the service
+///
+/// The `seed.v1` subprotocol is echoed back to clients offering it. With
+/// `strict_subprotocol`, clients offering only other subprotocols are refused.
+async fn handle_handshake<S, MR, DB>(
+    stream: S,
+    ws_service: Arc<WebSocketService<MR, DB>>,
+    version: ProtocolVersion,
+    strict_subprotocol: bool,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    MR: MessagesRepository + Clone + 'static,
+    DB: MessagesDB + Clone,
+{
+    #[allow(clippy::result_large_err)]
     let callback = |req: &Request, resp: Response| {
         if req.uri().path() != "/ws" {
             let response = Response::builder()
                 .status(StatusCode::NOT_FOUND)
-                .body(None::<String>).unwrap();
-            return Err(response)
+                .body(None::<String>)
+                .unwrap();
+            return Err(response);
         }
-        Ok(resp)
+        negotiate_subprotocol(req, resp, strict_subprotocol)
     };
 
     match accept_hdr_async(stream, callback).await {
         Ok(ws_stream) => {
-            let connection = WebSocketConnection::new(ws_stream);
+            let connection = WebSocketConnection::new(ws_stream).with_protocol_version(version);
             ws_service.handle_connection(connection).await;
         }
         Err(err) => error!("failed to accept connection: {err}"),
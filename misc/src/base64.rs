@@ -5,7 +5,62 @@ pub async fn encode_base64(input: &[u8]) -> String {
     general_purpose::STANDARD.encode(input)
 }
 
+/// Encodes a byte slice into a base64 string using the URL-safe alphabet.
+pub async fn encode_base64_url(input: &[u8]) -> String {
+    general_purpose::URL_SAFE.encode(input)
+}
+
 /// Decodes a base64 string into a byte vector.
+///
+/// Accepts the standard alphabet as well as the URL-safe one, with or
+/// without padding. Fails with the standard alphabet's error if no
+/// alphabet decodes the input.
 pub async fn decode_base64(input: String) -> Result<Vec<u8>, base64::DecodeError> {
-    general_purpose::STANDARD.decode(input)
+    general_purpose::STANDARD.decode(&input).or_else(|err| {
+        general_purpose::URL_SAFE
+            .decode(&input)
+            .or_else(|_| general_purpose::URL_SAFE_NO_PAD.decode(&input))
+            .map_err(|_| err)
+    })
+}
+
+/// Re-encodes a base64 string in the form [`encode_base64`] produces.
+///
+/// Returns `None` if the input is not valid base64.
+pub async fn canonical_base64(input: &str) -> Option<String> {
+    let decoded = decode_base64(input.to_string()).await.ok()?;
+    Some(encode_base64(&decoded).await)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// Bytes whose encodings differ between the standard and the URL-safe alphabet
+    const BYTES: [u8; 3] = [0xFF, 0xFE, 0xFD];
+
+    /// Tests that bytes round-trip through every accepted alphabet
+    #[tokio::test]
+    async fn test_round_trip_in_every_alphabet() {
+        let standard = encode_base64(&BYTES).await;
+        let url_safe = encode_base64_url(&BYTES).await;
+        assert_eq!(standard, "//79");
+        assert_eq!(url_safe, "__79");
+
+        let unpadded = general_purpose::URL_SAFE_NO_PAD.encode(&BYTES[..2]);
+        assert_eq!(unpadded, "__4");
+
+        assert_eq!(decode_base64(standard).await.unwrap(), BYTES);
+        assert_eq!(decode_base64(url_safe).await.unwrap(), BYTES);
+        assert_eq!(decode_base64(unpadded).await.unwrap(), BYTES[..2]);
+    }
+
+    /// Tests that URL-safe input is re-encoded with the standard alphabet
+    #[tokio::test]
+    async fn test_canonical_base64_accepts_url_safe() {
+        assert_eq!(canonical_base64("__79").await.unwrap(), "//79");
+        assert!(canonical_base64("not base64!").await.is_none());
+        assert!(decode_base64("not base64!".to_string()).await.is_err());
+    }
 }
@@ -0,0 +1,21 @@
+use std::{env::var, str::FromStr};
+
+use log::{debug, warn};
+
+/// Reads and parses an environment variable, falling back to a default value.
+///
+/// # Arguments
+/// * `key` - Name of the environment variable
+/// * `default` - Value used when the variable is unset or cannot be parsed
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match var(key) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            warn!("{key} environment variable has an invalid value, using default...");
+            default
+        }),
+        Err(_) => {
+            debug!("{key} environment variable is unset, using default...");
+            default
+        }
+    }
+}
@@ -1,2 +1,4 @@
 pub mod base64;
+pub mod env;
+pub mod net;
 pub mod tls;
@@ -0,0 +1,53 @@
+use std::net::{AddrParseError, IpAddr, SocketAddr};
+
+/// Builds the socket address a server binds to from a host and a port.
+///
+/// IPv6 hosts are accepted with or without surrounding brackets.
+///
+/// # Errors
+/// Fails if `host` is not an IPv4 or IPv6 address.
+pub fn bind_address(host: &str, port: u16) -> Result<SocketAddr, AddrParseError> {
+    let host = host.trim();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+
+    Ok(SocketAddr::new(host.parse::<IpAddr>()?, port))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// Tests that IPv4 hosts are combined with the port
+    #[test]
+    fn test_ipv4_bind_address() {
+        assert_eq!(
+            bind_address("127.0.0.1", 8080).unwrap(),
+            "127.0.0.1:8080".parse().unwrap()
+        );
+        assert_eq!(
+            bind_address(" 0.0.0.0 ", 80).unwrap(),
+            "0.0.0.0:80".parse().unwrap()
+        );
+    }
+
+    /// Tests that IPv6 hosts are accepted with and without brackets
+    #[test]
+    fn test_ipv6_bind_address() {
+        let expected: SocketAddr = "[::1]:8080".parse().unwrap();
+        assert_eq!(bind_address("::1", 8080).unwrap(), expected);
+        assert_eq!(bind_address("[::1]", 8080).unwrap(), expected);
+        assert!(bind_address("::", 8080).unwrap().is_ipv6());
+    }
+
+    /// Tests that hosts that are not IP addresses are rejected
+    #[test]
+    fn test_invalid_bind_address() {
+        for host in ["localhost", "", "127.0.0.1:8080", "[::1", "256.0.0.1"] {
+            assert!(bind_address(host, 8080).is_err(), "{host:?}");
+        }
+    }
+}
@@ -1,45 +1,321 @@
-use std::{fs::File, io::BufReader};
+use std::{fs, path::Path, thread, time::Duration};
 
-use anyhow::Result;
-use rustls_pemfile::{certs, pkcs8_private_keys};
+use anyhow::{Context, Result, anyhow};
+use log::warn;
+use rustls::crypto::{CryptoProvider, aws_lc_rs, ring};
+use rustls::pki_types::PrivateKeyDer;
+use rustls_pemfile::{certs, ec_private_keys, pkcs8_private_keys, rsa_private_keys};
+
+use crate::env::env_or;
+
+/// Installs the process-wide cryptographic provider used by Rustls.
+///
+/// AWS-LC is preferred, ring is used as a fallback. An already installed
+/// provider is kept as is.
+///
+/// # Errors
+/// - If neither AWS-LC nor ring could be installed
+pub fn install_crypto_provider() -> Result<()> {
+    if CryptoProvider::get_default().is_some() {
+        return Ok(());
+    }
+
+    if aws_lc_rs::default_provider().install_default().is_ok() {
+        return Ok(());
+    }
+    warn!("failed to install the AWS-LC crypto provider, falling back to ring...");
+
+    if ring::default_provider().install_default().is_ok() {
+        return Ok(());
+    }
+
+    // Another thread may have installed a provider in the meantime
+    match CryptoProvider::get_default() {
+        Some(_) => Ok(()),
+        None => Err(anyhow!(
+            "failed to install a crypto provider: neither AWS-LC nor ring is available"
+        )),
+    }
+}
+
+/// How often reading the certificate and key files is attempted at startup.
+///
+/// In orchestrated environments the secrets holding them may be mounted
+/// slightly after the process starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsLoadRetry {
+    /// Number of attempts to read each file, at least one is always made
+    pub attempts: u32,
+
+    /// Time to wait between two attempts
+    pub delay: Duration,
+}
+
+impl Default for TlsLoadRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl TlsLoadRetry {
+    /// Reads the retry settings from the environment.
+    ///
+    /// # Environment Variables
+    /// - `TLS_LOAD_ATTEMPTS` - Number of attempts to read each file (default: 5)
+    /// - `TLS_LOAD_RETRY_DELAY_MS` - Delay between two attempts in milliseconds (default: 1000)
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        Self {
+            attempts: env_or("TLS_LOAD_ATTEMPTS", default.attempts),
+            delay: Duration::from_millis(env_or(
+                "TLS_LOAD_RETRY_DELAY_MS",
+                default.delay.as_millis() as u64,
+            )),
+        }
+    }
+}
+
+/// Reads a whole file, retrying while it cannot be read.
+///
+/// # Arguments
+/// * `path` - The file to read
+/// * `retry` - How often and how far apart reading is attempted
+///
+/// # Errors
+/// - The error of the last attempt if every attempt failed
+pub fn read_with_retry(path: &Path, retry: TlsLoadRetry) -> Result<Vec<u8>> {
+    let attempts = retry.attempts.max(1);
+
+    for attempt in 1.. {
+        match fs::read(path) {
+            Ok(contents) => return Ok(contents),
+            Err(e) if attempt < attempts => {
+                warn!(
+                    "failed to read {} (attempt {attempt}/{attempts}): {e}, retrying...",
+                    path.display()
+                );
+                thread::sleep(retry.delay);
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "failed to read {} after {attempts} attempts",
+                        path.display()
+                    )
+                });
+            }
+        }
+    }
+
+    unreachable!("the attempt loop only ends by returning")
+}
+
+/// Protocols offered through ALPN by default, in order of preference.
+pub const DEFAULT_ALPN_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1"];
 
 /// Loads and configures TLS settings for a Rustls server.
 ///
 /// This function reads certificate and private key files from the current directory,
 /// and creates a server configuration with no client authentication required.
+/// Reading the files is retried as configured by [`TlsLoadRetry::from_env`],
+/// and [`DEFAULT_ALPN_PROTOCOLS`] are offered to clients.
 ///
 /// # Files Required
 /// - `cert.pem`: PEM-encoded certificate chain file
-/// - `key.pem`: PEM-encoded private key file in PKCS#8 format
+/// - `key.pem`: PEM-encoded private key file in PKCS#8, SEC1 or PKCS#1 format
 ///
 /// # Returns
 /// - A `Result` containing the configured `ServerConfig` or an error
 ///
 /// # Errors
-/// - If certificate or key files cannot be read
+/// - If no cryptographic provider can be installed
+/// - If certificate or key files cannot be read after every attempt
 /// - If PEM parsing fails
+/// - If the key file holds no private key in a supported format
 /// - If the certificate or key are invalid
 pub fn load_rustls_config() -> Result<rustls::ServerConfig> {
-    // Install AWS-LC as the cryptographic provider
-    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    load_rustls_config_from(
+        Path::new("cert.pem"),
+        Path::new("key.pem"),
+        TlsLoadRetry::from_env(),
+        DEFAULT_ALPN_PROTOCOLS,
+    )
+}
 
-    // Open and prepare certificate and key files for reading
-    let mut cert_file = BufReader::new(File::open("cert.pem")?);
-    let mut key_file = BufReader::new(File::open("key.pem")?);
+/// Loads and configures TLS settings for a Rustls server from the given files.
+///
+/// # Arguments
+/// * `cert_path` - PEM-encoded certificate chain file
+/// * `key_path` - PEM-encoded private key file in PKCS#8, SEC1 or PKCS#1 format
+/// * `retry` - How often and how far apart reading the files is attempted
+/// * `alpn_protocols` - Protocols offered through ALPN, in order of preference
+///
+/// # Errors
+/// See [`load_rustls_config`]
+pub fn load_rustls_config_from(
+    cert_path: &Path,
+    key_path: &Path,
+    retry: TlsLoadRetry,
+    alpn_protocols: &[&[u8]],
+) -> Result<rustls::ServerConfig> {
+    // Install AWS-LC, or ring if it is unavailable, as the cryptographic provider
+    install_crypto_provider()?;
 
-    // Parse certificate chain from PEM file
-    let cert_chain = certs(&mut cert_file).collect::<Result<Vec<_>, _>>()?;
+    // Read certificate and key files, waiting for them to appear
+    let cert_file = read_with_retry(cert_path, retry)?;
+    let key_file = read_with_retry(key_path, retry)?;
 
-    // Parse private keys from PEM file
-    let mut keys = pkcs8_private_keys(&mut key_file).collect::<Result<Vec<_>, _>>()?;
+    // Parse certificate chain from PEM file
+    let cert_chain = certs(&mut cert_file.as_slice()).collect::<Result<Vec<_>, _>>()?;
 
-    // Extract the first key (assuming there's at least one)
-    let key = keys.remove(0);
+    // Parse the first private key from PEM file
+    let key = first_private_key(&key_file)?.ok_or_else(|| {
+        anyhow!(
+            "no PKCS#8, SEC1 or PKCS#1 private key found in {}",
+            key_path.display()
+        )
+    })?;
 
     // Build server configuration with the parsed certificates and key
-    let config = rustls::ServerConfig::builder()
+    let mut config = rustls::ServerConfig::builder()
         .with_no_client_auth() // Don't require client certificates
-        .with_single_cert(cert_chain, key.into())?;
+        .with_single_cert(cert_chain, key)?;
+
+    // Let clients negotiate HTTP/2 or fall back to HTTP/1.1
+    config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
 
     Ok(config)
 }
+
+/// Returns the first private key of a PEM file.
+///
+/// PKCS#8 keys are preferred, followed by SEC1 (EC) and PKCS#1 (RSA) keys.
+fn first_private_key(pem: &[u8]) -> Result<Option<PrivateKeyDer<'static>>> {
+    if let Some(key) = pkcs8_private_keys(&mut &pem[..]).next() {
+        return Ok(Some(key?.into()));
+    }
+    if let Some(key) = ec_private_keys(&mut &pem[..]).next() {
+        return Ok(Some(key?.into()));
+    }
+    if let Some(key) = rsa_private_keys(&mut &pem[..]).next() {
+        return Ok(Some(key?.into()));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// Tests that a crypto provider is available once the setup has run, even if repeated
+    #[test]
+    fn test_crypto_provider_is_installed() {
+        install_crypto_provider().unwrap();
+        install_crypto_provider().unwrap();
+
+        assert!(CryptoProvider::get_default().is_some());
+    }
+
+    /// Tests that a file appearing after a short delay is read by a later attempt
+    #[test]
+    fn test_read_waits_for_delayed_file() {
+        let path = std::env::temp_dir().join(format!("seed-cert-{}.pem", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let retry = TlsLoadRetry {
+            attempts: 20,
+            delay: Duration::from_millis(25),
+        };
+
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                fs::write(path, "certificate").unwrap();
+            })
+        };
+        let contents = read_with_retry(&path, retry).unwrap();
+        writer.join().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(contents, b"certificate");
+    }
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// Tests that the fixture certificate loads with the default ALPN protocols offered
+    #[test]
+    fn test_config_offers_alpn_protocols() {
+        let config = load_rustls_config_from(
+            &fixture("cert.pem"),
+            &fixture("key.pem"),
+            TlsLoadRetry::default(),
+            DEFAULT_ALPN_PROTOCOLS,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.alpn_protocols,
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+    }
+
+    /// Tests that a SEC1 encoded EC key is accepted as well
+    #[test]
+    fn test_config_accepts_ec_key() {
+        let config = load_rustls_config_from(
+            &fixture("cert.pem"),
+            &fixture("ec_key.pem"),
+            TlsLoadRetry::default(),
+            DEFAULT_ALPN_PROTOCOLS,
+        );
+
+        assert!(config.is_ok());
+    }
+
+    /// Tests that a key file without any private key is reported instead of panicking
+    #[test]
+    fn test_empty_key_file_is_rejected() {
+        let path = std::env::temp_dir().join(format!("seed-key-{}.pem", std::process::id()));
+        fs::write(&path, "").unwrap();
+
+        let error = load_rustls_config_from(
+            &fixture("cert.pem"),
+            &path,
+            TlsLoadRetry::default(),
+            DEFAULT_ALPN_PROTOCOLS,
+        )
+        .unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert!(
+            error
+                .to_string()
+                .starts_with("no PKCS#8, SEC1 or PKCS#1 private key found")
+        );
+    }
+
+    /// Tests that the final error is returned once every attempt failed
+    #[test]
+    fn test_read_gives_up_after_attempts() {
+        let path = std::env::temp_dir().join("seed-cert-missing.pem");
+        let retry = TlsLoadRetry {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+        };
+
+        let error = read_with_retry(&path, retry).unwrap_err();
+
+        assert!(error.to_string().contains("after 3 attempts"));
+        assert!(error.downcast_ref::<std::io::Error>().is_some());
+    }
+}
@@ -0,0 +1,62 @@
+//! Benchmarks of the subscription bookkeeping in [`WebSocketManager`]
+
+use std::sync::Arc;
+
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use futures::SinkExt;
+use protocol::entity::websocket::{WebSocketConnection, WebSocketManager};
+use tokio_tungstenite::tungstenite::Error as WsError;
+
+/// Number of connections subscribed in each benchmark
+const CONNECTIONS: usize = 1_000;
+
+/// Number of chats the connections are spread over
+const CHATS: usize = 10;
+
+/// Creates a connection whose session halves never produce or accept anything
+fn idle_connection() -> Arc<WebSocketConnection> {
+    let sink = futures::sink::drain().sink_map_err(|_| WsError::ConnectionClosed);
+    Arc::new(WebSocketConnection::from_parts(
+        Box::pin(sink),
+        Box::pin(futures::stream::pending()),
+    ))
+}
+
+/// Subscribes every connection to one of the chats
+fn subscribed(connections: &[Arc<WebSocketConnection>]) -> WebSocketManager {
+    let manager = WebSocketManager::default();
+    for (i, connection) in connections.iter().enumerate() {
+        manager.add_subscription(connection, &format!("chat-{}", i % CHATS), usize::MAX);
+    }
+    manager
+}
+
+fn bench_manager(c: &mut Criterion) {
+    let connections: Vec<_> = (0..CONNECTIONS).map(|_| idle_connection()).collect();
+
+    c.bench_function("subscribe", |b| b.iter(|| subscribed(&connections)));
+
+    let manager = subscribed(&connections);
+    c.bench_function("resolve subscribers", |b| {
+        b.iter(|| {
+            (0..CHATS)
+                .map(|chat| manager.subscribers(&format!("chat-{chat}")).unwrap().len())
+                .sum::<usize>()
+        })
+    });
+
+    c.bench_function("disconnect", |b| {
+        b.iter_batched(
+            || subscribed(&connections),
+            |manager| {
+                for connection in &connections {
+                    manager.remove_connection(connection);
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_manager);
+criterion_main!(benches);
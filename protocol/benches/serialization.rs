@@ -0,0 +1,103 @@
+//! Benchmarks of serializing a delivered message into a frame
+//!
+//! Compares a fresh string per message with writing into a reused buffer.
+//! Allocations per message of each path are counted and printed up front.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use criterion::{Criterion, criterion_group, criterion_main};
+use protocol::entity::{
+    compat::ProtocolVersion,
+    message::OutcomeMessage,
+    response::{NewEventDetail, SeedResponse},
+};
+
+/// Allocator that counts the allocations it serves
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Number of messages serialized when counting allocations
+const MESSAGES: usize = 1_000;
+
+/// Returns a message with a payload the size of a typical chat message
+fn response() -> SeedResponse {
+    SeedResponse::NewEvent(NewEventDetail {
+        rtype: "new".to_string(),
+        message: OutcomeMessage {
+            nonce: 42,
+            chat_id: "Y2hhdC1pZC1vZi10aGlydHktdHdvLWJ5dGVzLi4u".to_string(),
+            signature: "c".repeat(44),
+            content: "x".repeat(512),
+            content_iv: "aXYtb2YtdHdlbHZl".to_string(),
+            ttl_seconds: None,
+        },
+    })
+}
+
+/// Serializes a fresh string for the message
+fn fresh(response: &SeedResponse) -> Bytes {
+    response.to_json(ProtocolVersion::V1).unwrap().into()
+}
+
+/// Writes the message into `buffer` and takes the written bytes without copying
+fn reused(response: &SeedResponse, buffer: &mut BytesMut) -> Bytes {
+    response
+        .write_json(ProtocolVersion::V1, (&mut *buffer).writer())
+        .unwrap();
+    buffer.split().freeze()
+}
+
+/// Returns the number of allocations `serialize` makes per message on average
+fn allocations_per_message(mut serialize: impl FnMut() -> Bytes) -> f64 {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..MESSAGES {
+        black_box(serialize());
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / MESSAGES as f64
+}
+
+fn bench_serialization(c: &mut Criterion) {
+    let response = response();
+    let mut buffer = BytesMut::new();
+
+    println!(
+        "allocations per message: fresh string {:.1}, reused buffer {:.1}",
+        allocations_per_message(|| fresh(&response)),
+        allocations_per_message(|| reused(&response, &mut buffer)),
+    );
+
+    c.bench_function("serialize fresh string", |b| {
+        b.iter(|| fresh(black_box(&response)))
+    });
+    c.bench_function("serialize reused buffer", |b| {
+        b.iter(|| reused(black_box(&response), &mut buffer))
+    });
+}
+
+criterion_group!(benches, bench_serialization);
+criterion_main!(benches);
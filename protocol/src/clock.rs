@@ -0,0 +1,68 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Source of the current time for time-dependent components.
+///
+/// Components take a clock instead of calling [`Instant::now`] so tests can
+/// move time forward without sleeping.
+pub trait Clock: Send + Sync {
+    /// Returns the current instant
+    fn now(&self) -> Instant;
+}
+
+/// Shared handle to a clock
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Clock reading the monotonic system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl SystemClock {
+    /// Returns a shared handle to the system clock
+    pub fn shared() -> SharedClock {
+        Arc::new(Self)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock that only moves when told to.
+///
+/// Starts at the time it was created.
+#[derive(Debug)]
+pub struct MockClock {
+    /// The instant reported as now
+    now: Mutex<Instant>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl MockClock {
+    /// Moves the clock forward
+    ///
+    /// # Arguments
+    ///
+    /// * `by` - How far to move the clock
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
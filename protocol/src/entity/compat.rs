@@ -0,0 +1,474 @@
+use std::{collections::BTreeMap, io, str::FromStr};
+
+use serde::{Serialize, Serializer};
+
+use super::{
+    message::OutcomeMessage,
+    response::{QuotaStatus, SeedErrorCode, SeedResponse},
+};
+
+/// Versions of the wire format a client can ask for.
+///
+/// Version 1 keeps the original mixed-case tags (`queueId`, `contentIV`,
+/// `event` for new events). Version 2 names every tag in snake_case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolVersion {
+    /// The original tag set
+    #[default]
+    V1,
+    /// The snake_case tag set
+    V2,
+}
+
+/// Newest wire format version the server speaks.
+///
+/// Bumped with every wire format change. Version 2 is the first to name every
+/// tag in snake_case, version 1 keeps the original tags for existing clients.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::V2;
+
+impl FromStr for ProtocolVersion {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "1" => Ok(Self::V1),
+            "2" => Ok(Self::V2),
+            other => Err(format!("unsupported protocol version {other:?}")),
+        }
+    }
+}
+
+/// A response serialized with the tag set of a protocol version.
+pub struct Versioned<'a> {
+    /// Version whose tag set is used
+    version: ProtocolVersion,
+    /// The response to serialize
+    response: &'a SeedResponse,
+}
+
+impl<'a> Versioned<'a> {
+    /// Wraps a response to be serialized for the given protocol version.
+    pub fn new(version: ProtocolVersion, response: &'a SeedResponse) -> Self {
+        Self { version, response }
+    }
+}
+
+impl Serialize for Versioned<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.version {
+            ProtocolVersion::V1 => self.response.serialize(serializer),
+            ProtocolVersion::V2 => v2::Response::from(self.response).serialize(serializer),
+        }
+    }
+}
+
+impl SeedResponse {
+    /// Serializes the response to JSON using the tag set of `version`.
+    pub fn to_json(&self, version: ProtocolVersion) -> serde_json::Result<String> {
+        serde_json::to_string(&Versioned::new(version, self))
+    }
+
+    /// Serializes the response like [`SeedResponse::to_json`], leaving out empty message fields.
+    ///
+    /// System messages such as typing or presence notifications carry no
+    /// signature or content, so clients that accept a lean shape are spared
+    /// the empty strings.
+    pub fn to_lean_json(&self, version: ProtocolVersion) -> serde_json::Result<String> {
+        serde_json::to_string(&self.lean_value(version)?)
+    }
+
+    /// Writes the same bytes as [`SeedResponse::to_json`] into `writer`.
+    ///
+    /// Lets the send path serialize into a buffer it reuses across messages.
+    pub fn write_json<W: io::Write>(
+        &self,
+        version: ProtocolVersion,
+        writer: W,
+    ) -> serde_json::Result<()> {
+        serde_json::to_writer(writer, &Versioned::new(version, self))
+    }
+
+    /// Writes the same bytes as [`SeedResponse::to_lean_json`] into `writer`.
+    pub fn write_lean_json<W: io::Write>(
+        &self,
+        version: ProtocolVersion,
+        writer: W,
+    ) -> serde_json::Result<()> {
+        serde_json::to_writer(writer, &self.lean_value(version)?)
+    }
+
+    fn lean_value(&self, version: ProtocolVersion) -> serde_json::Result<serde_json::Value> {
+        let mut value = serde_json::to_value(Versioned::new(version, self))?;
+        if let Some(serde_json::Value::Object(message)) = value.pointer_mut("/response/message") {
+            message.retain(|_, field| field.as_str() != Some(""));
+        }
+        Ok(value)
+    }
+}
+
+/// The snake_case tag set of protocol version 2.
+///
+/// Mirrors [`SeedResponse`] field for field, borrowing the values.
+mod v2 {
+    use super::*;
+
+    /// A server response.
+    #[derive(Serialize)]
+    #[serde(tag = "type", content = "response", rename_all = "snake_case")]
+    pub enum Response<'a> {
+        NewEvent(NewEvent<'a>),
+        WaitEvent(Queue<'a>),
+        Status(Status),
+        SendAck(SendAck<'a>),
+        Pong,
+        UpToDate(UpToDate<'a>),
+        SyncError(SyncError<'a>),
+        SubscribeComplete(SubscribeComplete<'a>),
+        ChatClosed(Queue<'a>),
+        QuotaStatus(Quota),
+        HeadNonces(HeadNonces<'a>),
+        Error(Error),
+    }
+
+    /// A message delivered to a subscriber.
+    #[derive(Serialize)]
+    pub struct NewEvent<'a> {
+        pub message: Message<'a>,
+    }
+
+    /// A stored or relayed message.
+    #[derive(Serialize)]
+    pub struct Message<'a> {
+        pub nonce: usize,
+        pub queue_id: &'a str,
+        pub signature: &'a str,
+        pub content: &'a str,
+        pub content_iv: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub ttl_seconds: Option<u64>,
+    }
+
+    /// A notification about a single chat.
+    #[derive(Serialize)]
+    pub struct Queue<'a> {
+        pub queue_id: &'a str,
+    }
+
+    /// A notification that a subscription is live.
+    #[derive(Serialize)]
+    pub struct SubscribeComplete<'a> {
+        pub queue_id: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub resume_token: Option<&'a str>,
+    }
+
+    /// The status of an operation.
+    #[derive(Serialize)]
+    pub struct Status {
+        pub status: bool,
+        pub code: Option<SeedErrorCode>,
+    }
+
+    /// An acknowledgement of a sent message.
+    #[derive(Serialize)]
+    pub struct SendAck<'a> {
+        pub queue_id: &'a str,
+        pub nonce: usize,
+    }
+
+    /// A notification that a replay caught up with the stored head.
+    #[derive(Serialize)]
+    pub struct UpToDate<'a> {
+        pub queue_id: &'a str,
+        pub head_nonce: usize,
+    }
+
+    /// A notification that a replay stopped early.
+    #[derive(Serialize)]
+    pub struct SyncError<'a> {
+        pub queue_id: &'a str,
+        pub last_delivered_nonce: usize,
+    }
+
+    /// The sending allowance left to a connection.
+    #[derive(Serialize)]
+    pub struct Quota {
+        pub messages_remaining: u64,
+        pub bytes_remaining: u64,
+        pub reset_in_ms: u64,
+    }
+
+    /// The head nonce of each requested chat.
+    #[derive(Serialize)]
+    pub struct HeadNonces<'a> {
+        pub heads: &'a BTreeMap<String, usize>,
+    }
+
+    /// A rejection of the client's input.
+    #[derive(Serialize)]
+    pub struct Error {
+        pub code: SeedErrorCode,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub quota: Option<Quota>,
+    }
+
+    impl From<&QuotaStatus> for Quota {
+        fn from(quota: &QuotaStatus) -> Self {
+            Self {
+                messages_remaining: quota.messages_remaining,
+                bytes_remaining: quota.bytes_remaining,
+                reset_in_ms: quota.reset_in_ms,
+            }
+        }
+    }
+
+    impl<'a> From<&'a OutcomeMessage> for Message<'a> {
+        fn from(message: &'a OutcomeMessage) -> Self {
+            Self {
+                nonce: message.nonce,
+                queue_id: &message.chat_id,
+                signature: &message.signature,
+                content: &message.content,
+                content_iv: &message.content_iv,
+                ttl_seconds: message.ttl_seconds,
+            }
+        }
+    }
+
+    impl<'a> From<&'a SeedResponse> for Response<'a> {
+        fn from(response: &'a SeedResponse) -> Self {
+            match response {
+                SeedResponse::NewEvent(detail) => Self::NewEvent(NewEvent {
+                    message: (&detail.message).into(),
+                }),
+                SeedResponse::WaitEvent(detail) => Self::WaitEvent(Queue {
+                    queue_id: &detail.chat_id,
+                }),
+                SeedResponse::Status(status) => Self::Status(Status {
+                    status: status.status,
+                    code: status.code,
+                }),
+                SeedResponse::SendAck(detail) => Self::SendAck(SendAck {
+                    queue_id: &detail.chat_id,
+                    nonce: detail.nonce,
+                }),
+                SeedResponse::Pong => Self::Pong,
+                SeedResponse::UpToDate(detail) => Self::UpToDate(UpToDate {
+                    queue_id: &detail.chat_id,
+                    head_nonce: detail.head_nonce,
+                }),
+                SeedResponse::SyncError(detail) => Self::SyncError(SyncError {
+                    queue_id: &detail.chat_id,
+                    last_delivered_nonce: detail.last_delivered_nonce,
+                }),
+                SeedResponse::SubscribeComplete(detail) => {
+                    Self::SubscribeComplete(SubscribeComplete {
+                        queue_id: &detail.chat_id,
+                        resume_token: detail.resume_token.as_deref(),
+                    })
+                }
+                SeedResponse::ChatClosed(detail) => Self::ChatClosed(Queue {
+                    queue_id: &detail.chat_id,
+                }),
+                SeedResponse::QuotaStatus(quota) => Self::QuotaStatus(quota.into()),
+                SeedResponse::HeadNonces(detail) => Self::HeadNonces(HeadNonces {
+                    heads: &detail.heads,
+                }),
+                SeedResponse::Error(error) => Self::Error(Error {
+                    code: error.code,
+                    quota: error.quota.as_ref().map(Quota::from),
+                }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::collections::HashSet;
+
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::entity::response::{
+        ChatClosedDetail, ErrorResponse, HeadNoncesDetail, NewEventDetail, SendAckDetail,
+        StatusResponse, SubscribeCompleteDetail, SyncErrorDetail, UpToDateDetail, WaitEventDetail,
+    };
+
+    /// Serializes a response for a version and parses it back for comparison
+    fn serialized(version: ProtocolVersion, response: &SeedResponse) -> Value {
+        serde_json::from_str(&response.to_json(version).unwrap()).unwrap()
+    }
+
+    /// Returns one response of each shape whose tags differ between versions
+    fn responses() -> [SeedResponse; 5] {
+        [
+            SeedResponse::NewEvent(NewEventDetail {
+                rtype: "new".to_string(),
+                message: OutcomeMessage {
+                    nonce: 1,
+                    chat_id: "chat".to_string(),
+                    signature: "sig".to_string(),
+                    content: "content".to_string(),
+                    content_iv: "iv".to_string(),
+                    ttl_seconds: None,
+                },
+            }),
+            SeedResponse::WaitEvent(WaitEventDetail {
+                rtype: "wait".to_string(),
+                chat_id: "chat".to_string(),
+            }),
+            SeedResponse::UpToDate(UpToDateDetail {
+                chat_id: "chat".to_string(),
+                head_nonce: 3,
+            }),
+            SeedResponse::SubscribeComplete(SubscribeCompleteDetail {
+                chat_id: "chat".to_string(),
+                resume_token: Some("token".to_string()),
+            }),
+            SeedResponse::Error(ErrorResponse {
+                code: SeedErrorCode::RateLimited,
+                quota: Some(QuotaStatus {
+                    messages_remaining: 0,
+                    bytes_remaining: 10,
+                    reset_in_ms: 500,
+                }),
+            }),
+        ]
+    }
+
+    /// Tests that version 1 keeps the original tag set
+    #[test]
+    fn test_v1_tag_set() {
+        let expected = [
+            json!({"type": "event", "response": {"type": "new", "message": {
+                "nonce": 1, "queueId": "chat", "signature": "sig",
+                "content": "content", "contentIV": "iv"}}}),
+            json!({"type": "wait", "response": {"type": "wait", "queueId": "chat"}}),
+            json!({"type": "upToDate", "response": {"queueId": "chat", "headNonce": 3}}),
+            json!({"type": "subscribeComplete", "response": {
+                "queueId": "chat", "resumeToken": "token"}}),
+            json!({"type": "error", "response": {"code": "rate_limited", "quota": {
+                "messagesRemaining": 0, "bytesRemaining": 10, "resetInMs": 500}}}),
+        ];
+
+        for (response, expected) in responses().iter().zip(expected) {
+            assert_eq!(serialized(ProtocolVersion::V1, response), expected);
+        }
+    }
+
+    /// Tests that version 2 uses snake_case tags and a distinct type per response
+    #[test]
+    fn test_v2_tag_set() {
+        let expected = [
+            json!({"type": "new_event", "response": {"message": {
+                "nonce": 1, "queue_id": "chat", "signature": "sig",
+                "content": "content", "content_iv": "iv"}}}),
+            json!({"type": "wait_event", "response": {"queue_id": "chat"}}),
+            json!({"type": "up_to_date", "response": {"queue_id": "chat", "head_nonce": 3}}),
+            json!({"type": "subscribe_complete", "response": {
+                "queue_id": "chat", "resume_token": "token"}}),
+            json!({"type": "error", "response": {"code": "rate_limited", "quota": {
+                "messages_remaining": 0, "bytes_remaining": 10, "reset_in_ms": 500}}}),
+        ];
+
+        for (response, expected) in responses().iter().zip(expected) {
+            assert_eq!(serialized(ProtocolVersion::V2, response), expected);
+        }
+
+        // Without a resume token the field is left out
+        let complete = SeedResponse::SubscribeComplete(SubscribeCompleteDetail {
+            chat_id: "chat".to_string(),
+            resume_token: None,
+        });
+        assert_eq!(
+            serialized(ProtocolVersion::V2, &complete),
+            json!({"type": "subscribe_complete", "response": {"queue_id": "chat"}})
+        );
+    }
+
+    /// Tests that every response can be told apart in each version
+    ///
+    /// Both versions give every response its own top-level type.
+    #[test]
+    fn test_response_types_are_unambiguous() {
+        let chat_id = || "chat".to_string();
+        let mut all = Vec::from(responses());
+        all.extend([
+            SeedResponse::Status(StatusResponse::new(Ok(()))),
+            SeedResponse::SendAck(SendAckDetail {
+                chat_id: chat_id(),
+                nonce: 1,
+            }),
+            SeedResponse::Pong,
+            SeedResponse::ChatClosed(ChatClosedDetail { chat_id: chat_id() }),
+            SeedResponse::SyncError(SyncErrorDetail {
+                chat_id: chat_id(),
+                last_delivered_nonce: 1,
+            }),
+            SeedResponse::QuotaStatus(QuotaStatus {
+                messages_remaining: 1,
+                bytes_remaining: 1,
+                reset_in_ms: 1,
+            }),
+            SeedResponse::HeadNonces(HeadNoncesDetail {
+                heads: BTreeMap::new(),
+            }),
+        ]);
+
+        let v1_types: HashSet<(Value, Value)> = all
+            .iter()
+            .map(|response| {
+                let value = serialized(ProtocolVersion::V1, response);
+                (value["type"].clone(), value["response"]["type"].clone())
+            })
+            .collect();
+        assert_eq!(v1_types.len(), all.len(), "{v1_types:?}");
+
+        let v2_types: HashSet<Value> = all
+            .iter()
+            .map(|response| serialized(ProtocolVersion::V2, response)["type"].clone())
+            .collect();
+        assert_eq!(v2_types.len(), all.len(), "{v2_types:?}");
+    }
+
+    /// Tests that writing into a reused buffer produces the bytes of the string serializers
+    #[test]
+    fn test_written_json_is_byte_identical() {
+        let mut buffer = Vec::new();
+
+        for version in [ProtocolVersion::V1, ProtocolVersion::V2] {
+            for response in responses() {
+                buffer.clear();
+                response.write_json(version, &mut buffer).unwrap();
+                assert_eq!(buffer, response.to_json(version).unwrap().into_bytes());
+
+                buffer.clear();
+                response.write_lean_json(version, &mut buffer).unwrap();
+                assert_eq!(buffer, response.to_lean_json(version).unwrap().into_bytes());
+            }
+        }
+    }
+
+    /// Tests that every protocol version tells events apart by their top-level type
+    #[test]
+    fn test_versions_have_distinct_event_types() {
+        let [new, wait, ..] = responses();
+
+        for version in [ProtocolVersion::V1, PROTOCOL_VERSION] {
+            let new = serialized(version, &new);
+            let wait = serialized(version, &wait);
+
+            assert_ne!(new["type"], wait["type"]);
+        }
+    }
+
+    /// Tests that protocol versions parse from their numbers
+    #[test]
+    fn test_version_parses() {
+        assert_eq!("1".parse(), Ok(ProtocolVersion::V1));
+        assert_eq!("2".parse(), Ok(ProtocolVersion::V2));
+        assert!("3".parse::<ProtocolVersion>().is_err());
+    }
+}
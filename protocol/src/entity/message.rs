@@ -1,8 +1,12 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::websocket::{ResumeRequest, SubscriptionRequest};
 
 /// Represents incoming messages from clients with different action types.
 /// Uses a tagged enum format for JSON serialization/deserialization.
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, JsonSchema)]
 #[serde(tag = "type", content = "message")]
 pub enum IncomeMessage {
     /// Simple ping message for connection checking
@@ -11,21 +15,117 @@ pub enum IncomeMessage {
     /// Message to send content to a specific chat
     #[serde(rename = "send")]
     Send(Message),
-    /// Message to subscribe to a specific chat
+    /// Request to subscribe to a specific chat
     #[serde(rename = "subscribe")]
-    Subscribe(Message),
+    Subscribe(SubscriptionRequest),
     /// Message to unsubscribe from a specific chat
     #[serde(rename = "unsubscribe")]
     Unsubscribe(Message),
+    /// Request to restore the subscriptions of a previous connection
+    #[serde(rename = "resume")]
+    Resume(ResumeRequest),
+    /// Request for the sending allowance left to the connection
+    #[serde(rename = "quota")]
+    Quota,
+    /// Request for the head nonce of several chats at once
+    #[serde(rename = "headNonces")]
+    HeadNonces(HeadNoncesRequest),
+    /// Request for only the messages of a chat the client does not have yet
+    #[serde(rename = "syncDiff")]
+    SyncDiff(SyncDiffRequest),
+    /// Request to unsubscribe from every chat, forget the resume state and close
+    #[serde(rename = "logout")]
+    Logout,
     /// Empty message or placeholder
     None,
+    /// Any message whose type the server does not know
+    #[serde(untagged)]
+    #[schemars(skip)]
+    Unknown(UnknownMessage),
+}
+
+impl IncomeMessage {
+    /// Every type the server knows, as sent in the `type` field
+    pub const TYPES: &[&str] = &[
+        "ping",
+        "send",
+        "subscribe",
+        "unsubscribe",
+        "resume",
+        "quota",
+        "headNonces",
+        "syncDiff",
+        "logout",
+        "None",
+    ];
+
+    /// Returns every chat ID the message refers to, for rewriting in place
+    pub fn chat_ids_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            Self::Send(message) | Self::Unsubscribe(message) => vec![&mut message.chat_id],
+            Self::Subscribe(request) => vec![&mut request.chat_id],
+            Self::HeadNonces(request) => request.chats.iter_mut().collect(),
+            Self::SyncDiff(request) => vec![&mut request.chat_id],
+            Self::Ping
+            | Self::Resume(_)
+            | Self::Quota
+            | Self::Logout
+            | Self::None
+            | Self::Unknown(_) => Vec::new(),
+        }
+    }
+}
+
+/// A message of a type the server does not know.
+///
+/// Keeps the received type so the rejection can name it. A known type whose
+/// body does not match it ends up here as well.
+#[derive(Deserialize, Clone, Debug)]
+pub struct UnknownMessage {
+    /// The type the client sent
+    #[serde(rename = "type")]
+    pub rtype: String,
+}
+
+impl UnknownMessage {
+    /// Returns whether the type is known and only the body was malformed
+    pub fn is_malformed(&self) -> bool {
+        IncomeMessage::TYPES.contains(&self.rtype.as_str())
+    }
+}
+
+/// Request for the highest stored nonce of several chats.
+///
+/// Lets a reconnecting client find out which chats have new messages
+/// before deciding what to replay.
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct HeadNoncesRequest {
+    /// Identifiers of the chats to look up
+    #[serde(rename = "queueIds")]
+    pub chats: Vec<String>,
+}
+
+/// Request for the stored messages of a chat outside the nonce ranges the client has.
+///
+/// Lets a client whose history has gaps, e.g. after messages expired while it
+/// was offline, fetch only what it is missing instead of replaying a whole range.
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct SyncDiffRequest {
+    /// Identifier of the chat to sync
+    #[serde(rename = "queueId")]
+    pub chat_id: String,
+    /// Inclusive nonce ranges the client already has, in any order
+    #[serde(rename = "haveRanges")]
+    pub have_ranges: Vec<(usize, usize)>,
 }
 
 /// Represents the core message structure used for communication.
 /// Contains encryption and identification details.
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct Message {
     /// Unique number for message sequencing and identification
+    ///
+    /// [`Message::AUTO_NONCE`] lets the server assign the next nonce of the chat.
     pub nonce: usize,
     /// Identifier for the chat/queue this message belongs to
     #[serde(rename = "queueId")]
@@ -37,11 +137,28 @@ pub struct Message {
     /// Initialization vector used for content encryption
     #[serde(rename = "contentIV")]
     pub content_iv: String,
+    /// Seconds after which the server deletes the message, if it expires at all
+    #[serde(
+        rename = "ttlSeconds",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ttl_seconds: Option<u64>,
+}
+
+impl Message {
+    /// Nonce asking the server to assign the next nonce of the chat
+    pub const AUTO_NONCE: usize = 0;
+
+    /// Returns whether the server is asked to assign the nonce
+    pub fn has_auto_nonce(&self) -> bool {
+        self.nonce == Self::AUTO_NONCE
+    }
 }
 
 /// Outcoming message struct for sending responses back to clients.
 /// Has the same structure as Message but separated for clear direction indication.
-#[derive(Serialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct OutcomeMessage {
     /// Unique number for message sequencing and identification
     pub nonce: usize,
@@ -55,6 +172,22 @@ pub struct OutcomeMessage {
     /// Initialization vector used for content encryption
     #[serde(rename = "contentIV")]
     pub content_iv: String,
+    /// Seconds after which the server deletes the message, if it expires at all
+    #[serde(
+        rename = "ttlSeconds",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ttl_seconds: Option<u64>,
+}
+
+/// Event relayed between the instances of a cluster
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClusterEvent {
+    /// Instance that accepted the message and already delivered it to its subscribers
+    pub origin: Uuid,
+    /// The broadcast message
+    pub message: OutcomeMessage,
 }
 
 /// Conversion implementation from OutcomeMessage to Message.
@@ -68,6 +201,7 @@ impl From<OutcomeMessage> for Message {
             signature: msg.signature,
             content: msg.content,
             content_iv: msg.content_iv,
+            ttl_seconds: msg.ttl_seconds,
         }
     }
 }
@@ -83,6 +217,7 @@ impl From<Message> for OutcomeMessage {
             signature: msg.signature,
             content: msg.content,
             content_iv: msg.content_iv,
+            ttl_seconds: msg.ttl_seconds,
         }
     }
 }
@@ -92,11 +227,10 @@ impl From<Message> for OutcomeMessage {
 impl From<IncomeMessage> for Option<Message> {
     /// Converts an IncomeMessage to Option<Message>.
     /// Returns Some(message) for action messages containing a Message payload,
-    /// or None for messages without content (like Ping).
+    /// or None for messages without content (like Ping or Subscribe).
     fn from(msg: IncomeMessage) -> Self {
         match msg {
             IncomeMessage::Send(message) => Some(message),
-            IncomeMessage::Subscribe(message) => Some(message),
             IncomeMessage::Unsubscribe(message) => Some(message),
             _ => None,
         }
@@ -112,7 +246,6 @@ impl From<IncomeMessage> for OutcomeMessage {
     fn from(msg: IncomeMessage) -> Self {
         match msg {
             IncomeMessage::Send(message) => OutcomeMessage::from(message),
-            IncomeMessage::Subscribe(message) => OutcomeMessage::from(message),
             IncomeMessage::Unsubscribe(message) => OutcomeMessage::from(message),
             _ => OutcomeMessage::from(Message::default()),
         }
@@ -146,4 +279,39 @@ mod tests {
             _ => panic!("Deserialized to wrong variant, expected IncomeMessage::Send"),
         }
     }
+
+    /// Tests that IncomeMessage::Subscribe deserializes into a SubscriptionRequest
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_income_message_subscribe_deserialization() {
+        let json_str = r#"{"type":"subscribe","message":{"type":"subscribe","queueId":"chat-123456","nonce":42}}"#;
+
+        let deserialized: IncomeMessage = serde_json::from_str(json_str).unwrap();
+
+        match deserialized {
+            IncomeMessage::Subscribe(request) => {
+                assert_eq!(request.rtype, "subscribe");
+                assert_eq!(request.chat_id, "chat-123456");
+                assert_eq!(request.nonce, 42);
+            }
+            _ => panic!("Deserialized to wrong variant, expected IncomeMessage::Subscribe"),
+        }
+    }
+    /// Tests that an unrecognized type deserializes to IncomeMessage::Unknown, with or without a body
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_income_message_unknown_type_deserialization() {
+        for json_str in [
+            r#"{"type":"teleport"}"#,
+            r#"{"type":"teleport","message":null}"#,
+            r#"{"type":"teleport","message":{"queueId":"chat-123456"}}"#,
+        ] {
+            let deserialized: IncomeMessage = serde_json::from_str(json_str).unwrap();
+
+            assert!(
+                matches!(deserialized, IncomeMessage::Unknown(ref m) if m.rtype == "teleport"),
+                "{json_str} did not deserialize to IncomeMessage::Unknown"
+            );
+        }
+    }
 }
@@ -1,3 +1,5 @@
+pub mod compat;
 pub mod message;
+pub mod quota;
 pub mod response;
 pub mod websocket;
@@ -0,0 +1,161 @@
+use std::time::{Duration, Instant};
+
+use super::response::QuotaStatus;
+
+/// Allowance a connection gets for sending messages in every window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaLimits {
+    /// Number of messages that may be sent per window
+    pub messages: u64,
+
+    /// Number of content bytes that may be sent per window
+    pub bytes: u64,
+
+    /// Length of a window, after which the allowance is refilled
+    pub window: Duration,
+}
+
+/// A bucket of tokens that is refilled to capacity at the end of every window
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Number of tokens the bucket holds when full
+    capacity: u64,
+    /// Tokens left in the current window
+    tokens: u64,
+    /// Length of a window
+    window: Duration,
+    /// When the current window started
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket whose first window starts at `now`
+    pub fn new(capacity: u64, window: Duration, now: Instant) -> Self {
+        Self {
+            capacity,
+            tokens: capacity,
+            window,
+            refilled_at: now,
+        }
+    }
+
+    /// Refills the bucket if the current window has passed
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        if elapsed >= self.window {
+            // Keep windows aligned to the first one
+            let into_window = elapsed.as_nanos() % self.window.as_nanos().max(1);
+            self.refilled_at = now - Duration::from_nanos(into_window as u64);
+            self.tokens = self.capacity;
+        }
+    }
+
+    /// Returns the number of tokens left in the current window
+    pub fn remaining(&mut self, now: Instant) -> u64 {
+        self.refill(now);
+        self.tokens
+    }
+
+    /// Returns the time left until the bucket is refilled
+    pub fn reset_in(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        (self.refilled_at + self.window).saturating_duration_since(now)
+    }
+
+    /// Takes `amount` tokens if that many are left
+    ///
+    /// # Returns
+    /// Whether the tokens were taken
+    pub fn try_take(&mut self, amount: u64, now: Instant) -> bool {
+        self.refill(now);
+        if amount > self.tokens {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+/// Message and byte allowance of a single connection
+#[derive(Debug, Clone)]
+pub struct SendQuota {
+    /// Bucket counting sent messages
+    messages: TokenBucket,
+    /// Bucket counting sent content bytes
+    bytes: TokenBucket,
+}
+
+impl SendQuota {
+    /// Creates a full quota whose first window starts at `now`
+    pub fn new(limits: QuotaLimits, now: Instant) -> Self {
+        Self {
+            messages: TokenBucket::new(limits.messages, limits.window, now),
+            bytes: TokenBucket::new(limits.bytes, limits.window, now),
+        }
+    }
+
+    /// Returns the allowance left in the current window
+    pub fn status(&mut self, now: Instant) -> QuotaStatus {
+        QuotaStatus {
+            messages_remaining: self.messages.remaining(now),
+            bytes_remaining: self.bytes.remaining(now),
+            reset_in_ms: self.messages.reset_in(now).as_millis() as u64,
+        }
+    }
+
+    /// Checks whether a message of `bytes` content bytes fits the allowance, taking nothing
+    ///
+    /// # Returns
+    /// The allowance left, as an error if the message exceeds it
+    pub fn check(&mut self, bytes: u64, now: Instant) -> Result<QuotaStatus, QuotaStatus> {
+        if self.messages.remaining(now) < 1 || self.bytes.remaining(now) < bytes {
+            return Err(self.status(now));
+        }
+        Ok(self.status(now))
+    }
+
+    /// Accounts for a message of `bytes` content bytes
+    ///
+    /// Nothing is taken from either bucket unless both have enough left.
+    ///
+    /// # Returns
+    /// The allowance left afterwards, or the unchanged allowance if the message exceeds it
+    pub fn try_consume(&mut self, bytes: u64, now: Instant) -> Result<QuotaStatus, QuotaStatus> {
+        self.check(bytes, now)?;
+        self.messages.try_take(1, now);
+        self.bytes.try_take(bytes, now);
+
+        Ok(self.status(now))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// Tests that the allowance shrinks with every message and refills after the window
+    #[test]
+    fn test_quota_decreases_and_resets() {
+        let start = Instant::now();
+        let limits = QuotaLimits {
+            messages: 2,
+            bytes: 100,
+            window: Duration::from_secs(10),
+        };
+        let mut quota = SendQuota::new(limits, start);
+
+        let first = quota.try_consume(30, start).unwrap();
+        assert_eq!((first.messages_remaining, first.bytes_remaining), (1, 70));
+        assert!(quota.try_consume(80, start).is_err());
+        let second = quota.try_consume(70, start).unwrap();
+        assert_eq!((second.messages_remaining, second.bytes_remaining), (0, 0));
+
+        let exhausted = quota.try_consume(1, start + Duration::from_secs(4));
+        assert_eq!(exhausted.unwrap_err().reset_in_ms, 6000);
+
+        let reset = quota.status(start + Duration::from_secs(10));
+        assert_eq!((reset.messages_remaining, reset.bytes_remaining), (2, 100));
+        assert_eq!(reset.reset_in_ms, 10_000);
+    }
+}
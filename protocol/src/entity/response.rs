@@ -1,4 +1,7 @@
-use serde::Serialize;
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 use super::message::OutcomeMessage;
 
@@ -6,7 +9,7 @@ use super::message::OutcomeMessage;
 ///
 /// This enum represents different types of responses that can be sent
 /// from the server to the client, including event notifications and status updates.
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 #[serde(tag = "type", content = "response")]
 pub enum SeedResponse {
     /// Represents a new event notification.
@@ -18,7 +21,8 @@ pub enum SeedResponse {
     /// Represents a wait event notification.
     ///
     /// This variant is used when the client needs to wait for an event to complete.
-    #[serde(rename = "event")]
+    /// Its own `wait` type tells it apart from new events without the inner type.
+    #[serde(rename = "wait")]
     WaitEvent(WaitEventDetail),
 
     /// Represents a status response.
@@ -26,12 +30,66 @@ pub enum SeedResponse {
     /// This variant is used to communicate the success or failure of an operation.
     #[serde(rename = "response")]
     Status(StatusResponse),
+
+    /// Acknowledges a sent message.
+    ///
+    /// This variant replaces the positive status of a send, naming the message that was accepted.
+    #[serde(rename = "sendAck")]
+    SendAck(SendAckDetail),
+
+    /// Answers a ping.
+    ///
+    /// Kept apart from [`SeedResponse::Status`] so a ping is never mistaken for an acknowledgement.
+    #[serde(rename = "pong")]
+    Pong,
+
+    /// Signals that the client has received every stored message of a chat.
+    ///
+    /// This variant is sent when a history replay yields no messages.
+    #[serde(rename = "upToDate")]
+    UpToDate(UpToDateDetail),
+
+    /// Signals that a history replay stopped because the history could not be read.
+    ///
+    /// This variant tells the client where to resume the replay from.
+    #[serde(rename = "syncError")]
+    SyncError(SyncErrorDetail),
+
+    /// Signals that the initial subscribe sequence of a chat is finished.
+    ///
+    /// This variant is sent after the wait event that follows the history replay.
+    #[serde(rename = "subscribeComplete")]
+    SubscribeComplete(SubscribeCompleteDetail),
+
+    /// Signals that a chat was closed and no further events will be delivered.
+    ///
+    /// This variant is sent to every subscriber before the chat is torn down.
+    #[serde(rename = "chatClosed")]
+    ChatClosed(ChatClosedDetail),
+
+    /// Reports the sending allowance the connection has left.
+    ///
+    /// This variant is sent in reply to a quota request.
+    #[serde(rename = "quotaStatus")]
+    QuotaStatus(QuotaStatus),
+
+    /// Reports the head nonce of each requested chat.
+    ///
+    /// This variant is sent in reply to a head nonces request.
+    #[serde(rename = "headNonces")]
+    HeadNonces(HeadNoncesDetail),
+
+    /// Represents an error that is not tied to a specific operation status.
+    ///
+    /// This variant is used to tell the client why its input was rejected.
+    #[serde(rename = "error")]
+    Error(ErrorResponse),
 }
 
 /// Details for a new event notification.
 ///
 /// Contains information about the type of event and the message content.
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct NewEventDetail {
     /// The type of the event.
     ///
@@ -46,7 +104,7 @@ pub struct NewEventDetail {
 /// Details for a wait event notification.
 ///
 /// Contains information about the type of event and the chat ID to wait on.
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct WaitEventDetail {
     /// The type of the wait event.
     ///
@@ -61,15 +119,211 @@ pub struct WaitEventDetail {
     pub chat_id: String,
 }
 
+/// Details for a send acknowledgement.
+///
+/// Contains the chat and nonce of the accepted message.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SendAckDetail {
+    /// The chat ID the message was sent to.
+    ///
+    /// This field is renamed to "queueId" in the serialized JSON.
+    #[serde(rename = "queueId")]
+    pub chat_id: String,
+
+    /// The nonce of the accepted message.
+    pub nonce: usize,
+}
+
+/// Details for an up-to-date notification.
+///
+/// Contains the chat that was replayed and the highest nonce stored for it.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct UpToDateDetail {
+    /// The chat ID the notification refers to.
+    ///
+    /// This field is renamed to "queueId" in the serialized JSON.
+    #[serde(rename = "queueId")]
+    pub chat_id: String,
+
+    /// The highest nonce stored for the chat, 0 if the chat is empty.
+    #[serde(rename = "headNonce")]
+    pub head_nonce: usize,
+}
+
+/// Details for a sync error notification.
+///
+/// Contains the chat whose replay stopped and the last nonce delivered before it did.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SyncErrorDetail {
+    /// The chat ID the notification refers to.
+    ///
+    /// This field is renamed to "queueId" in the serialized JSON.
+    #[serde(rename = "queueId")]
+    pub chat_id: String,
+
+    /// The nonce of the last message delivered by the replay.
+    ///
+    /// One below the requested nonce if nothing was delivered.
+    #[serde(rename = "lastDeliveredNonce")]
+    pub last_delivered_nonce: usize,
+}
+
+/// Details for a subscribe completion notification.
+///
+/// Contains the chat whose subscription setup has finished.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SubscribeCompleteDetail {
+    /// The chat ID the subscription was made for.
+    ///
+    /// This field is renamed to "queueId" in the serialized JSON.
+    #[serde(rename = "queueId")]
+    pub chat_id: String,
+
+    /// Signed token restoring every subscription of the connection on a later resume.
+    ///
+    /// Only issued when the server has a subscription secret. The token expires
+    /// after the resume TTL and is revoked once its session logs out.
+    #[serde(
+        rename = "resumeToken",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub resume_token: Option<String>,
+}
+
+/// Details for a chat closed notification.
+///
+/// Contains the chat that was closed.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ChatClosedDetail {
+    /// The chat ID that was closed.
+    ///
+    /// This field is renamed to "queueId" in the serialized JSON.
+    #[serde(rename = "queueId")]
+    pub chat_id: String,
+}
+
+/// Sending allowance left to a connection in the current window.
+///
+/// An unlimited allowance is reported as the largest 64-bit number.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaStatus {
+    /// Number of messages that may still be sent.
+    #[serde(rename = "messagesRemaining")]
+    pub messages_remaining: u64,
+
+    /// Number of content bytes that may still be sent.
+    #[serde(rename = "bytesRemaining")]
+    pub bytes_remaining: u64,
+
+    /// Milliseconds until the allowance is refilled.
+    #[serde(rename = "resetInMs")]
+    pub reset_in_ms: u64,
+}
+
+/// Details for a head nonces response.
+///
+/// Contains the highest stored nonce of each requested chat.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct HeadNoncesDetail {
+    /// Head nonce per chat ID, 0 for chats without messages.
+    pub heads: BTreeMap<String, usize>,
+}
+
 /// Response containing operation status.
 ///
 /// A simple response that indicates whether an operation succeeded or failed.
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct StatusResponse {
     /// The status of the operation.
     ///
     /// true indicates success, false indicates failure.
     pub status: bool,
+
+    /// The reason for a failure, null on success.
+    pub code: Option<SeedErrorCode>,
+}
+
+impl StatusResponse {
+    /// Creates the status of an operation that succeeded or failed with `code`.
+    pub fn new(outcome: Result<(), SeedErrorCode>) -> Self {
+        Self {
+            status: outcome.is_ok(),
+            code: outcome.err(),
+        }
+    }
+}
+
+/// Machine-readable error codes sent to clients.
+///
+/// Serialized in snake_case, e.g. `db_timeout`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SeedErrorCode {
+    /// A database query took longer than the configured statement timeout.
+    DbTimeout,
+
+    /// No database connection became free in time, the request may be retried later.
+    Busy,
+
+    /// A message field is not valid base64.
+    InvalidEncoding,
+
+    /// A decoded message field does not have its expected length.
+    InvalidLength,
+
+    /// The decoded chat id does not have the configured length.
+    InvalidChatId,
+
+    /// A decoded message field exceeds the configured size limit.
+    MessageTooLarge,
+
+    /// The message signature does not verify against the chat's public key.
+    InvalidSignature,
+
+    /// The connection reached its maximum lifetime and must reconnect.
+    ReauthRequired,
+
+    /// The connection used up its sending allowance for the current window.
+    RateLimited,
+
+    /// The connection sent to a chat it is not subscribed to.
+    NotSubscribed,
+
+    /// The request contradicts itself, e.g. its inner type does not match its tag.
+    BadRequest,
+
+    /// The message type is not one the server knows.
+    UnknownType,
+
+    /// The message nonce does not follow the last stored nonce of its chat.
+    InvalidNonce,
+
+    /// The chat has reached its subscriber limit.
+    ChatFull,
+
+    /// The connection has reached its subscription limit.
+    TooManySubscriptions,
+
+    /// The chat's message queue is full and the message was not accepted.
+    QueueFull,
+
+    /// The connection is not allowed to act under the identity it asked for.
+    Unauthorized,
+
+    /// The server failed to carry out the request.
+    Internal,
+}
+
+/// Response describing why the client's input was rejected.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ErrorResponse {
+    /// The reason for the rejection.
+    pub code: SeedErrorCode,
+
+    /// The allowance left, attached to rate limit errors.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<QuotaStatus>,
 }
 
 #[cfg(test)]
@@ -82,9 +336,79 @@ mod tests {
     /// Verifies that the JSON serialization produces the expected format.
     #[test]
     fn test_status_serialization() {
-        let response = SeedResponse::Status(StatusResponse { status: true });
+        let response = SeedResponse::Status(StatusResponse::new(Ok(())));
         let serialized = serde_json::to_string(&response).unwrap();
-        let expected = r#"{"type":"response","response":{"status":true}}"#;
+        let expected = r#"{"type":"response","response":{"status":true,"code":null}}"#;
         assert_eq!(serialized, expected);
     }
+
+    /// Test that a failed StatusResponse carries its code in snake_case.
+    #[test]
+    fn test_failed_status_serialization() {
+        for (code, expected) in [
+            (
+                SeedErrorCode::InvalidNonce,
+                r#"{"type":"response","response":{"status":false,"code":"invalid_nonce"}}"#,
+            ),
+            (
+                SeedErrorCode::QueueFull,
+                r#"{"type":"response","response":{"status":false,"code":"queue_full"}}"#,
+            ),
+        ] {
+            let response = SeedResponse::Status(StatusResponse::new(Err(code)));
+            assert_eq!(serde_json::to_string(&response).unwrap(), expected);
+        }
+    }
+
+    /// Test that SendAck carries the chat and nonce of the message and round-trips.
+    #[test]
+    fn test_send_ack_serialization() {
+        let response = SeedResponse::SendAck(SendAckDetail {
+            chat_id: "chat".to_string(),
+            nonce: 7,
+        });
+        let serialized = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            serialized,
+            r#"{"type":"sendAck","response":{"queueId":"chat","nonce":7}}"#
+        );
+        let value: serde_json::Value = serde_json::from_str(&serialized).unwrap();
+        let detail: SendAckDetail = serde_json::from_value(value["response"].clone()).unwrap();
+        assert_eq!(detail.chat_id, "chat");
+        assert_eq!(detail.nonce, 7);
+    }
+
+    /// Test that Pong serializes to a bare type.
+    #[test]
+    fn test_pong_serialization() {
+        let serialized = serde_json::to_string(&SeedResponse::Pong).unwrap();
+        assert_eq!(serialized, r#"{"type":"pong"}"#);
+    }
+
+    /// Test that wait and new events are told apart by their top-level type.
+    #[test]
+    fn test_event_serialization() {
+        let wait = SeedResponse::WaitEvent(WaitEventDetail {
+            rtype: "wait".to_string(),
+            chat_id: "chat".to_string(),
+        });
+        let new = SeedResponse::NewEvent(NewEventDetail {
+            rtype: "new".to_string(),
+            message: OutcomeMessage {
+                nonce: 1,
+                chat_id: "chat".to_string(),
+                ..Default::default()
+            },
+        });
+
+        let wait = serde_json::to_string(&wait).unwrap();
+        assert_eq!(
+            wait,
+            r#"{"type":"wait","response":{"type":"wait","queueId":"chat"}}"#
+        );
+        let new = serde_json::to_string(&new).unwrap();
+        assert!(
+            new.starts_with(r#"{"type":"event","response":{"type":"new","message":{"nonce":1,"#)
+        );
+    }
 }
@@ -1,25 +1,52 @@
 use std::{
+    collections::BTreeMap,
     hash::{Hash, Hasher},
-    sync::Arc,
+    pin::Pin,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
-use dashmap::{DashMap, DashSet};
-use futures::lock::Mutex;
+use dashmap::{DashMap, DashSet, mapref::entry::Entry};
+use futures::{Sink, SinkExt, Stream, StreamExt, lock::Mutex};
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
-use tokio_tungstenite::WebSocketStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{Semaphore, SemaphorePermit},
+};
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{
+        Error as WsError, Message,
+        protocol::{CloseFrame, frame::coding::CloseCode},
+    },
+};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
 use uuid::Uuid;
 
-use super::message::IncomeMessage;
+use crate::clock::{SharedClock, SystemClock};
 
+use super::{
+    compat::ProtocolVersion,
+    message::IncomeMessage,
+    quota::{QuotaLimits, SendQuota},
+    response::QuotaStatus,
+};
+
+/// A request to subscribe to a chat queue.
 ///
 /// This structure represents the JSON payload sent by clients
 /// when they want to subscribe to messages from a specific chat queue.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct SubscriptionRequest {
     /// The type of the request, typically "subscribe"
-    #[serde(rename = "type")]
+    ///
+    /// Defaults to "subscribe" when omitted.
+    #[serde(rename = "type", default = "SubscriptionRequest::default_rtype")]
     pub rtype: String,
 
     /// The unique identifier of the chat queue to subscribe to
@@ -28,6 +55,53 @@ pub struct SubscriptionRequest {
 
     /// A client-provided identifier to correlate requests with responses
     pub nonce: usize,
+
+    /// Number of most recent messages to deliver before the unread replay, if any
+    #[serde(
+        rename = "recentContext",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub recent_context: Option<usize>,
+
+    /// Number of history messages fetched per batch, capped by the server (default: 100)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+
+    /// Base64 encoded HMAC-SHA256 of the queue ID, required when the server has a secret
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl SubscriptionRequest {
+    /// Request type expected for subscription requests
+    pub const RTYPE: &str = "subscribe";
+
+    /// Returns the default request type
+    fn default_rtype() -> String {
+        Self::RTYPE.to_string()
+    }
+}
+
+/// A request to restore the subscriptions of a previous connection.
+///
+/// Binds the connection to a client-stable identity; subscriptions saved
+/// under that identity are re-established with their cursors. A resume
+/// token restores the subscriptions it was issued for instead. When the
+/// server requires subscribe tokens, only a resume token restores anything.
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct ResumeRequest {
+    /// Identity chosen by the client, stable across reconnects
+    #[serde(rename = "clientId", default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+
+    /// Token from the last subscribe complete notification of a previous connection
+    #[serde(
+        rename = "resumeToken",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub resume_token: Option<String>,
 }
 
 /// A message received from a connected WebSocket client.
@@ -41,17 +115,70 @@ pub struct ConnectedMessage {
     pub message: IncomeMessage,
 }
 
+/// Process-wide unique number of a connection.
+///
+/// Keys the connection maps of [`WebSocketManager`], as it is cheaper to hash
+/// and copy than an `Arc<WebSocketConnection>`. Unlike the connection's UUID
+/// it never repeats within the process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnId(u64);
+
+impl ConnId {
+    /// Returns the next unused connection number
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Live state of a single chat, as reported to operators.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChatStat {
+    /// The chat ID as sent by clients
+    pub chat_id: String,
+
+    /// Number of connections subscribed to the chat
+    pub subscriber_count: usize,
+
+    /// Number of messages waiting in the chat's queue
+    pub queued_messages: usize,
+}
+
+/// Summary of the server's state, as reported on the status endpoint.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServerStatus {
+    /// Unix time in seconds at which the server started
+    pub started_at: u64,
+
+    /// Seconds since the server started
+    pub uptime_seconds: u64,
+
+    /// Number of WebSocket connections accepted since the server started
+    pub total_connections: usize,
+
+    /// Number of connections currently subscribed to at least one chat
+    pub active_connections: usize,
+}
+
 /// Manages WebSocket connections and message routing between clients and chat queues.
 ///
 /// This central manager keeps track of all active connections and their subscriptions,
 /// enabling efficient message distribution to the appropriate subscribers.
+/// Subscriptions are keyed by [`ConnId`], the connections themselves are
+/// looked up in a side table.
 #[derive(Clone, Default)]
 pub struct WebSocketManager {
     /// Maps each connection to the set of chat IDs it is subscribed to
-    pub connections: DashMap<Arc<WebSocketConnection>, DashSet<String>>,
+    pub connections: DashMap<ConnId, DashSet<String>>,
 
     /// Maps each chat ID to the set of connections subscribed to it
-    pub chats: DashMap<String, DashSet<Arc<WebSocketConnection>>>,
+    pub chats: DashMap<String, DashSet<ConnId>>,
+
+    /// Connections holding at least one subscription
+    pub handles: DashMap<ConnId, Arc<WebSocketConnection>>,
+
+    /// Maps the UUID of each connection in `handles` to its number
+    pub ids: DashMap<Uuid, ConnId>,
 
     /// Message queues for each chat, containing sender and receiver channels for message distribution
     pub message_queues: DashMap<
@@ -64,68 +191,789 @@ pub struct WebSocketManager {
 }
 
 impl WebSocketManager {
-    /// Creates a new empty WebSocketManager instance.
-    ///
-    /// Initializes the connection tracking maps and message queues with no entries.
-    pub fn new(
-        connections: DashMap<Arc<WebSocketConnection>, DashSet<String>>,
-        chats: DashMap<String, DashSet<Arc<WebSocketConnection>>>,
-        message_queues: DashMap<
-            String,
-            (
-                flume::Sender<ConnectedMessage>,
-                flume::Receiver<ConnectedMessage>,
-            ),
-        >,
-    ) -> Self {
-        Self {
-            connections,
-            chats,
-            message_queues,
+    /// Gives a connection a fresh id while its id is used by a registered connection.
+    ///
+    /// Other components identify connections by id, so a clash would make
+    /// control events, resumes and broadcast reports ambiguous.
+    ///
+    /// # Returns
+    ///
+    /// Whether the id had to be regenerated
+    pub fn ensure_unique_id(&self, connection: &mut WebSocketConnection) -> bool {
+        let mut regenerated = false;
+        while self.ids.contains_key(&connection.id) {
+            connection.id = Uuid::new_v4();
+            regenerated = true;
+        }
+        regenerated
+    }
+
+    /// Returns whether a different connection is registered under the connection's id.
+    pub fn id_clashes(&self, connection: &WebSocketConnection) -> bool {
+        self.ids
+            .get(&connection.id)
+            .is_some_and(|conn_id| *conn_id != connection.conn_id())
+    }
+
+    /// Subscribes a connection to a chat unless the chat is full.
+    ///
+    /// A connection already subscribed to the chat is not counted against the limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The connection to subscribe
+    /// * `chat_id` - The chat to subscribe to
+    /// * `limit` - Maximum number of subscribers of the chat
+    ///
+    /// # Returns
+    ///
+    /// Whether the connection is subscribed
+    pub fn add_subscription(
+        &self,
+        connection: &Arc<WebSocketConnection>,
+        chat_id: &str,
+        limit: usize,
+    ) -> bool {
+        let conn_id = connection.conn_id();
+        {
+            let subscribers = self.chats.entry(chat_id.to_string()).or_default();
+            if !subscribers.contains(&conn_id) && subscribers.len() >= limit {
+                return false;
+            }
+            // Make the connection resolvable before a broadcast can see the subscription
+            self.handles
+                .entry(conn_id)
+                .or_insert_with(|| connection.clone());
+            self.ids.insert(connection.id, conn_id);
+            subscribers.insert(conn_id);
+        }
+        self.connections
+            .entry(conn_id)
+            .or_default()
+            .insert(chat_id.to_string());
+
+        true
+    }
+
+    /// Unsubscribes a connection from a chat.
+    ///
+    /// Chats left without subscribers and connections left without
+    /// subscriptions are removed.
+    pub fn remove_subscription(&self, connection: &WebSocketConnection, chat_id: &str) {
+        let conn_id = connection.conn_id();
+
+        // Removing while holding an entry guard would deadlock on the map shard
+        if let Some(chats) = self.connections.get(&conn_id) {
+            chats.remove(chat_id);
+        }
+        if self
+            .connections
+            .remove_if(&conn_id, |_, chats| chats.is_empty())
+            .is_some()
+        {
+            self.forget(connection);
+        }
+
+        if let Some(subscribers) = self.chats.get(chat_id) {
+            subscribers.remove(&conn_id);
+        }
+        self.chats
+            .remove_if(chat_id, |_, subscribers| subscribers.is_empty());
+    }
+
+    /// Sends a close frame to every connection holding a subscription.
+    ///
+    /// The connections are marked closed first so replays and broadcasts to
+    /// them stop, then the frames are sent concurrently. The sessions finish
+    /// their cleanup once the clients answer the close.
+    ///
+    /// # Returns
+    ///
+    /// The number of connections a close frame was sent to
+    pub async fn close_all(&self) -> usize {
+        let connections: Vec<_> = self
+            .handles
+            .iter()
+            .map(|handle| handle.value().clone())
+            .collect();
+
+        let closes = connections.iter().map(|connection| async move {
+            connection.mark_closed();
+            let frame = CloseFrame {
+                code: CloseCode::Away,
+                reason: DisconnectReason::Shutdown.as_str().into(),
+            };
+            connection
+                .session
+                .lock()
+                .await
+                .send(Message::Close(Some(frame)))
+                .await
+                .is_ok()
+        });
+
+        futures::future::join_all(closes)
+            .await
+            .into_iter()
+            .filter(|sent| *sent)
+            .count()
+    }
+
+    /// Removes a connection and its subscriptions from every chat.
+    pub fn remove_connection(&self, connection: &WebSocketConnection) {
+        for chat_id in self.subscriptions(connection) {
+            self.remove_subscription(connection, &chat_id);
+        }
+        self.connections.remove(&connection.conn_id());
+        self.forget(connection);
+    }
+
+    /// Drops a connection from the side tables
+    fn forget(&self, connection: &WebSocketConnection) {
+        let conn_id = connection.conn_id();
+        self.handles.remove(&conn_id);
+        self.ids.remove_if(&connection.id, |_, id| *id == conn_id);
+    }
+
+    /// Returns the connections subscribed to a chat, or `None` if the chat has no entry.
+    pub fn subscribers(&self, chat_id: &str) -> Option<Vec<Arc<WebSocketConnection>>> {
+        let subscribers = self.chats.get(chat_id)?;
+        Some(
+            subscribers
+                .iter()
+                .filter_map(|conn_id| self.handles.get(&*conn_id).map(|handle| handle.clone()))
+                .collect(),
+        )
+    }
+
+    /// Returns the chats a connection is subscribed to.
+    pub fn subscriptions(&self, connection: &WebSocketConnection) -> Vec<String> {
+        self.connections
+            .get(&connection.conn_id())
+            .map(|chats| chats.iter().map(|chat_id| chat_id.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of connections holding at least one subscription.
+    ///
+    /// Reads the map length, which only locks each shard briefly instead of
+    /// walking the entries.
+    pub fn active_connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Returns the subscriber count and queue length of every live chat, ordered by chat id.
+    ///
+    /// A chat is live if it has subscribers or a message queue. Each map is
+    /// walked on its own, so no shard lock is held while the other is read.
+    pub fn snapshot(&self) -> Vec<ChatStat> {
+        let mut stats: BTreeMap<String, ChatStat> = self
+            .chats
+            .iter()
+            .map(|entry| {
+                let stat = ChatStat {
+                    chat_id: entry.key().clone(),
+                    subscriber_count: entry.value().len(),
+                    queued_messages: 0,
+                };
+                (stat.chat_id.clone(), stat)
+            })
+            .collect();
+        for entry in self.message_queues.iter() {
+            let queued = entry.value().0.len();
+            stats
+                .entry(entry.key().clone())
+                .or_insert_with(|| ChatStat {
+                    chat_id: entry.key().clone(),
+                    subscriber_count: 0,
+                    queued_messages: 0,
+                })
+                .queued_messages = queued;
+        }
+        stats.into_values().collect()
+    }
+
+    /// Removes the message queue of a chat that has no subscribers left.
+    ///
+    /// Dropping the queue lets its processor persist the buffered messages
+    /// and stop. Returns whether a queue was removed.
+    pub fn release_idle_queue(&self, chat_id: &str) -> bool {
+        self.message_queues
+            .remove_if(chat_id, |_, _| !self.chats.contains_key(chat_id))
+            .is_some()
+    }
+
+    /// Returns the number of chats a connection is subscribed to.
+    pub fn subscription_count(&self, connection: &WebSocketConnection) -> usize {
+        self.connections
+            .get(&connection.conn_id())
+            .map_or(0, |chats| chats.len())
+    }
+
+    /// Returns whether a connection is subscribed to a chat.
+    pub fn is_subscribed(&self, connection: &WebSocketConnection, chat_id: &str) -> bool {
+        self.connections
+            .get(&connection.conn_id())
+            .is_some_and(|chats| chats.contains(chat_id))
+    }
+
+    /// Returns the number of chats with at least one subscriber.
+    pub fn active_chats(&self) -> usize {
+        self.chats.len()
+    }
+
+    /// Removes subscriptions held by connections whose sessions are closed.
+    ///
+    /// Guards against stale entries left behind when disconnect cleanup races
+    /// with a concurrent subscribe. Chats left without subscribers are removed.
+    ///
+    /// # Returns
+    ///
+    /// The number of stale subscriptions that were removed
+    pub fn reconcile_subscribers(&self) -> usize {
+        let mut removed = 0;
+
+        for subscribers in self.chats.iter() {
+            let before = subscribers.len();
+            subscribers.retain(|conn_id| {
+                self.handles
+                    .get(conn_id)
+                    .is_some_and(|connection| !connection.is_closed())
+            });
+            removed += before - subscribers.len();
+        }
+
+        self.chats.retain(|_, subscribers| !subscribers.is_empty());
+        let closed: Vec<_> = self
+            .handles
+            .iter()
+            .filter(|connection| connection.is_closed())
+            .map(|connection| connection.clone())
+            .collect();
+        for connection in closed {
+            self.connections.remove(&connection.conn_id());
+            self.forget(&connection);
+        }
+
+        removed
+    }
+}
+
+/// Reason the server ended a WebSocket connection while processing a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    /// The message failed validation
+    InvalidMessage,
+
+    /// The message nonce did not follow the chat's last nonce
+    InvalidNonce,
+
+    /// The chat id could not be decoded
+    InvalidChatId,
+
+    /// The message could not be persisted
+    StorageFailure,
+
+    /// The connection outlived its maximum lifetime and must reconnect
+    ReauthRequired,
+
+    /// No frame arrived within the idle timeout
+    IdleTimeout,
+
+    /// The client logged out
+    Logout,
+
+    /// The server is shutting down
+    Shutdown,
+}
+
+impl DisconnectReason {
+    /// Returns the reason as a snake_case label, e.g. for metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::InvalidMessage => "invalid_message",
+            Self::InvalidNonce => "invalid_nonce",
+            Self::InvalidChatId => "invalid_chat_id",
+            Self::StorageFailure => "storage_failure",
+            Self::ReauthRequired => "reauth_required",
+            Self::IdleTimeout => "idle_timeout",
+            Self::Logout => "logout",
+            Self::Shutdown => "shutdown",
         }
     }
 }
 
+/// Reason sent to the client in the close frame when the server ends a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SeedCloseReason {
+    /// The session ended normally (1000)
+    Normal,
+
+    /// The client broke a rule of the protocol (1008)
+    PolicyViolation,
+
+    /// The server failed to serve the session (1011)
+    ServerError,
+
+    /// The client is not allowed to use the session (4001)
+    Unauthorized,
+}
+
+impl SeedCloseReason {
+    /// Returns the WebSocket close code of the reason
+    pub fn code(&self) -> CloseCode {
+        match self {
+            Self::Normal => CloseCode::Normal,
+            Self::PolicyViolation => CloseCode::Policy,
+            Self::ServerError => CloseCode::Error,
+            Self::Unauthorized => CloseCode::Library(4001),
+        }
+    }
+
+    /// Returns the reason as a snake_case label, sent as the close frame reason
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::PolicyViolation => "policy_violation",
+            Self::ServerError => "server_error",
+            Self::Unauthorized => "unauthorized",
+        }
+    }
+}
+
+impl From<SeedCloseReason> for CloseFrame {
+    fn from(reason: SeedCloseReason) -> Self {
+        CloseFrame {
+            code: reason.code(),
+            reason: reason.as_str().into(),
+        }
+    }
+}
+
+/// Subscription change recorded as a control event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ControlAction {
+    /// The connection subscribed to the chat
+    Subscribe,
+
+    /// The connection stopped receiving events of the chat
+    Unsubscribe,
+}
+
+impl ControlAction {
+    /// Returns the action as a lowercase label, as stored in the control event table
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Subscribe => "subscribe",
+            Self::Unsubscribe => "unsubscribe",
+        }
+    }
+}
+
+/// Outcome of broadcasting a single event to every subscriber of a chat.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BroadcastReport {
+    /// Number of subscribers the event was delivered to
+    pub delivered: usize,
+
+    /// Identifiers of the connections the event could not be delivered to,
+    /// up to the configured number of reported failures
+    pub failed: Vec<Uuid>,
+
+    /// Number of further failed connections that are not listed in `failed`
+    pub failed_overflow: usize,
+
+    /// Identifiers of the connections that were disconnected because a send
+    /// timed out, these are counted as failed too
+    pub timed_out: Vec<Uuid>,
+}
+
+impl BroadcastReport {
+    /// Returns the number of connections the event could not be delivered to
+    pub fn failed_count(&self) -> usize {
+        self.failed.len() + self.failed_overflow
+    }
+}
+
+/// Sending half of a WebSocket session
+pub type SessionSink = Pin<Box<dyn Sink<Message, Error = WsError> + Send>>;
+
+/// Receiving half of a WebSocket session
+pub type SessionStream = Pin<Box<dyn Stream<Item = Result<Message, WsError>> + Send>>;
+
 /// Represents a WebSocket connection to a client.
 ///
-/// Wraps both the WebSocket session for sending messages and a unique identifier
+/// Wraps both halves of the WebSocket session and a unique identifier
 /// to track this specific connection throughout the system.
 pub struct WebSocketConnection {
     /// Unique identifier for this connection
     pub id: Uuid,
 
-    /// The WebSocket session wrapped in Arc<Mutex<>> for thread-safe access
-    pub session: Mutex<WebSocketStream<TcpStream>>,
+    /// Number keying the connection in the [`WebSocketManager`]
+    conn_id: ConnId,
+
+    /// The sending half of the WebSocket session wrapped in Mutex<> for thread-safe access
+    pub session: Mutex<SessionSink>,
+
+    /// The receiving half of the WebSocket session
+    pub stream: Mutex<SessionStream>,
+
+    /// Cancelled once the session starts closing, stopping pending sends
+    closing: CancellationToken,
+
+    /// When the connection was established
+    connected_at: Instant,
+
+    /// Identity the client resumed under, if any
+    client_id: OnceLock<String>,
+
+    /// Resume session the connection's resume tokens are issued in
+    resume_session: OnceLock<Uuid>,
+
+    /// Next nonce to replay for each subscribed chat
+    cursors: DashMap<String, usize>,
+
+    /// Sending allowance, created with the limits of the first quota check
+    quota: OnceLock<std::sync::Mutex<SendQuota>>,
+
+    /// Wire format version whose tag set responses are serialized with
+    protocol_version: ProtocolVersion,
+
+    /// When a wait event was last sent for each chat
+    wait_events: DashMap<String, Instant>,
+
+    /// Source of the current time for the lifetime, quota and debounce checks
+    clock: SharedClock,
+
+    /// Serialized payload bytes sent to the client
+    bytes_sent: AtomicU64,
+
+    /// Serialized payload bytes sent to the client for each chat
+    chat_bytes_sent: DashMap<String, u64>,
+
+    /// Slots for history frames on their way to the client and their total,
+    /// created with the limit of the first reservation
+    history_slots: OnceLock<(Semaphore, usize)>,
 }
 
 impl WebSocketConnection {
-    /// Constructs a new WebSocketConnection from an HTTP request and payload.
+    /// Constructs a new WebSocketConnection from an accepted WebSocket stream.
+    ///
+    /// The stream is split so that responses can be sent while the
+    /// connection's incoming messages are being read.
+    ///
+    /// # Arguments
+    ///
+    /// * `connection` - The WebSocket stream returned by the handshake
+    pub fn new<S>(connection: WebSocketStream<S>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sink, stream) = connection.split();
+
+        Self::from_parts(Box::pin(sink), Box::pin(stream))
+    }
+
+    /// Constructs a new WebSocketConnection from separate session halves.
+    ///
+    /// # Arguments
+    ///
+    /// * `sink` - The half used to send messages to the client
+    /// * `stream` - The half used to receive messages from the client
+    pub fn from_parts(sink: SessionSink, stream: SessionStream) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            conn_id: ConnId::next(),
+            session: Mutex::new(sink),
+            stream: Mutex::new(stream),
+            closing: CancellationToken::new(),
+            connected_at: Instant::now(),
+            client_id: OnceLock::new(),
+            resume_session: OnceLock::new(),
+            cursors: DashMap::new(),
+            quota: OnceLock::new(),
+            protocol_version: ProtocolVersion::default(),
+            wait_events: DashMap::new(),
+            clock: SystemClock::shared(),
+            bytes_sent: AtomicU64::new(0),
+            chat_bytes_sent: DashMap::new(),
+            history_slots: OnceLock::new(),
+        }
+    }
+
+    /// Returns the number keying the connection in the [`WebSocketManager`]
+    pub fn conn_id(&self) -> ConnId {
+        self.conn_id
+    }
+
+    /// Sets the wire format version responses are serialized with.
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - The version the client asked for during the handshake
+    pub fn with_protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    /// Sets the clock the connection reads the current time from.
+    ///
+    /// The connection counts as established at the clock's current time.
+    ///
+    /// # Arguments
+    ///
+    /// * `clock` - The clock to use instead of the system clock
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.connected_at = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Returns the current time of the connection's clock
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Returns the wire format version responses are serialized with
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Returns the sending allowance of the connection, creating it with `limits` on first use
+    fn quota(&self, limits: QuotaLimits) -> &std::sync::Mutex<SendQuota> {
+        self.quota
+            .get_or_init(|| std::sync::Mutex::new(SendQuota::new(limits, self.clock.now())))
+    }
+
+    /// Returns the sending allowance left in the current window
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - Allowance per window, used if this is the first quota check
+    pub fn quota_status(&self, limits: QuotaLimits) -> QuotaStatus {
+        let mut quota = self.quota(limits).lock().unwrap_or_else(|e| e.into_inner());
+        quota.status(self.clock.now())
+    }
+
+    /// Checks whether a message of `bytes` content bytes fits the sending allowance
+    ///
+    /// Nothing is taken from the allowance, see [`WebSocketConnection::consume_quota`].
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - Allowance per window, used if this is the first quota check
+    /// * `bytes` - Size of the message content
+    ///
+    /// # Returns
+    ///
+    /// The allowance left, as an error if the message exceeds it
+    pub fn check_quota(&self, limits: QuotaLimits, bytes: u64) -> Result<QuotaStatus, QuotaStatus> {
+        let mut quota = self.quota(limits).lock().unwrap_or_else(|e| e.into_inner());
+        quota.check(bytes, self.clock.now())
+    }
+
+    /// Accounts for a message of `bytes` content bytes against the sending allowance
+    ///
+    /// # Arguments
+    ///
+    /// * `limits` - Allowance per window, used if this is the first quota check
+    /// * `bytes` - Size of the message content
+    ///
+    /// # Returns
+    ///
+    /// The allowance left afterwards, or the allowance left if the message exceeds it
+    pub fn consume_quota(
+        &self,
+        limits: QuotaLimits,
+        bytes: u64,
+    ) -> Result<QuotaStatus, QuotaStatus> {
+        let mut quota = self.quota(limits).lock().unwrap_or_else(|e| e.into_inner());
+        quota.try_consume(bytes, self.clock.now())
+    }
+
+    /// Claims the next wait event for a chat unless one was sent within `window`
+    ///
+    /// # Arguments
+    ///
+    /// * `chat_id` - The chat the wait event is for
+    /// * `window` - Time in which repeated wait events are coalesced
+    ///
+    /// # Returns
     ///
-    /// This method handles the WebSocket handshake and returns the HTTP response
-    /// to complete the handshake, the WebSocketConnection object for sending messages,
-    /// and the MessageStream for receiving messages.
+    /// Whether the wait event should be sent
+    pub fn claim_wait_event(&self, chat_id: &str, window: Duration) -> bool {
+        let now = self.clock.now();
+        match self.wait_events.entry(chat_id.to_string()) {
+            Entry::Occupied(entry) if now.duration_since(*entry.get()) < window => false,
+            Entry::Occupied(mut entry) => {
+                entry.insert(now);
+                true
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+
+    /// Accounts for a response sent to the client
     ///
     /// # Arguments
     ///
-    /// * `connection` - The WebSocket connection object
+    /// * `chat_id` - The chat the response belongs to, if any
+    /// * `bytes` - Size of the serialized payload
+    pub fn record_sent(&self, chat_id: Option<&str>, bytes: usize) {
+        let bytes = bytes as u64;
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(chat_id) = chat_id {
+            *self.chat_bytes_sent.entry(chat_id.to_string()).or_default() += bytes;
+        }
+    }
+
+    /// Returns the serialized payload bytes sent to the client
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Returns the serialized payload bytes sent for each chat, ordered by chat id
+    pub fn chat_bytes_sent(&self) -> Vec<(String, u64)> {
+        let mut sent: Vec<_> = self
+            .chat_bytes_sent
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        sent.sort();
+        sent
+    }
+
+    /// Binds the connection to a client identity
     ///
     /// # Returns
+    /// `false` if the connection is already bound to a different identity
+    pub fn set_client_id(&self, client_id: String) -> bool {
+        let bound = self.client_id.get_or_init(|| client_id.clone());
+        *bound == client_id
+    }
+
+    /// Returns the identity the client resumed under, if any
+    pub fn client_id(&self) -> Option<&str> {
+        self.client_id.get().map(String::as_str)
+    }
+
+    /// Continues the resume session of a token the client resumed with
+    ///
+    /// Like the client identity, the session is bound once.
+    ///
+    /// # Returns
+    /// `false` if the connection already belongs to another session
+    pub fn join_resume_session(&self, session: Uuid) -> bool {
+        *self.resume_session.get_or_init(|| session) == session
+    }
+
+    /// Returns the resume session of the connection
+    ///
+    /// A connection that did not resume with a token starts its own session,
+    /// named after its ID.
+    pub fn resume_session(&self) -> Uuid {
+        *self.resume_session.get_or_init(|| self.id)
+    }
+
+    /// Starts tracking a subscribed chat, replaying from `nonce` on resume
+    pub fn track_subscription(&self, chat_id: &str, nonce: usize) {
+        self.cursors.insert(chat_id.to_string(), nonce);
+    }
+
+    /// Stops tracking a chat the connection unsubscribed from
+    pub fn forget_subscription(&self, chat_id: &str) {
+        self.cursors.remove(chat_id);
+    }
+
+    /// Stops tracking every chat, leaving nothing to resume
+    pub fn forget_subscriptions(&self) {
+        self.cursors.clear();
+    }
+
+    /// Advances the cursor of a subscribed chat past a delivered message
+    pub fn record_delivery(&self, chat_id: &str, nonce: usize) {
+        if let Some(mut cursor) = self.cursors.get_mut(chat_id) {
+            *cursor = (*cursor).max(nonce + 1);
+        }
+    }
+
+    /// Returns the subscribed chats and their cursors, ordered by chat id
+    pub fn subscription_snapshot(&self) -> Vec<(String, usize)> {
+        let mut snapshot: Vec<_> = self
+            .cursors
+            .iter()
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        snapshot.sort();
+        snapshot
+    }
+
+    /// Returns when the connection was established
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+    /// Returns how long the connection has been open
+    pub fn age(&self) -> Duration {
+        self.clock
+            .now()
+            .saturating_duration_since(self.connected_at)
+    }
+
+    /// Returns how long the connection may stay open given a maximum lifetime
+    pub fn remaining_lifetime(&self, lifetime: Duration) -> Duration {
+        lifetime.saturating_sub(self.age())
+    }
+
+    /// Returns whether the session has been closed
+    pub fn is_closed(&self) -> bool {
+        self.closing.is_cancelled()
+    }
+
+    /// Waits for room to buffer another history frame for the client
     ///
-    /// A tuple containing:
-    /// * The HTTP response to send back to the client
-    /// * The WebSocketConnection for tracking and sending messages
-    /// * The MessageStream for receiving messages from this connection
+    /// The frame counts as buffered until the returned permit is dropped, so
+    /// replays of all chats share the limit. Only the limit of the first
+    /// reservation is used.
     ///
-    /// # Errors
+    /// # Arguments
+    ///
+    /// * `limit` - Maximum number of history frames buffered at the same time
     ///
-    /// Returns an actix_web::Error if the WebSocket handshake fails
-    pub fn new(
-        connection: WebSocketStream<TcpStream>,
-    ) -> Self {
-        let uuid = uuid::Uuid::new_v4();
-        let session = Mutex::new(connection);
-        
-        Self { id: uuid, session }
+    /// # Returns
+    ///
+    /// The permit, or `None` once the session has been marked closed
+    pub async fn reserve_history_frame(&self, limit: usize) -> Option<SemaphorePermit<'_>> {
+        let (slots, _) = self.history_slots.get_or_init(|| {
+            let limit = limit.max(1);
+            (Semaphore::new(limit), limit)
+        });
+        slots.acquire().await.ok()
+    }
+
+    /// Returns the number of history frames currently buffered for the client
+    pub fn buffered_history_frames(&self) -> usize {
+        self.history_slots
+            .get()
+            .map_or(0, |(slots, limit)| limit - slots.available_permits())
+    }
+
+    /// Marks the session as closed so it is no longer considered a live subscriber
+    ///
+    /// Pending sends waiting on [`WebSocketConnection::closed`] are cancelled.
+    pub fn mark_closed(&self) {
+        self.closing.cancel();
+        if let Some((slots, _)) = self.history_slots.get() {
+            slots.close();
+        }
+    }
+
+    /// Completes once the session has been marked closed
+    pub fn closed(&self) -> WaitForCancellationFuture<'_> {
+        self.closing.cancelled()
     }
 }
 
@@ -144,12 +992,92 @@ impl Hash for WebSocketConnection {
 }
 
 #[cfg(test)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::unwrap_used)]
 mod tests {
+    use futures::SinkExt;
+
     use super::*;
 
     fn send_and_sync<T: Send + Sync>() {}
     fn websocket_manager_send_and_sync() {
         send_and_sync::<WebSocketManager>();
     }
+
+    /// Creates a connection whose session halves never produce or accept anything
+    fn idle_connection() -> Arc<WebSocketConnection> {
+        let sink = futures::sink::drain().sink_map_err(|_| WsError::ConnectionClosed);
+        Arc::new(WebSocketConnection::from_parts(
+            Box::pin(sink),
+            Box::pin(futures::stream::pending()),
+        ))
+    }
+
+    /// Tests that reconciliation removes closed subscribers and keeps live ones
+    #[test]
+    fn test_reconcile_removes_closed_subscribers() {
+        let manager = WebSocketManager::default();
+        let live = idle_connection();
+        let closed = idle_connection();
+        closed.mark_closed();
+
+        for connection in [&live, &closed] {
+            assert!(manager.add_subscription(connection, "chat", usize::MAX));
+        }
+
+        assert_eq!(manager.reconcile_subscribers(), 1);
+
+        let subscribers = manager.chats.get("chat").expect("chat should remain");
+        assert!(subscribers.contains(&live.conn_id()));
+        assert!(!subscribers.contains(&closed.conn_id()));
+        assert!(!manager.connections.contains_key(&closed.conn_id()));
+        assert!(!manager.handles.contains_key(&closed.conn_id()));
+    }
+
+    /// Tests that closing all connections sends a close frame to every subscribed session
+    #[tokio::test]
+    async fn test_close_all_closes_every_session() {
+        let manager = WebSocketManager::default();
+        let mut receivers = Vec::new();
+        for chat_id in ["first", "second"] {
+            let (tx, rx) = futures::channel::mpsc::unbounded();
+            let sink = tx.sink_map_err(|_| WsError::ConnectionClosed);
+            let connection = Arc::new(WebSocketConnection::from_parts(
+                Box::pin(sink),
+                Box::pin(futures::stream::pending()),
+            ));
+            manager.add_subscription(&connection, chat_id, usize::MAX);
+            receivers.push((connection, rx));
+        }
+
+        assert_eq!(manager.close_all().await, 2);
+
+        for (connection, mut rx) in receivers {
+            assert!(connection.is_closed());
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(Message::Close(Some(frame))) if frame.code == CloseCode::Away
+            ));
+        }
+    }
+
+    /// Tests that a connection reusing a registered id gets a new one instead of evicting the first
+    #[test]
+    fn test_duplicate_id_does_not_evict_existing_connection() {
+        let manager = WebSocketManager::default();
+        let existing = idle_connection();
+        manager.add_subscription(&existing, "chat", usize::MAX);
+
+        let sink = futures::sink::drain().sink_map_err(|_| WsError::ConnectionClosed);
+        let mut duplicate =
+            WebSocketConnection::from_parts(Box::pin(sink), Box::pin(futures::stream::pending()));
+        duplicate.id = existing.id;
+
+        assert!(manager.ensure_unique_id(&mut duplicate));
+        assert_ne!(duplicate.id, existing.id);
+        manager.add_subscription(&Arc::new(duplicate), "other", usize::MAX);
+
+        assert_eq!(manager.active_connections(), 2);
+        let chats = manager.connections.get(&existing.conn_id()).unwrap();
+        assert!(chats.contains("chat") && !chats.contains("other"));
+    }
 }
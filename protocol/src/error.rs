@@ -1,9 +1,90 @@
 use thiserror::Error;
 
+use crate::entity::response::SeedErrorCode;
+
 /// Represents errors that can occur in seed protocol.
 #[derive(Error, Debug)]
 pub enum SeedError {
     /// Error returned when a nonce is invalid.
     #[error("invalid nonce")]
     InvalidNonce,
+
+    /// Error returned when a chat has reached its subscriber limit.
+    #[error("chat has reached its subscriber limit")]
+    ChatFull,
+
+    /// Error returned when a connection has reached its subscription limit.
+    #[error("connection has reached its subscription limit")]
+    TooManySubscriptions,
+
+    /// Error returned when another registered connection already uses the connection's id.
+    #[error("connection id is already in use")]
+    DuplicateConnectionId,
+
+    /// Error returned when a subscription token is missing or does not match the chat.
+    #[error("subscription token is missing or invalid")]
+    InvalidToken,
+}
+
+/// Represents reasons a client message fails validation.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// Error returned when a field is not valid base64.
+    #[error("{0} is not valid base64")]
+    InvalidEncoding(&'static str),
+
+    /// Error returned when the decoded chat id does not have the configured length.
+    #[error("chat id must decode to {expected} bytes, got {actual}")]
+    InvalidChatId { expected: usize, actual: usize },
+
+    /// Error returned when a decoded field does not have its expected length.
+    #[error("{field} must decode to {expected} bytes, got {actual}")]
+    InvalidLength {
+        field: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+
+    /// Error returned when a decoded field is larger than allowed.
+    #[error("{field} must decode to at most {limit} bytes, got {actual}")]
+    TooLarge {
+        field: &'static str,
+        limit: usize,
+        actual: usize,
+    },
+
+    /// Error returned when the signature does not verify for the message.
+    #[error("signature does not match the message")]
+    InvalidSignature,
+
+    /// Error returned when a message leaves its nonce to the server while signatures are verified.
+    #[error("signed messages must carry their nonce")]
+    MissingNonce,
+}
+
+impl SeedError {
+    /// Returns the error code reported to the client.
+    pub fn code(&self) -> SeedErrorCode {
+        match self {
+            Self::InvalidNonce => SeedErrorCode::InvalidNonce,
+            Self::ChatFull => SeedErrorCode::ChatFull,
+            Self::TooManySubscriptions => SeedErrorCode::TooManySubscriptions,
+            Self::DuplicateConnectionId => SeedErrorCode::Internal,
+            Self::InvalidToken => SeedErrorCode::Unauthorized,
+        }
+    }
+}
+
+impl ValidationError {
+    /// Returns the error code reported to the client.
+    pub fn code(&self) -> SeedErrorCode {
+        match self {
+            Self::InvalidEncoding(_) => SeedErrorCode::InvalidEncoding,
+            Self::InvalidChatId { .. } => SeedErrorCode::InvalidChatId,
+            Self::InvalidLength { .. } => SeedErrorCode::InvalidLength,
+            Self::TooLarge { .. } => SeedErrorCode::MessageTooLarge,
+            Self::InvalidSignature => SeedErrorCode::InvalidSignature,
+            Self::MissingNonce => SeedErrorCode::InvalidNonce,
+        }
+    }
 }
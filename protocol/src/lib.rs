@@ -1,2 +1,4 @@
+pub mod clock;
 pub mod entity;
 pub mod error;
+pub mod schema;
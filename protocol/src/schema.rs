@@ -0,0 +1,51 @@
+use schemars::schema_for;
+use serde_json::{Value, json};
+
+use crate::entity::{message::IncomeMessage, response::SeedResponse};
+
+/// Generates the JSON Schema describing the seed wire format.
+///
+/// The schemas are derived from the protocol types themselves, so they always
+/// match what the server accepts and produces.
+///
+/// # Returns
+/// A JSON object with an `income` schema for client frames and a `response`
+/// schema for server frames
+pub fn protocol_schema() -> Value {
+    json!({
+        "income": schema_for!(IncomeMessage),
+        "response": schema_for!(SeedResponse),
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// Tests that the income schema accepts a valid send frame and rejects one without a queueId
+    #[test]
+    fn test_income_schema_validates_send() {
+        let schema = protocol_schema();
+        let validator = jsonschema::validator_for(&schema["income"]).unwrap();
+
+        let valid = json!({
+            "type": "send",
+            "message": {
+                "nonce": 1,
+                "queueId": "Y2hhdA==",
+                "signature": "c2lnbmF0dXJl",
+                "content": "Y29udGVudA==",
+                "contentIV": "aXY="
+            }
+        });
+        assert!(validator.is_valid(&valid));
+
+        let mut invalid = valid.clone();
+        invalid["message"]
+            .as_object_mut()
+            .unwrap()
+            .remove("queueId");
+        assert!(!validator.is_valid(&invalid));
+    }
+}
@@ -1,11 +1,23 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Result;
+use futures::Stream;
 
-use protocol::entity::{self, websocket::WebSocketConnection};
+use protocol::{
+    entity::{
+        self,
+        response::SeedErrorCode,
+        websocket::{ControlAction, WebSocketConnection},
+    },
+    error::ValidationError,
+};
+use uuid::Uuid;
 
 /// Repository trait for handling websocket message events and responses
-pub trait MessagesRepository {
+///
+/// The futures used by chat message processors are `Send`, as processors run
+/// as tasks of their own.
+pub trait MessagesRepository: Send + Sync {
     /// Waits for an event response on the websocket connection for a specific chat
     fn wait_event_response(
         &self,
@@ -18,33 +30,152 @@ pub trait MessagesRepository {
         &self,
         connection: Arc<WebSocketConnection>,
         message: entity::message::OutcomeMessage,
-    ) -> impl Future<Output = Result<()>>;
+    ) -> impl Future<Output = Result<()>> + Send;
 
-    /// Sends a status response indicating connection state
+    /// Sends a status response telling whether an operation succeeded or why it failed
     fn status_response(
         &self,
         connection: Arc<WebSocketConnection>,
-        status: bool,
+        outcome: Result<(), SeedErrorCode>,
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Acknowledges that the message with `nonce` sent to a chat was accepted
+    fn send_ack_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+        nonce: usize,
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Answers a ping from the client
+    fn pong_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Notifies the client that it has received every stored message of a chat
+    fn up_to_date_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+        head_nonce: usize,
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Notifies the client that a history replay stopped after `last_delivered_nonce`
+    fn sync_error_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+        last_delivered_nonce: usize,
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Notifies the client that the subscribe sequence for a chat is finished,
+    /// handing out a token to resume its subscriptions with if there is one
+    fn subscribe_complete_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+        resume_token: Option<String>,
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Notifies the client that a chat it subscribed to was closed
+    fn chat_closed_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Sends an error response describing why the client's input was rejected
+    fn error_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        code: entity::response::SeedErrorCode,
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Sends the sending allowance the connection has left
+    fn quota_status_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        quota: entity::response::QuotaStatus,
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Sends a rate limit error carrying the sending allowance the connection has left
+    fn rate_limited_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        quota: entity::response::QuotaStatus,
     ) -> impl Future<Output = Result<()>>;
 
-    /// Sends a response about unread messages for a chat
+    /// Sends a response about unread messages for a chat, fetching `limit` messages per batch
     fn unread_message_response(
         &self,
         connection: Arc<WebSocketConnection>,
         chat_id: &[u8],
         nonce: usize,
+        limit: Option<usize>,
+    ) -> impl Future<Output = ()>;
+
+    /// Sends the most recent messages of a chat, regardless of the client's sync cursor
+    fn recent_context_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &[u8],
+        amount: usize,
     ) -> impl Future<Output = ()>;
 
+    /// Sends the head nonce of each requested chat, 0 for chats without messages
+    fn head_nonces_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_ids: &[String],
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Sends the stored messages of a chat outside the nonce ranges the client has
+    fn sync_diff_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+        have_ranges: &[(usize, usize)],
+    ) -> impl Future<Output = Result<()>>;
+
     /// Validates if a message meets required criteria
-    fn is_valid_message(&self, message: entity::message::OutcomeMessage) -> impl Future<Output = bool>;
+    fn validate_message(
+        &self,
+        message: entity::message::OutcomeMessage,
+    ) -> impl Future<Output = Result<(), ValidationError>>;
+
+    fn insert_message(
+        &self,
+        message: entity::message::Message,
+    ) -> impl Future<Output = Result<InsertOutcome>> + Send;
+}
+
+/// Outcome of a successful message insert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The message was stored under the given nonce
+    Inserted(usize),
 
-    fn insert_message(&self, message: entity::message::Message) -> impl Future<Output = Result<()>>;
+    /// The same message was already stored under its nonce, e.g. by a retried send
+    Duplicate,
 }
 
 /// Database interface for message persistence
-pub trait MessagesDB {
+pub trait MessagesDB: Send + Sync {
     /// Inserts a new message into the database
-    fn insert_message(&self, message: entity::message::Message) -> impl Future<Output = Result<()>>;
+    ///
+    /// A message whose nonce is already taken by a message with the same
+    /// signature is reported as [`InsertOutcome::Duplicate`] instead of an
+    /// invalid nonce, so retried sends stay idempotent.
+    ///
+    /// A message sent with [`entity::message::Message::AUTO_NONCE`] is stored
+    /// under the next nonce of the chat. Picking the nonce and storing the
+    /// message happen atomically, so concurrent sends get contiguous nonces.
+    /// Such messages are never reported as duplicates.
+    fn insert_message(
+        &self,
+        message: entity::message::Message,
+    ) -> impl Future<Output = Result<InsertOutcome>> + Send;
 
     /// Retrieves message history for a chat with pagination
     ///
@@ -58,4 +189,61 @@ pub trait MessagesDB {
         nonce: usize,
         amount: usize,
     ) -> impl Future<Output = Result<Vec<entity::message::OutcomeMessage>>>;
+
+    /// Streams message history for a chat, yielding rows as the database returns them
+    ///
+    /// Yields the same messages in the same order as [`MessagesDB::fetch_history`]
+    /// without holding the whole batch in memory.
+    ///
+    /// # Arguments
+    /// * `chat_id` - The ID of the chat to fetch history for
+    /// * `nonce` - Pagination token
+    /// * `amount` - Number of messages to retrieve
+    fn stream_history<'a>(
+        &'a self,
+        chat_id: &'a [u8],
+        nonce: usize,
+        amount: usize,
+    ) -> impl Stream<Item = Result<entity::message::OutcomeMessage>> + Send + 'a;
+
+    /// Retrieves the last `amount` messages of a chat in ascending nonce order
+    ///
+    /// # Arguments
+    /// * `chat_id` - The ID of the chat to fetch messages for
+    /// * `amount` - Number of messages to retrieve
+    fn fetch_recent(
+        &self,
+        chat_id: &[u8],
+        amount: usize,
+    ) -> impl Future<Output = Result<Vec<entity::message::OutcomeMessage>>>;
+
+    /// Returns the highest stored nonce for a chat, or 0 if the chat has no messages
+    fn last_nonce(&self, chat_id: &[u8]) -> impl Future<Output = Result<usize>>;
+
+    /// Returns the highest stored nonce of each given chat in a single query
+    ///
+    /// Chats without messages are left out of the result.
+    ///
+    /// # Arguments
+    /// * `chat_ids` - The IDs of the chats to look up
+    fn head_nonces(
+        &self,
+        chat_ids: &[Vec<u8>],
+    ) -> impl Future<Output = Result<HashMap<Vec<u8>, usize>>>;
+
+    /// Durably records that a connection subscribed to or unsubscribed from a chat
+    ///
+    /// # Arguments
+    /// * `connection_id` - The connection whose subscriptions changed
+    /// * `chat_id` - The chat ID as sent by the client
+    /// * `action` - Whether the connection subscribed or unsubscribed
+    fn record_control_event(
+        &self,
+        connection_id: Uuid,
+        chat_id: &str,
+        action: ControlAction,
+    ) -> impl Future<Output = Result<()>>;
+
+    /// Checks that the store is reachable with the cheapest possible round trip
+    fn ping(&self) -> impl Future<Output = Result<()>>;
 }
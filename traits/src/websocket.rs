@@ -1,18 +1,22 @@
-use protocol::entity::{
-    message::IncomeMessage,
-    websocket::{WebSocketConnection, WebSocketManager},
+use protocol::{
+    entity::{
+        message::IncomeMessage,
+        websocket::{BroadcastReport, SeedCloseReason, WebSocketConnection, WebSocketManager},
+    },
+    error::SeedError,
 };
 use std::sync::Arc;
 
 /// Repository trait for handling WebSocket operations
 pub trait WebsocketRepository {
-    /// Handles subscription to a chat room
+    /// Handles subscription to a chat room, checking the subscription token if one is required
     fn handle_subscribe(
         &self,
         ws: Arc<WebSocketManager>,
         connection: Arc<WebSocketConnection>,
         chat_id: &str,
-    ) -> impl Future<Output = ()>;
+        token: Option<&str>,
+    ) -> impl Future<Output = Result<(), SeedError>>;
     /// Handles unsubscription from a chat room
     fn handle_unsubscribe(
         &self,
@@ -20,8 +24,21 @@ pub trait WebsocketRepository {
         connection: Arc<WebSocketConnection>,
         chat_id: &str,
     ) -> impl Future<Output = ()>;
-    /// Broadcasts an event to connected clients
-    fn broadcast_event(&self, ws: Arc<WebSocketManager>, message: IncomeMessage) -> impl Future<Output = ()>;
-    /// Handles client disconnection
-    fn disconnect(&self, ws: Arc<WebSocketManager>, connection: Arc<WebSocketConnection>) -> impl Future<Output = ()>;
+    /// Broadcasts an event to connected clients, reporting per-subscriber delivery
+    fn broadcast_event(
+        &self,
+        ws: Arc<WebSocketManager>,
+        message: IncomeMessage,
+    ) -> impl Future<Output = BroadcastReport>;
+    /// Closes a chat, notifying every subscriber before tearing it down
+    ///
+    /// Returns the number of subscribers that were notified.
+    fn close_chat(&self, ws: Arc<WebSocketManager>, chat_id: &str) -> impl Future<Output = usize>;
+    /// Handles client disconnection, telling the client why if a reason is given
+    fn disconnect(
+        &self,
+        ws: Arc<WebSocketManager>,
+        connection: Arc<WebSocketConnection>,
+        reason: Option<SeedCloseReason>,
+    ) -> impl Future<Output = ()>;
 }
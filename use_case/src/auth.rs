@@ -0,0 +1,121 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use misc::base64::{decode_base64, encode_base64};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Length in bytes of an HMAC-SHA256 tag
+const TAG_LEN: usize = 32;
+
+/// Subscriptions restored from a verified resume token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeState {
+    /// The resume session the token was issued in
+    ///
+    /// A session starts with the connection that first subscribed and is
+    /// carried on by every connection resuming with one of its tokens.
+    pub session: Uuid,
+
+    /// The subscribed chats and the nonces to replay them from
+    pub chats: Vec<(String, usize)>,
+}
+
+/// Server secret subscription tokens are signed with
+///
+/// A token is the base64 encoded HMAC-SHA256 of the queue ID in the standard
+/// padded base64 alphabet, so whoever hands out chat IDs can hand out tokens
+/// too. Queue IDs sent in the URL-safe alphabet are checked in that form.
+#[derive(Clone)]
+pub struct SubscriptionSecret(Arc<[u8]>);
+
+impl SubscriptionSecret {
+    /// Wraps the raw secret bytes
+    pub fn new(secret: impl AsRef<[u8]>) -> Self {
+        Self(secret.as_ref().into())
+    }
+
+    /// Returns an HMAC keyed with the secret over arbitrary bytes
+    fn mac(&self, data: impl AsRef<[u8]>) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts keys of any length");
+        mac.update(data.as_ref());
+        mac
+    }
+
+    /// Creates the token that authorizes subscribing to a chat
+    pub async fn sign(&self, chat_id: &str) -> String {
+        encode_base64(&self.mac(chat_id).finalize().into_bytes()).await
+    }
+
+    /// Checks in constant time whether a token authorizes subscribing to a chat
+    pub async fn verify(&self, chat_id: &str, token: &str) -> bool {
+        match decode_base64(token.to_string()).await {
+            Ok(tag) => self.mac(chat_id).verify_slice(&tag).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Creates an opaque token a reconnecting client resumes its subscriptions with
+    ///
+    /// The token is the base64 encoded JSON of the session ID, the issue time
+    /// in seconds since the Unix epoch and the chat/nonce pairs, followed by
+    /// their HMAC-SHA256 tag.
+    ///
+    /// # Arguments
+    /// * `session` - The resume session the token belongs to
+    /// * `chats` - The subscribed chats and the nonces to replay them from
+    /// * `now` - The time the token is issued at
+    pub async fn sign_resume(
+        &self,
+        session: Uuid,
+        chats: &[(String, usize)],
+        now: SystemTime,
+    ) -> String {
+        let issued_at = unix_seconds(now);
+        let mut token = serde_json::to_vec(&(session, issued_at, chats))
+            .expect("a UUID, a timestamp and chat/nonce pairs always serialize");
+        let tag = self.mac(&token).finalize().into_bytes();
+        token.extend_from_slice(&tag);
+        encode_base64(&token).await
+    }
+
+    /// Returns the subscriptions of a resume token if it was signed with this secret
+    ///
+    /// # Arguments
+    /// * `token` - The token sent by the client
+    /// * `now` - The current time
+    /// * `ttl` - How long a token is accepted after it was issued
+    pub async fn verify_resume(
+        &self,
+        token: &str,
+        now: SystemTime,
+        ttl: Duration,
+    ) -> Option<ResumeState> {
+        let token = decode_base64(token.to_string()).await.ok()?;
+        let (payload, tag) = token.split_at(token.len().checked_sub(TAG_LEN)?);
+        self.mac(payload).verify_slice(tag).ok()?;
+
+        let (session, issued_at, chats): (Uuid, u64, _) = serde_json::from_slice(payload).ok()?;
+        if unix_seconds(now).saturating_sub(issued_at) >= ttl.as_secs() {
+            return None;
+        }
+        Some(ResumeState { session, chats })
+    }
+}
+
+impl fmt::Debug for SubscriptionSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SubscriptionSecret(..)")
+    }
+}
+
+/// Returns a time in whole seconds since the Unix epoch
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
@@ -0,0 +1,58 @@
+use log::error;
+use protocol::entity::message::{ClusterEvent, OutcomeMessage};
+use uuid::Uuid;
+
+/// Link of an instance to the other instances of a cluster
+///
+/// Events published through the link are tagged with the instance's origin,
+/// so the instance can recognize and skip them when they come back from the
+/// cluster.
+#[derive(Clone)]
+pub struct ClusterLink {
+    /// Identifies the events this instance published
+    origin: Uuid,
+    /// Events waiting to be relayed to the other instances
+    outbound: flume::Sender<ClusterEvent>,
+}
+
+impl ClusterLink {
+    /// Creates a link with a fresh origin
+    ///
+    /// # Returns
+    /// The link and the receiver the relay takes published events from
+    pub fn new() -> (Self, flume::Receiver<ClusterEvent>) {
+        let (outbound, rx) = flume::unbounded();
+        let link = Self {
+            origin: Uuid::new_v4(),
+            outbound,
+        };
+        (link, rx)
+    }
+
+    /// Returns the origin events published through this link carry
+    pub fn origin(&self) -> Uuid {
+        self.origin
+    }
+
+    /// Queues a broadcast message for the other instances
+    ///
+    /// # Arguments
+    /// * `message` - Message this instance delivers to its own subscribers
+    pub fn publish(&self, message: &OutcomeMessage) {
+        let event = ClusterEvent {
+            origin: self.origin,
+            message: message.clone(),
+        };
+        if self.outbound.send(event).is_err() {
+            error!(
+                "Error relaying event for chat {}: cluster relay stopped",
+                message.chat_id
+            );
+        }
+    }
+
+    /// Checks whether an event was published through this link
+    pub fn is_own(&self, event: &ClusterEvent) -> bool {
+        event.origin == self.origin
+    }
+}
@@ -0,0 +1,522 @@
+use std::time::Duration;
+
+use misc::env::env_or;
+use protocol::entity::quota::QuotaLimits;
+
+use crate::auth::SubscriptionSecret;
+
+/// Runtime configuration shared by the seed use cases.
+///
+/// Every field can be overridden by the environment variable named in its
+/// documentation; unset variables fall back to [`SeedConfig::default`].
+#[derive(Clone, Debug)]
+pub struct SeedConfig {
+    /// Number of attempts made to deliver a broadcast event to a single subscriber
+    ///
+    /// Environment variable: `BROADCAST_RETRY_ATTEMPTS` (default: 3)
+    pub broadcast_retry_attempts: usize,
+
+    /// Base delay between broadcast attempts, multiplied by the attempt number
+    ///
+    /// Environment variable: `BROADCAST_RETRY_BACKOFF_MS` (default: 50)
+    pub broadcast_retry_backoff: Duration,
+
+    /// Maximum number of subscribers a single broadcast sends to concurrently
+    ///
+    /// Environment variable: `BROADCAST_CONCURRENCY` (default: 64)
+    pub broadcast_concurrency: usize,
+
+    /// Maximum time a single send to a subscriber may take before the subscriber is disconnected
+    ///
+    /// Environment variable: `BROADCAST_SEND_TIMEOUT_MS` (default: 5000)
+    pub broadcast_send_timeout: Duration,
+
+    /// Maximum number of connections that may subscribe to a single chat
+    ///
+    /// Environment variable: `MAX_SUBSCRIBERS_PER_CHAT` (default: 10000)
+    pub max_subscribers_per_chat: usize,
+
+    /// Maximum number of chats a single connection may be subscribed to at once
+    ///
+    /// Environment variable: `MAX_SUBS_PER_CONN` (default: 256)
+    pub max_subscriptions_per_connection: usize,
+
+    /// Interval between scans removing subscribers whose sessions are closed
+    ///
+    /// Environment variable: `SUBSCRIBER_RECONCILE_SECS` (default: 30)
+    pub subscriber_reconcile_interval: Duration,
+
+    /// Whether an explicit up-to-date notification is sent when a history replay is empty
+    ///
+    /// Environment variable: `REPLAY_UP_TO_DATE_SIGNAL` (default: true)
+    pub up_to_date_signal: bool,
+
+    /// Whether a sync error notification is sent when a history replay fails part way
+    ///
+    /// Environment variable: `REPLAY_SYNC_ERROR_SIGNAL` (default: true)
+    pub sync_error_signal: bool,
+
+    /// Number of bytes a chat id must decode to
+    ///
+    /// Environment variable: `CHAT_ID_BYTES` (default: 32), falling back to the
+    /// older `CHAT_ID_LENGTH` when unset
+    pub chat_id_bytes: usize,
+
+    /// Number of bytes a content initialization vector must decode to
+    ///
+    /// Environment variable: `CONTENT_IV_LENGTH` (default: 12)
+    pub content_iv_length: usize,
+
+    /// Maximum number of bytes message content may decode to
+    ///
+    /// Environment variable: `MAX_MESSAGE_BYTES` (default: 65536)
+    pub max_message_bytes: usize,
+
+    /// Whether empty fields of delivered messages are left out instead of sent as empty strings
+    ///
+    /// Environment variable: `LEAN_MESSAGES` (default: false)
+    pub lean_messages: bool,
+
+    /// Whether responses are serialized into a per-thread buffer reused across messages
+    ///
+    /// Environment variable: `REUSE_SERIALIZATION_BUFFER` (default: true)
+    pub reuse_serialization_buffer: bool,
+
+    /// Maximum time a connection may stay open before it must reconnect, if any
+    ///
+    /// Environment variable: `MAX_CONNECTION_LIFETIME_SECS` (default: 0, unlimited)
+    pub max_connection_lifetime: Option<Duration>,
+
+    /// Time a connection may stay silent before it is closed, if any
+    ///
+    /// Every received frame, including pongs, counts as activity.
+    ///
+    /// Environment variable: `WS_IDLE_TIMEOUT_SECS` (default: 60, 0 disables)
+    pub idle_timeout: Option<Duration>,
+
+    /// Interval between pings sent to keep connections alive, if any
+    ///
+    /// Environment variable: `WS_PING_INTERVAL_SECS` (default: 20, 0 disables)
+    pub ping_interval: Option<Duration>,
+
+    /// Whether messages are stored and replayed to subscribers
+    ///
+    /// When disabled the server acts as a pure relay: sent messages are only
+    /// broadcast to live subscribers and the database is never queried.
+    ///
+    /// Environment variable: `HISTORY_ENABLED` (default: true)
+    pub history_enabled: bool,
+
+    /// Maximum number of recent messages a subscriber may request as context
+    ///
+    /// Environment variable: `MAX_RECENT_CONTEXT` (default: 50)
+    pub max_recent_context: usize,
+
+    /// Maximum number of history messages a subscriber may request per batch
+    ///
+    /// Environment variable: `MAX_HISTORY_BATCH` (default: 500)
+    pub max_history_batch: usize,
+
+    /// Number of newest messages kept in memory per chat to answer recent context
+    /// and replays without a database query, 0 disables the cache
+    ///
+    /// Only messages stored by this server are cached, so keep it disabled when
+    /// several servers share a database. It is always disabled in cluster mode.
+    ///
+    /// Environment variable: `RECENT_CACHE_MESSAGES` (default: 0)
+    pub recent_cache_messages: usize,
+
+    /// Maximum number of chats whose newest messages are kept in memory
+    ///
+    /// Environment variable: `RECENT_CACHE_CHATS` (default: 1024)
+    pub recent_cache_chats: usize,
+
+    /// Number of recently accepted messages remembered to ignore replays
+    ///
+    /// Environment variable: `REPLAY_CACHE_CAPACITY` (default: 10000)
+    pub replay_cache_capacity: usize,
+
+    /// Interval between sweeps deleting messages whose TTL has passed
+    ///
+    /// Environment variable: `EXPIRY_SWEEP_SECS` (default: 60)
+    pub expiry_sweep_interval: Duration,
+
+    /// Age after which stored messages are deleted whatever their TTL, if any
+    ///
+    /// Only the Postgres backend prunes messages by age.
+    ///
+    /// Environment variable: `MESSAGE_TTL_SECS` (default: 0, kept forever)
+    pub message_retention: Option<Duration>,
+
+    /// Interval between sweeps deleting messages older than `message_retention`
+    ///
+    /// Environment variable: `RETENTION_SWEEP_SECS` (default: 3600)
+    pub retention_sweep_interval: Duration,
+
+    /// Maximum number of client identities whose subscriptions are kept for a resume
+    ///
+    /// Environment variable: `RESUME_REGISTRY_CAPACITY` (default: 10000)
+    pub resume_registry_capacity: usize,
+
+    /// How long saved subscriptions can be resumed after they were last changed,
+    /// and how long a resume token is accepted after it was issued
+    ///
+    /// Environment variable: `RESUME_TTL_SECS` (default: 300)
+    pub resume_ttl: Duration,
+
+    /// Maximum number of chats a single connection replays at the same time on resume
+    ///
+    /// Environment variable: `RESUME_REPLAY_CONCURRENCY` (default: 4)
+    pub resume_replay_concurrency: usize,
+
+    /// Maximum number of history frames buffered for a single connection across all its chats
+    ///
+    /// A slow reader throttles every replay of its connection once the limit is reached.
+    ///
+    /// Environment variable: `MAX_BUFFERED_HISTORY_FRAMES` (default: 2)
+    pub max_buffered_history_frames: usize,
+
+    /// Maximum number of bytes an HTTP request head may occupy
+    ///
+    /// Environment variable: `MAX_REQUEST_HEAD_BYTES` (default: 8192)
+    pub max_request_head_bytes: usize,
+
+    /// Maximum number of bytes the target of an HTTP request may occupy
+    ///
+    /// Environment variable: `MAX_REQUEST_URI_BYTES` (default: 2048)
+    pub max_request_uri_bytes: usize,
+
+    /// Maximum time a disconnect waits for the WebSocket close handshake
+    ///
+    /// Environment variable: `CLOSE_TIMEOUT_MS` (default: 5000)
+    pub close_timeout: Duration,
+
+    /// Maximum time the health check waits for the database to answer
+    ///
+    /// Environment variable: `HEALTH_CHECK_TIMEOUT_MS` (default: 2000)
+    pub health_check_timeout: Duration,
+
+    /// Maximum time a shutdown waits for queued messages to be stored
+    ///
+    /// Environment variable: `SHUTDOWN_TIMEOUT_MS` (default: 10000)
+    pub shutdown_timeout: Duration,
+
+    /// Number of messages a connection may send per quota window, if limited
+    ///
+    /// Environment variable: `QUOTA_MESSAGES` (default: 0, unlimited)
+    pub quota_messages: Option<u64>,
+
+    /// Number of content bytes a connection may send per quota window, if limited
+    ///
+    /// Environment variable: `QUOTA_BYTES` (default: 0, unlimited)
+    pub quota_bytes: Option<u64>,
+
+    /// Length of the window after which a connection's sending allowance is refilled
+    ///
+    /// Environment variable: `QUOTA_WINDOW_SECS` (default: 60)
+    pub quota_window: Duration,
+
+    /// Number of messages per second a connection may send, if limited
+    ///
+    /// Messages over the limit are rejected with a failed status, the
+    /// connection stays open.
+    ///
+    /// Environment variable: `WS_RATE_LIMIT` (default: 0, unlimited)
+    pub rate_limit: Option<u64>,
+
+    /// Whether messages for chats with subscribers are broadcast before they are persisted
+    ///
+    /// The sender is acknowledged once subscribers received the message, and
+    /// the message is stored in the background afterwards. This trades
+    /// durability for latency: a message can be delivered and acknowledged
+    /// but never stored if persisting it fails, for example because the
+    /// database is down or the nonce does not follow the stored head.
+    ///
+    /// Environment variable: `LIVE_FIRST` (default: false)
+    pub live_first: bool,
+
+    /// Whether subscribe and unsubscribe actions are recorded durably as control events
+    ///
+    /// Environment variable: `CONTROL_EVENTS` (default: false)
+    pub control_events: bool,
+
+    /// Maximum number of messages waiting in a chat's queue, if bounded
+    ///
+    /// Messages sent to a chat whose queue is full are rejected with a failed status.
+    ///
+    /// Environment variable: `CHAT_QUEUE_CAP` (default: 0, unbounded)
+    pub chat_queue_capacity: Option<usize>,
+
+    /// Number of queued messages a chat's processor handles before yielding to other tasks
+    ///
+    /// Environment variable: `PROCESSOR_YIELD_EVERY` (default: 64)
+    pub processor_yield_every: usize,
+
+    /// Whether a chat's processor keeps going with the next message after handling one
+    /// panicked, instead of closing the chat
+    ///
+    /// Environment variable: `RESTART_PANICKED_PROCESSOR` (default: true)
+    pub restart_panicked_processor: bool,
+
+    /// Window in which repeated wait events for the same chat are coalesced into one, if any
+    ///
+    /// Environment variable: `WAIT_EVENT_DEBOUNCE_MS` (default: 0, disabled)
+    pub wait_event_debounce: Option<Duration>,
+
+    /// Whether live events carry the chat ID re-encoded like history events do
+    ///
+    /// History events encode the stored chat ID bytes, so with this enabled a
+    /// chat ID is the same string whichever path delivered the event.
+    ///
+    /// Environment variable: `CANONICAL_CHAT_IDS` (default: true)
+    pub canonical_chat_ids: bool,
+
+    /// Maximum number of failed connections listed in a broadcast report, the rest are only counted
+    ///
+    /// Environment variable: `MAX_REPORTED_FAILURES` (default: 100)
+    pub max_reported_failures: usize,
+
+    /// Whether a connection must be subscribed to a chat before it may send to it
+    ///
+    /// Environment variable: `STRICT_SUBSCRIPTIONS` (default: false)
+    pub strict_subscriptions: bool,
+
+    /// Whether broadcasts are relayed to the other instances of a cluster over Redis pub/sub
+    ///
+    /// Environment variable: `CLUSTER_MODE` (default: false)
+    pub cluster_mode: bool,
+
+    /// Whether handshakes offering only subprotocols other than `seed.v1` are refused
+    ///
+    /// Clients offering no subprotocol at all are always accepted.
+    ///
+    /// Environment variable: `STRICT_SUBPROTOCOL` (default: false)
+    pub strict_subprotocol: bool,
+
+    /// Secret subscription tokens are verified against, if subscribing requires a token
+    ///
+    /// While set, resuming by client id alone is refused, a signed resume token is required.
+    ///
+    /// Environment variable: `SEED_HMAC_SECRET` (default: unset, no token required)
+    pub subscription_secret: Option<SubscriptionSecret>,
+}
+
+impl Default for SeedConfig {
+    fn default() -> Self {
+        Self {
+            broadcast_retry_attempts: 3,
+            broadcast_retry_backoff: Duration::from_millis(50),
+            broadcast_concurrency: 64,
+            broadcast_send_timeout: Duration::from_secs(5),
+            max_subscribers_per_chat: 10_000,
+            max_subscriptions_per_connection: 256,
+            subscriber_reconcile_interval: Duration::from_secs(30),
+            up_to_date_signal: true,
+            sync_error_signal: true,
+            chat_id_bytes: 32,
+            content_iv_length: 12,
+            max_message_bytes: 64 * 1024,
+            lean_messages: false,
+            reuse_serialization_buffer: true,
+            max_connection_lifetime: None,
+            idle_timeout: Some(Duration::from_secs(60)),
+            ping_interval: Some(Duration::from_secs(20)),
+            history_enabled: true,
+            max_recent_context: 50,
+            max_history_batch: 500,
+            recent_cache_messages: 0,
+            recent_cache_chats: 1024,
+            replay_cache_capacity: 10_000,
+            expiry_sweep_interval: Duration::from_secs(60),
+            message_retention: None,
+            retention_sweep_interval: Duration::from_secs(3600),
+            resume_registry_capacity: 10_000,
+            resume_ttl: Duration::from_secs(300),
+            resume_replay_concurrency: 4,
+            max_buffered_history_frames: 2,
+            max_request_head_bytes: 8192,
+            max_request_uri_bytes: 2048,
+            close_timeout: Duration::from_secs(5),
+            health_check_timeout: Duration::from_secs(2),
+            shutdown_timeout: Duration::from_secs(10),
+            quota_messages: None,
+            quota_bytes: None,
+            quota_window: Duration::from_secs(60),
+            rate_limit: None,
+            live_first: false,
+            control_events: false,
+            chat_queue_capacity: None,
+            processor_yield_every: 64,
+            restart_panicked_processor: true,
+            wait_event_debounce: None,
+            canonical_chat_ids: true,
+            max_reported_failures: 100,
+            strict_subscriptions: false,
+            cluster_mode: false,
+            strict_subprotocol: false,
+            subscription_secret: None,
+        }
+    }
+}
+
+impl SeedConfig {
+    /// Builds a configuration from environment variables
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        Self {
+            broadcast_retry_attempts: env_or(
+                "BROADCAST_RETRY_ATTEMPTS",
+                default.broadcast_retry_attempts,
+            ),
+            broadcast_retry_backoff: Duration::from_millis(env_or(
+                "BROADCAST_RETRY_BACKOFF_MS",
+                default.broadcast_retry_backoff.as_millis() as u64,
+            )),
+            broadcast_concurrency: env_or("BROADCAST_CONCURRENCY", default.broadcast_concurrency),
+            broadcast_send_timeout: Duration::from_millis(env_or(
+                "BROADCAST_SEND_TIMEOUT_MS",
+                default.broadcast_send_timeout.as_millis() as u64,
+            )),
+            max_subscribers_per_chat: env_or(
+                "MAX_SUBSCRIBERS_PER_CHAT",
+                default.max_subscribers_per_chat,
+            ),
+            max_subscriptions_per_connection: env_or(
+                "MAX_SUBS_PER_CONN",
+                default.max_subscriptions_per_connection,
+            ),
+            subscriber_reconcile_interval: Duration::from_secs(env_or(
+                "SUBSCRIBER_RECONCILE_SECS",
+                default.subscriber_reconcile_interval.as_secs(),
+            )),
+            up_to_date_signal: env_or("REPLAY_UP_TO_DATE_SIGNAL", default.up_to_date_signal),
+            sync_error_signal: env_or("REPLAY_SYNC_ERROR_SIGNAL", default.sync_error_signal),
+            chat_id_bytes: env_or(
+                "CHAT_ID_BYTES",
+                env_or("CHAT_ID_LENGTH", default.chat_id_bytes),
+            ),
+            content_iv_length: env_or("CONTENT_IV_LENGTH", default.content_iv_length),
+            max_message_bytes: env_or("MAX_MESSAGE_BYTES", default.max_message_bytes),
+            lean_messages: env_or("LEAN_MESSAGES", default.lean_messages),
+            reuse_serialization_buffer: env_or(
+                "REUSE_SERIALIZATION_BUFFER",
+                default.reuse_serialization_buffer,
+            ),
+            max_connection_lifetime: match env_or("MAX_CONNECTION_LIFETIME_SECS", 0) {
+                0 => default.max_connection_lifetime,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            idle_timeout: match env_or("WS_IDLE_TIMEOUT_SECS", 60) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            ping_interval: match env_or("WS_PING_INTERVAL_SECS", 20) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            history_enabled: env_or("HISTORY_ENABLED", default.history_enabled),
+            max_recent_context: env_or("MAX_RECENT_CONTEXT", default.max_recent_context),
+            max_history_batch: env_or("MAX_HISTORY_BATCH", default.max_history_batch),
+            recent_cache_messages: env_or("RECENT_CACHE_MESSAGES", default.recent_cache_messages),
+            recent_cache_chats: env_or("RECENT_CACHE_CHATS", default.recent_cache_chats),
+            replay_cache_capacity: env_or("REPLAY_CACHE_CAPACITY", default.replay_cache_capacity),
+            expiry_sweep_interval: Duration::from_secs(env_or(
+                "EXPIRY_SWEEP_SECS",
+                default.expiry_sweep_interval.as_secs(),
+            )),
+            message_retention: match env_or("MESSAGE_TTL_SECS", 0) {
+                0 => default.message_retention,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            retention_sweep_interval: Duration::from_secs(env_or(
+                "RETENTION_SWEEP_SECS",
+                default.retention_sweep_interval.as_secs(),
+            )),
+            resume_registry_capacity: env_or(
+                "RESUME_REGISTRY_CAPACITY",
+                default.resume_registry_capacity,
+            ),
+            resume_ttl: Duration::from_secs(env_or(
+                "RESUME_TTL_SECS",
+                default.resume_ttl.as_secs(),
+            )),
+            resume_replay_concurrency: env_or(
+                "RESUME_REPLAY_CONCURRENCY",
+                default.resume_replay_concurrency,
+            ),
+            max_buffered_history_frames: env_or(
+                "MAX_BUFFERED_HISTORY_FRAMES",
+                default.max_buffered_history_frames,
+            ),
+            max_request_head_bytes: env_or(
+                "MAX_REQUEST_HEAD_BYTES",
+                default.max_request_head_bytes,
+            ),
+            max_request_uri_bytes: env_or("MAX_REQUEST_URI_BYTES", default.max_request_uri_bytes),
+            close_timeout: Duration::from_millis(env_or(
+                "CLOSE_TIMEOUT_MS",
+                default.close_timeout.as_millis() as u64,
+            )),
+            health_check_timeout: Duration::from_millis(env_or(
+                "HEALTH_CHECK_TIMEOUT_MS",
+                default.health_check_timeout.as_millis() as u64,
+            )),
+            shutdown_timeout: Duration::from_millis(env_or(
+                "SHUTDOWN_TIMEOUT_MS",
+                default.shutdown_timeout.as_millis() as u64,
+            )),
+            quota_messages: match env_or("QUOTA_MESSAGES", 0) {
+                0 => default.quota_messages,
+                messages => Some(messages),
+            },
+            quota_bytes: match env_or("QUOTA_BYTES", 0) {
+                0 => default.quota_bytes,
+                bytes => Some(bytes),
+            },
+            quota_window: Duration::from_secs(env_or(
+                "QUOTA_WINDOW_SECS",
+                default.quota_window.as_secs(),
+            )),
+            rate_limit: match env_or("WS_RATE_LIMIT", 0) {
+                0 => default.rate_limit,
+                rate => Some(rate),
+            },
+            live_first: env_or("LIVE_FIRST", default.live_first),
+            control_events: env_or("CONTROL_EVENTS", default.control_events),
+            chat_queue_capacity: match env_or("CHAT_QUEUE_CAP", 0) {
+                0 => default.chat_queue_capacity,
+                capacity => Some(capacity),
+            },
+            processor_yield_every: env_or("PROCESSOR_YIELD_EVERY", default.processor_yield_every),
+            restart_panicked_processor: env_or(
+                "RESTART_PANICKED_PROCESSOR",
+                default.restart_panicked_processor,
+            ),
+            wait_event_debounce: match env_or("WAIT_EVENT_DEBOUNCE_MS", 0) {
+                0 => default.wait_event_debounce,
+                millis => Some(Duration::from_millis(millis)),
+            },
+            canonical_chat_ids: env_or("CANONICAL_CHAT_IDS", default.canonical_chat_ids),
+            max_reported_failures: env_or("MAX_REPORTED_FAILURES", default.max_reported_failures),
+            strict_subscriptions: env_or("STRICT_SUBSCRIPTIONS", default.strict_subscriptions),
+            cluster_mode: env_or("CLUSTER_MODE", default.cluster_mode),
+            strict_subprotocol: env_or("STRICT_SUBPROTOCOL", default.strict_subprotocol),
+            subscription_secret: std::env::var("SEED_HMAC_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty())
+                .map(SubscriptionSecret::new),
+        }
+    }
+
+    /// Returns the sending allowance each connection gets per quota window
+    ///
+    /// An unlimited quota is an allowance that cannot run out.
+    pub fn quota_limits(&self) -> QuotaLimits {
+        QuotaLimits {
+            messages: self.quota_messages.unwrap_or(u64::MAX),
+            bytes: self.quota_bytes.unwrap_or(u64::MAX),
+            window: self.quota_window,
+        }
+    }
+}
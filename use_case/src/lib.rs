@@ -1,2 +1,9 @@
+pub mod auth;
+pub mod cluster;
+pub mod config;
 pub mod messages;
+pub mod signature;
 pub mod websocket;
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
@@ -1,30 +1,56 @@
-use std::sync::Arc;
+use std::{cell::RefCell, pin::pin, sync::Arc};
 
 use anyhow::Result;
 
-use futures::SinkExt;
-use misc::base64::decode_base64;
+use bytes::{BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use misc::base64::{decode_base64, encode_base64};
 
-use tokio_tungstenite::tungstenite::Message;
-use traits::message::{MessagesDB, MessagesRepository};
+use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+use traits::message::{InsertOutcome, MessagesDB, MessagesRepository};
 
-use protocol::entity::{
-    self,
-    response::{SeedResponse, WaitEventDetail},
-    websocket::WebSocketConnection,
+use protocol::{
+    entity::{
+        self,
+        compat::ProtocolVersion,
+        response::{
+            ChatClosedDetail, ErrorResponse, HeadNoncesDetail, QuotaStatus, SeedErrorCode,
+            SeedResponse, SendAckDetail, SubscribeCompleteDetail, SyncErrorDetail, UpToDateDetail,
+            WaitEventDetail,
+        },
+        websocket::WebSocketConnection,
+    },
+    error::ValidationError,
 };
 
-/// Maximum number of messages to fetch in a single request
+use crate::{
+    config::SeedConfig,
+    signature::{NoopVerifier, SignatureVerifier},
+};
+
+/// Number of messages fetched in a single request unless the client asks for another amount
 const MESSAGES_LIMIT: usize = 100;
 
+/// Spare capacity above which a reused serialization buffer is dropped after a message
+const MAX_REUSED_BUFFER_BYTES: usize = 64 * 1024;
+
+thread_local! {
+    /// Buffer responses are serialized into, frames take the written bytes without copying
+    static SERIALIZATION_BUFFER: RefCell<BytesMut> = RefCell::new(BytesMut::new());
+}
+
 /// Use case for handling message operations
 ///
 /// This struct implements the business logic for message operations
 /// such as sending, receiving, and validating messages.
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct MessagesUseCase<T: MessagesDB> {
     /// Database interface for message storage
     pub db: T,
+    /// Runtime configuration
+    config: Arc<SeedConfig>,
+    /// Checks message signatures before a message is accepted
+    verifier: Arc<dyn SignatureVerifier>,
 }
 
 impl<T: MessagesDB> MessagesUseCase<T> {
@@ -32,15 +58,128 @@ impl<T: MessagesDB> MessagesUseCase<T> {
     ///
     /// # Arguments
     /// * `db` - Database implementation for message storage
-    pub fn new(db: T) -> Self {
-        Self { db }
+    /// * `config` - Runtime configuration
+    ///
+    /// Signatures are not checked until a verifier is set with
+    /// [`MessagesUseCase::with_verifier`].
+    pub fn new(db: T, config: Arc<SeedConfig>) -> Self {
+        Self {
+            db,
+            config,
+            verifier: Arc::new(NoopVerifier),
+        }
+    }
+
+    /// Checks message signatures with `verifier` before messages are accepted
+    pub fn with_verifier(mut self, verifier: Arc<dyn SignatureVerifier>) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    /// Serializes a response in the connection's wire format and sends it
+    ///
+    /// The serialized payload size is accounted to the connection and, if given,
+    /// the chat. With `lean_messages` empty message fields are left out.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `chat_id` - Chat the response belongs to, if any
+    /// * `outgoing` - Response to send
+    async fn send_response(
+        &self,
+        connection: &WebSocketConnection,
+        chat_id: Option<&str>,
+        outgoing: &SeedResponse,
+    ) -> Result<()> {
+        let mut session = connection.session.lock().await;
+
+        let message = self.serialize(outgoing, connection.protocol_version())?;
+        let bytes = message.len();
+        session.send(Message::Text(message)).await?;
+
+        connection.record_sent(chat_id, bytes);
+
+        Ok(())
+    }
+
+    /// Serializes a response in the shape the configuration asks for
+    ///
+    /// With `reuse_serialization_buffer` the JSON is written into a per-thread
+    /// buffer and the frame takes the written bytes as they are. Once the frame
+    /// is sent, its allocation is reused for later messages instead of growing
+    /// a fresh string for every message.
+    fn serialize(&self, outgoing: &SeedResponse, version: ProtocolVersion) -> Result<Utf8Bytes> {
+        let lean = self.config.lean_messages;
+        if !self.config.reuse_serialization_buffer {
+            let json = if lean {
+                outgoing.to_lean_json(version)?
+            } else {
+                outgoing.to_json(version)?
+            };
+            return Ok(json.into());
+        }
+
+        SERIALIZATION_BUFFER.with_borrow_mut(|buffer| {
+            buffer.clear();
+            let writer = (&mut *buffer).writer();
+            if lean {
+                outgoing.write_lean_json(version, writer)?;
+            } else {
+                outgoing.write_json(version, writer)?;
+            }
+            let json = buffer.split().freeze();
+            if buffer.capacity() > MAX_REUSED_BUFFER_BYTES {
+                *buffer = BytesMut::new();
+            }
+            // SAFETY: serde_json only writes valid UTF-8
+            Ok(unsafe { Utf8Bytes::from_bytes_unchecked(json) })
+        })
+    }
+
+    /// Sends an up-to-date notification for a chat
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `chat_id` - Binary chat identifier
+    /// * `head_nonce` - Highest nonce stored for the chat
+    async fn send_up_to_date(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &[u8],
+        head_nonce: usize,
+    ) {
+        let chat_id = encode_base64(chat_id).await;
+        if let Err(e) = self
+            .up_to_date_response(connection, &chat_id, head_nonce)
+            .await
+        {
+            log::error!("failed to send up-to-date response: {e}");
+        }
+    }
+
+    /// Tells the client that a history replay of a chat stopped after `last_delivered`
+    async fn send_sync_error(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &[u8],
+        last_delivered: usize,
+    ) {
+        let chat_id = encode_base64(chat_id).await;
+        if let Err(e) = self
+            .sync_error_response(connection, &chat_id, last_delivered)
+            .await
+        {
+            log::error!("failed to send sync error response: {e}");
+        }
     }
 }
 
 impl<T: MessagesDB> MessagesRepository for MessagesUseCase<T> {
     /// Sends a wait event response to the client
     ///
-    /// Notifies the client to wait for events on a specific chat.
+    /// Notifies the client to wait for events on a specific chat. With a
+    /// `wait_event_debounce` window, repeated wait events for the chat within
+    /// the window are dropped.
     ///
     /// # Arguments
     /// * `connection` - WebSocket connection to the client
@@ -50,18 +189,21 @@ impl<T: MessagesDB> MessagesRepository for MessagesUseCase<T> {
         connection: Arc<WebSocketConnection>,
         chat_id: &str,
     ) -> Result<()> {
+        // Coalesce repeated wait events, e.g. from quick resubscribes
+        if let Some(window) = self.config.wait_event_debounce
+            && !connection.claim_wait_event(chat_id, window)
+        {
+            return Ok(());
+        }
+
         let outgoing = SeedResponse::WaitEvent(WaitEventDetail {
             rtype: "wait".to_string(),
             chat_id: chat_id.to_string(),
         });
 
-        let mut session = connection.session.lock().await;
-
-        let message = serde_json::to_string(&outgoing)?;
-        let message = Message::Text(message.into());
+        self.send_response(&connection, Some(chat_id), &outgoing)
+            .await?;
 
-        session.send(message).await?;
-        
         Ok(())
     }
 
@@ -77,17 +219,17 @@ impl<T: MessagesDB> MessagesRepository for MessagesUseCase<T> {
         connection: Arc<WebSocketConnection>,
         message: protocol::entity::message::OutcomeMessage,
     ) -> Result<()> {
+        let (chat_id, nonce) = (message.chat_id.clone(), message.nonce);
         let outgoing = SeedResponse::NewEvent(entity::response::NewEventDetail {
             rtype: "new".to_string(),
             message: message.clone(),
         });
 
-        let mut session = connection.session.lock().await;
-
-        let message = serde_json::to_string(&outgoing)?;
-        let message = Message::Text(message.into());
+        self.send_response(&connection, Some(&chat_id), &outgoing)
+            .await?;
 
-        session.send(message).await?;
+        // Remember how far the client got so a resume continues from here
+        connection.record_delivery(&chat_id, nonce);
 
         Ok(())
     }
@@ -98,137 +240,1087 @@ impl<T: MessagesDB> MessagesRepository for MessagesUseCase<T> {
     ///
     /// # Arguments
     /// * `connection` - WebSocket connection to the client
-    /// * `status` - Status of the operation (true = success, false = failure)
+    /// * `outcome` - Success, or the code of the reason the operation failed
     async fn status_response(
         &self,
         connection: Arc<WebSocketConnection>,
-        status: bool,
+        outcome: Result<(), SeedErrorCode>,
     ) -> Result<()> {
-        let outgoing = SeedResponse::Status(entity::response::StatusResponse { status });
+        let outgoing = SeedResponse::Status(entity::response::StatusResponse::new(outcome));
 
-        let mut session = connection.session.lock().await;
+        self.send_response(&connection, None, &outgoing).await?;
+
+        Ok(())
+    }
+
+    /// Sends a send acknowledgement to the client
+    ///
+    /// Replaces the positive status of a send, so a client pipelining sends
+    /// knows exactly which message was accepted.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `chat_id` - Identifier of the chat the message was sent to
+    /// * `nonce` - Nonce of the accepted message
+    async fn send_ack_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+        nonce: usize,
+    ) -> Result<()> {
+        let outgoing = SeedResponse::SendAck(SendAckDetail {
+            chat_id: chat_id.to_string(),
+            nonce,
+        });
+
+        self.send_response(&connection, Some(chat_id), &outgoing)
+            .await?;
+
+        Ok(())
+    }
 
-        let message = serde_json::to_string(&outgoing)?;
-        let message = Message::Text(message.into());
-        session.send(message).await?;
+    /// Sends a pong response to the client
+    ///
+    /// Answers a ping without touching the status channel used for operation results.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    async fn pong_response(&self, connection: Arc<WebSocketConnection>) -> Result<()> {
+        self.send_response(&connection, None, &SeedResponse::Pong)
+            .await
+    }
+
+    /// Sends an up-to-date notification to the client
+    ///
+    /// Tells the client that a history replay found nothing newer than its cursor.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `chat_id` - Identifier for the chat session
+    /// * `head_nonce` - Highest nonce stored for the chat
+    async fn up_to_date_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+        head_nonce: usize,
+    ) -> Result<()> {
+        let outgoing = SeedResponse::UpToDate(UpToDateDetail {
+            chat_id: chat_id.to_string(),
+            head_nonce,
+        });
+
+        self.send_response(&connection, Some(chat_id), &outgoing)
+            .await?;
 
         Ok(())
     }
 
+    /// Sends a sync error notification to the client
+    ///
+    /// Tells the client that a history replay stopped early and where to resume it.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `chat_id` - Identifier for the chat session
+    /// * `last_delivered_nonce` - Nonce of the last message the replay delivered
+    async fn sync_error_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+        last_delivered_nonce: usize,
+    ) -> Result<()> {
+        let outgoing = SeedResponse::SyncError(SyncErrorDetail {
+            chat_id: chat_id.to_string(),
+            last_delivered_nonce,
+        });
+
+        self.send_response(&connection, Some(chat_id), &outgoing)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sends a subscribe complete response to the client
+    ///
+    /// Marks the end of the responses sent while subscribing to a chat.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `chat_id` - Identifier for the chat session
+    /// * `resume_token` - Token restoring the subscriptions of the connection, if issued
+    async fn subscribe_complete_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+        resume_token: Option<String>,
+    ) -> Result<()> {
+        let outgoing = SeedResponse::SubscribeComplete(SubscribeCompleteDetail {
+            chat_id: chat_id.to_string(),
+            resume_token,
+        });
+
+        self.send_response(&connection, Some(chat_id), &outgoing)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sends a chat closed response to the client
+    ///
+    /// Tells the client to stop expecting events for the chat.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `chat_id` - Identifier for the closed chat
+    async fn chat_closed_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+    ) -> Result<()> {
+        let outgoing = SeedResponse::ChatClosed(ChatClosedDetail {
+            chat_id: chat_id.to_string(),
+        });
+
+        self.send_response(&connection, Some(chat_id), &outgoing)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sends an error response to the client
+    ///
+    /// Tells the client why its input was rejected.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `code` - Reason for the rejection
+    async fn error_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        code: SeedErrorCode,
+    ) -> Result<()> {
+        let outgoing = SeedResponse::Error(ErrorResponse { code, quota: None });
+
+        self.send_response(&connection, None, &outgoing).await?;
+
+        Ok(())
+    }
+
+    /// Sends the sending allowance left to the client
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `quota` - Allowance left in the current window
+    async fn quota_status_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        quota: QuotaStatus,
+    ) -> Result<()> {
+        let outgoing = SeedResponse::QuotaStatus(quota);
+
+        self.send_response(&connection, None, &outgoing).await?;
+
+        Ok(())
+    }
+
+    /// Sends a rate limit error to the client
+    ///
+    /// The error carries the allowance left so the client knows when it may send again.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `quota` - Allowance left in the current window
+    async fn rate_limited_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        quota: QuotaStatus,
+    ) -> Result<()> {
+        let outgoing = SeedResponse::Error(ErrorResponse {
+            code: SeedErrorCode::RateLimited,
+            quota: Some(quota),
+        });
+
+        self.send_response(&connection, None, &outgoing).await?;
+
+        Ok(())
+    }
+
+    /// Sends the head nonce of each requested chat to the client
+    ///
+    /// Looks every chat up in a single query, chats without messages are
+    /// reported with a head nonce of 0.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `chat_ids` - Identifiers of the chats to look up
+    ///
+    /// # Errors
+    /// Sends an error response and fails if a chat ID is not valid base64
+    async fn head_nonces_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_ids: &[String],
+    ) -> Result<()> {
+        let mut decoded = Vec::with_capacity(chat_ids.len());
+        for chat_id in chat_ids {
+            match decode_field("chat id", chat_id.clone()).await {
+                Ok(chat_id) => decoded.push(chat_id),
+                Err(err) => {
+                    let _ = self.error_response(connection, err.code()).await;
+                    return Err(err.into());
+                }
+            }
+        }
+
+        let heads = self.db.head_nonces(&decoded).await?;
+        let heads = chat_ids
+            .iter()
+            .zip(&decoded)
+            .map(|(chat_id, decoded)| (chat_id.clone(), heads.get(decoded).copied().unwrap_or(0)))
+            .collect();
+        let outgoing = SeedResponse::HeadNonces(HeadNoncesDetail { heads });
+
+        self.send_response(&connection, None, &outgoing).await?;
+
+        Ok(())
+    }
+
+    /// Sends the stored messages of a chat the client does not have yet
+    ///
+    /// The nonces up to the chat's head that lie outside every declared range
+    /// are streamed in batches, sharing the connection's buffered history
+    /// frames with replays. An up-to-date notification carrying the head nonce
+    /// ends the diff.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `chat_id` - Identifier of the chat to sync
+    /// * `have_ranges` - Inclusive nonce ranges the client already has
+    ///
+    /// # Errors
+    /// Sends an error response and fails if the chat ID is not valid base64
+    async fn sync_diff_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &str,
+        have_ranges: &[(usize, usize)],
+    ) -> Result<()> {
+        let decoded = match decode_field("chat id", chat_id.to_string()).await {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                let _ = self.error_response(connection, err.code()).await;
+                return Err(err.into());
+            }
+        };
+
+        let head_nonce = self.db.last_nonce(&decoded).await?;
+        for (start, end) in missing_ranges(have_ranges, head_nonce) {
+            let mut current_nonce = start;
+            while current_nonce <= end {
+                let amount = MESSAGES_LIMIT.min(end - current_nonce + 1);
+                let mut messages = pin!(
+                    self.db
+                        .stream_history(&decoded, current_nonce, amount)
+                        .take_until(connection.closed())
+                );
+                let mut sent = 0;
+                loop {
+                    let limit = self.config.max_buffered_history_frames;
+                    let Some(_slot) = connection.reserve_history_frame(limit).await else {
+                        return Ok(());
+                    };
+                    // Deleted nonces let a batch run past the end of the gap
+                    let Some(msg) = messages.next().await else {
+                        break;
+                    };
+                    let msg = msg?;
+                    if msg.nonce > end {
+                        break;
+                    }
+                    sent += 1;
+                    current_nonce = msg.nonce + 1;
+                    self.new_event_response(connection.clone(), msg).await?;
+                }
+
+                if sent < amount {
+                    break;
+                }
+            }
+        }
+
+        self.up_to_date_response(connection, chat_id, head_nonce)
+            .await
+    }
+
     /// Sends unread messages to the client
     ///
-    /// Fetches and sends historical messages from the database in batches,
-    /// starting from the specified nonce value.
+    /// Streams historical messages from the database in batches, starting
+    /// from the specified nonce value, sending each message as it arrives.
+    /// Each batch starts after the last nonce of the previous one, so gaps in
+    /// the stored nonces neither skip nor repeat messages. If the replay yields
+    /// no messages at all, an up-to-date notification is sent instead. A start
+    /// beyond the head nonce of the chat is answered that way without reading
+    /// any history. The replay stops as soon as the connection starts closing,
+    /// pending sends are dropped. If the history cannot be read part way, a
+    /// sync error notification tells the client the last nonce it was sent.
+    /// Replays of all chats of a connection share `max_buffered_history_frames`
+    /// slots, so a slow reader throttles every replay.
     ///
     /// # Arguments
     /// * `connection` - WebSocket connection to the client
     /// * `chat_id` - Identifier for the chat session
     /// * `nonce` - Starting position for fetching messages
+    /// * `limit` - Messages fetched per batch, capped by `max_history_batch` (default: 100)
     async fn unread_message_response(
         &self,
         connection: Arc<WebSocketConnection>,
         chat_id: &[u8],
         nonce: usize,
+        limit: Option<usize>,
     ) {
+        let batch = limit
+            .unwrap_or(MESSAGES_LIMIT)
+            .clamp(1, self.config.max_history_batch.max(1));
         let mut current_nonce = nonce;
+        let mut last_delivered = nonce.saturating_sub(1);
+        let mut last_fetched = None;
+
+        // A client ahead of the stored head is caught up, there is nothing to stream
+        let head_nonce = match self.db.last_nonce(chat_id).await {
+            Ok(head_nonce) => Some(head_nonce),
+            Err(e) => {
+                log::error!("failed to fetch head nonce: {e}");
+                None
+            }
+        };
+        if let Some(head_nonce) = head_nonce.filter(|&head_nonce| nonce > head_nonce) {
+            if self.config.up_to_date_signal {
+                self.send_up_to_date(connection, chat_id, head_nonce).await;
+            }
+            return;
+        }
 
         loop {
-            // Fetch a batch of messages from the database
-            let messages = self
-                .db
-                .fetch_history(chat_id, current_nonce, MESSAGES_LIMIT)
-                .await;
-            let messages = match messages {
-                Ok(msg) => msg,
-                Err(e) => {
-                    log::error!("failed to fetch history: {e}");
+            // Stream a batch of messages from the database, sending each as it arrives
+            let mut messages = pin!(
+                self.db
+                    .stream_history(chat_id, current_nonce, batch)
+                    .take_until(connection.closed())
+            );
+            let mut sent = 0;
+            loop {
+                // Wait for room before taking another frame off the database
+                let limit = self.config.max_buffered_history_frames;
+                let Some(_slot) = connection.reserve_history_frame(limit).await else {
+                    break;
+                };
+                let Some(msg) = messages.next().await else {
                     break;
+                };
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        log::error!("failed to fetch history: {e}");
+                        if self.config.sync_error_signal {
+                            self.send_sync_error(connection.clone(), chat_id, last_delivered)
+                                .await;
+                        }
+                        return;
+                    }
+                };
+                sent += 1;
+                let msg_nonce = msg.nonce;
+                last_fetched = Some(msg_nonce);
+
+                // Don't send into a session that is being closed
+                tokio::select! {
+                    biased;
+                    _ = connection.closed() => break,
+                    result = self.new_event_response(connection.clone(), msg) => {
+                        match result {
+                            Ok(()) => last_delivered = msg_nonce,
+                            Err(e) => log::error!("failed to send history message: {e}"),
+                        }
+                    }
                 }
-            };
+            }
 
-            // Prepare futures for sending each message
-            let mut futures = Vec::new();
-            for msg in messages {
-                futures.push(self.new_event_response(connection.clone(), msg));
+            if connection.is_closed() {
+                log::debug!(
+                    "Stopped replaying history to closed connection {}",
+                    connection.id
+                );
+                return;
             }
 
-            // If we have fewer messages than the limit, this is the last batch
-            if futures.len() < MESSAGES_LIMIT {
-                futures::future::join_all(futures)
-                    .await
-                    .into_iter()
-                    .for_each(|r| {
-                        if let Err(e) = r {
-                            log::error!("failed to send history message: {e}");
-                        }
-                    });
+            // Tell the client explicitly when there was nothing to replay
+            if sent == 0 && current_nonce == nonce {
+                if let Some(head_nonce) = head_nonce.filter(|_| self.config.up_to_date_signal) {
+                    self.send_up_to_date(connection.clone(), chat_id, head_nonce)
+                        .await;
+                }
                 break;
-            };
+            }
 
-            // Process all message sending futures
-            futures::future::join_all(futures)
-                .await
-                .into_iter()
-                .for_each(|r| {
-                    if let Err(e) = r {
-                        log::error!("failed to send history message: {e}");
-                    }
-                });
+            // If we have fewer messages than the limit, this is the last batch
+            if sent < batch {
+                break;
+            }
 
-            // Move to the next batch of messages
+            // Continue right after the last message of this batch
             // Overflow check:
-            current_nonce = match current_nonce.checked_add(MESSAGES_LIMIT) {
+            current_nonce = match last_fetched.and_then(|last| last.checked_add(1)) {
                 // If no overflow occurred, update the nonce
                 Some(int) => int,
                 // If overflow occurred, send a status response and finish processing
                 None => {
-                    let _ = self.status_response(connection, false).await;
+                    let _ = self
+                        .status_response(connection.clone(), Err(SeedErrorCode::InvalidNonce))
+                        .await;
                     return;
                 }
             };
         }
     }
 
+    /// Sends the most recent messages of a chat to the client
+    ///
+    /// The requested amount is capped by `max_recent_context`.
+    ///
+    /// # Arguments
+    /// * `connection` - WebSocket connection to the client
+    /// * `chat_id` - Identifier for the chat session
+    /// * `amount` - Number of messages requested by the client
+    async fn recent_context_response(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        chat_id: &[u8],
+        amount: usize,
+    ) {
+        let amount = amount.min(self.config.max_recent_context);
+        if amount == 0 {
+            return;
+        }
+
+        let messages = match self.db.fetch_recent(chat_id, amount).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                log::error!("failed to fetch recent messages: {e}");
+                return;
+            }
+        };
+
+        // Deliver in order so the client sees the context as it was written
+        for msg in messages {
+            if let Err(e) = self.new_event_response(connection.clone(), msg).await {
+                log::error!("failed to send recent message: {e}");
+            }
+        }
+    }
+
     /// Validates message format and encoding
     ///
-    /// Checks if the message has properly encoded fields.
+    /// Checks that every binary field is valid base64, that fixed-size
+    /// fields decode to their configured length, that the content does
+    /// not exceed `max_message_bytes` and that the signature verifies. While
+    /// signatures are verified, messages without a nonce are rejected.
     ///
     /// # Arguments
     /// * `message` - Message to validate
     ///
     /// # Returns
-    /// * `bool` - true if message is valid, false otherwise
-    async fn is_valid_message(&self, message: entity::message::OutcomeMessage) -> bool {
-        // Validate chat_id
-        let chat_id = decode_base64(message.chat_id).await;
-        if chat_id.is_err() {
-            log::error!("invalid chat id");
-            return false;
+    /// * `Result<(), ValidationError>` - The first problem found in the message
+    async fn validate_message(
+        &self,
+        message: entity::message::OutcomeMessage,
+    ) -> Result<(), ValidationError> {
+        // Validate chat_id, bounding the keys clients can make us store
+        let chat_id = decode_field("chat id", message.chat_id).await?;
+        if chat_id.len() != self.config.chat_id_bytes {
+            return Err(ValidationError::InvalidChatId {
+                expected: self.config.chat_id_bytes,
+                actual: chat_id.len(),
+            });
         }
 
-        // Validate signature
-        let signature = decode_base64(message.signature).await;
-        if signature.is_err() {
-            log::error!("invalid signature");
-            return false;
+        let signature = decode_field("signature", message.signature).await?;
+
+        // Validate content size
+        let content = decode_field("content", message.content).await?;
+        if content.len() > self.config.max_message_bytes {
+            return Err(ValidationError::TooLarge {
+                field: "content",
+                limit: self.config.max_message_bytes,
+                actual: content.len(),
+            });
         }
 
         // Validate content initialization vector
-        let content_iv = decode_base64(message.content_iv).await;
-        if content_iv.is_err() {
-            log::error!("invalid content iv");
-            return false;
+        let content_iv = decode_field("content iv", message.content_iv).await?;
+        expect_length("content iv", &content_iv, self.config.content_iv_length)?;
+
+        // A replayed message without a nonce would be stored anew under the
+        // next nonce, so signed messages must name the nonce they are signed for
+        if message.nonce == entity::message::Message::AUTO_NONCE && self.verifier.is_enforcing() {
+            return Err(ValidationError::MissingNonce);
+        }
+
+        // Validate signature over the decoded fields
+        if !self
+            .verifier
+            .verify(&chat_id, message.nonce, &content, &content_iv, &signature)
+        {
+            return Err(ValidationError::InvalidSignature);
         }
 
-        true
+        Ok(())
     }
 
     /// Inserts a message into the database
     ///
     /// # Arguments
     /// * `message` - Message to be stored
-    async fn insert_message(&self, message: entity::message::Message) -> Result<()> {
-        self.db.insert_message(message).await?;
-        Ok(())
+    ///
+    /// # Returns
+    /// * `Result<InsertOutcome>` - Whether the message was stored or already present
+    async fn insert_message(&self, message: entity::message::Message) -> Result<InsertOutcome> {
+        self.db.insert_message(message).await
+    }
+}
+
+/// Returns the inclusive nonce ranges up to `head_nonce` not covered by `have_ranges`
+///
+/// Ranges whose start lies past their end are ignored.
+fn missing_ranges(have_ranges: &[(usize, usize)], head_nonce: usize) -> Vec<(usize, usize)> {
+    let mut have: Vec<_> = have_ranges
+        .iter()
+        .copied()
+        .filter(|(start, end)| start <= end)
+        .collect();
+    have.sort_unstable();
+
+    let mut missing = Vec::new();
+    let mut next = 1;
+    for (start, end) in have {
+        if next > head_nonce {
+            break;
+        }
+        if start > next {
+            missing.push((next, (start - 1).min(head_nonce)));
+        }
+        next = next.max(end.saturating_add(1));
+    }
+    if next <= head_nonce {
+        missing.push((next, head_nonce));
+    }
+
+    missing
+}
+
+/// Decodes a base64 message field, naming the field on failure
+async fn decode_field(field: &'static str, value: String) -> Result<Vec<u8>, ValidationError> {
+    decode_base64(value)
+        .await
+        .map_err(|_| ValidationError::InvalidEncoding(field))
+}
+
+/// Checks that a decoded field has exactly the expected number of bytes
+fn expect_length(
+    field: &'static str,
+    value: &[u8],
+    expected: usize,
+) -> Result<(), ValidationError> {
+    if value.len() != expected {
+        return Err(ValidationError::InvalidLength {
+            field,
+            expected,
+            actual: value.len(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    use super::*;
+    use crate::{
+        signature::{Ed25519Verifier, signed_payload},
+        test_utils::{MockDb, channel_connection, sent_responses},
+    };
+
+    /// Tests that replaying from beyond the head sends an up-to-date notification
+    /// without reading any history
+    #[tokio::test]
+    async fn test_replay_beyond_head_sends_up_to_date() {
+        let chat_id = encode_base64(b"chat").await;
+
+        for start in [4, 10, usize::MAX] {
+            let db = MockDb::with_history(&chat_id, &[1, 2, 3]);
+            let use_case = MessagesUseCase::new(db.clone(), Arc::new(SeedConfig::default()));
+            let (connection, mut rx) = channel_connection();
+
+            use_case
+                .unread_message_response(connection, b"chat", start, None)
+                .await;
+
+            let responses = sent_responses(&mut rx);
+            assert_eq!(responses.len(), 1);
+            assert_eq!(responses[0]["type"], "upToDate");
+            assert_eq!(responses[0]["response"]["queueId"], chat_id);
+            assert_eq!(responses[0]["response"]["headNonce"], 3);
+            // Only the head was looked up
+            assert_eq!(db.queries.load(Ordering::SeqCst), 1, "start {start}");
+        }
+    }
+
+    /// Tests that replaying from before the first message delivers the whole history
+    #[tokio::test]
+    async fn test_replay_before_first_delivers_everything() {
+        let chat_id = encode_base64(b"chat").await;
+        let db = MockDb::with_history(&chat_id, &[5, 6, 7]);
+        let use_case = MessagesUseCase::new(db, Arc::new(SeedConfig::default()));
+
+        for start in [0, 1] {
+            let (connection, mut rx) = channel_connection();
+
+            use_case
+                .unread_message_response(connection, b"chat", start, None)
+                .await;
+
+            let delivered: Vec<_> = sent_responses(&mut rx)
+                .iter()
+                .map(|r| r["response"]["message"]["nonce"].as_u64().unwrap())
+                .collect();
+            assert_eq!(delivered, [5, 6, 7], "start {start}");
+        }
+    }
+
+    /// Tests that replaying from the head delivers only the last message
+    #[tokio::test]
+    async fn test_replay_at_head_delivers_last_message() {
+        let chat_id = encode_base64(b"chat").await;
+        let db = MockDb::with_history(&chat_id, &[1, 2, 3]);
+        let use_case = MessagesUseCase::new(db, Arc::new(SeedConfig::default()));
+        let (connection, mut rx) = channel_connection();
+
+        use_case
+            .unread_message_response(connection, b"chat", 3, None)
+            .await;
+
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["response"]["type"], "new");
+        assert_eq!(responses[0]["response"]["message"]["nonce"], 3);
+    }
+
+    /// Tests that a history failure on a later batch reports where the replay stopped
+    #[tokio::test]
+    async fn test_failed_batch_sends_sync_error() {
+        let chat_id = encode_base64(b"chat").await;
+        let nonces: Vec<usize> = (1..=MESSAGES_LIMIT + 10).collect();
+        let db = MockDb {
+            history_failures_after: Some(1),
+            ..MockDb::with_history(&chat_id, &nonces)
+        };
+        let use_case = MessagesUseCase::new(db, Arc::new(SeedConfig::default()));
+        let (connection, mut rx) = channel_connection();
+
+        use_case
+            .unread_message_response(connection, b"chat", 1, None)
+            .await;
+
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses.len(), MESSAGES_LIMIT + 1);
+        let sync_error = &responses[MESSAGES_LIMIT];
+        assert_eq!(sync_error["type"], "syncError");
+        assert_eq!(sync_error["response"]["queueId"], chat_id);
+        assert_eq!(sync_error["response"]["lastDeliveredNonce"], MESSAGES_LIMIT);
+    }
+
+    /// Tests that a replay over gaps in the nonces delivers every message exactly once
+    #[tokio::test]
+    async fn test_sparse_history_is_replayed_once() {
+        let chat_id = encode_base64(b"chat").await;
+        // A few gaps within one batch, and gaps across batch boundaries
+        let across_batches: Vec<usize> = (1..=3 * MESSAGES_LIMIT).step_by(2).collect();
+        for nonces in [vec![1, 2, 5, 6], across_batches] {
+            let db = MockDb::with_history(&chat_id, &nonces);
+            let use_case = MessagesUseCase::new(db, Arc::new(SeedConfig::default()));
+            let (connection, mut rx) = channel_connection();
+
+            use_case
+                .unread_message_response(connection, b"chat", 1, None)
+                .await;
+
+            let delivered: Vec<_> = sent_responses(&mut rx)
+                .iter()
+                .map(|r| r["response"]["message"]["nonce"].as_u64().unwrap() as usize)
+                .collect();
+            assert_eq!(delivered, nonces);
+        }
+    }
+
+    /// Tests that the replay batch size defaults to 100, follows the client and is capped
+    #[tokio::test]
+    async fn test_replay_batch_size() {
+        let chat_id = encode_base64(b"chat").await;
+        let nonces: Vec<usize> = (1..=250).collect();
+        let config = Arc::new(SeedConfig {
+            max_history_batch: 200,
+            ..Default::default()
+        });
+
+        // One head lookup, then every batch but the last is full and an exact
+        // multiple needs one more empty query
+        for (limit, queries) in [(None, 4), (Some(50), 7), (Some(200), 3), (Some(1000), 3)] {
+            let db = MockDb::with_history(&chat_id, &nonces);
+            let use_case = MessagesUseCase::new(db.clone(), config.clone());
+            let (connection, mut rx) = channel_connection();
+
+            use_case
+                .unread_message_response(connection, b"chat", 1, limit)
+                .await;
+
+            assert_eq!(sent_responses(&mut rx).len(), nonces.len());
+            assert_eq!(
+                db.queries.load(Ordering::SeqCst),
+                queries,
+                "limit {limit:?}"
+            );
+        }
+    }
+
+    /// Tests that replays of several chats to a slow reader share the buffered frame limit
+    #[tokio::test]
+    async fn test_slow_reader_throttles_replays_of_all_chats() {
+        let chats: [&[u8]; 3] = [b"first", b"second", b"third"];
+        let db = MockDb::default();
+        for chat in chats {
+            let chat_id = encode_base64(chat).await;
+            let history = MockDb::with_history(&chat_id, &[1, 2, 3, 4, 5]);
+            let history = history.messages.lock().unwrap().clone();
+            db.messages.lock().unwrap().extend(history);
+        }
+        let config = SeedConfig {
+            max_buffered_history_frames: 2,
+            ..Default::default()
+        };
+        let use_case = MessagesUseCase::new(db, Arc::new(config));
+        let delivered = Arc::new(AtomicUsize::new(0));
+        // The client reads a frame every few milliseconds
+        let sink = futures::sink::unfold(delivered.clone(), |delivered, _: Message| async move {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            delivered.fetch_add(1, Ordering::SeqCst);
+            Ok(delivered)
+        });
+        let connection = Arc::new(WebSocketConnection::from_parts(
+            Box::pin(sink),
+            Box::pin(futures::stream::pending()),
+        ));
+
+        let replays = futures::future::join_all(
+            chats.map(|chat| use_case.unread_message_response(connection.clone(), chat, 1, None)),
+        );
+        let mut replays = pin!(replays);
+        let mut peak = 0;
+        loop {
+            tokio::select! {
+                _ = &mut replays => break,
+                _ = tokio::time::sleep(Duration::from_millis(1)) => {
+                    peak = peak.max(connection.buffered_history_frames());
+                }
+            }
+        }
+
+        assert!((1..=2).contains(&peak), "peak of {peak} buffered frames");
+        assert_eq!(connection.buffered_history_frames(), 0);
+        assert_eq!(delivered.load(Ordering::SeqCst), 15);
+    }
+
+    /// Builds a message whose chat id and content iv decode to the given lengths
+    async fn message_with_lengths(
+        chat_id: usize,
+        content_iv: usize,
+    ) -> entity::message::OutcomeMessage {
+        entity::message::OutcomeMessage {
+            nonce: 1,
+            chat_id: encode_base64(&vec![7; chat_id]).await,
+            signature: encode_base64(b"signature").await,
+            content: encode_base64(b"content").await,
+            content_iv: encode_base64(&vec![9; content_iv]).await,
+            ..Default::default()
+        }
+    }
+
+    /// Tests that fields decoding to their configured lengths are accepted
+    #[tokio::test]
+    async fn test_validate_message_accepts_expected_lengths() {
+        let use_case = MessagesUseCase::new(MockDb::default(), Arc::new(SeedConfig::default()));
+
+        let result = use_case
+            .validate_message(message_with_lengths(32, 12).await)
+            .await;
+
+        assert_eq!(result, Ok(()));
+    }
+
+    /// Returns a use case verifying Ed25519 signatures with the key of the test chat
+    fn ed25519_use_case() -> (MessagesUseCase<MockDb>, Ed25519KeyPair) {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[5; 32]).unwrap();
+        let keys = HashMap::from([(vec![7; 32], key_pair.public_key().as_ref().to_vec())]);
+        let use_case = MessagesUseCase::new(MockDb::default(), Arc::new(SeedConfig::default()))
+            .with_verifier(Arc::new(Ed25519Verifier::new(keys)));
+        (use_case, key_pair)
+    }
+
+    /// Tests that a message signed with the chat's key is accepted
+    #[tokio::test]
+    async fn test_validate_message_accepts_valid_signature() {
+        let (use_case, key_pair) = ed25519_use_case();
+        let mut message = message_with_lengths(32, 12).await;
+        let payload = signed_payload(&[7; 32], message.nonce, b"content", &[9; 12]);
+        message.signature = encode_base64(key_pair.sign(&payload).as_ref()).await;
+
+        assert_eq!(use_case.validate_message(message).await, Ok(()));
+    }
+
+    /// Tests that a signature over other fields or from another key is rejected
+    #[tokio::test]
+    async fn test_validate_message_rejects_bad_signature() {
+        let (use_case, key_pair) = ed25519_use_case();
+        let other_key = Ed25519KeyPair::from_seed_unchecked(&[6; 32]).unwrap();
+        let payload = signed_payload(&[7; 32], 1, b"content", &[9; 12]);
+        let tampered = signed_payload(&[7; 32], 2, b"content", &[9; 12]);
+
+        for signature in [key_pair.sign(&tampered), other_key.sign(&payload)] {
+            let mut message = message_with_lengths(32, 12).await;
+            message.signature = encode_base64(signature.as_ref()).await;
+
+            let result = use_case.validate_message(message).await;
+            assert_eq!(result, Err(ValidationError::InvalidSignature));
+            assert_eq!(result.unwrap_err().code(), SeedErrorCode::InvalidSignature);
+        }
+
+        // Chats without a registered key accept no signature at all
+        let mut message = message_with_lengths(32, 12).await;
+        message.chat_id = encode_base64(&[8; 32]).await;
+        message.signature = encode_base64(key_pair.sign(&payload).as_ref()).await;
+        let result = use_case.validate_message(message).await;
+        assert_eq!(result, Err(ValidationError::InvalidSignature));
+    }
+
+    /// Tests that a validly signed message without a nonce is rejected while signatures are verified
+    #[tokio::test]
+    async fn test_validate_message_rejects_signed_auto_nonce() {
+        let (use_case, key_pair) = ed25519_use_case();
+        let mut message = message_with_lengths(32, 12).await;
+        message.nonce = entity::message::Message::AUTO_NONCE;
+        let payload = signed_payload(&[7; 32], message.nonce, b"content", &[9; 12]);
+        message.signature = encode_base64(key_pair.sign(&payload).as_ref()).await;
+
+        let result = use_case.validate_message(message).await;
+        assert_eq!(result, Err(ValidationError::MissingNonce));
+        assert_eq!(result.unwrap_err().code(), SeedErrorCode::InvalidNonce);
+    }
+
+    /// Tests that without a verifier any well-formed signature is accepted
+    #[tokio::test]
+    async fn test_validate_message_noop_verifier_accepts_any_signature() {
+        let use_case = MessagesUseCase::new(MockDb::default(), Arc::new(SeedConfig::default()))
+            .with_verifier(Arc::new(NoopVerifier));
+        let mut message = message_with_lengths(32, 12).await;
+        message.signature = encode_base64(&[0; 64]).await;
+        assert_eq!(use_case.validate_message(message.clone()).await, Ok(()));
+
+        // Without signatures the server may still assign the nonce
+        message.nonce = entity::message::Message::AUTO_NONCE;
+        assert_eq!(use_case.validate_message(message).await, Ok(()));
+    }
+
+    /// Tests that a content iv shorter than configured is rejected
+    #[tokio::test]
+    async fn test_validate_message_rejects_short_iv() {
+        let use_case = MessagesUseCase::new(MockDb::default(), Arc::new(SeedConfig::default()));
+
+        let result = use_case
+            .validate_message(message_with_lengths(32, 1).await)
+            .await;
+
+        assert_eq!(
+            result,
+            Err(ValidationError::InvalidLength {
+                field: "content iv",
+                expected: 12,
+                actual: 1,
+            })
+        );
+    }
+
+    /// Tests that a chat id longer than configured is rejected
+    #[tokio::test]
+    async fn test_validate_message_rejects_long_chat_id() {
+        let use_case = MessagesUseCase::new(MockDb::default(), Arc::new(SeedConfig::default()));
+
+        let result = use_case
+            .validate_message(message_with_lengths(33, 12).await)
+            .await;
+
+        assert_eq!(
+            result,
+            Err(ValidationError::InvalidChatId {
+                expected: 32,
+                actual: 33,
+            })
+        );
+        assert_eq!(result.unwrap_err().code(), SeedErrorCode::InvalidChatId);
+    }
+
+    /// Tests that a chat id shorter than configured is rejected
+    #[tokio::test]
+    async fn test_validate_message_rejects_short_chat_id() {
+        let config = SeedConfig {
+            chat_id_bytes: 16,
+            ..Default::default()
+        };
+        let use_case = MessagesUseCase::new(MockDb::default(), Arc::new(config));
+
+        let accepted = use_case
+            .validate_message(message_with_lengths(16, 12).await)
+            .await;
+        let rejected = use_case
+            .validate_message(message_with_lengths(15, 12).await)
+            .await;
+
+        assert_eq!(accepted, Ok(()));
+        assert_eq!(
+            rejected,
+            Err(ValidationError::InvalidChatId {
+                expected: 16,
+                actual: 15,
+            })
+        );
+    }
+
+    /// Tests that the byte counters match the serialized payloads sent to the connection
+    #[tokio::test]
+    async fn test_bytes_sent_match_serialized_payloads() {
+        let use_case = MessagesUseCase::new(MockDb::default(), Arc::new(SeedConfig::default()));
+        let (connection, mut rx) = channel_connection();
+        let chat_id = encode_base64(b"chat").await;
+
+        for nonce in 1..=3 {
+            let message = entity::message::OutcomeMessage {
+                nonce,
+                chat_id: chat_id.clone(),
+                content: encode_base64(&vec![1; nonce * 10]).await,
+                ..Default::default()
+            };
+            use_case
+                .new_event_response(connection.clone(), message)
+                .await
+                .unwrap();
+        }
+        use_case
+            .status_response(connection.clone(), Ok(()))
+            .await
+            .unwrap();
+
+        let mut sizes = Vec::new();
+        while let Ok(Message::Text(text)) = rx.try_recv() {
+            sizes.push(text.len() as u64);
+        }
+        assert_eq!(sizes.len(), 4);
+        assert_eq!(connection.bytes_sent(), sizes.iter().sum::<u64>());
+        // The status response belongs to no chat
+        assert_eq!(
+            connection.chat_bytes_sent(),
+            [(chat_id, sizes[..3].iter().sum::<u64>())]
+        );
+    }
+
+    /// Tests that lean mode leaves the empty fields of a system message out
+    #[tokio::test]
+    async fn test_lean_mode_omits_empty_fields() {
+        let message = entity::message::OutcomeMessage {
+            nonce: 1,
+            chat_id: "chat".to_string(),
+            ..Default::default()
+        };
+
+        for (lean, keys) in [
+            (
+                false,
+                vec!["content", "contentIV", "nonce", "queueId", "signature"],
+            ),
+            (true, vec!["nonce", "queueId"]),
+        ] {
+            let config = SeedConfig {
+                lean_messages: lean,
+                ..Default::default()
+            };
+            let use_case = MessagesUseCase::new(MockDb::default(), Arc::new(config));
+            let (connection, mut rx) = channel_connection();
+
+            use_case
+                .new_event_response(connection, message.clone())
+                .await
+                .unwrap();
+
+            let responses = sent_responses(&mut rx);
+            let sent = responses[0]["response"]["message"].as_object().unwrap();
+            let mut sent_keys: Vec<_> = sent.keys().map(String::as_str).collect();
+            sent_keys.sort();
+            assert_eq!(sent_keys, keys);
+        }
+    }
+
+    /// Tests that the reused buffer produces the same bytes as a fresh string for every shape
+    #[test]
+    fn test_reused_buffer_matches_fresh_serialization() {
+        let responses = [
+            SeedResponse::NewEvent(entity::response::NewEventDetail {
+                rtype: "new".to_string(),
+                message: entity::message::OutcomeMessage {
+                    nonce: 1,
+                    chat_id: "chat".to_string(),
+                    ..Default::default()
+                },
+            }),
+            SeedResponse::UpToDate(UpToDateDetail {
+                chat_id: "chat".to_string(),
+                head_nonce: 1,
+            }),
+            SeedResponse::Pong,
+        ];
+
+        for lean in [false, true] {
+            let use_case = |reuse| {
+                let config = SeedConfig {
+                    lean_messages: lean,
+                    reuse_serialization_buffer: reuse,
+                    ..Default::default()
+                };
+                MessagesUseCase::new(MockDb::default(), Arc::new(config))
+            };
+            let (fresh, reused) = (use_case(false), use_case(true));
+
+            for version in [ProtocolVersion::V1, ProtocolVersion::V2] {
+                for response in &responses {
+                    assert_eq!(
+                        reused.serialize(response, version).unwrap(),
+                        fresh.serialize(response, version).unwrap(),
+                    );
+                }
+            }
+        }
     }
 }
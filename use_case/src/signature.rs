@@ -0,0 +1,189 @@
+use std::{collections::HashMap, env::var, fmt, str::FromStr, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use misc::base64::decode_base64;
+use ring::signature::{ED25519, UnparsedPublicKey};
+
+/// How message signatures are checked before a send is accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SignatureMode {
+    /// Signatures are stored without being checked, see [`NoopVerifier`]
+    #[default]
+    None,
+    /// Signatures must verify against the chat's key, see [`Ed25519Verifier`]
+    Ed25519,
+}
+
+impl FromStr for SignatureMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "ed25519" => Ok(Self::Ed25519),
+            other => Err(anyhow!(
+                "unknown signature mode {other:?}, expected none or ed25519"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SignatureMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Ed25519 => "ed25519",
+        })
+    }
+}
+
+/// Checks the signature of a message before it is accepted
+///
+/// All fields are passed decoded from base64.
+pub trait SignatureVerifier: Send + Sync {
+    /// Returns whether `signature` is valid for the message
+    fn verify(
+        &self,
+        chat_id: &[u8],
+        nonce: usize,
+        content: &[u8],
+        content_iv: &[u8],
+        signature: &[u8],
+    ) -> bool;
+
+    /// Returns whether signatures are actually checked
+    ///
+    /// A signature covers the nonce the client sent, so while signatures are
+    /// checked messages must not leave their nonce for the server to assign.
+    fn is_enforcing(&self) -> bool {
+        true
+    }
+}
+
+/// Verifier that accepts every signature
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopVerifier;
+
+impl SignatureVerifier for NoopVerifier {
+    fn verify(&self, _: &[u8], _: usize, _: &[u8], _: &[u8], _: &[u8]) -> bool {
+        true
+    }
+
+    fn is_enforcing(&self) -> bool {
+        false
+    }
+}
+
+/// Source of the public key messages of a chat are signed with
+pub trait PublicKeys: Send + Sync {
+    /// Returns the raw public key of a chat, if it has one
+    fn public_key(&self, chat_id: &[u8]) -> Option<&[u8]>;
+}
+
+impl PublicKeys for HashMap<Vec<u8>, Vec<u8>> {
+    fn public_key(&self, chat_id: &[u8]) -> Option<&[u8]> {
+        self.get(chat_id).map(Vec::as_slice)
+    }
+}
+
+/// Verifier checking Ed25519 signatures against a per-chat public key
+///
+/// Messages to chats without a key are rejected.
+pub struct Ed25519Verifier<K: PublicKeys = HashMap<Vec<u8>, Vec<u8>>> {
+    /// Public keys of the chats messages are accepted for
+    keys: K,
+}
+
+impl<K: PublicKeys> Ed25519Verifier<K> {
+    /// Creates a verifier looking up chat keys in `keys`
+    pub fn new(keys: K) -> Self {
+        Self { keys }
+    }
+}
+
+impl Ed25519Verifier {
+    /// Creates a verifier with the chat keys listed in the environment
+    ///
+    /// # Errors
+    /// Will return an error if an entry is not a pair of base64 values
+    ///
+    /// # Environment Variables
+    /// - `SIGNATURE_KEYS` - Comma separated `chatId:publicKey` pairs, both base64 (default: none)
+    pub async fn from_env() -> Result<Self> {
+        let mut keys = HashMap::new();
+        for entry in var("SIGNATURE_KEYS").unwrap_or_default().split(',') {
+            let entry = entry.trim();
+            if entry.is_empty() {
+                continue;
+            }
+            let (chat_id, key) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow!("signature key entry {entry:?} is not chatId:publicKey"))?;
+            keys.insert(
+                decode_base64(chat_id.to_string()).await?,
+                decode_base64(key.to_string()).await?,
+            );
+        }
+        if keys.is_empty() {
+            warn!("SIGNATURE_KEYS lists no chats, every message will be rejected");
+        }
+
+        Ok(Self::new(keys))
+    }
+}
+
+impl<K: PublicKeys> SignatureVerifier for Ed25519Verifier<K> {
+    fn verify(
+        &self,
+        chat_id: &[u8],
+        nonce: usize,
+        content: &[u8],
+        content_iv: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        let Some(key) = self.keys.public_key(chat_id) else {
+            return false;
+        };
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(
+                &signed_payload(chat_id, nonce, content, content_iv),
+                signature,
+            )
+            .is_ok()
+    }
+}
+
+/// Returns the bytes a message signature covers
+///
+/// The chat ID, the nonce as a big-endian `u64`, the content and the
+/// content IV are concatenated. Chat IDs and IVs have a fixed length, so the
+/// layout is unambiguous.
+pub fn signed_payload(chat_id: &[u8], nonce: usize, content: &[u8], content_iv: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(chat_id.len() + 8 + content.len() + content_iv.len());
+    payload.extend_from_slice(chat_id);
+    payload.extend_from_slice(&(nonce as u64).to_be_bytes());
+    payload.extend_from_slice(content);
+    payload.extend_from_slice(content_iv);
+    payload
+}
+
+/// Creates the verifier selected by the environment
+///
+/// # Errors
+/// Will return an error if the mode is unknown or its keys cannot be read
+///
+/// # Environment Variables
+/// - `SIGNATURE_MODE` - One of `none` or `ed25519` (default: "none")
+pub async fn verifier_from_env() -> Result<Arc<dyn SignatureVerifier>> {
+    let mode = match var("SIGNATURE_MODE") {
+        Ok(value) => value.parse()?,
+        Err(_) => SignatureMode::default(),
+    };
+    info!("Using the {mode} signature mode");
+
+    Ok(match mode {
+        SignatureMode::None => Arc::new(NoopVerifier),
+        SignatureMode::Ed25519 => Arc::new(Ed25519Verifier::from_env().await?),
+    })
+}
@@ -0,0 +1,274 @@
+//! Shared fixtures for the use case tests
+#![allow(clippy::unwrap_used)]
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+use futures::{SinkExt, Stream, TryStreamExt, channel::mpsc, stream};
+use misc::base64::encode_base64;
+use protocol::{
+    entity::{
+        message::{Message, OutcomeMessage},
+        websocket::{ControlAction, WebSocketConnection},
+    },
+    error::SeedError,
+};
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::{self, Error as WsError};
+use traits::message::{InsertOutcome, MessagesDB};
+
+use crate::{config::SeedConfig, messages::MessagesUseCase, websocket::WebSocketUseCase};
+use uuid::Uuid;
+
+/// In-memory message store that counts the queries it receives
+#[derive(Clone, Default)]
+pub struct MockDb {
+    /// Messages stored so far, in insertion order
+    pub messages: Arc<Mutex<Vec<OutcomeMessage>>>,
+    /// Number of read queries executed
+    pub queries: Arc<AtomicUsize>,
+    /// Number of history streams currently being fetched
+    pub active_streams: Arc<AtomicUsize>,
+    /// Highest number of history streams fetched at the same time
+    pub peak_streams: Arc<AtomicUsize>,
+    /// Time each history stream takes before yielding its first page
+    pub stream_delay: Option<Duration>,
+    /// Control events recorded so far, oldest first
+    pub control_events: Arc<Mutex<Vec<(Uuid, String, ControlAction)>>>,
+    /// Number of upcoming inserts that panic instead of storing the message
+    pub insert_panics: Arc<AtomicUsize>,
+    /// Number of history streams that succeed before every later one fails
+    pub history_failures_after: Option<usize>,
+    /// Number of history streams started so far
+    pub streams: Arc<AtomicUsize>,
+    /// Whether pings fail as if the database were unreachable
+    pub unhealthy: bool,
+}
+
+impl MockDb {
+    /// Creates a store pre-populated with messages for `chat_id` at the given nonces
+    pub fn with_history(chat_id: &str, nonces: &[usize]) -> Self {
+        let db = Self::default();
+        db.messages
+            .lock()
+            .unwrap()
+            .extend(nonces.iter().map(|&nonce| OutcomeMessage {
+                nonce,
+                chat_id: chat_id.to_string(),
+                ..Default::default()
+            }));
+        db
+    }
+}
+
+impl MessagesDB for MockDb {
+    async fn insert_message(&self, message: Message) -> Result<InsertOutcome> {
+        let panics = self
+            .insert_panics
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if panics.is_ok() {
+            panic!("injected insert panic");
+        }
+
+        let mut messages = self.messages.lock().unwrap();
+        let head = messages
+            .iter()
+            .filter(|m| m.chat_id == message.chat_id)
+            .map(|m| m.nonce)
+            .max()
+            .unwrap_or(0);
+        let nonce = head + 1;
+        if !message.has_auto_nonce() && message.nonce != nonce {
+            return Err(anyhow!(SeedError::InvalidNonce));
+        }
+
+        messages.push(OutcomeMessage {
+            nonce,
+            ..message.into()
+        });
+        Ok(InsertOutcome::Inserted(nonce))
+    }
+
+    async fn fetch_history(
+        &self,
+        chat_id: &[u8],
+        nonce: usize,
+        amount: usize,
+    ) -> Result<Vec<OutcomeMessage>> {
+        self.queries.fetch_add(1, Ordering::SeqCst);
+        let chat_id = encode_base64(chat_id).await;
+
+        let mut messages: Vec<OutcomeMessage> = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.chat_id == chat_id && m.nonce >= nonce)
+            .cloned()
+            .collect();
+        messages.sort_by_key(|m| m.nonce);
+        messages.truncate(amount);
+
+        Ok(messages)
+    }
+
+    fn stream_history<'a>(
+        &'a self,
+        chat_id: &'a [u8],
+        nonce: usize,
+        amount: usize,
+    ) -> impl Stream<Item = Result<OutcomeMessage>> + Send + 'a {
+        let failed = self
+            .history_failures_after
+            .is_some_and(|after| self.streams.fetch_add(1, Ordering::SeqCst) >= after);
+        let page = async move {
+            if failed {
+                return Err(anyhow!("injected history failure"));
+            }
+            let active = self.active_streams.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak_streams.fetch_max(active, Ordering::SeqCst);
+            if let Some(delay) = self.stream_delay {
+                tokio::time::sleep(delay).await;
+            }
+            let page = self.fetch_history(chat_id, nonce, amount).await;
+            self.active_streams.fetch_sub(1, Ordering::SeqCst);
+            page
+        };
+
+        stream::once(page)
+            .map_ok(|messages| stream::iter(messages.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    async fn fetch_recent(&self, chat_id: &[u8], amount: usize) -> Result<Vec<OutcomeMessage>> {
+        self.queries.fetch_add(1, Ordering::SeqCst);
+        let chat_id = encode_base64(chat_id).await;
+
+        let mut messages: Vec<OutcomeMessage> = self
+            .messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.chat_id == chat_id)
+            .cloned()
+            .collect();
+        messages.sort_by_key(|m| m.nonce);
+        let skip = messages.len().saturating_sub(amount);
+
+        Ok(messages.split_off(skip))
+    }
+
+    async fn last_nonce(&self, chat_id: &[u8]) -> Result<usize> {
+        self.queries.fetch_add(1, Ordering::SeqCst);
+        let chat_id = encode_base64(chat_id).await;
+
+        let messages = self.messages.lock().unwrap();
+        let head = messages
+            .iter()
+            .filter(|m| m.chat_id == chat_id)
+            .map(|m| m.nonce)
+            .max();
+
+        Ok(head.unwrap_or(0))
+    }
+
+    async fn head_nonces(&self, chat_ids: &[Vec<u8>]) -> Result<HashMap<Vec<u8>, usize>> {
+        self.queries.fetch_add(1, Ordering::SeqCst);
+
+        let mut heads = HashMap::new();
+        for chat_id in chat_ids {
+            let encoded = encode_base64(chat_id).await;
+            let messages = self.messages.lock().unwrap();
+            let head = messages
+                .iter()
+                .filter(|m| m.chat_id == encoded)
+                .map(|m| m.nonce)
+                .max();
+            if let Some(head) = head {
+                heads.insert(chat_id.clone(), head);
+            }
+        }
+
+        Ok(heads)
+    }
+
+    async fn record_control_event(
+        &self,
+        connection_id: Uuid,
+        chat_id: &str,
+        action: ControlAction,
+    ) -> Result<()> {
+        self.control_events
+            .lock()
+            .unwrap()
+            .push((connection_id, chat_id.to_string(), action));
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        if self.unhealthy {
+            return Err(anyhow!("injected database outage"));
+        }
+        Ok(())
+    }
+}
+
+/// Creates a WebSocket use case over `db` using the given configuration
+pub async fn websocket_use_case(
+    db: MockDb,
+    config: SeedConfig,
+) -> WebSocketUseCase<MessagesUseCase<MockDb>> {
+    let config = Arc::new(config);
+    let messages = MessagesUseCase::new(db, config.clone());
+    WebSocketUseCase::new(messages, config).await
+}
+
+/// Creates a connection whose sent frames are forwarded to the returned receiver
+///
+/// The connection never receives any frames.
+pub fn channel_connection() -> (
+    Arc<WebSocketConnection>,
+    mpsc::UnboundedReceiver<tungstenite::Message>,
+) {
+    let (tx, rx) = mpsc::unbounded();
+    let sink = tx.sink_map_err(|_| WsError::ConnectionClosed);
+    let connection =
+        WebSocketConnection::from_parts(Box::pin(sink), Box::pin(futures::stream::pending()));
+
+    (Arc::new(connection), rx)
+}
+
+/// Creates a connection that receives `incoming` and then reports the stream as ended
+///
+/// Frames sent on the connection are forwarded to the returned receiver.
+pub fn scripted_connection(
+    incoming: Vec<tungstenite::Message>,
+) -> (
+    WebSocketConnection,
+    mpsc::UnboundedReceiver<tungstenite::Message>,
+) {
+    let (tx, rx) = mpsc::unbounded();
+    let sink = tx.sink_map_err(|_| WsError::ConnectionClosed);
+    let stream = futures::stream::iter(incoming.into_iter().map(Ok));
+    let connection = WebSocketConnection::from_parts(Box::pin(sink), Box::pin(stream));
+
+    (connection, rx)
+}
+
+/// Drains every frame received so far, parsing text frames as JSON
+pub fn sent_responses(rx: &mut mpsc::UnboundedReceiver<tungstenite::Message>) -> Vec<Value> {
+    let mut responses = Vec::new();
+    while let Ok(frame) = rx.try_recv() {
+        if let tungstenite::Message::Text(text) = frame {
+            responses.push(serde_json::from_str(&text).unwrap());
+        }
+    }
+    responses
+}
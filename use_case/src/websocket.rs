@@ -1,101 +1,472 @@
-use std::sync::Arc;
+use std::{any::Any, panic::AssertUnwindSafe, sync::Arc};
 
-use log::{error, info};
+use futures::{FutureExt, SinkExt, StreamExt};
+use log::{debug, error, info, warn};
+use misc::base64::canonical_base64;
+use prometheus::IntCounter;
 
-use traits::{message::MessagesRepository, websocket::WebsocketRepository};
+use traits::{
+    message::{InsertOutcome, MessagesRepository},
+    websocket::WebsocketRepository,
+};
 
-use protocol::entity::{
-    message::{IncomeMessage, OutcomeMessage},
-    websocket::{WebSocketConnection, WebSocketManager},
+use protocol::{
+    entity::{
+        message::{ClusterEvent, IncomeMessage, OutcomeMessage},
+        websocket::{
+            BroadcastReport, ConnectedMessage, DisconnectReason, SeedCloseReason,
+            WebSocketConnection, WebSocketManager,
+        },
+    },
+    error::SeedError,
+};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::{
+    Message,
+    protocol::{CloseFrame, frame::coding::CloseCode},
 };
 
+use crate::{cluster::ClusterLink, config::SeedConfig};
+
+/// Counters the use case advances while delivering messages
+///
+/// The counters are not registered anywhere, the service exposing the
+/// metrics registers them in its own registry.
+#[derive(Clone)]
+pub struct DeliveryCounters {
+    /// Messages stored by the chat message processors
+    pub persisted: IntCounter,
+    /// Broadcast sends that failed after every retry
+    pub broadcast_failures: IntCounter,
+}
+
+impl Default for DeliveryCounters {
+    fn default() -> Self {
+        Self {
+            persisted: IntCounter::new("seed_messages_persisted_total", "Messages stored")
+                .expect("metric options are valid"),
+            broadcast_failures: IntCounter::new(
+                "seed_broadcast_failures_total",
+                "Events that could not be delivered to a subscriber",
+            )
+            .expect("metric options are valid"),
+        }
+    }
+}
+
+/// How sending an event to a single subscriber ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendOutcome {
+    /// The event reached the subscriber
+    Delivered,
+    /// Every attempt failed
+    Failed,
+    /// An attempt did not complete within `broadcast_send_timeout`
+    TimedOut,
+}
+
 /// WebSocketUseCase handles WebSocket communication and message processing
 /// for chat functionality. It manages connections, subscriptions, and message
 /// broadcasting.
 ///
 /// Type parameter `T` represents a repository implementation for message persistence.
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct WebSocketUseCase<T: MessagesRepository> {
     /// Repository for storing and retrieving messages
     messages_repository: T,
+    /// Runtime configuration
+    config: Arc<SeedConfig>,
+    /// Counters shared by every clone of the use case
+    counters: DeliveryCounters,
+    /// Link broadcasts are relayed to other instances through, in cluster mode
+    cluster: Option<ClusterLink>,
 }
 
-impl<T: MessagesRepository> WebSocketUseCase<T> {
+impl<T: MessagesRepository + Clone + 'static> WebSocketUseCase<T> {
     /// Creates a new WebSocketUseCase instance with the provided message repository
     ///
     /// # Arguments
     /// * `messages_repository` - Repository implementation for message handling
+    /// * `config` - Runtime configuration
     ///
     /// # Returns
     /// A new WebSocketUseCase instance
-    pub async fn new(messages_repository: T) -> Self {
+    pub async fn new(messages_repository: T, config: Arc<SeedConfig>) -> Self {
         Self {
             messages_repository,
+            config,
+            counters: DeliveryCounters::default(),
+            cluster: None,
+        }
+    }
+
+    /// Relays every broadcast to the other instances of a cluster through `link`
+    pub fn with_cluster(mut self, link: ClusterLink) -> Self {
+        self.cluster = Some(link);
+        self
+    }
+
+    /// Returns the runtime configuration
+    pub fn config(&self) -> &SeedConfig {
+        &self.config
+    }
+
+    /// Returns the counters advanced while delivering messages
+    pub fn counters(&self) -> &DeliveryCounters {
+        &self.counters
+    }
+
+    /// Sends an event to a single subscriber, retrying transient failures
+    ///
+    /// Makes up to `broadcast_retry_attempts` attempts, waiting a linearly
+    /// growing backoff between them. An attempt that takes longer than
+    /// `broadcast_send_timeout` is not retried, the subscriber is considered stuck.
+    ///
+    /// # Arguments
+    /// * `connection` - Subscriber to deliver the event to
+    /// * `message` - Event to deliver
+    ///
+    /// # Returns
+    /// How the delivery ended
+    async fn send_with_retry(
+        &self,
+        connection: Arc<WebSocketConnection>,
+        message: OutcomeMessage,
+    ) -> SendOutcome {
+        let attempts = self.config.broadcast_retry_attempts.max(1);
+
+        for attempt in 1..=attempts {
+            let send = self
+                .messages_repository
+                .new_event_response(connection.clone(), message.clone());
+            let Ok(sent) = tokio::time::timeout(self.config.broadcast_send_timeout, send).await
+            else {
+                warn!(
+                    "Broadcast to connection {} timed out, disconnecting it",
+                    connection.id
+                );
+                return SendOutcome::TimedOut;
+            };
+
+            match sent {
+                Ok(()) => return SendOutcome::Delivered,
+                Err(e) if attempt < attempts => {
+                    warn!(
+                        "Broadcast to connection {} failed (attempt {attempt}/{attempts}): {e}",
+                        connection.id
+                    );
+                    tokio::time::sleep(self.config.broadcast_retry_backoff * attempt as u32).await;
+                }
+                Err(e) => {
+                    error!(
+                        "Error broadcasting event to connection {}: {e}",
+                        connection.id
+                    );
+                }
+            }
+        }
+
+        SendOutcome::Failed
+    }
+
+    /// Delivers an event relayed by another instance of the cluster
+    ///
+    /// Events this instance published itself are skipped, their local
+    /// subscribers were served when the event was broadcast.
+    ///
+    /// # Arguments
+    /// * `ws` - WebSocketManager instance
+    /// * `event` - Event received from the cluster
+    ///
+    /// # Returns
+    /// A [`BroadcastReport`] describing which local subscribers received the event
+    pub async fn deliver_remote(
+        &self,
+        ws: Arc<WebSocketManager>,
+        event: ClusterEvent,
+    ) -> BroadcastReport {
+        if self
+            .cluster
+            .as_ref()
+            .is_some_and(|link| link.is_own(&event))
+        {
+            return BroadcastReport::default();
+        }
+
+        self.deliver_locally(ws, event.message)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// Sends an event to the connections of this instance subscribed to its chat
+    ///
+    /// # Arguments
+    /// * `ws` - WebSocketManager instance
+    /// * `message` - Event to deliver
+    ///
+    /// # Returns
+    /// A [`BroadcastReport`], or `None` if no connection is subscribed to the chat
+    async fn deliver_locally(
+        &self,
+        ws: Arc<WebSocketManager>,
+        mut message: OutcomeMessage,
+    ) -> Option<BroadcastReport> {
+        // Get all connections subscribed to this chat
+        let connections = ws.subscribers(&message.chat_id)?;
+
+        // Encode the chat ID the way history events do
+        if self.config.canonical_chat_ids
+            && let Some(chat_id) = canonical_base64(&message.chat_id).await
+        {
+            message.chat_id = chat_id;
+        }
+
+        // Create tasks to send the message to each connection
+        let tasks = connections.into_iter().map(|conn| {
+            let message = message.clone();
+            async move {
+                let outcome = self.send_with_retry(conn.clone(), message).await;
+                (conn, outcome)
+            }
+        });
+
+        // Execute the tasks in waves of at most `broadcast_concurrency` and collect the outcome
+        let outcomes: Vec<_> = futures::stream::iter(tasks)
+            .buffer_unordered(self.config.broadcast_concurrency.max(1))
+            .collect()
+            .await;
+
+        let mut report = BroadcastReport::default();
+        for (conn, outcome) in outcomes {
+            if outcome == SendOutcome::Delivered {
+                report.delivered += 1;
+                continue;
+            }
+            if report.failed.len() < self.config.max_reported_failures {
+                report.failed.push(conn.id);
+            } else {
+                report.failed_overflow += 1;
+            }
+            if outcome == SendOutcome::TimedOut {
+                // Stop broadcasting to the stuck session and clean it up in the background
+                conn.mark_closed();
+                report.timed_out.push(conn.id);
+                let use_case = self.clone();
+                let ws = ws.clone();
+                let reason = Some(SeedCloseReason::PolicyViolation);
+                tokio::spawn(async move { use_case.disconnect(ws, conn, reason).await });
+            }
+        }
+        self.counters
+            .broadcast_failures
+            .inc_by(report.failed_count() as u64);
+
+        Some(report)
+    }
+
+    /// Creates a message queue for a chat
+    ///
+    /// The queue holds at most `chat_queue_capacity` messages if set.
+    pub fn chat_queue(
+        &self,
+    ) -> (
+        flume::Sender<ConnectedMessage>,
+        flume::Receiver<ConnectedMessage>,
+    ) {
+        match self.config.chat_queue_capacity {
+            Some(capacity) => flume::bounded(capacity),
+            None => flume::unbounded(),
         }
     }
 
     /// Starts a message processor for a specific chat
     ///
-    /// This function sets up a message queue for a chat and processes incoming messages,
-    /// persisting them to the message repository.
+    /// This function sets up a message queue for a chat, unless one was already
+    /// registered, and processes incoming messages, persisting them to the message
+    /// repository and broadcasting every stored message to the chat's subscribers.
+    /// In `live_first` mode subscribers already received the message before it was
+    /// queued, so it is only persisted. After every `processor_yield_every`
+    /// messages the processor yields, so a burst on one chat does not starve others.
+    ///
+    /// A panic while handling a message is caught and logged. With
+    /// `restart_panicked_processor` the processor carries on with the next
+    /// message, otherwise the chat is closed and its subscribers are notified.
     ///
     /// # Arguments
     /// * `ws` - WebSocketManager instance
     /// * `chat_id` - ID of the chat to process messages for
     pub async fn start_message_processor(&self, ws: Arc<WebSocketManager>, chat_id: &str) {
         let chat_id = chat_id.to_string();
-        // Create unbounded channel for message queue
-        let (sender, reciever) = flume::unbounded();
+        // Create the message queue
         ws.message_queues
-            .insert(chat_id.clone(), (sender, reciever.clone()));
+            .entry(chat_id.clone())
+            .or_insert_with(|| self.chat_queue());
 
-        // Process each message in the queue
-        match ws.message_queues.get(&chat_id) {
-            Some(reciever) => {
-                for event in reciever.1.iter() {
-                    let message = match event.message {
-                        IncomeMessage::Send(msg) => msg,
-                        IncomeMessage::Subscribe(msg) => msg,
-                        IncomeMessage::Unsubscribe(msg) => msg,
-                        _ => continue, // Skip other message types
-                    };
-                    // Persist the message to the repository
-                    let _ = self
-                        .messages_repository
-                        .insert_message(message)
-                        .await
-                        .inspect_err(|e| error!("Error inserting message: {e}"));
-                }
+        // Don't hold the map entry while waiting for messages
+        let reciever = ws.message_queues.get(&chat_id).map(|queue| queue.1.clone());
 
-                info!("All users have unsubscribed from chat {chat_id}");
-            }
+        // Process each message in the queue
+        match reciever {
+            Some(reciever) => self.process_queue(ws, &chat_id, reciever).await,
             None => {
                 error!("Failed to start message processor for chat {chat_id}: channel not found")
             }
         }
     }
 
+    /// Processes the messages of a chat queue until every sender is gone
+    ///
+    /// Messages still buffered when the queue is released are persisted before
+    /// the processor stops.
+    ///
+    /// # Arguments
+    /// * `ws` - WebSocketManager instance
+    /// * `chat_id` - ID of the chat the queue belongs to
+    /// * `reciever` - Receiving end of the chat queue
+    async fn process_queue(
+        &self,
+        ws: Arc<WebSocketManager>,
+        chat_id: &str,
+        reciever: flume::Receiver<ConnectedMessage>,
+    ) {
+        let yield_every = self.config.processor_yield_every.max(1);
+        let mut drained = 0;
+        while let Ok(event) = reciever.recv_async().await {
+            drained += 1;
+            if drained % yield_every == 0 {
+                tokio::task::yield_now().await;
+            }
+
+            let message = match &event.message {
+                IncomeMessage::Send(msg) => msg.clone(),
+                IncomeMessage::Unsubscribe(msg) => msg.clone(),
+                _ => continue, // Skip other message types
+            };
+            // Persist the message to the repository, surviving a panic while doing so
+            let insert = self.messages_repository.insert_message(message);
+            match AssertUnwindSafe(insert).catch_unwind().await {
+                Ok(Ok(InsertOutcome::Duplicate)) => {
+                    debug!("Skipping retried message already stored in chat {chat_id}");
+                }
+                Ok(Ok(InsertOutcome::Inserted(nonce))) => {
+                    self.counters.persisted.inc();
+                    // Only stored messages reach the subscribers, unless they
+                    // already received it live
+                    if !self.config.live_first {
+                        // Subscribers see the nonce the store picked, if it picked one
+                        let mut message = event.message;
+                        if let IncomeMessage::Send(msg) = &mut message {
+                            msg.nonce = nonce;
+                        }
+                        let report = self.broadcast_event(ws.clone(), message).await;
+                        debug!(
+                            "Delivered message to {} subscribers of chat {chat_id}, {} failed",
+                            report.delivered,
+                            report.failed_count()
+                        );
+                    }
+                }
+                Ok(Err(e)) => error!("Error inserting message: {e}"),
+                Err(panic) if self.config.restart_panicked_processor => {
+                    error!(
+                        "Message processor for chat {chat_id} panicked, skipping the message: {}",
+                        panic_message(&panic)
+                    );
+                }
+                Err(panic) => {
+                    error!(
+                        "Message processor for chat {chat_id} panicked, closing the chat: {}",
+                        panic_message(&panic)
+                    );
+                    self.close_chat(ws.clone(), chat_id).await;
+                    return;
+                }
+            }
+        }
+
+        info!("All users have unsubscribed from chat {chat_id}");
+    }
+
+    /// Spawns a background task that periodically removes stale subscribers
+    ///
+    /// The scan interval is taken from `subscriber_reconcile_interval`.
+    ///
+    /// # Arguments
+    /// * `ws` - WebSocketManager instance
+    pub fn spawn_subscriber_reconciliation(&self, ws: Arc<WebSocketManager>) -> JoinHandle<()> {
+        let period = self.config.subscriber_reconcile_interval;
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let removed = ws.reconcile_subscribers();
+                if removed > 0 {
+                    info!("Removed {removed} stale subscriptions");
+                }
+                debug!(
+                    "{} active connections in {} active chats",
+                    ws.active_connections(),
+                    ws.active_chats()
+                );
+            }
+        })
+    }
+
     /// Subscribes a connection to a chat
     ///
     /// # Arguments
     /// * `ws` - WebSocketManager instance
     /// * `connection` - Connection to subscribe
     /// * `chat_id` - ID of the chat to subscribe to
+    ///
+    /// # Errors
+    /// Returns [`SeedError::ChatFull`] if the chat has reached `max_subscribers_per_chat`
+    /// and [`SeedError::DuplicateConnectionId`] if another connection is registered under
+    /// the same id
     async fn subscribe_to_chat(
         &self,
         ws: Arc<WebSocketManager>,
         connection: Arc<WebSocketConnection>,
         chat_id: &str,
-    ) {
-        // Add connection to connection map
-        ws.connections.entry(connection).or_default();
-        // Add chat to chat map
-        ws.chats.entry(chat_id.to_string()).or_default();
+    ) -> Result<(), SeedError> {
+        // Never add chats to the entry of a different connection sharing the id
+        if ws.id_clashes(&connection) {
+            error!(
+                "Connection id {} is already used by another connection",
+                connection.id
+            );
+            return Err(SeedError::DuplicateConnectionId);
+        }
 
-        // Start message processor if it doesn't exist for this chat
-        if !ws.message_queues.contains_key(chat_id) {
-            self.start_message_processor(ws, chat_id).await;
+        // Add connection to chat's subscribers unless the chat is full
+        if !ws.add_subscription(&connection, chat_id, self.config.max_subscribers_per_chat) {
+            warn!("Chat {chat_id} has reached its subscriber limit");
+            return Err(SeedError::ChatFull);
         }
+
+        // Start message processor if it doesn't exist for this chat, a pure
+        // relay has nothing to persist. The queue is registered before the
+        // processor runs so messages sent right after subscribing are queued.
+        // The receiver is handed over directly so buffered messages are still
+        // processed if the queue is released before the processor starts.
+        let mut reciever = None;
+        if self.config.history_enabled {
+            ws.message_queues
+                .entry(chat_id.to_string())
+                .or_insert_with(|| {
+                    let queue = self.chat_queue();
+                    reciever = Some(queue.1.clone());
+                    queue
+                });
+        }
+        if let Some(reciever) = reciever {
+            let processor = self.clone();
+            let chat_id = chat_id.to_string();
+            tokio::spawn(async move { processor.process_queue(ws, &chat_id, reciever).await });
+        }
+
+        Ok(())
     }
 
     /// Unsubscribes a connection from a chat
@@ -112,42 +483,94 @@ impl<T: MessagesRepository> WebSocketUseCase<T> {
         connection: Arc<WebSocketConnection>,
         chat_id: String,
     ) {
-        // Remove chat from connection's subscribed chats
-        if let Some(conn) = ws.connections.get_mut(&connection) {
-            conn.remove(&chat_id);
+        ws.remove_subscription(&connection, &chat_id);
 
-            // Remove connection entirely if it's not subscribed to any chats
-            if conn.is_empty() {
-                ws.connections.remove(&connection);
-            }
+        // The processor drains what is still buffered once the queue is gone
+        if ws.release_idle_queue(&chat_id) {
+            debug!("Released message queue of chat {chat_id}");
         }
+    }
 
-        // Remove connection from chat's subscribers
-        if let Some(chats) = ws.chats.get_mut(&chat_id) {
-            chats.remove(&connection);
-
-            // Remove chat entirely if it has no subscribers
-            if chats.is_empty() {
-                ws.chats.remove(&chat_id);
-            }
+    /// Logs a connection out
+    ///
+    /// Unsubscribes the connection from every chat, forgets its cursors so
+    /// nothing is left to resume, removes it from the manager and sends a
+    /// normal close frame.
+    ///
+    /// # Arguments
+    /// * `ws` - WebSocketManager instance
+    /// * `connection` - Connection that logs out
+    ///
+    /// # Returns
+    /// The chats the connection was subscribed to
+    pub async fn logout(
+        &self,
+        ws: Arc<WebSocketManager>,
+        connection: Arc<WebSocketConnection>,
+    ) -> Vec<String> {
+        let chat_ids = ws.subscriptions(&connection);
+        for chat_id in &chat_ids {
+            self.unsubscribe_from_chat(ws.clone(), connection.clone(), chat_id.clone())
+                .await;
         }
+        connection.forget_subscriptions();
+        ws.remove_connection(&connection);
+
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: DisconnectReason::Logout.as_str().into(),
+        };
+        let _ = connection
+            .session
+            .lock()
+            .await
+            .send(Message::Close(Some(frame)))
+            .await
+            .map_err(|e| error!("Error sending close frame: {e}"));
+
+        chat_ids
     }
 }
 
-impl<T: MessagesRepository> WebsocketRepository for WebSocketUseCase<T> {
+impl<T: MessagesRepository + Clone + 'static> WebsocketRepository for WebSocketUseCase<T> {
     /// Handles subscription requests to a chat
     ///
+    /// When a subscription secret is configured, the request must carry a
+    /// token signed with it for the chat.
+    ///
     /// # Arguments
     /// * `ws` - WebSocketManager instance
     /// * `connection` - Connection requesting subscription
     /// * `chat_id` - ID of the chat to subscribe to
+    /// * `token` - Subscription token sent with the request, if any
     async fn handle_subscribe(
         &self,
         ws: Arc<WebSocketManager>,
         connection: Arc<WebSocketConnection>,
         chat_id: &str,
-    ) {
-        self.subscribe_to_chat(ws, connection, chat_id).await;
+        token: Option<&str>,
+    ) -> Result<(), SeedError> {
+        if let Some(secret) = &self.config.subscription_secret {
+            let authorized = match token {
+                Some(token) => secret.verify(chat_id, token).await,
+                None => false,
+            };
+            if !authorized {
+                return Err(SeedError::InvalidToken);
+            }
+        }
+
+        // Subscribing again to a chat the connection already has takes no new slot
+        let limit = self.config.max_subscriptions_per_connection;
+        if ws.subscription_count(&connection) >= limit && !ws.is_subscribed(&connection, chat_id) {
+            warn!(
+                "Connection {} has reached its limit of {limit} subscriptions",
+                connection.id
+            );
+            return Err(SeedError::TooManySubscriptions);
+        }
+
+        self.subscribe_to_chat(ws, connection, chat_id).await
     }
 
     /// Handles unsubscription requests from a chat
@@ -168,73 +591,1064 @@ impl<T: MessagesRepository> WebsocketRepository for WebSocketUseCase<T> {
 
     /// Broadcasts an event to all connections subscribed to a chat
     ///
+    /// Sends run with at most `broadcast_concurrency` subscribers in flight, and
+    /// failed sends are retried per subscriber before being counted as failed.
+    /// In cluster mode the event is also relayed to the other instances,
+    /// whether or not this instance has subscribers for the chat.
+    ///
     /// # Arguments
     /// * `ws` - WebSocketManager instance
     /// * `message` - Message to broadcast
+    ///
+    /// # Returns
+    /// A [`BroadcastReport`] describing which local subscribers received the event
     async fn broadcast_event(
         &self,
         ws: Arc<WebSocketManager>,
         message: protocol::entity::message::IncomeMessage,
-    ) {
+    ) -> BroadcastReport {
         // Convert incoming message to outgoing format
         let message: OutcomeMessage = message.into();
 
-        // Get all connections subscribed to this chat
-        let connections = match ws.chats.get(&message.chat_id) {
-            Some(chats) => chats,
+        if let Some(cluster) = &self.cluster {
+            cluster.publish(&message);
+        }
+
+        match self.deliver_locally(ws, message.clone()).await {
+            Some(report) => report,
             None => {
-                error!(
-                    "Error broadcasting event to chat {}: Chat not found",
-                    message.chat_id
-                );
-                return;
+                // Subscribers on other instances are not known here
+                if self.cluster.is_none() {
+                    error!(
+                        "Error broadcasting event to chat {}: Chat not found",
+                        message.chat_id
+                    );
+                }
+                BroadcastReport::default()
             }
-        };
+        }
+    }
 
-        // Create tasks to send the message to each connection
-        let tasks = connections.iter().map(|conn| {
+    /// Closes a chat and tears down its subscriptions
+    ///
+    /// Every current subscriber receives a chat closed notification before
+    /// the chat is removed from the manager, so clients stop waiting for events.
+    ///
+    /// # Arguments
+    /// * `ws` - WebSocketManager instance
+    /// * `chat_id` - ID of the chat to close
+    ///
+    /// # Returns
+    /// Number of subscribers that were notified
+    async fn close_chat(&self, ws: Arc<WebSocketManager>, chat_id: &str) -> usize {
+        let subscribers = ws.subscribers(chat_id).unwrap_or_default();
+
+        // Notify subscribers while the chat still exists
+        let notifications = subscribers.iter().map(|conn| {
             self.messages_repository
-                .new_event_response(conn.clone(), message.clone())
+                .chat_closed_response(conn.clone(), chat_id)
         });
+        let notified = futures::future::join_all(notifications)
+            .await
+            .into_iter()
+            .filter(|result| {
+                result
+                    .as_ref()
+                    .inspect_err(|e| {
+                        warn!("Failed to notify subscriber of closed chat {chat_id}: {e}")
+                    })
+                    .is_ok()
+            })
+            .count();
 
-        // Execute all tasks concurrently
-        let results = futures::future::join_all(tasks).await;
-        for result in results {
-            if let Err(e) = result {
-                log::error!("Error broadcasting event: {}", e);
-            }
+        // Tear the chat down
+        ws.chats.remove(chat_id);
+        ws.message_queues.remove(chat_id);
+        for connection in subscribers {
+            connection.forget_subscription(chat_id);
+            ws.remove_subscription(&connection, chat_id);
         }
+
+        info!("Closed chat {chat_id}, notified {notified} subscribers");
+        notified
     }
 
     /// Handles disconnection of a client
     ///
-    /// Closes the connection and removes it from all subscribed chats.
+    /// Closes the connection and removes it from all subscribed chats. The close
+    /// handshake is abandoned after `close_timeout` so a wedged peer cannot delay
+    /// the cleanup.
     ///
     /// # Arguments
     /// * `ws` - WebSocketManager instance
     /// * `connection` - Connection that is disconnecting
-    async fn disconnect(&self, ws: Arc<WebSocketManager>, connection: Arc<WebSocketConnection>) {
-        // Close the WebSocket session
-        let _ = connection
-            .session
-            .lock()
+    /// * `reason` - Reason sent in the close frame, if any
+    async fn disconnect(
+        &self,
+        ws: Arc<WebSocketManager>,
+        connection: Arc<WebSocketConnection>,
+        reason: Option<SeedCloseReason>,
+    ) {
+        // Stop treating the connection as a live subscriber
+        connection.mark_closed();
+
+        // Close the WebSocket session, giving up if the peer does not respond in time
+        let close = async {
+            let mut session = connection.session.lock().await;
+            match reason {
+                Some(reason) => session.send(Message::Close(Some(reason.into()))).await,
+                None => session.close().await,
+            }
+        };
+        match tokio::time::timeout(self.config.close_timeout, close).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Error closing WebSocket session: {e}"),
+            Err(_) => warn!(
+                "Closing WebSocket session {} timed out, cleaning up anyway",
+                connection.id
+            ),
+        }
+
+        // Unsubscribe from all chats this connection was subscribed to
+        let chat_ids = ws.subscriptions(&connection);
+        let handles = chat_ids
+            .into_iter()
+            .map(|id| self.unsubscribe_from_chat(ws.clone(), connection.clone(), id))
+            .collect::<Vec<_>>();
+
+        // Wait for all unsubscribe operations to complete
+        futures::future::join_all(handles).await;
+
+        // Remove the connection completely
+        ws.remove_connection(&connection);
+    }
+}
+
+/// Returns the message a panic was raised with, if it carries one
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use futures::{Sink, channel::mpsc};
+    use misc::base64::encode_base64;
+    use protocol::entity::message::Message;
+    use tokio_tungstenite::tungstenite::{self, Error as WsError};
+
+    use super::*;
+    use crate::{
+        auth::SubscriptionSecret,
+        messages::MessagesUseCase,
+        test_utils::{MockDb, channel_connection, sent_responses, websocket_use_case},
+    };
+
+    /// Session sink that fails a fixed number of sends before forwarding frames
+    struct FlakySink {
+        failures_left: usize,
+        tx: mpsc::UnboundedSender<tungstenite::Message>,
+    }
+
+    impl Sink<tungstenite::Message> for FlakySink {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: tungstenite::Message) -> Result<(), WsError> {
+            if self.failures_left > 0 {
+                self.failures_left -= 1;
+                return Err(WsError::ConnectionClosed);
+            }
+            self.tx
+                .unbounded_send(item)
+                .map_err(|_| WsError::ConnectionClosed)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Session sink whose close never completes, like a peer with a wedged TCP connection
+    struct WedgedSink;
+
+    impl Sink<tungstenite::Message> for WedgedSink {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _item: tungstenite::Message) -> Result<(), WsError> {
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Pending
+        }
+    }
+
+    /// Session sink that never accepts a frame, like a client that stopped reading
+    struct HangingSink;
+
+    impl Sink<tungstenite::Message> for HangingSink {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Pending
+        }
+
+        fn start_send(self: Pin<&mut Self>, _item: tungstenite::Message) -> Result<(), WsError> {
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Pending
+        }
+    }
+
+    /// Session sink that counts delivered frames and frames sent after it was closed
+    ///
+    /// Each flush yields once before completing so a replay can be interrupted.
+    struct ClosableSink {
+        delivered: Arc<AtomicUsize>,
+        sent_after_close: Arc<AtomicUsize>,
+        closed: bool,
+        flushing: bool,
+    }
+
+    impl Sink<tungstenite::Message> for ClosableSink {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            _item: tungstenite::Message,
+        ) -> Result<(), WsError> {
+            if self.closed {
+                self.sent_after_close.fetch_add(1, Ordering::SeqCst);
+                return Err(WsError::AlreadyClosed);
+            }
+            self.flushing = true;
+            Ok(())
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            if self.flushing {
+                self.flushing = false;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.delivered.fetch_add(1, Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), WsError>> {
+            self.closed = true;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Session sink that records how many sends are in flight at once
+    ///
+    /// Each flush yields once before completing so concurrent sends overlap.
+    struct GaugeSink {
+        in_flight: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+        delivered: Arc<AtomicUsize>,
+        flushing: bool,
+    }
+
+    impl Sink<tungstenite::Message> for GaugeSink {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            mut self: Pin<&mut Self>,
+            _item: tungstenite::Message,
+        ) -> Result<(), WsError> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(in_flight, Ordering::SeqCst);
+            self.flushing = true;
+            Ok(())
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            if self.flushing {
+                self.flushing = false;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.delivered.fetch_add(1, Ordering::SeqCst);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Tests that a subscriber whose first send fails still receives the event
+    #[tokio::test]
+    async fn test_broadcast_retries_failed_send() {
+        let config = SeedConfig {
+            broadcast_retry_attempts: 3,
+            broadcast_retry_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let use_case = websocket_use_case(MockDb::default(), config).await;
+
+        let (tx, mut rx) = mpsc::unbounded();
+        let sink = FlakySink {
+            failures_left: 1,
+            tx,
+        };
+        let connection = Arc::new(WebSocketConnection::from_parts(
+            Box::pin(sink),
+            Box::pin(futures::stream::pending()),
+        ));
+
+        let ws = Arc::new(WebSocketManager::default());
+        ws.add_subscription(&connection, "chat", usize::MAX);
+
+        let message = IncomeMessage::Send(Message {
+            chat_id: "chat".to_string(),
+            ..Default::default()
+        });
+        let report = use_case.broadcast_event(ws, message).await;
+
+        assert_eq!(report.delivered, 1);
+        assert!(report.failed.is_empty());
+        assert!(matches!(rx.try_recv(), Ok(tungstenite::Message::Text(_))));
+    }
+
+    /// Tests that chat queues are bounded only when a capacity is configured
+    #[tokio::test]
+    async fn test_chat_queue_capacity() {
+        for (capacity, expected) in [(None, None), (Some(1), Some(1))] {
+            let config = SeedConfig {
+                chat_queue_capacity: capacity,
+                ..Default::default()
+            };
+            let use_case = websocket_use_case(MockDb::default(), config).await;
+
+            let (sender, _receiver) = use_case.chat_queue();
+            assert_eq!(sender.capacity(), expected);
+        }
+    }
+
+    /// Tests that a burst on one chat does not keep another chat's processor from progressing
+    #[tokio::test]
+    async fn test_processor_burst_does_not_starve_other_chats() {
+        const BURST: usize = 5_000;
+        let config = SeedConfig {
+            processor_yield_every: 16,
+            ..Default::default()
+        };
+        let db = MockDb::default();
+        let use_case = websocket_use_case(db.clone(), config).await;
+        let ws = Arc::new(WebSocketManager::default());
+
+        for chat_id in ["busy", "quiet"] {
+            let (use_case, ws) = (use_case.clone(), ws.clone());
+            tokio::spawn(async move { use_case.start_message_processor(ws, chat_id).await });
+        }
+        while ws.message_queues.len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let (connection, _rx) = channel_connection();
+        let enqueue = |chat_id: &str, nonce: usize| {
+            let queue = ws.message_queues.get(chat_id).unwrap();
+            let message = IncomeMessage::Send(Message {
+                nonce,
+                chat_id: chat_id.to_string(),
+                ..Default::default()
+            });
+            queue
+                .0
+                .send(ConnectedMessage {
+                    connection: connection.clone(),
+                    message,
+                })
+                .unwrap();
+        };
+        for nonce in 1..=BURST {
+            enqueue("busy", nonce);
+        }
+        enqueue("quiet", 1);
+
+        let stored = |chat_id: &str| {
+            let messages = db.messages.lock().unwrap();
+            messages.iter().filter(|m| m.chat_id == chat_id).count()
+        };
+        while stored("quiet") == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(stored("busy") < BURST);
+        while stored("busy") < BURST {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Starts a processor for `chat` with one subscriber, the first insert of which panics
+    async fn panicking_processor(
+        restart: bool,
+    ) -> (
+        MockDb,
+        Arc<WebSocketManager>,
+        Arc<WebSocketConnection>,
+        mpsc::UnboundedReceiver<tungstenite::Message>,
+        JoinHandle<()>,
+    ) {
+        let config = SeedConfig {
+            restart_panicked_processor: restart,
+            ..Default::default()
+        };
+        let db = MockDb::default();
+        db.insert_panics.store(1, Ordering::SeqCst);
+        let use_case = websocket_use_case(db.clone(), config).await;
+        let ws = Arc::new(WebSocketManager::default());
+
+        let (connection, rx) = channel_connection();
+        ws.add_subscription(&connection, "chat", usize::MAX);
+
+        let processor = {
+            let ws = ws.clone();
+            tokio::spawn(async move { use_case.start_message_processor(ws, "chat").await })
+        };
+        while !ws.message_queues.contains_key("chat") {
+            tokio::task::yield_now().await;
+        }
+
+        (db, ws, connection, rx, processor)
+    }
+
+    /// Queues a message with the given nonce for `chat`
+    fn enqueue(ws: &WebSocketManager, connection: &Arc<WebSocketConnection>, nonce: usize) {
+        let message = IncomeMessage::Send(Message {
+            nonce,
+            chat_id: "chat".to_string(),
+            ..Default::default()
+        });
+        ws.message_queues
+            .get("chat")
+            .unwrap()
+            .0
+            .send(ConnectedMessage {
+                connection: connection.clone(),
+                message,
+            })
+            .unwrap();
+    }
+
+    /// Tests that a panicking processor closes its chat and notifies the subscribers
+    #[tokio::test]
+    async fn test_processor_panic_closes_chat() {
+        let (_db, ws, connection, mut rx, processor) = panicking_processor(false).await;
+
+        enqueue(&ws, &connection, 1);
+        processor.await.unwrap();
+
+        assert!(ws.chats.is_empty());
+        assert!(ws.message_queues.is_empty());
+        let responses = sent_responses(&mut rx);
+        assert_eq!(responses[0]["type"], "chatClosed");
+        assert_eq!(responses[0]["response"]["queueId"], "chat");
+    }
+
+    /// Tests that a restarting processor skips the message that panicked and keeps going
+    #[tokio::test]
+    async fn test_processor_recovers_from_panic() {
+        let (db, ws, connection, mut rx, processor) = panicking_processor(true).await;
+
+        enqueue(&ws, &connection, 1);
+        enqueue(&ws, &connection, 1);
+        while db.messages.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(!processor.is_finished());
+        assert!(ws.chats.contains_key("chat"));
+        // Only the stored message reaches the subscriber
+        let mut responses = Vec::new();
+        while responses.is_empty() {
+            tokio::task::yield_now().await;
+            responses = sent_responses(&mut rx);
+        }
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["response"]["type"], "new");
+    }
+
+    /// Tests that a large chat is fanned out with a bounded number of sends in flight
+    #[tokio::test]
+    async fn test_broadcast_respects_concurrency_limit() {
+        let config = SeedConfig {
+            broadcast_concurrency: 4,
+            ..Default::default()
+        };
+        let use_case = websocket_use_case(MockDb::default(), config).await;
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let delivered = Arc::new(AtomicUsize::new(0));
+
+        let ws = Arc::new(WebSocketManager::default());
+        for _ in 0..50 {
+            let sink = GaugeSink {
+                in_flight: in_flight.clone(),
+                peak: peak.clone(),
+                delivered: delivered.clone(),
+                flushing: false,
+            };
+            let connection = Arc::new(WebSocketConnection::from_parts(
+                Box::pin(sink),
+                Box::pin(futures::stream::pending()),
+            ));
+            ws.add_subscription(&connection, "chat", usize::MAX);
+        }
+
+        let message = IncomeMessage::Send(Message {
+            chat_id: "chat".to_string(),
+            ..Default::default()
+        });
+        let report = use_case.broadcast_event(ws, message).await;
+
+        assert_eq!(report.delivered, 50);
+        assert_eq!(delivered.load(Ordering::SeqCst), 50);
+        assert!(peak.load(Ordering::SeqCst) <= 4);
+        assert!(peak.load(Ordering::SeqCst) > 1);
+    }
+
+    /// Tests that the failed list of a broadcast report is capped and the rest are counted
+    #[tokio::test]
+    async fn test_broadcast_report_caps_failures() {
+        let config = SeedConfig {
+            broadcast_retry_attempts: 1,
+            max_reported_failures: 10,
+            ..Default::default()
+        };
+        let use_case = websocket_use_case(MockDb::default(), config).await;
+
+        let ws = Arc::new(WebSocketManager::default());
+        let mut receivers = Vec::new();
+        for _ in 0..30 {
+            let (tx, rx) = mpsc::unbounded();
+            receivers.push(rx);
+            let sink = FlakySink {
+                failures_left: usize::MAX,
+                tx,
+            };
+            let connection = Arc::new(WebSocketConnection::from_parts(
+                Box::pin(sink),
+                Box::pin(futures::stream::pending()),
+            ));
+            ws.add_subscription(&connection, "chat", usize::MAX);
+        }
+        for _ in 0..5 {
+            let (connection, rx) = channel_connection();
+            receivers.push(rx);
+            ws.add_subscription(&connection, "chat", usize::MAX);
+        }
+
+        let message = IncomeMessage::Send(Message {
+            chat_id: "chat".to_string(),
+            ..Default::default()
+        });
+        let report = use_case.broadcast_event(ws, message).await;
+
+        assert_eq!(report.delivered, 5);
+        assert_eq!(report.failed.len(), 10);
+        assert_eq!(report.failed_overflow, 20);
+        assert_eq!(report.failed_count(), 30);
+    }
+
+    /// Tests that closing a chat notifies each subscriber before removing the chat
+    #[tokio::test]
+    async fn test_close_chat_notifies_subscribers() {
+        let use_case = websocket_use_case(MockDb::default(), SeedConfig::default()).await;
+        let ws = Arc::new(WebSocketManager::default());
+
+        let mut receivers = Vec::new();
+        for _ in 0..2 {
+            let (connection, rx) = channel_connection();
+            ws.add_subscription(&connection, "chat", usize::MAX);
+            receivers.push(rx);
+        }
+
+        let notified = use_case.close_chat(ws.clone(), "chat").await;
+
+        assert_eq!(notified, 2);
+        for mut rx in receivers {
+            let responses = sent_responses(&mut rx);
+            assert_eq!(responses.len(), 1);
+            assert_eq!(responses[0]["type"], "chatClosed");
+            assert_eq!(responses[0]["response"]["queueId"], "chat");
+        }
+        assert!(ws.chats.is_empty());
+        assert!(ws.connections.is_empty());
+    }
+
+    /// Tests that a disconnect cleans up even when the session close never resolves
+    #[tokio::test]
+    async fn test_disconnect_cleans_up_when_close_hangs() {
+        let config = SeedConfig {
+            close_timeout: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let use_case = websocket_use_case(MockDb::default(), config).await;
+        let connection = Arc::new(WebSocketConnection::from_parts(
+            Box::pin(WedgedSink),
+            Box::pin(futures::stream::pending()),
+        ));
+
+        let ws = Arc::new(WebSocketManager::default());
+        ws.add_subscription(&connection, "chat", usize::MAX);
+
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            use_case.disconnect(ws.clone(), connection, None),
+        )
+        .await
+        .unwrap();
+
+        assert!(ws.connections.is_empty());
+        assert!(
+            ws.chats
+                .get("chat")
+                .is_none_or(|subscribers| subscribers.is_empty())
+        );
+    }
+
+    /// Tests that disconnecting mid-replay stops the replay without sending into the closed session
+    #[tokio::test]
+    async fn test_disconnect_stops_history_replay() {
+        const STORED: usize = 250;
+        let chat_id = [4; 32];
+        let nonces: Vec<_> = (1..=STORED).collect();
+        let db = MockDb::with_history(&encode_base64(&chat_id).await, &nonces);
+        let use_case = websocket_use_case(db, SeedConfig::default()).await;
+        let messages = use_case.messages_repository.clone();
+
+        let delivered = Arc::new(AtomicUsize::new(0));
+        let sent_after_close = Arc::new(AtomicUsize::new(0));
+        let sink = ClosableSink {
+            delivered: delivered.clone(),
+            sent_after_close: sent_after_close.clone(),
+            closed: false,
+            flushing: false,
+        };
+        let connection = Arc::new(WebSocketConnection::from_parts(
+            Box::pin(sink),
+            Box::pin(futures::stream::pending()),
+        ));
+
+        let replay = tokio::spawn({
+            let connection = connection.clone();
+            async move {
+                messages
+                    .unread_message_response(connection, &chat_id, 1, None)
+                    .await
+            }
+        });
+        while delivered.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+        let ws = Arc::new(WebSocketManager::default());
+        use_case.disconnect(ws, connection, None).await;
+
+        tokio::time::timeout(Duration::from_secs(1), replay)
             .await
-            .close(None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(sent_after_close.load(Ordering::SeqCst), 0);
+        assert!(delivered.load(Ordering::SeqCst) < STORED);
+    }
+
+    /// Tests that the active counts track subscribes, unsubscribes and disconnects
+    #[tokio::test]
+    async fn test_active_counts_track_subscriptions() {
+        let config = SeedConfig {
+            history_enabled: false,
+            ..Default::default()
+        };
+        let use_case = websocket_use_case(MockDb::default(), config).await;
+        let ws = Arc::new(WebSocketManager::default());
+        let (alice, _alice_rx) = channel_connection();
+        let (bob, _bob_rx) = channel_connection();
+        let counts = |ws: &WebSocketManager| (ws.active_connections(), ws.active_chats());
+
+        assert_eq!(counts(&ws), (0, 0));
+        for (connection, chat_id) in [(&alice, "a"), (&alice, "b"), (&bob, "b"), (&bob, "c")] {
+            use_case
+                .handle_subscribe(ws.clone(), connection.clone(), chat_id, None)
+                .await
+                .unwrap();
+        }
+        assert_eq!(counts(&ws), (2, 3));
+
+        use_case
+            .handle_unsubscribe(ws.clone(), bob.clone(), "c")
+            .await;
+        assert_eq!(counts(&ws), (2, 2));
+
+        use_case.disconnect(ws.clone(), alice, None).await;
+        assert_eq!(counts(&ws), (1, 1));
+
+        use_case.disconnect(ws.clone(), bob, None).await;
+        assert_eq!(counts(&ws), (0, 0));
+    }
+
+    /// Tests that the last unsubscribe releases the chat queue after persisting what it buffered
+    #[tokio::test]
+    async fn test_unsubscribe_drains_and_releases_queue() {
+        let db = MockDb::default();
+        let use_case = websocket_use_case(db.clone(), SeedConfig::default()).await;
+        let ws = Arc::new(WebSocketManager::default());
+        let (connection, _rx) = channel_connection();
+        use_case
+            .handle_subscribe(ws.clone(), connection.clone(), "chat", None)
             .await
-            .map_err(|e| log::error!("Error closing WebSocket session: {}", e));
+            .unwrap();
 
-        // Unsubscribe from all chats this connection was subscribed to
-        if let Some(chat_id) = ws.connections.get(&connection) {
-            let handles = chat_id
-                .iter()
-                .map(|id| self.unsubscribe_from_chat(ws.clone(), connection.clone(), id.to_owned()))
-                .collect::<Vec<_>>();
+        let message = IncomeMessage::Send(Message {
+            nonce: 1,
+            chat_id: "chat".to_string(),
+            ..Default::default()
+        });
+        ws.message_queues
+            .get("chat")
+            .unwrap()
+            .0
+            .send(ConnectedMessage {
+                connection: connection.clone(),
+                message,
+            })
+            .unwrap();
 
-            // Wait for all unsubscribe operations to complete
-            futures::future::join_all(handles).await;
+        use_case
+            .handle_unsubscribe(ws.clone(), connection, "chat")
+            .await;
+        assert!(!ws.message_queues.contains_key("chat"));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while db.messages.lock().unwrap().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(db.messages.lock().unwrap()[0].nonce, 1);
+    }
+
+    /// Tests that subscribe, broadcast and disconnect keep the keyed maps and side tables in step
+    #[tokio::test]
+    async fn test_subscribe_broadcast_disconnect_round_trip() {
+        let config = SeedConfig {
+            history_enabled: false,
+            ..Default::default()
+        };
+        let use_case = websocket_use_case(MockDb::default(), config).await;
+        let ws = Arc::new(WebSocketManager::default());
+        let (alice, mut alice_rx) = channel_connection();
+        let (bob, mut bob_rx) = channel_connection();
+        for (connection, chat_id) in [(&alice, "a"), (&alice, "b"), (&bob, "b")] {
+            use_case
+                .handle_subscribe(ws.clone(), connection.clone(), chat_id, None)
+                .await
+                .unwrap();
         }
+        assert_eq!((ws.handles.len(), ws.ids.len()), (2, 2));
 
-        // Remove the connection completely
-        ws.connections.remove(&connection);
+        let broadcast = async |chat_id: &str| {
+            let message = IncomeMessage::Send(Message {
+                chat_id: chat_id.to_string(),
+                ..Default::default()
+            });
+            use_case.broadcast_event(ws.clone(), message).await
+        };
+        assert_eq!(broadcast("b").await.delivered, 2);
+        assert_eq!(broadcast("a").await.delivered, 1);
+        assert_eq!(sent_responses(&mut alice_rx).len(), 2);
+        assert_eq!(sent_responses(&mut bob_rx).len(), 1);
+
+        use_case.disconnect(ws.clone(), alice.clone(), None).await;
+        assert!(!ws.is_subscribed(&alice, "b"));
+        let subscribers = ws.subscribers("b").unwrap();
+        assert!(subscribers.len() == 1 && Arc::ptr_eq(&subscribers[0], &bob));
+        assert!(ws.subscribers("a").is_none());
+        assert_eq!(broadcast("b").await.delivered, 1);
+
+        use_case.disconnect(ws.clone(), bob, None).await;
+        assert!(ws.connections.is_empty() && ws.chats.is_empty());
+        assert!(ws.handles.is_empty() && ws.ids.is_empty());
+    }
+
+    /// Tests that a connection sharing a registered id cannot subscribe through the other's entry
+    #[tokio::test]
+    async fn test_subscribe_rejects_duplicate_connection_id() {
+        let config = SeedConfig {
+            history_enabled: false,
+            ..Default::default()
+        };
+        let use_case = websocket_use_case(MockDb::default(), config).await;
+        let ws = Arc::new(WebSocketManager::default());
+        let (existing, _existing_rx) = channel_connection();
+        use_case
+            .handle_subscribe(ws.clone(), existing.clone(), "a", None)
+            .await
+            .unwrap();
+
+        let (tx, _rx) = mpsc::unbounded();
+        let sink = tx.sink_map_err(|_| WsError::ConnectionClosed);
+        let mut duplicate =
+            WebSocketConnection::from_parts(Box::pin(sink), Box::pin(futures::stream::pending()));
+        duplicate.id = existing.id;
+
+        let result = use_case
+            .handle_subscribe(ws.clone(), Arc::new(duplicate), "b", None)
+            .await;
+
+        assert!(matches!(result, Err(SeedError::DuplicateConnectionId)));
+        // The collision is the server's fault, not something the client may not do
+        assert_eq!(
+            SeedError::DuplicateConnectionId.code(),
+            protocol::entity::response::SeedErrorCode::Internal
+        );
+        let chats = ws.connections.get(&existing.conn_id()).unwrap();
+        assert!(chats.contains("a") && !chats.contains("b"));
+        assert!(
+            ws.chats
+                .get("b")
+                .is_none_or(|subscribers| subscribers.is_empty())
+        );
+    }
+
+    /// Creates a use case that requires subscription tokens signed with `secret`
+    async fn token_use_case(secret: &str) -> WebSocketUseCase<MessagesUseCase<MockDb>> {
+        let config = SeedConfig {
+            history_enabled: false,
+            subscription_secret: Some(SubscriptionSecret::new(secret)),
+            ..Default::default()
+        };
+        websocket_use_case(MockDb::default(), config).await
+    }
+
+    /// Tests that a token signed with the server secret for the chat allows subscribing
+    #[tokio::test]
+    async fn test_subscribe_with_valid_token() {
+        let use_case = token_use_case("server secret").await;
+        let ws = Arc::new(WebSocketManager::default());
+        let (connection, _rx) = channel_connection();
+        let token = SubscriptionSecret::new("server secret").sign("chat").await;
+
+        use_case
+            .handle_subscribe(ws.clone(), connection.clone(), "chat", Some(&token))
+            .await
+            .unwrap();
+
+        assert!(ws.is_subscribed(&connection, "chat"));
+    }
+
+    /// Tests that forged, misplaced and missing tokens are rejected without subscribing
+    #[tokio::test]
+    async fn test_subscribe_with_forged_token_is_rejected() {
+        let use_case = token_use_case("server secret").await;
+        let ws = Arc::new(WebSocketManager::default());
+        let (connection, _rx) = channel_connection();
+        let forged = SubscriptionSecret::new("guessed secret").sign("chat").await;
+        let other_chat = SubscriptionSecret::new("server secret").sign("other").await;
+
+        for token in [Some(forged.as_str()), Some(&other_chat), Some("%%%"), None] {
+            let result = use_case
+                .handle_subscribe(ws.clone(), connection.clone(), "chat", token)
+                .await;
+            assert!(matches!(result, Err(SeedError::InvalidToken)));
+        }
+        assert!(!ws.is_subscribed(&connection, "chat"));
+    }
+
+    /// Tests that subscribing past the per-connection limit is rejected until a slot frees up
+    #[tokio::test]
+    async fn test_subscriptions_per_connection_are_limited() {
+        let config = SeedConfig {
+            history_enabled: false,
+            max_subscriptions_per_connection: 2,
+            ..Default::default()
+        };
+        let use_case = websocket_use_case(MockDb::default(), config).await;
+        let ws = Arc::new(WebSocketManager::default());
+        let (connection, _rx) = channel_connection();
+        let subscribe =
+            |chat_id| use_case.handle_subscribe(ws.clone(), connection.clone(), chat_id, None);
+
+        subscribe("first").await.unwrap();
+        subscribe("second").await.unwrap();
+        assert!(matches!(
+            subscribe("third").await,
+            Err(SeedError::TooManySubscriptions)
+        ));
+        // Subscribing again to a chat it already has is not a new subscription
+        subscribe("second").await.unwrap();
+
+        use_case
+            .handle_unsubscribe(ws.clone(), connection.clone(), "first")
+            .await;
+        subscribe("third").await.unwrap();
+        assert_eq!(ws.subscription_count(&connection), 2);
+        assert!(!ws.is_subscribed(&connection, "first"));
+    }
+
+    /// Tests that a broadcast on one instance reaches subscribers of another exactly once
+    #[tokio::test]
+    async fn test_cluster_relays_broadcast_once() {
+        let config = SeedConfig {
+            cluster_mode: true,
+            ..Default::default()
+        };
+        let mut instances = Vec::new();
+        let mut relays = Vec::new();
+        for _ in 0..2 {
+            let (link, relay) = ClusterLink::new();
+            let use_case = websocket_use_case(MockDb::default(), config.clone())
+                .await
+                .with_cluster(link);
+            let ws = Arc::new(WebSocketManager::default());
+            let (connection, rx) = channel_connection();
+            ws.add_subscription(&connection, "chat", usize::MAX);
+            instances.push((use_case, ws, rx));
+            relays.push(relay);
+        }
+
+        let (origin, origin_ws, _) = &instances[0];
+        let message = IncomeMessage::Send(Message {
+            chat_id: "chat".to_string(),
+            ..Default::default()
+        });
+        let report = origin.broadcast_event(origin_ws.clone(), message).await;
+        assert_eq!(report.delivered, 1);
+
+        // Like pub/sub, the relay hands the event to every instance, the origin included
+        let event = relays[0].try_recv().unwrap();
+        for (use_case, ws, _) in &instances {
+            use_case.deliver_remote(ws.clone(), event.clone()).await;
+        }
+        assert!(relays[1].is_empty());
+
+        for (_, _, rx) in &mut instances {
+            let events = sent_responses(rx);
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0]["response"]["type"], "new");
+        }
+    }
+
+    /// Tests that a hanging subscriber is disconnected without holding up the others
+    #[tokio::test]
+    async fn test_broadcast_disconnects_hanging_subscriber() {
+        let config = SeedConfig {
+            broadcast_send_timeout: Duration::from_millis(20),
+            close_timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let use_case = websocket_use_case(MockDb::default(), config).await;
+        let ws = Arc::new(WebSocketManager::default());
+
+        let (responsive, mut responsive_rx) = channel_connection();
+        let hanging = Arc::new(WebSocketConnection::from_parts(
+            Box::pin(HangingSink),
+            Box::pin(futures::stream::pending()),
+        ));
+        ws.add_subscription(&responsive, "chat", usize::MAX);
+        ws.add_subscription(&hanging, "chat", usize::MAX);
+
+        let message = IncomeMessage::Send(Message {
+            chat_id: "chat".to_string(),
+            ..Default::default()
+        });
+        let report = tokio::time::timeout(
+            Duration::from_secs(1),
+            use_case.broadcast_event(ws.clone(), message),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.delivered, 1);
+        assert_eq!(report.timed_out, [hanging.id]);
+        assert_eq!(report.failed, [hanging.id]);
+        assert_eq!(sent_responses(&mut responsive_rx).len(), 1);
+        assert!(hanging.is_closed());
+
+        // The stuck session is removed in the background
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while ws.is_subscribed(&hanging, "chat") {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(ws.is_subscribed(&responsive, "chat"));
+    }
+
+    /// Tests that the close reason given to a disconnect reaches the session with its close code
+    #[tokio::test]
+    async fn test_disconnect_sends_close_reason() {
+        let use_case = websocket_use_case(MockDb::default(), SeedConfig::default()).await;
+        let ws = Arc::new(WebSocketManager::default());
+
+        for (reason, code) in [
+            (SeedCloseReason::Normal, 1000),
+            (SeedCloseReason::PolicyViolation, 1008),
+            (SeedCloseReason::ServerError, 1011),
+            (SeedCloseReason::Unauthorized, 4001),
+        ] {
+            let (connection, mut rx) = channel_connection();
+
+            use_case
+                .disconnect(ws.clone(), connection, Some(reason))
+                .await;
+
+            let Ok(tungstenite::Message::Close(Some(frame))) = rx.try_recv() else {
+                panic!("expected a close frame");
+            };
+            assert_eq!(u16::from(frame.code), code);
+            assert_eq!(frame.reason, reason.as_str());
+        }
     }
 }